@@ -22,6 +22,7 @@ pub enum Platform {
     Twitter,
     Facebook,
     WhatsApp,
+    Instagram,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -33,12 +34,27 @@ pub struct AuthConfig {
     redirect_uri: Option<String>,
 }
 
+// One connected account or workspace. A principal can hold several per
+// platform, told apart by their labels.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Connection {
+    id: String,
+    platform: Platform,
+    label: String,
+    created_at: u64,
+    last_synced_at: Option<u64>,
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct Attachment {
     attachment_type: String,
     url: Option<String>,
-    content: Option<Vec<u8>>,
     name: Option<String>,
+    mime_type: Option<String>,
+    size: Option<u64>,
+    // SHA-256 of the content in the blob store. Content is never inlined
+    // in messages; fetch it with get_attachment_chunk or from `url`.
+    blob_hash: Option<String>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -76,6 +92,10 @@ pub struct Conversation {
     participants: Vec<User>,
     created_at: u64,
     last_message_at: Option<u64>,
+    // Connection the conversation was last synced through; None for
+    // conversations stored before connections existed. Conversations are
+    // shared, so filters use each user's own record (storage::connections).
+    connection_id: Option<String>,
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
@@ -84,6 +104,159 @@ pub struct QueryResult {
     context: String,
 }
 
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HighlightRange {
+    start: u64,
+    end: u64,
+}
+
+// Passage of a message's attachment that matched the query
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AttachmentSnippet {
+    name: Option<String>,
+    blob_hash: Option<String>,
+    fragment: String,
+    highlights: Vec<HighlightRange>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SearchHit {
+    message: Message,
+    score: f32,
+    fragment: String,
+    highlights: Vec<HighlightRange>,
+    matched_terms: Vec<String>,
+    attachment_match: Option<AttachmentSnippet>,
+    context_before: Vec<Message>,
+    context_after: Vec<Message>,
+    explanation: Option<indexing::ranking::ScoreBreakdown>,
+    duplicate_ids: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SearchOptions {
+    context_size: Option<u64>,
+    explain: Option<bool>,
+    collapse_duplicates: Option<bool>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SearchResult {
+    hits: Vec<SearchHit>,
+    context: String,
+    suggestion: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SemanticSearchFilters {
+    platform: Option<Platform>,
+    connection_id: Option<String>,
+    conversation_id: Option<String>,
+    start_time: Option<u64>,
+    end_time: Option<u64>,
+}
+
+impl SemanticSearchFilters {
+    // The filters as a predicate for a user, with the connection filter
+    // resolved to the conversations they synced through it
+    fn matcher<'a>(&'a self, user_id: &str) -> impl Fn(&Message) -> bool + 'a {
+        let synced_by = self.connection_id.as_ref()
+            .map(|connection_id| storage::connections::conversation_ids(user_id, connection_id));
+        move |message| self.matches(message, synced_by.as_ref())
+    }
+    
+    fn matches(&self, message: &Message, synced_by: Option<&std::collections::HashSet<String>>) -> bool {
+        if let Some(platform) = &self.platform {
+            if platform_to_string(platform) != platform_to_string(&message.platform) {
+                return false;
+            }
+        }
+        
+        if let Some(synced_by) = synced_by {
+            if !synced_by.contains(&message.conversation_id) {
+                return false;
+            }
+        }
+        
+        if let Some(conversation_id) = &self.conversation_id {
+            if conversation_id != &message.conversation_id {
+                return false;
+            }
+        }
+        
+        self.start_time.map_or(true, |start| message.timestamp >= start)
+            && self.end_time.map_or(true, |end| message.timestamp <= end)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SemanticHit {
+    message: Message,
+    similarity: f32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SimilarMessage {
+    message: Message,
+    similarity: f32,
+    is_duplicate: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EmbeddingStats {
+    model: String,
+    dimensions: u64,
+    embedded_count: u64,
+    pending_count: u64,
+    cached_queries: u64,
+}
+
+// Progress of a vector index rebuild; call again until done
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RebuildProgress {
+    indexed: u64,
+    done: bool,
+}
+
+// HTTP gateway interface, used to serve stored blobs
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    streaming_strategy: Option<StreamingStrategy>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: candid::Func,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackToken {
+    hash: String,
+    index: u32,
+    // URL token of the request, checked again for every chunk
+    access_token: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    body: Vec<u8>,
+    token: Option<StreamingCallbackToken>,
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum Error {
     NotAuthenticated,
@@ -96,6 +269,30 @@ pub enum Error {
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type Result<T> = std::result::Result<T, Error>;
 
+// Maximum number of surrounding messages returned on each side of a search hit
+const MAX_SEARCH_CONTEXT_SIZE: u64 = 10;
+
+// Hits fetched per requested hit when near-duplicates are collapsed
+const DUPLICATE_OVERFETCH: usize = 4;
+
+// Maximum number of results from a semantic search
+const MAX_SEMANTIC_RESULTS: u64 = 100;
+
+// Messages embedded at the end of a sync; the rest stay queued for the next run
+const EMBEDDINGS_PER_SYNC: usize = 256;
+
+// Conversation summaries updated at the end of a sync; the rest stay queued
+const SUMMARIES_PER_SYNC: usize = 5;
+
+// Documents read per process_pending_extractions call by default
+const EXTRACTIONS_PER_CALL: usize = 10;
+
+// Image blobs given thumbnails and metadata per process_pending_media call by default
+const IMAGES_PER_CALL: usize = 10;
+
+// Images processed at the end of a sync, within the instruction budget
+const IMAGES_PER_SYNC: usize = 10;
+
 // Stable memory storage
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
@@ -127,10 +324,34 @@ thread_local! {
 }
 
 // Authentication and setup
+
+// Connect an account or workspace. Each call without a connection id adds a
+// connection, so several Slack workspaces or Twitter accounts can be
+// connected side by side. Passing an existing connection's id replaces its
+// credentials instead (e.g. a rotated token), keeping its synced
+// conversations, cursors and rules. Returns the connection's id.
 #[update]
-async fn connect_platform(config: AuthConfig) -> Result<String> {
+async fn connect_platform(config: AuthConfig, label: Option<String>, connection_id: Option<String>) -> Result<String> {
     let caller = ic_cdk::caller();
-    let platform_key = format!("{}:{}", caller.to_string(), platform_to_string(&config.platform));
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    let owner = caller.to_string();
+    let label = label.map(validate_label).transpose()?;
+    
+    let existing = match &connection_id {
+        Some(connection_id) => {
+            let connection = storage::connections::get_connection(&owner, connection_id)
+                .ok_or(Error::NotAuthenticated)?;
+            if platform_to_string(&connection.platform) != platform_to_string(&config.platform) {
+                return Err(Error::InvalidParameters(format!(
+                    "Connection {} is a {} connection", connection_id, platform_to_string(&connection.platform)
+                )));
+            }
+            Some(connection)
+        },
+        None => None,
+    };
     
     // Validate auth config based on platform
     match &config.platform {
@@ -140,14 +361,11 @@ async fn connect_platform(config: AuthConfig) -> Result<String> {
         Platform::Twitter => auth::twitter::validate_auth(&config)?,
         Platform::Facebook => auth::facebook::validate_auth(&config)?,
         Platform::WhatsApp => auth::whatsapp::validate_auth(&config)?,
+        // Instagram Direct is read with the linked Facebook page's token
+        Platform::Instagram => auth::facebook::validate_auth(&config)?,
     }
     
-    // Store auth config
-    AUTH_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(platform_key.clone(), config.clone())
-    });
-    
-    // Initialize platform connection
+    // Initialize platform connection; nothing is stored if it fails
     match config.platform {
         Platform::Telegram => connectors::telegram::init_connection(&config).await,
         Platform::Slack => connectors::slack::init_connection(&config).await,
@@ -155,74 +373,250 @@ async fn connect_platform(config: AuthConfig) -> Result<String> {
         Platform::Twitter => connectors::twitter::init_connection(&config).await,
         Platform::Facebook => connectors::facebook::init_connection(&config).await,
         Platform::WhatsApp => connectors::whatsapp::init_connection(&config).await,
+        Platform::Instagram => connectors::instagram::init_connection(&config).await,
     }?;
     
-    Ok(format!("Successfully connected to {}", platform_to_string(&config.platform)))
+    // Store auth config. A connection removed while it was being verified
+    // isn't brought back.
+    let connection = match existing {
+        Some(connection) if storage::connections::get_connection(&owner, &connection.id).is_none() => {
+            return Err(Error::NotAuthenticated);
+        },
+        Some(connection) => match label {
+            Some(label) => storage::connections::set_label(&owner, &connection.id, label).unwrap_or(connection),
+            None => connection,
+        },
+        None => storage::connections::create_connection(&owner, config.platform.clone(), label),
+    };
+    AUTH_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(auth_key(&caller, &connection.id), config)
+    });
+    
+    Ok(connection.id)
 }
 
+// Disconnect every connection of a platform
 #[update]
 fn disconnect_platform(platform: Platform) -> Result<bool> {
     let caller = ic_cdk::caller();
-    let platform_key = format!("{}:{}", caller.to_string(), platform_to_string(&platform));
+    let connections = storage::connections::list_connections(&caller.to_string(), Some(&platform));
+    
+    if connections.is_empty() {
+        return Err(Error::NotAuthenticated);
+    }
     
+    for connection in connections {
+        remove_connection(&caller, &connection.id);
+    }
+    Ok(true)
+}
+
+// Disconnect one connection. Its synced conversations are kept.
+#[update]
+fn disconnect_connection(connection_id: String) -> Result<bool> {
+    let caller = ic_cdk::caller();
+    
+    if remove_connection(&caller, &connection_id) {
+        Ok(true)
+    } else {
+        Err(Error::NotAuthenticated)
+    }
+}
+
+fn remove_connection(caller: &Principal, connection_id: &str) -> bool {
     AUTH_STORAGE.with(|storage| {
-        if storage.borrow().get(&platform_key).is_some() {
-            storage.borrow_mut().remove(&platform_key);
-            Ok(true)
-        } else {
-            Err(Error::NotAuthenticated)
-        }
-    })
+        storage.borrow_mut().remove(&auth_key(caller, connection_id));
+    });
+    storage::connections::remove_connection(&caller.to_string(), connection_id)
 }
 
 #[query]
 fn get_connected_platforms() -> Vec<Platform> {
     let caller = ic_cdk::caller();
-    let prefix = format!("{}:", caller.to_string());
+    let mut platforms: Vec<Platform> = Vec::new();
     
+    for connection in storage::connections::list_connections(&caller.to_string(), None) {
+        if !platforms.iter().any(|p| platform_to_string(p) == platform_to_string(&connection.platform)) {
+            platforms.push(connection.platform);
+        }
+    }
+    platforms
+}
+
+#[query]
+fn get_connections() -> Vec<Connection> {
+    let caller = ic_cdk::caller();
+    storage::connections::list_connections(&caller.to_string(), None)
+}
+
+#[update]
+fn rename_connection(connection_id: String, label: String) -> Result<Connection> {
+    let caller = ic_cdk::caller();
+    let label = validate_label(label)?;
+    
+    storage::connections::set_label(&caller.to_string(), &connection_id, label)
+        .ok_or(Error::NotAuthenticated)
+}
+
+fn validate_label(label: String) -> Result<String> {
+    let label = label.trim().to_string();
+    if label.is_empty() || label.chars().count() > storage::connections::MAX_LABEL_LEN {
+        return Err(Error::InvalidParameters(format!(
+            "Connection label must be between 1 and {} characters", storage::connections::MAX_LABEL_LEN
+        )));
+    }
+    Ok(label)
+}
+
+// Credentials are stored per connection
+fn auth_key(caller: &Principal, connection_id: &str) -> String {
+    format!("{}:{}", caller.to_string(), connection_id)
+}
+
+fn connection_auth(caller: &Principal, connection_id: &str) -> Result<AuthConfig> {
     AUTH_STORAGE.with(|storage| {
-        storage.borrow().iter()
-            .filter(|(k, _)| k.starts_with(&prefix))
-            .map(|(_, v)| v.platform.clone())
-            .collect()
+        storage.borrow().get(&auth_key(caller, connection_id))
+            .ok_or(Error::NotAuthenticated)
     })
 }
 
+// Sync scope
+
+// Preview the conversations a connection can see upstream and whether its
+// rules sync them
+#[update]
+async fn list_remote_conversations(connection_id: String) -> Result<Vec<connectors::scope::RemoteConversation>> {
+    let caller = ic_cdk::caller();
+    let owner = caller.to_string();
+    
+    let connection = storage::connections::get_connection(&owner, &connection_id)
+        .ok_or(Error::NotAuthenticated)?;
+    let auth_config = connection_auth(&caller, &connection_id)?;
+    let rules = storage::connections::get_rules(&owner, &connection_id);
+    
+    match connection.platform {
+        Platform::Telegram => connectors::telegram::list_remote_conversations(&auth_config, &rules).await,
+        Platform::Slack => connectors::slack::list_remote_conversations(&auth_config, &connection_id, &rules).await,
+        Platform::Discord => connectors::discord::list_remote_conversations(&auth_config, &connection_id, &rules).await,
+        Platform::Twitter => Ok(connectors::twitter::list_remote_conversations(&auth_config, &connection_id, &rules)),
+        Platform::Facebook => connectors::facebook::list_remote_conversations(&auth_config, &connection_id, &rules).await,
+        Platform::WhatsApp => connectors::whatsapp::list_remote_conversations(&auth_config, &rules).await,
+        Platform::Instagram => connectors::instagram::list_remote_conversations(&auth_config, &connection_id, &rules).await,
+    }
+}
+
+#[query]
+fn get_sync_rules(connection_id: String) -> Result<connectors::scope::SyncRules> {
+    let owner = ic_cdk::caller().to_string();
+    
+    storage::connections::get_connection(&owner, &connection_id)
+        .ok_or(Error::NotAuthenticated)?;
+    Ok(storage::connections::get_rules(&owner, &connection_id))
+}
+
+// Replace a connection's include/exclude rules. They apply from the next
+// sync; conversations synced earlier are kept.
+#[update]
+fn set_sync_rules(connection_id: String, rules: connectors::scope::SyncRules) -> Result<bool> {
+    let owner = ic_cdk::caller().to_string();
+    
+    storage::connections::get_connection(&owner, &connection_id)
+        .ok_or(Error::NotAuthenticated)?;
+    rules.validate()?;
+    
+    storage::connections::set_rules(&owner, &connection_id, rules);
+    Ok(true)
+}
+
 // Data retrieval
+
+// Sync one connection, or every connection of the platform when none is given
 #[update]
-async fn sync_messages(platform: Platform) -> Result<u64> {
+async fn sync_messages(platform: Platform, connection_id: Option<String>) -> Result<u64> {
     let caller = ic_cdk::caller();
-    let platform_key = format!("{}:{}", caller.to_string(), platform_to_string(&platform));
+    let owner = caller.to_string();
+    
+    let connections = match connection_id {
+        Some(connection_id) => {
+            let connection = storage::connections::get_connection(&owner, &connection_id)
+                .filter(|c| platform_to_string(&c.platform) == platform_to_string(&platform))
+                .ok_or(Error::NotAuthenticated)?;
+            vec![connection]
+        },
+        None => storage::connections::list_connections(&owner, Some(&platform)),
+    };
     
-    // Get auth config
-    let auth_config = AUTH_STORAGE.with(|storage| {
-        storage.borrow().get(&platform_key)
-            .ok_or(Error::NotAuthenticated)
-    })?;
+    if connections.is_empty() {
+        return Err(Error::NotAuthenticated);
+    }
     
-    // Sync messages from platform
-    let count = match platform {
-        Platform::Telegram => connectors::telegram::sync_messages(&auth_config).await,
-        Platform::Slack => connectors::slack::sync_messages(&auth_config).await,
-        Platform::Discord => connectors::discord::sync_messages(&auth_config).await,
-        Platform::Twitter => connectors::twitter::sync_messages(&auth_config).await,
-        Platform::Facebook => connectors::facebook::sync_messages(&auth_config).await,
-        Platform::WhatsApp => connectors::whatsapp::sync_messages(&auth_config).await,
-    }?;
+    let mut count = 0;
+    let mut first_error = None;
+    let mut synced_any = false;
+    
+    for connection in &connections {
+        // Get auth config and the conversations it may sync
+        let auth_config = connection_auth(&caller, &connection.id)?;
+        let rules = storage::connections::get_rules(&owner, &connection.id);
+        
+        // Sync messages from platform
+        let synced = match platform {
+            Platform::Telegram => connectors::telegram::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::Slack => connectors::slack::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::Discord => connectors::discord::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::Twitter => connectors::twitter::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::Facebook => connectors::facebook::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::WhatsApp => connectors::whatsapp::sync_messages(&auth_config, &connection.id, &rules).await,
+            Platform::Instagram => connectors::instagram::sync_messages(&auth_config, &connection.id, &rules).await,
+        };
+        
+        // One failing workspace doesn't hold up the others
+        match synced {
+            Ok(synced) => {
+                count += synced;
+                synced_any = true;
+                storage::connections::record_sync(&owner, &connection.id);
+            },
+            Err(e) => {
+                ic_cdk::println!("Failed to sync connection {}: {:?}", connection.id, e);
+                first_error.get_or_insert(e);
+            },
+        }
+    }
+    
+    if let (false, Some(e)) = (synced_any, first_error) {
+        return Err(e);
+    }
+    
+    // Embed the newly synced messages; failures leave them queued for retry
+    if let Err(e) = openchat::embeddings::process_pending(EMBEDDINGS_PER_SYNC).await {
+        ic_cdk::println!("Failed to embed synced messages: {:?}", e);
+    }
+    
+    // Fold the new messages into their conversation summaries
+    if let Err(e) = openchat::summaries::process_pending(SUMMARIES_PER_SYNC).await {
+        ic_cdk::println!("Failed to update conversation summaries: {:?}", e);
+    }
+    
+    // Images are held back until processed, so handle the ones the caller
+    // holds while the call has room; the rest wait for the next sync
+    indexing::images::process_pending(&owner, IMAGES_PER_SYNC);
     
     Ok(count)
 }
 
 #[query]
-fn get_conversations(platform: Platform) -> Result<Vec<Conversation>> {
+fn get_conversations(platform: Platform, connection_id: Option<String>) -> Result<Vec<Conversation>> {
     let caller = ic_cdk::caller();
     let platform_string = platform_to_string(&platform);
+    let synced_by = connection_id.map(|id| storage::connections::conversation_ids(&caller.to_string(), &id));
     
     CONVERSATIONS.with(|storage| {
         let conversations = storage.borrow().iter()
             .filter(|(_, v)| 
                 platform_to_string(&v.platform) == platform_string && 
-                v.participants.iter().any(|p| p.id.starts_with(&caller.to_string()))
+                v.participants.iter().any(|p| p.id.starts_with(&caller.to_string())) &&
+                synced_by.as_ref().map_or(true, |ids| ids.contains(&v.id))
             )
             .map(|(_, v)| v.clone())
             .collect();
@@ -279,10 +673,17 @@ fn query_conversations(query_text: String) -> Result<QueryResult> {
     let (clean_query, filters) = indexing::search::SearchFilters::from_natural_language(&query_text);
     
     // Process the query using the advanced indexing system
-    let search_results = storage::messages::search_messages(&clean_query, &filters)?;
+    let ranking = indexing::ranking::RankingContext::for_user(&caller.to_string());
+    let results = storage::messages::search_message_hits(&clean_query, &filters, &ranking)?;
+    let text_hits = results.iter().filter(|(_, hit)| hit.text_match.is_some()).count();
+    let search_results: Vec<Message> = results.into_iter().map(|(message, _)| message).collect();
     
     // Generate context information
-    let context = generate_search_context(&query_text, &clean_query, &filters, &search_results);
+    let mut context = generate_search_context(&query_text, &clean_query, &filters, &search_results);
+    
+    if let Some(suggestion) = search_suggestion(&clean_query, text_hits) {
+        context.push_str(&format!("\nDid you mean: \"{}\"?", suggestion));
+    }
     
     Ok(QueryResult {
         messages: search_results,
@@ -290,22 +691,329 @@ fn query_conversations(query_text: String) -> Result<QueryResult> {
     })
 }
 
-// AI-enhanced query using OpenChat SDK
+// Search returning highlighted fragments and surrounding messages for each hit
 #[query]
-async fn ai_enhanced_query(query_text: String) -> Result<QueryResult> {
+fn detailed_search(query_text: String, options: SearchOptions) -> Result<SearchResult> {
     let caller = ic_cdk::caller();
     
-    // Use OpenChat for enhanced querying
-    openchat::query_with_openchat(&query_text, None, &caller.to_string(), 50).await
+    // Parse the query to extract filters and structured information
+    let (clean_query, mut filters) = indexing::search::SearchFilters::from_natural_language(&query_text);
+    
+    // Surrounding context is capped to keep responses small
+    let context_size = options.context_size.unwrap_or(0).min(MAX_SEARCH_CONTEXT_SIZE) as usize;
+    let explain = options.explain.unwrap_or(false);
+    
+    // Collapsing folds hits into one another, so more are fetched and the
+    // page is cut to the limit afterwards
+    let collapse = options.collapse_duplicates.unwrap_or(false);
+    let limit = filters.limit;
+    if collapse {
+        filters.limit = limit.saturating_mul(DUPLICATE_OVERFETCH);
+    }
+    
+    // Only hits in the caller's conversations, so neither they nor their
+    // surrounding messages come from anyone else's. The indices apply this
+    // before their limits, so pages aren't cut short.
+    filters.conversation_ids = Some(storage::conversations::get_user_conversations(&caller.to_string(), None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect());
+    
+    let ranking = indexing::ranking::RankingContext::for_user(&caller.to_string());
+    let results = storage::messages::search_message_hits(&clean_query, &filters, &ranking)?;
+    let text_hits = results.iter().filter(|(_, hit)| hit.text_match.is_some()).count();
+    
+    // Fold cross-posts into the best-ranked copy when asked
+    let results = if collapse {
+        collapse_duplicate_hits(results)
+    } else {
+        results.into_iter().map(|(message, hit)| (message, hit, Vec::new())).collect()
+    };
+    
+    let hits: Vec<SearchHit> = results.into_iter()
+        .take(limit)
+        .map(|(message, hit, duplicate_ids)| {
+            let (context_before, context_after) = storage::messages::get_surrounding_messages(&message, context_size);
+            
+            let (fragment, highlights, matched_terms) = match hit.text_match {
+                Some(text_match) => (
+                    text_match.fragment,
+                    text_match.highlights.into_iter()
+                        .map(|(start, end)| HighlightRange { start: start as u64, end: end as u64 })
+                        .collect(),
+                    text_match.matched_terms,
+                ),
+                // Metadata-only matches have no highlighted terms
+                None => (truncate_text(&message.content.text, 160), Vec::new(), Vec::new()),
+            };
+            
+            let attachment_match = hit.attachment_match.map(|attachment_match| AttachmentSnippet {
+                name: attachment_match.attachment_name,
+                blob_hash: attachment_match.blob_hash,
+                fragment: attachment_match.fragment,
+                highlights: attachment_match.highlights.into_iter()
+                    .map(|(start, end)| HighlightRange { start: start as u64, end: end as u64 })
+                    .collect(),
+            });
+            
+            SearchHit {
+                message,
+                score: hit.score,
+                fragment,
+                highlights,
+                matched_terms,
+                attachment_match,
+                context_before,
+                context_after,
+                explanation: if explain { Some(hit.breakdown) } else { None },
+                duplicate_ids,
+            }
+        })
+        .collect();
+    
+    let messages: Vec<Message> = hits.iter().map(|hit| hit.message.clone()).collect();
+    let context = generate_search_context(&query_text, &clean_query, &filters, &messages);
+    let suggestion = search_suggestion(&clean_query, text_hits);
+    
+    Ok(SearchResult {
+        hits,
+        context,
+        suggestion,
+    })
+}
+
+// Keep the first (best-ranked) hit of each near-duplicate cluster, along with
+// the IDs of the hits folded into it
+fn collapse_duplicate_hits(
+    results: Vec<(Message, indexing::search::SearchHit)>
+) -> Vec<(Message, indexing::search::SearchHit, Vec<String>)> {
+    let mut collapsed: Vec<(Message, indexing::search::SearchHit, Vec<String>)> = Vec::new();
+    let mut cluster_positions: HashMap<String, usize> = HashMap::new();
+    
+    for (message, hit) in results {
+        let cluster = indexing::dedup::cluster_key(&message.id);
+        match cluster_positions.get(&cluster) {
+            Some(&position) => collapsed[position].2.push(message.id),
+            None => {
+                cluster_positions.insert(cluster, collapsed.len());
+                collapsed.push((message, hit, Vec::new()));
+            },
+        }
+    }
+    
+    collapsed
+}
+
+// Search ranking preferences
+#[query]
+fn get_ranking_weights() -> indexing::ranking::RankingWeights {
+    let caller = ic_cdk::caller();
+    storage::preferences::get_ranking_weights(&caller.to_string())
+}
+
+// Set the caller's ranking weights; None restores the defaults
+#[update]
+fn set_ranking_weights(weights: Option<indexing::ranking::RankingWeights>) -> Result<bool> {
+    let caller = ic_cdk::caller();
+    
+    match weights {
+        Some(weights) => storage::preferences::set_ranking_weights(&caller.to_string(), weights)?,
+        None => storage::preferences::reset_ranking_weights(&caller.to_string()),
+    }
+    
+    Ok(true)
+}
+
+// Record that the caller read a conversation (feeds conversation affinity in ranking)
+#[update]
+fn mark_conversation_read(conversation_id: String) -> Result<bool> {
+    let caller = ic_cdk::caller();
+    
+    let conversation = storage::conversations::get_conversation(&conversation_id)
+        .ok_or(Error::InvalidParameters(format!("Conversation not found: {}", conversation_id)))?;
+    
+    // Verify user has access
+    if !conversation.participants.iter().any(|p| p.id.starts_with(&caller.to_string())) {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    storage::engagement::record_conversation_read(&caller.to_string(), &conversation_id);
+    Ok(true)
+}
+
+// Follow a Twitter recent-search query; matching tweets are synced into a
+// conversation of their own. Without a connection id the caller's first
+// Twitter connection runs the search.
+#[update]
+fn save_twitter_search(query: String, connection_id: Option<String>) -> Result<Conversation> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    let owner = caller.to_string();
+    let connection = match connection_id {
+        Some(connection_id) => storage::connections::get_connection(&owner, &connection_id)
+            .filter(|c| matches!(c.platform, Platform::Twitter)),
+        None => storage::connections::list_connections(&owner, Some(&Platform::Twitter)).into_iter().next(),
+    }.ok_or(Error::NotAuthenticated)?;
+    
+    connectors::twitter::save_search(&owner, &connection.id, &query)
+}
+
+// Stop following a saved search and delete its synced tweets, a batch per
+// call. Returns how many remain; call again until none do.
+#[update]
+fn remove_twitter_search(conversation_id: String) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    connectors::twitter::remove_search(&caller.to_string(), &conversation_id)
+}
+
+// Offer a "did you mean" correction when nothing matched the query text itself
+fn search_suggestion(clean_query: &str, text_hits: usize) -> Option<String> {
+    if text_hits > 0 || clean_query.trim().is_empty() {
+        return None;
+    }
+    
+    indexing::suggest(clean_query).unwrap_or_else(|e| {
+        ic_cdk::println!("Failed to build search suggestion: {:?}", e);
+        None
+    })
+}
+
+// Nearest-neighbour search over message embeddings
+#[update]
+async fn semantic_search(query_text: String, k: u64, filters: Option<SemanticSearchFilters>) -> Result<Vec<SemanticHit>> {
+    let caller = ic_cdk::caller();
+    
+    if query_text.trim().is_empty() {
+        return Err(Error::InvalidParameters("Query cannot be empty".to_string()));
+    }
+    
+    let k = k.clamp(1, MAX_SEMANTIC_RESULTS) as usize;
+    let filters = filters.unwrap_or_default();
+    
+    let results = openchat::semantic_search(&query_text, k, &caller.to_string(), &filters).await?;
+    
+    Ok(results.into_iter()
+        .map(|(message, similarity)| SemanticHit { message, similarity })
+        .collect())
+}
+
+// Answer a question from the caller's messages with citations to the messages used
+#[update]
+async fn answer_question(question: String, filters: Option<SemanticSearchFilters>) -> Result<openchat::rag::Answer> {
+    let caller = ic_cdk::caller();
+    
+    if question.trim().is_empty() {
+        return Err(Error::InvalidParameters("Question cannot be empty".to_string()));
+    }
+    
+    let filters = filters.unwrap_or_default();
+    openchat::rag::answer_question(&question, &caller.to_string(), &filters).await
+}
+
+// "More like this": messages closest to the given one in embedding space
+#[query]
+fn find_similar(message_id: String, k: u64) -> Result<Vec<SimilarMessage>> {
+    let caller = ic_cdk::caller();
+    
+    let message = storage::messages::get_message(&message_id)
+        .ok_or(Error::InvalidParameters(format!("Message not found: {}", message_id)))?;
+    
+    let conversation = storage::conversations::get_conversation(&message.conversation_id)
+        .ok_or(Error::InvalidParameters(format!("Conversation not found: {}", message.conversation_id)))?;
+    
+    // Verify user has access
+    if !conversation.participants.iter().any(|p| p.id.starts_with(&caller.to_string())) {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    let k = k.clamp(1, MAX_SEMANTIC_RESULTS) as usize;
+    let results = openchat::find_similar(&message, k, &caller.to_string())?;
+    
+    let duplicates = indexing::dedup::get_duplicates(&message.id);
+    Ok(results.into_iter()
+        .map(|(similar, similarity)| SimilarMessage {
+            is_duplicate: duplicates.contains(&similar.id),
+            message: similar,
+            similarity,
+        })
+        .collect())
+}
+
+// Other messages in the same near-duplicate cluster (cross-posts of the same text)
+#[query]
+fn get_duplicates(message_id: String) -> Result<Vec<Message>> {
+    let caller = ic_cdk::caller();
+    
+    let message = storage::messages::get_message(&message_id)
+        .ok_or(Error::InvalidParameters(format!("Message not found: {}", message_id)))?;
+    
+    let user_conversations: std::collections::HashSet<String> = storage::conversations::get_user_conversations(&caller.to_string(), None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    // Verify user has access
+    if !user_conversations.contains(&message.conversation_id) {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    Ok(indexing::dedup::get_duplicates(&message_id).into_iter()
+        .filter(|id| id != &message_id)
+        .filter_map(|id| storage::messages::get_message(&id))
+        .filter(|m| user_conversations.contains(&m.conversation_id))
+        .collect())
+}
+
+// AI-enhanced query, routed to a handler for the detected intent
+#[update]
+async fn ai_enhanced_query(query_text: String) -> Result<openchat::intents::QueryAnswer> {
+    let caller = ic_cdk::caller();
+    
+    openchat::intents::dispatch(&query_text, None, &caller.to_string(), 50).await
 }
 
 // Platform-specific AI query
+#[update]
+async fn ai_query_platform(query_text: String, platform: Platform) -> Result<openchat::intents::QueryAnswer> {
+    let caller = ic_cdk::caller();
+    
+    openchat::intents::dispatch(&query_text, Some(platform), &caller.to_string(), 50).await
+}
+
+// Matching messages per time bucket with spikes, across the caller's conversations
 #[query]
-async fn ai_query_platform(query_text: String, platform: Platform) -> Result<QueryResult> {
+fn topic_trend(
+    query: String,
+    bucket: openchat::trends::TrendBucket,
+    range: Option<openchat::trends::TimeRange>,
+    platforms: Option<Vec<Platform>>
+) -> Result<openchat::trends::TopicTrend> {
     let caller = ic_cdk::caller();
     
-    // Use OpenChat for platform-specific querying
-    openchat::query_with_openchat(&query_text, Some(platform), &caller.to_string(), 50).await
+    if query.trim().is_empty() {
+        return Err(Error::InvalidParameters("Query is empty".to_string()));
+    }
+    
+    let user_conversations: std::collections::HashSet<String> = storage::conversations::get_user_conversations(&caller.to_string(), None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    openchat::trends::topic_trend(&query, bucket, range, platforms, &user_conversations)
+}
+
+// Discover topics in the caller's messages; results are kept for `topic:<id>` search
+#[update]
+fn get_topics(range: Option<openchat::trends::TimeRange>) -> Result<Vec<openchat::topics::TopicCluster>> {
+    let caller = ic_cdk::caller();
+    openchat::topics::discover_topics(&caller.to_string(), range)
 }
 
 // Perform topic analysis on messages
@@ -348,136 +1056,56 @@ async fn generate_conversation_insights(conversation_id: String) -> Result<openc
     // Get messages for this conversation
     let messages = storage::messages::get_conversation_messages(&conversation_id, 1000, None);
     
-    // Format messages for analysis
-    let analysis_messages: Vec<openchat::types::MessageForAnalysis> = messages.iter()
-        .map(|msg| openchat::types::convert_to_analysis_message(msg))
-        .collect();
-    
-    // Create analysis request
-    let request = openchat::types::EnhancedAnalysisRequest {
-        messages: analysis_messages,
-        query: "Generate comprehensive insights".to_string(),
-        analysis_type: openchat::types::AnalysisType::ComprehensiveAnalysis,
-        options: openchat::types::AnalysisOptions {
-            include_explanations: true,
-            detail_level: openchat::types::DetailLevel::Detailed,
-            max_results: Some(10),
-        },
-    };
+    // Analyse with the LLM provider (cached until the conversation changes)
+    openchat::insights::conversation_insights(&conversation_id, &messages).await
+}
+
+// Rolling summary of a conversation, with what changed after `since` (ms)
+#[update]
+async fn get_conversation_summary(conversation_id: String, since: Option<u64>) -> Result<openchat::summaries::SummaryDigest> {
+    let caller = ic_cdk::caller();
     
-    // Call OpenChat for analysis
-    let client = openchat::client::create_openchat_client().await?;
+    let conversation = storage::conversations::get_conversation(&conversation_id)
+        .ok_or(Error::InvalidParameters(format!("Conversation not found: {}", conversation_id)))?;
     
-    // In a real implementation, we would process the result from OpenChat
-    // For this demo, we'll create a placeholder response
-    let insights = generate_placeholder_insights(&messages);
+    if !conversation.participants.iter().any(|p| p.id.starts_with(&caller.to_string())) {
+        return Err(Error::NotAuthenticated);
+    }
     
-    Ok(insights)
+    openchat::summaries::conversation_digest(&conversation_id, since).await
 }
 
-// Generate placeholder insights (in a real implementation, this would use the OpenChat response)
-fn generate_placeholder_insights(messages: &[Message]) -> openchat::types::QueryInsights {
-    use openchat::types::*;
-    use std::collections::HashMap;
-    
-    // Extract entities (simple implementation)
-    let mut entity_mentions = HashMap::new();
-    for msg in messages {
-        let words: Vec<&str> = msg.content.text.split_whitespace().collect();
-        for word in words {
-            if word.len() > 1 && word.chars().next().unwrap().is_uppercase() {
-                *entity_mentions.entry(word.to_string()).or_insert(0) += 1;
-            }
-        }
-    }
+// Look up an entity by name across the caller's conversations
+#[query]
+fn lookup_entity(name: String) -> Result<Vec<indexing::entities::EntityDetails>> {
+    let caller = ic_cdk::caller();
     
-    // Create entities from the mentions
-    let mut entities = Vec::new();
-    for (name, count) in entity_mentions.iter().take(5) {
-        entities.push(Entity {
-            name: name.clone(),
-            entity_type: EntityType::Other("Unknown".to_string()),
-            mentions: *count,
-            sentiment_score: Some(0.0),
-            related_entities: Vec::new(),
-        });
+    if name.trim().is_empty() {
+        return Err(Error::InvalidParameters("Entity name is empty".to_string()));
     }
     
-    // Create placeholder topics
-    let topics = vec![
-        Topic {
-            name: "Main Topic".to_string(),
-            relevance_score: 0.9,
-            message_count: messages.len() as i32,
-            summary: "This is a placeholder summary of the main topic.".to_string(),
-        },
-        Topic {
-            name: "Secondary Topic".to_string(),
-            relevance_score: 0.7,
-            message_count: (messages.len() / 2) as i32,
-            summary: "This is a placeholder summary of the secondary topic.".to_string(),
-        },
-    ];
-    
-    // Create placeholder timeline if there are enough messages
-    let timeline = if messages.len() >= 3 {
-        // Sort by timestamp
-        let mut sorted_msgs = messages.to_vec();
-        sorted_msgs.sort_by_key(|m| m.timestamp);
-        
-        // Take a few key messages for the timeline
-        let indices = [0, sorted_msgs.len() / 2, sorted_msgs.len() - 1];
-        let timeline_events = indices.iter().map(|&i| {
-            let msg = &sorted_msgs[i];
-            TimelineEvent {
-                timestamp: msg.timestamp,
-                description: format!("{}: {}", msg.sender.name, truncate_text(&msg.content.text, 50)),
-                related_message_ids: vec![msg.id.clone()],
-                importance: 1,
-            }
-        }).collect();
-        
-        Some(timeline_events)
-    } else {
-        None
-    };
+    let user_conversations: std::collections::HashSet<String> = storage::conversations::get_user_conversations(&caller.to_string(), None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
     
-    // Create placeholder sentiment analysis
-    let sentiment = Some(SentimentAnalysis {
-        overall_sentiment: 0.2,  // Slightly positive
-        sentiment_breakdown: {
-            let mut map = HashMap::new();
-            map.insert("Positive".to_string(), 0.6);
-            map.insert("Neutral".to_string(), 0.3);
-            map.insert("Negative".to_string(), 0.1);
-            map
-        },
-        key_positive_points: vec!["Placeholder positive point".to_string()],
-        key_negative_points: vec!["Placeholder negative point".to_string()],
-    });
+    Ok(indexing::entities::lookup(&name, &user_conversations))
+}
+
+// Lexicon sentiment of a conversation, overall, per sender and over time
+#[query]
+fn get_sentiment_summary(conversation_id: String, bucket: Option<indexing::sentiment::TimeBucket>) -> Result<indexing::sentiment::SentimentSummary> {
+    let caller = ic_cdk::caller();
     
-    // Create placeholder conversation flow
-    let conversation_flow = Some(ConversationFlow {
-        main_threads: vec![
-            ConversationThread {
-                topic: "Main Discussion".to_string(),
-                message_ids: messages.iter().map(|m| m.id.clone()).collect(),
-                participants: messages.iter().map(|m| m.sender.name.clone()).collect::<std::collections::HashSet<_>>().into_iter().collect(),
-                resolved: true,
-            }
-        ],
-        key_decisions: vec!["Placeholder decision".to_string()],
-        action_items: vec!["Placeholder action item".to_string()],
-    });
+    let conversation = storage::conversations::get_conversation(&conversation_id)
+        .ok_or(Error::InvalidParameters(format!("Conversation not found: {}", conversation_id)))?;
     
-    // Return the complete insights
-    QueryInsights {
-        entities,
-        topics,
-        timeline,
-        sentiment,
-        conversation_flow,
+    if !conversation.participants.iter().any(|p| p.id.starts_with(&caller.to_string())) {
+        return Err(Error::NotAuthenticated);
     }
+    
+    let messages = storage::messages::get_conversation_messages(&conversation_id, 1000, None);
+    Ok(indexing::sentiment::summarize(&messages, bucket.unwrap_or(indexing::sentiment::TimeBucket::Day)))
 }
 
 // Helper function to truncate text
@@ -529,13 +1157,22 @@ fn generate_search_context(
         }
     }
     
+    // Connection filter
+    if let Some(connection_id) = &filters.connection_id {
+        let label = storage::connections::get_connection(&ic_cdk::caller().to_string(), connection_id)
+            .map(|c| c.label)
+            .unwrap_or_else(|| connection_id.clone());
+        
+        context_parts.push(format!("Account: {}", label));
+    }
+    
     // Conversation filter
     if let Some(conv_id) = &filters.conversation_id {
         // Try to get the conversation name
         let conv_name = storage::conversations::get_conversation(conv_id)
             .map(|c| c.name)
             .unwrap_or_else(|| format!("Conversation {}", conv_id));
-            
+        
         context_parts.push(format!("In conversation: {}", conv_name));
     }
     
@@ -566,6 +1203,17 @@ fn generate_search_context(
         context_parts.push("Edited messages".to_string());
     }
     
+    if let Some(sentiment) = &filters.sentiment {
+        context_parts.push(format!("Sentiment: {}", sentiment.as_str()));
+    }
+    
+    if let Some(topic_id) = &filters.topic_id {
+        let label = storage::topics::get_topic(&ic_cdk::caller().to_string(), topic_id)
+            .map(|topic| topic.label)
+            .unwrap_or_else(|| topic_id.clone());
+        context_parts.push(format!("Topic: {}", label));
+    }
+    
     // Result summary
     let result_count = results.len();
     
@@ -652,7 +1300,8 @@ fn advanced_search(query: String, platform: Option<Platform>,
                   has_attachments: Option<bool>, attachment_type: Option<String>,
                   is_reply: Option<bool>, in_thread: Option<bool>, is_edited: Option<bool>,
                   sort_by: String, sort_direction: String,
-                  limit: Option<u64>, offset: Option<u64>) -> Result<QueryResult> {
+                  limit: Option<u64>, offset: Option<u64>,
+                  connection_id: Option<String>) -> Result<QueryResult> {
     
     // Create search filters from parameters
     let mut filters = indexing::search::SearchFilters::default();
@@ -666,7 +1315,8 @@ fn advanced_search(query: String, platform: Option<Platform>,
     filters.start_time = start_time;
     filters.end_time = end_time;
     
-    // Set conversation filter
+    // Set connection and conversation filters
+    filters.connection_id = connection_id;
     filters.conversation_id = conversation_id;
     
     // Set sender filter
@@ -710,7 +1360,9 @@ fn advanced_search(query: String, platform: Option<Platform>,
     }
     
     // Perform the search
-    let search_results = storage::messages::search_messages(&query, &filters)?;
+    let caller = ic_cdk::caller();
+    let ranking = indexing::ranking::RankingContext::for_user(&caller.to_string());
+    let search_results = storage::messages::search_messages(&query, &filters, &ranking)?;
     
     // Generate context information
     let context = generate_search_context(&query, &query, &filters, &search_results);
@@ -721,6 +1373,362 @@ fn advanced_search(query: String, platform: Option<Platform>,
     })
 }
 
+// Embedding administration (controllers only)
+fn require_controller() -> Result<()> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(Error::NotAuthenticated)
+    }
+}
+
+#[update]
+fn set_embedding_config(config: openchat::embeddings::EmbeddingConfig) -> Result<bool> {
+    require_controller()?;
+    openchat::embeddings::set_config(config)?;
+    Ok(true)
+}
+
+#[update]
+async fn process_pending_embeddings(limit: Option<u64>) -> Result<u64> {
+    require_controller()?;
+    openchat::embeddings::process_pending(limit.unwrap_or(EMBEDDINGS_PER_SYNC as u64) as usize).await
+}
+
+// Rebuild the vector index from stored vectors. Each call does as much as
+// fits in one message and resumes where the last one stopped.
+#[update]
+fn rebuild_vector_index() -> Result<RebuildProgress> {
+    require_controller()?;
+    let provider = openchat::embeddings::active_provider();
+    openchat::vector_index::start_rebuild(&provider.model());
+    
+    let (indexed, done) = openchat::vector_index::advance_job();
+    Ok(RebuildProgress { indexed, done })
+}
+
+#[query]
+fn get_embedding_stats() -> EmbeddingStats {
+    let provider = openchat::embeddings::active_provider();
+    
+    EmbeddingStats {
+        model: provider.model(),
+        dimensions: provider.dimensions() as u64,
+        embedded_count: storage::embeddings::embedding_count(),
+        pending_count: storage::embeddings::pending_count(),
+        cached_queries: openchat::embeddings::get_cache_size() as u64,
+    }
+}
+
+// LLM provider (controllers only)
+#[update]
+fn set_llm_config(config: openchat::llm::LlmConfig) -> Result<bool> {
+    require_controller()?;
+    openchat::llm::set_config(config)?;
+    Ok(true)
+}
+
+// Calls, tokens and cycles spent per model
+#[query]
+fn get_llm_usage() -> Result<Vec<(String, storage::llm::LlmUsage)>> {
+    require_controller()?;
+    Ok(storage::llm::get_usage())
+}
+
+// Transform for HTTPS outcalls: drops headers that vary between replicas
+#[query]
+fn transform_http_response(args: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    connectors::http::transform_response(args)
+}
+
+// Transform for LLM completion outcalls: keeps only the completion and usage
+#[query]
+fn transform_llm_response(args: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    openchat::llm::transform_response(args)
+}
+
+// Transform for embedding outcalls: keeps only the rounded vectors
+#[query]
+fn transform_embedding_response(args: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    openchat::embeddings::transform_response(args)
+}
+
+// Store one chunk (up to 1 MB) of an attachment upload. The upload ID is
+// chosen by the client. Returns the bytes received so far.
+#[update]
+fn upload_attachment_chunk(upload_id: String, index: u32, data: Vec<u8>) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    storage::blobs::upload_chunk(&caller.to_string(), &upload_id, index, data)
+}
+
+// Finish an upload, returning an attachment that references the stored blob
+// with a URL signed for the caller
+#[update]
+async fn commit_attachment(upload_id: String, name: Option<String>, mime_type: String) -> Result<Attachment> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    let owner = caller.to_string();
+    
+    storage::blobs::ensure_url_key(&owner).await?;
+    let blob = storage::blobs::commit_upload(&owner, &upload_id, &mime_type)?;
+    let attachment_type = match blob.mime_type.split('/').next().unwrap_or("") {
+        "image" => "image",
+        "video" => "video",
+        "audio" => "audio",
+        _ => "file",
+    };
+    
+    Ok(Attachment {
+        attachment_type: attachment_type.to_string(),
+        url: storage::blobs::blob_url(&blob.hash, &owner),
+        name,
+        mime_type: Some(blob.mime_type),
+        size: Some(blob.size),
+        blob_hash: Some(blob.hash),
+    })
+}
+
+// Pass the id of the message carrying the attachment to read one shared in
+// a conversation by another participant
+#[query]
+fn get_attachment_info(blob_hash: String, message_id: Option<String>) -> Result<storage::blobs::BlobInfo> {
+    can_read_attachment(&blob_hash, message_id.as_deref())?;
+    
+    served_blob(&blob_hash)
+        .ok_or_else(|| Error::InvalidParameters(format!("No attachment {}", blob_hash)))
+}
+
+// Download an attachment one chunk at a time; chunk_count is in get_attachment_info
+#[query]
+fn get_attachment_chunk(blob_hash: String, index: u32, message_id: Option<String>) -> Result<Vec<u8>> {
+    can_read_attachment(&blob_hash, message_id.as_deref())?;
+    
+    served_blob(&blob_hash)
+        .and_then(|blob| storage::blobs::get_chunk(&blob.hash, index))
+        .ok_or_else(|| Error::InvalidParameters(format!("Attachment {} has no chunk {}", blob_hash, index)))
+}
+
+// Stop holding an attachment and get its bytes back in the quota. It is
+// deleted once nobody holds it; messages referencing it keep the reference.
+#[update]
+fn delete_attachment(blob_hash: String) -> Result<bool> {
+    let caller = ic_cdk::caller();
+    if !storage::blobs::remove_owner(&blob_hash, &caller.to_string()) {
+        return Err(Error::NotAuthenticated);
+    }
+    Ok(true)
+}
+
+#[query]
+fn get_attachment_quota() -> storage::blobs::BlobQuota {
+    storage::blobs::get_quota(&ic_cdk::caller().to_string())
+}
+
+// Image attachments across all platforms, newest first, with thumbnails
+#[query]
+fn list_media(filters: storage::media::MediaFilters, cursor: Option<String>) -> Result<storage::media::MediaPage> {
+    let caller = ic_cdk::caller();
+    
+    // Only the caller's conversations are scanned
+    let conversation_ids: Vec<String> = storage::conversations::get_user_conversations(&caller.to_string(), filters.platform.clone())
+        .into_iter()
+        .map(|c| c.id)
+        .filter(|id| filters.conversation_id.as_ref().map_or(true, |conversation_id| conversation_id == id))
+        .collect();
+    
+    Ok(storage::media::list_media(&filters, cursor, &conversation_ids, &caller.to_string()))
+}
+
+// Read the text of queued documents the caller holds so searches reach
+// their contents. Runs apart from syncs, which only queue documents.
+#[update]
+fn process_pending_extractions(limit: Option<u64>) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    indexing::process_pending_extractions(&caller.to_string(), limit.unwrap_or(EXTRACTIONS_PER_CALL as u64) as usize)
+}
+
+// Thumbnails and metadata for queued images the caller holds, beyond those
+// handled at the end of a sync. They aren't served until processed.
+#[update]
+fn process_pending_media(limit: Option<u64>) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(Error::NotAuthenticated);
+    }
+    
+    Ok(indexing::images::process_pending(&caller.to_string(), limit.unwrap_or(IMAGES_PER_CALL as u64) as usize))
+}
+
+// Attachments can be read by their owners, and by participants of the
+// conversation holding `message_id` when that message references the blob
+fn can_read_attachment(hash: &str, message_id: Option<&str>) -> Result<()> {
+    let caller = ic_cdk::caller().to_string();
+    if storage::blobs::is_owner(hash, &caller) {
+        return Ok(());
+    }
+    
+    let shared = message_id
+        .and_then(storage::messages::get_message)
+        .filter(|message| message.content.attachments.iter().any(|a| a.blob_hash.as_deref() == Some(hash)))
+        .and_then(|message| storage::conversations::get_conversation(&message.conversation_id))
+        .map_or(false, |conversation| conversation.participants.iter().any(|p| p.id.starts_with(&caller)));
+    if !shared {
+        return Err(Error::NotAuthenticated);
+    }
+    Ok(())
+}
+
+// The content served for a blob: images are held back until their location
+// data has been checked, then served without it
+fn served_blob(hash: &str) -> Option<storage::blobs::BlobInfo> {
+    if storage::media::is_pending(hash) {
+        return None;
+    }
+    storage::blobs::get_blob_info(hash)?;
+    storage::blobs::get_blob_info(&storage::blobs::served_hash(hash))
+}
+
+// Serve stored blobs at /blobs/<sha256>?token=<token>, certified and streamed
+// in chunks. Content hashes can be known to anyone, so only URLs signed by
+// one of the blob's owners are served (see storage::blobs::blob_url).
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let (path, query) = request.url.split_once('?').unwrap_or((request.url.as_str(), ""));
+    let path = path.to_string();
+    let access_token = query.split('&')
+        .find_map(|param| param.strip_prefix("token="))
+        .unwrap_or("")
+        .to_string();
+    
+    let blob = path.strip_prefix("/blobs/")
+        .filter(|_| request.method.eq_ignore_ascii_case("GET"))
+        .filter(|hash| storage::blobs::url_token_valid(hash, &access_token))
+        .and_then(|hash| served_blob(hash).map(|blob| (hash.to_string(), blob)));
+    let (hash, blob) = match blob {
+        Some(found) => found,
+        None => return HttpResponse {
+            status_code: 404,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: b"Not found".to_vec(),
+            streaming_strategy: None,
+        },
+    };
+    
+    // Types stored before the allowlist are served as opaque bytes
+    let content_type = storage::blobs::allowed_mime_type(&blob.mime_type)
+        .unwrap_or_else(|| storage::blobs::FALLBACK_MIME_TYPE.to_string());
+    let mut headers = vec![
+        ("Content-Type".to_string(), content_type),
+        ("Content-Length".to_string(), blob.size.to_string()),
+        ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+        // Kept out of shared caches; a URL stops working once its owner
+        // deletes the blob
+        ("Cache-Control".to_string(), "private, max-age=3600".to_string()),
+    ];
+    if !storage::blobs::is_inline_image(&blob.mime_type) {
+        headers.push(("Content-Disposition".to_string(), "attachment".to_string()));
+    }
+    if let Some(certificate) = storage::blobs::certificate_header(&path) {
+        headers.push(("IC-Certificate".to_string(), certificate));
+    }
+    
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: storage::blobs::get_chunk(&blob.hash, 0).unwrap_or_default(),
+        streaming_strategy: next_blob_chunk(&hash, &access_token, 1, blob.chunk_count).map(|token| StreamingStrategy::Callback {
+            callback: candid::Func {
+                principal: ic_cdk::id(),
+                method: "http_request_streaming_callback".to_string(),
+            },
+            token,
+        }),
+    }
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    // Tokens name the requested blob, so access and content are resolved again
+    let blob = match Some(&token.hash)
+        .filter(|hash| storage::blobs::url_token_valid(hash, &token.access_token))
+        .and_then(|hash| served_blob(hash)) {
+        Some(blob) => blob,
+        None => return StreamingCallbackHttpResponse { body: Vec::new(), token: None },
+    };
+    
+    StreamingCallbackHttpResponse {
+        body: storage::blobs::get_chunk(&blob.hash, token.index).unwrap_or_default(),
+        token: next_blob_chunk(&token.hash, &token.access_token, token.index + 1, blob.chunk_count),
+    }
+}
+
+fn next_blob_chunk(hash: &str, access_token: &str, index: u32, chunk_count: u32) -> Option<StreamingCallbackToken> {
+    if index < chunk_count {
+        Some(StreamingCallbackToken { hash: hash.to_string(), index, access_token: access_token.to_string() })
+    } else {
+        None
+    }
+}
+
+// The certified asset tree lives on the heap and is rebuilt after an upgrade
+#[post_upgrade]
+fn post_upgrade() {
+    storage::blobs::certify_all();
+    storage::messages::backfill_range_index();
+    storage::entities::backfill_conversation_mentions();
+    migrate_platform_credentials();
+}
+
+// Credentials used to be keyed "<principal>:<platform>", one per platform.
+// Each becomes a connection of its own, and the conversations synced with
+// it are tagged with the connection.
+fn migrate_platform_credentials() {
+    let legacy: Vec<(String, AuthConfig)> = AUTH_STORAGE.with(|storage| {
+        storage.borrow().iter()
+            .filter(|(key, config)| key.ends_with(&format!(":{}", platform_to_string(&config.platform))))
+            .collect()
+    });
+    
+    for (key, config) in legacy {
+        let owner = match key.rsplit_once(':') {
+            Some((owner, _)) => owner.to_string(),
+            None => continue,
+        };
+        let owner_principal = match Principal::from_text(&owner) {
+            Ok(principal) => principal,
+            Err(_) => continue,
+        };
+        
+        let connection = storage::connections::create_connection(&owner, config.platform.clone(), None);
+        
+        for mut conversation in storage::conversations::get_user_conversations(&owner, Some(config.platform.clone())) {
+            storage::connections::tag_conversation(&owner, &connection.id, &conversation.id);
+            if conversation.connection_id.is_none() {
+                conversation.connection_id = Some(connection.id.clone());
+                if let Err(e) = storage::conversations::store_conversation(conversation) {
+                    ic_cdk::println!("Failed to tag conversation with {}: {:?}", connection.id, e);
+                }
+            }
+        }
+        
+        AUTH_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            storage.remove(&key);
+            storage.insert(auth_key(&owner_principal, &connection.id), config);
+        });
+    }
+}
+
 // Get index statistics for monitoring
 #[query]
 fn get_index_stats() -> Result<IndexStats> {
@@ -762,6 +1770,7 @@ fn platform_to_string(platform: &Platform) -> String {
         Platform::Twitter => "twitter".to_string(),
         Platform::Facebook => "facebook".to_string(),
         Platform::WhatsApp => "whatsapp".to_string(),
+        Platform::Instagram => "instagram".to_string(),
     }
 }
 
@@ -1,19 +1,30 @@
 use crate::{AuthConfig, Error, Result};
 use hmac::{Hmac, Mac};
-use sha2::Sha1;
-use rand::{thread_rng, Rng};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 use base64::{Engine as _, engine::general_purpose};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::cell::RefCell;
 
-// Twitter uses OAuth 1.0a which requires HMAC-SHA1 signatures
+// OAuth 1.0a requests are signed with HMAC-SHA1
 type HmacSha1 = Hmac<Sha1>;
 
+thread_local! {
+    // Makes nonces unique within one round
+    static NONCE_COUNTER: RefCell<u64> = RefCell::new(0);
+}
+
+// Two kinds of token are accepted: an OAuth 2.0 user access token on its
+// own, or an OAuth 1.0a "token:secret" pair with the app's API key and secret
 pub fn validate_auth(auth_config: &AuthConfig) -> Result<()> {
     // Check that OAuth token is provided
     if auth_config.token.is_empty() {
         return Err(Error::InvalidParameters("Twitter OAuth token is required".to_string()));
     }
     
+    if uses_bearer_token(auth_config) {
+        return Ok(());
+    }
+    
     // Check that consumer key is provided
     if auth_config.api_key.is_none() {
         return Err(Error::InvalidParameters("Twitter API key is required".to_string()));
@@ -24,17 +35,29 @@ pub fn validate_auth(auth_config: &AuthConfig) -> Result<()> {
         return Err(Error::InvalidParameters("Twitter API secret is required".to_string()));
     }
     
-    // Twitter requires a token secret alongside the token
-    // In a real implementation, we'd store this as part of the token or in a separate field
-    if !auth_config.token.contains(":") {
-        return Err(Error::InvalidParameters(
-            "Twitter OAuth token must include token secret in format 'token:secret'".to_string()
-        ));
-    }
-    
     Ok(())
 }
 
+// OAuth 1.0a tokens carry their secret after a colon; OAuth 2.0 tokens don't
+pub fn uses_bearer_token(auth_config: &AuthConfig) -> bool {
+    !auth_config.token.contains(':')
+}
+
+// Authorization header value for a request. `params` are the query
+// parameters, which an OAuth 1.0a signature covers.
+pub fn authorization_header(
+    auth_config: &AuthConfig,
+    method: &str,
+    url: &str,
+    params: &[(String, String)],
+) -> Result<String> {
+    if uses_bearer_token(auth_config) {
+        Ok(format!("Bearer {}", auth_config.token))
+    } else {
+        generate_oauth_signature(auth_config, method, url, params)
+    }
+}
+
 // Generate OAuth 1.0a signature for Twitter API requests
 pub fn generate_oauth_signature(
     auth_config: &AuthConfig,
@@ -60,15 +83,11 @@ pub fn generate_oauth_signature(
     let token = token_parts[0].to_string();
     let token_secret = token_parts[1].to_string();
     
-    // Generate OAuth nonce (random string)
-    let nonce = generate_nonce();
+    // Generate OAuth nonce
+    let nonce = generate_nonce(url);
     
     // Get current timestamp
-    let timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .map_err(|e| Error::InternalError(format!("Failed to get timestamp: {}", e)))?
-        .as_secs()
-        .to_string();
+    let timestamp = (ic_cdk::api::time() / 1_000_000_000).to_string();
     
     // Create OAuth parameters
     let mut oauth_params = vec![
@@ -126,15 +145,24 @@ pub fn generate_oauth_signature(
     Ok(format!("OAuth {}", auth_header))
 }
 
-// Generate random OAuth nonce
-fn generate_nonce() -> String {
-    let mut rng = thread_rng();
-    let random_bytes: Vec<u8> = (0..32).map(|_| rng.gen::<u8>()).collect();
-    general_purpose::STANDARD.encode(&random_bytes)
+// Generate OAuth nonce. Every replica must send an identical request, so
+// it's derived from the time and a counter rather than randomness.
+fn generate_nonce(url: &str) -> String {
+    let count = NONCE_COUNTER.with(|counter| {
+        let mut counter = counter.borrow_mut();
+        *counter += 1;
+        *counter
+    });
+    
+    let mut hasher = Sha256::new();
+    hasher.update(ic_cdk::api::time().to_be_bytes());
+    hasher.update(count.to_be_bytes());
+    hasher.update(url.as_bytes());
+    hasher.finalize()[..16].iter().map(|b| format!("{:02x}", b)).collect()
 }
 
 // URL encode a string according to OAuth 1.0a specs
-fn url_encode(input: &str) -> String {
+pub fn url_encode(input: &str) -> String {
     let mut result = String::new();
     for c in input.chars() {
         match c {
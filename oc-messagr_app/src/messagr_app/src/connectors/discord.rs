@@ -1,46 +1,344 @@
 use crate::{
-    AuthConfig, Conversation, Message, MessageContent, User, 
+    AuthConfig, Conversation, Message, MessageContent, User,
     Attachment, Platform, Error, Result
 };
-use ic_cdk::api::time;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use crate::storage::{connections, conversations, messages, sync_state};
+use super::http;
+use super::scope::{ConversationKind, RemoteConversation, SyncRules};
+
+const API_BASE: &str = "https://discord.com/api/v10";
+
+// Messages per page (the API maximum)
+const PAGE_SIZE: usize = 100;
+
+// Pages fetched per channel in one sync; the cursors resume where this stops
+const MAX_PAGES_PER_CHANNEL: usize = 5;
+
+const MAX_RESPONSE_BYTES: u64 = 1_000_000;
+
+// Backfill cursor value once a channel's history has been fetched completely
+const BACKFILL_DONE: &str = "done";
+
+// Discord's epoch (2015-01-01) in Unix milliseconds
+const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+// Guilds of the bot, with approximate member counts for the sync rules
+const GUILDS_PATH: &str = "/users/@me/guilds?with_counts=true";
+
+// Channel types
+const GUILD_TEXT: u8 = 0;
+const GUILD_ANNOUNCEMENT: u8 = 5;
+const ANNOUNCEMENT_THREAD: u8 = 10;
+const PUBLIC_THREAD: u8 = 11;
+const PRIVATE_THREAD: u8 = 12;
+const GUILD_FORUM: u8 = 15;
+
+// Channels stored as conversations. Forum channels only hold threads.
+const CONVERSATION_CHANNEL_TYPES: [u8; 3] = [GUILD_TEXT, GUILD_ANNOUNCEMENT, GUILD_FORUM];
+const MESSAGE_CHANNEL_TYPES: [u8; 2] = [GUILD_TEXT, GUILD_ANNOUNCEMENT];
+const THREAD_CHANNEL_TYPES: [u8; 3] = [ANNOUNCEMENT_THREAD, PUBLIC_THREAD, PRIVATE_THREAD];
+
+// How long a route (or the whole connection) is left alone after a 429. The
+// transform drops Discord's own timings, which differ between replicas.
+const RATE_LIMIT_BACKOFF_NS: u64 = 5 * 1_000_000_000;
+
+thread_local! {
+    // End of a rate limit by connection and route (path without query, so
+    // major parameters are included), in nanoseconds. Each connection has
+    // its own bot token.
+    static RATE_LIMITS: RefCell<HashMap<(String, String), u64>> = RefCell::new(HashMap::new());
+    
+    // End of a connection's global rate limit, in nanoseconds
+    static GLOBAL_RESET_AT: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
 
 // Initialize connection to Discord
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
     // Verify token validity by making a getCurrentUser request
-    let bot_info = get_bot_info(auth_config).await?;
+    let bot_info = get_bot_info(auth_config, None).await?;
     ic_cdk::println!("Connected to Discord as: {}", bot_info.username);
     
     Ok(())
 }
 
-// Sync messages from Discord
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
-    // In a real implementation:
-    // 1. Use Discord gateway for real-time events or REST API for historical data
-    // 2. Process messages and store them
-    // 3. Return count of synced messages
+// Sync messages from the text channels and active threads of every guild the
+// bot is in. Returns the number of messages stored.
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    let caller = ic_cdk::caller();
+    let guilds: Vec<DiscordGuild> = api_get(auth_config, Some(connection_id), GUILDS_PATH).await?;
+    
+    let mut total_synced = 0;
     
-    // Similar to Telegram, we would need the management canister for HTTP requests
-    // or use a relay to handle Discord API communication
+    for guild in guilds {
+        // One inaccessible guild shouldn't stop the others
+        let channels: Vec<DiscordChannel> = match api_get(auth_config, Some(connection_id), &format!("/guilds/{}/channels", guild.id)).await {
+            Ok(channels) => channels,
+            Err(e) => {
+                ic_cdk::println!("Failed to list channels of Discord guild {}: {:?}", guild.id, e);
+                continue;
+            },
+        };
+        let threads = match api_get::<DiscordThreadList>(auth_config, Some(connection_id), &format!("/guilds/{}/threads/active", guild.id)).await {
+            Ok(list) => list.threads,
+            Err(e) => {
+                ic_cdk::println!("Failed to list threads of Discord guild {}: {:?}", guild.id, e);
+                Vec::new()
+            },
+        };
+        
+        // Channels outside the connection's scope are never stored
+        let scoped: Vec<&DiscordChannel> = channels.iter()
+            .filter(|c| CONVERSATION_CHANNEL_TYPES.contains(&c.channel_type))
+            .filter(|c| rules.allows(&remote_conversation(c, &guild)))
+            .collect();
+        
+        for channel in &scoped {
+            store_channel(channel, &guild, &caller.to_string(), connection_id)?;
+        }
+        
+        // (channel to read, conversation it belongs to, thread)
+        let mut sources: Vec<(&str, &str, Option<&str>)> = scoped.iter()
+            .filter(|c| MESSAGE_CHANNEL_TYPES.contains(&c.channel_type))
+            .map(|c| (c.id.as_str(), c.id.as_str(), None))
+            .collect();
+        
+        // Thread messages go into their parent channel's conversation
+        for thread in threads.iter().filter(|t| THREAD_CHANNEL_TYPES.contains(&t.channel_type)) {
+            let parent = thread.parent_id.as_deref()
+                .filter(|parent_id| scoped.iter().any(|c| c.id == *parent_id));
+            if let Some(parent_id) = parent {
+                sources.push((thread.id.as_str(), parent_id, Some(thread.id.as_str())));
+            }
+        }
+        
+        for (channel_id, conversation_id, thread_id) in sources {
+            match sync_channel(auth_config, connection_id, channel_id, conversation_id, thread_id).await {
+                Ok(count) => total_synced += count,
+                // Rate-limited or unreadable channels are picked up on the next sync
+                Err(e) => ic_cdk::println!("Failed to sync Discord channel {}: {:?}", channel_id, e),
+            }
+        }
+    }
     
-    // Placeholder for demo purposes:
-    Ok(0)
+    Ok(total_synced)
+}
+
+// Text, announcement and forum channels of every guild the bot is in, and
+// whether the connection's rules sync them
+pub async fn list_remote_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    let guilds: Vec<DiscordGuild> = api_get(auth_config, Some(connection_id), GUILDS_PATH).await?;
+    let mut remote = Vec::new();
+    
+    for guild in guilds {
+        let channels: Vec<DiscordChannel> = api_get(auth_config, Some(connection_id), &format!("/guilds/{}/channels", guild.id)).await?;
+        remote.extend(channels.iter()
+            .filter(|c| CONVERSATION_CHANNEL_TYPES.contains(&c.channel_type))
+            .map(|c| remote_conversation(c, &guild).scoped(rules)));
+    }
+    
+    Ok(remote)
+}
+
+// Guild channels are visible to the whole guild, so its size stands in
+// for the channel's member count
+fn remote_conversation(channel: &DiscordChannel, guild: &DiscordGuild) -> RemoteConversation {
+    RemoteConversation::new(
+        channel.id.clone(),
+        channel_name(channel, Some(guild)),
+        ConversationKind::Group,
+        false,
+        guild.approximate_member_count,
+    )
+}
+
+// Fetch new messages of a channel after its newest synced message, then
+// continue backfilling older history with the remaining page budget. The
+// cursors belong to the connection, so each bot reads the channel itself.
+async fn sync_channel(
+    auth_config: &AuthConfig,
+    connection_id: &str,
+    channel_id: &str,
+    conversation_id: &str,
+    thread_id: Option<&str>
+) -> Result<u64> {
+    let newest_key = format!("discord:{}:{}:newest", connection_id, channel_id);
+    let oldest_key = format!("discord:{}:{}:oldest", connection_id, channel_id);
+    let mut pages = 0;
+    let mut synced = 0;
+    
+    if let Some(mut after) = sync_state::get_cursor(&newest_key) {
+        while pages < MAX_PAGES_PER_CHANNEL {
+            let page = get_channel_messages(auth_config, connection_id, channel_id, &format!("after={}", after)).await?;
+            pages += 1;
+            
+            let full = page.len() >= PAGE_SIZE;
+            let newest = match newest_id(&page) {
+                Some(newest) => newest,
+                None => break,
+            };
+            synced += store_page(page, conversation_id, thread_id)?;
+            sync_state::set_cursor(&newest_key, &newest);
+            after = newest;
+            
+            if !full {
+                break;
+            }
+        }
+    }
+    
+    while pages < MAX_PAGES_PER_CHANNEL {
+        let before = sync_state::get_cursor(&oldest_key);
+        if before.as_deref() == Some(BACKFILL_DONE) {
+            break;
+        }
+        
+        let query = before.map(|id| format!("before={}", id)).unwrap_or_default();
+        let page = get_channel_messages(auth_config, connection_id, channel_id, &query).await?;
+        pages += 1;
+        
+        let full = page.len() >= PAGE_SIZE;
+        let newest = newest_id(&page);
+        let oldest = oldest_id(&page);
+        synced += store_page(page, conversation_id, thread_id)?;
+        
+        // The first page of a new channel also starts the forward cursor
+        if sync_state::get_cursor(&newest_key).is_none() {
+            if let Some(newest) = &newest {
+                sync_state::set_cursor(&newest_key, newest);
+            }
+        }
+        
+        match oldest {
+            Some(oldest) if full => sync_state::set_cursor(&oldest_key, &oldest),
+            _ => {
+                sync_state::set_cursor(&oldest_key, BACKFILL_DONE);
+                break;
+            },
+        }
+    }
+    
+    Ok(synced)
+}
+
+async fn get_channel_messages(auth_config: &AuthConfig, connection_id: &str, channel_id: &str, query: &str) -> Result<Vec<DiscordMessage>> {
+    let mut path = format!("/channels/{}/messages?limit={}", channel_id, PAGE_SIZE);
+    if !query.is_empty() {
+        path.push('&');
+        path.push_str(query);
+    }
+    
+    api_get(auth_config, Some(connection_id), &path).await
+}
+
+// Store a page of messages and move the conversation's last message time forward
+fn store_page(page: Vec<DiscordMessage>, conversation_id: &str, thread_id: Option<&str>) -> Result<u64> {
+    let mut stored = 0;
+    let mut latest = 0;
+    
+    for msg in page {
+        let message = discord_message_to_message(msg, conversation_id, thread_id)?;
+        latest = latest.max(message.timestamp);
+        messages::store_message(message)?;
+        stored += 1;
+    }
+    
+    let previous = conversations::get_conversation(conversation_id).and_then(|c| c.last_message_at);
+    if stored > 0 && previous.map(|p| latest > p).unwrap_or(true) {
+        conversations::update_conversation_last_message(conversation_id, latest)?;
+    }
+    
+    Ok(stored)
+}
+
+fn store_channel(channel: &DiscordChannel, guild: &DiscordGuild, caller: &str, connection_id: &str) -> Result<()> {
+    let participants = conversations::merge_participants(&channel.id, vec![User {
+        id: caller.to_string(),
+        name: "Current User".to_string(),
+        platform: Platform::Discord,
+        avatar_url: None,
+    }]);
+    
+    let mut conversation = discord_channel_to_conversation(channel, Some(guild), participants, connection_id);
+    
+    // Keep what earlier syncs learned
+    if let Some(existing) = conversations::get_conversation(&conversation.id) {
+        conversation.last_message_at = existing.last_message_at;
+    }
+    connections::tag_conversation(caller, connection_id, &conversation.id);
+    
+    conversations::store_conversation(conversation)
 }
 
 // Get bot information
-async fn get_bot_info(auth_config: &AuthConfig) -> Result<DiscordUser> {
-    // This would normally use HTTP outbound calls to the Discord API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(DiscordUser {
-        id: "987654321".to_string(),
-        username: "MessageAggregator".to_string(),
-        discriminator: "0001".to_string(),
-        avatar: Some("abc123".to_string()),
-        bot: true,
-    })
+async fn get_bot_info(auth_config: &AuthConfig, connection_id: Option<&str>) -> Result<DiscordUser> {
+    api_get(auth_config, connection_id, "/users/@me").await
+}
+
+// GET an API path as the bot, honouring the rate limits from the connection's
+// earlier responses. A token still being verified has no connection yet, and
+// its limits aren't tracked.
+async fn api_get<T: DeserializeOwned>(auth_config: &AuthConfig, connection_id: Option<&str>, path: &str) -> Result<T> {
+    let route = path.split('?').next().unwrap_or(path).to_string();
+    let limit_key = connection_id.map(|id| (id.to_string(), route.clone()));
+    let now = ic_cdk::api::time();
+    
+    // Don't spend an outcall on a request Discord would reject
+    let global_reset_at = connection_id
+        .and_then(|id| GLOBAL_RESET_AT.with(|reset_at| reset_at.borrow().get(id).copied()))
+        .unwrap_or(0);
+    let route_reset_at = limit_key.as_ref()
+        .and_then(|key| RATE_LIMITS.with(|limits| limits.borrow().get(key).copied()))
+        .unwrap_or(0);
+    let reset_at = global_reset_at.max(route_reset_at);
+    if reset_at > now {
+        return Err(rate_limited(&route, reset_at - now));
+    }
+    
+    let url = format!("{}{}", API_BASE, path);
+    let request = http::OutgoingRequest::get(&url)
+        .with_header("Authorization", &format!("Bot {}", auth_config.token))
+        .with_max_response_bytes(MAX_RESPONSE_BYTES);
+    let response = http::send_with_headers(request).await?;
+    
+    if response.status == 429 {
+        let reset_at = now + RATE_LIMIT_BACKOFF_NS;
+        if response.header("x-ratelimit-global").is_some() || response.header("x-ratelimit-scope") == Some("global") {
+            if let Some(id) = connection_id {
+                GLOBAL_RESET_AT.with(|global| {
+                    global.borrow_mut().insert(id.to_string(), reset_at);
+                });
+            }
+        } else if let Some(key) = limit_key {
+            RATE_LIMITS.with(|limits| {
+                limits.borrow_mut().insert(key, reset_at);
+            });
+        }
+        return Err(rate_limited(&route, RATE_LIMIT_BACKOFF_NS));
+    }
+    
+    http::decode_json(&url, (response.status, response.body))
+}
+
+fn rate_limited(route: &str, wait_ns: u64) -> Error {
+    Error::PlatformError(format!(
+        "Discord rate limit reached for {}; retry in {}s", route, (wait_ns + 999_999_999) / 1_000_000_000
+    ))
+}
+
+// Snowflake IDs grow with time, so they order messages
+fn snowflake(id: &str) -> u64 {
+    id.parse().unwrap_or(0)
+}
+
+fn newest_id(page: &[DiscordMessage]) -> Option<String> {
+    page.iter().max_by_key(|m| snowflake(&m.id)).map(|m| m.id.clone())
+}
+
+fn oldest_id(page: &[DiscordMessage]) -> Option<String> {
+    page.iter().min_by_key(|m| snowflake(&m.id)).map(|m| m.id.clone())
 }
 
 // Discord API response structures
@@ -48,8 +346,12 @@ async fn get_bot_info(auth_config: &AuthConfig) -> Result<DiscordUser> {
 struct DiscordUser {
     id: String,
     username: String,
+    #[serde(default)]
     discriminator: String,
+    #[serde(default)]
+    global_name: Option<String>,
     avatar: Option<String>,
+    #[serde(default)]
     bot: bool,
 }
 
@@ -58,6 +360,9 @@ struct DiscordGuild {
     id: String,
     name: String,
     icon: Option<String>,
+    // Only returned when guilds are listed with_counts
+    #[serde(default)]
+    approximate_member_count: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -65,12 +370,23 @@ struct DiscordChannel {
     id: String,
     #[serde(rename = "type")]
     channel_type: u8,
+    #[serde(default)]
     guild_id: Option<String>,
+    #[serde(default)]
+    parent_id: Option<String>,
+    #[serde(default)]
     name: Option<String>,
+    #[serde(default)]
     topic: Option<String>,
+    #[serde(default)]
     last_message_id: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct DiscordThreadList {
+    threads: Vec<DiscordChannel>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DiscordMessage {
     id: String,
@@ -79,11 +395,21 @@ struct DiscordMessage {
     content: String,
     timestamp: String,
     edited_timestamp: Option<String>,
+    #[serde(default)]
     tts: bool,
+    #[serde(default)]
     mention_everyone: bool,
+    #[serde(default)]
     mentions: Vec<DiscordUser>,
-    reference: Option<DiscordMessageReference>,
-    // Additional fields would be added for embeds, attachments, etc.
+    #[serde(default)]
+    message_reference: Option<DiscordMessageReference>,
+    #[serde(default)]
+    attachments: Vec<DiscordAttachment>,
+    #[serde(default)]
+    embeds: Vec<DiscordEmbed>,
+    // Thread started from this message
+    #[serde(default)]
+    thread: Option<DiscordChannel>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -93,36 +419,70 @@ struct DiscordMessageReference {
     guild_id: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct DiscordAttachment {
+    id: String,
+    filename: String,
+    #[serde(default)]
+    content_type: Option<String>,
+    url: String,
+    #[serde(default)]
+    size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DiscordEmbed {
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    url: Option<String>,
+}
+
 // Convert Discord entities to our domain model
+fn channel_name(channel: &DiscordChannel, guild: Option<&DiscordGuild>) -> String {
+    let channel_name = channel.name.clone();
+    match guild {
+        Some(g) => format!("{} > {}", g.name, channel_name.unwrap_or_default()),
+        None => channel_name.unwrap_or_else(|| "Direct Message".to_string()),
+    }
+}
+
 fn discord_channel_to_conversation(
-    channel: DiscordChannel, 
-    guild: Option<DiscordGuild>,
-    participants: Vec<User>
+    channel: &DiscordChannel,
+    guild: Option<&DiscordGuild>,
+    participants: Vec<User>,
+    connection_id: &str
 ) -> Conversation {
-    let name = match guild {
-        Some(g) => format!("{} > {}", g.name, channel.name.unwrap_or_default()),
-        None => channel.name.unwrap_or_else(|| "Direct Message".to_string()),
-    };
-    
     Conversation {
-        id: channel.id,
+        id: channel.id.clone(),
         platform: Platform::Discord,
-        name,
+        name: channel_name(channel, guild),
         participants,
-        created_at: time(),
+        // Snowflakes carry their creation time
+        created_at: (snowflake(&channel.id) >> 22) + DISCORD_EPOCH_MS,
         last_message_at: None,
+        connection_id: Some(connection_id.to_string()),
     }
 }
 
-fn discord_message_to_message(msg: DiscordMessage) -> Result<Message> {
+fn discord_message_to_message(msg: DiscordMessage, conversation_id: &str, thread_id: Option<&str>) -> Result<Message> {
     // Parse ISO 8601 timestamp
     let timestamp = chrono::DateTime::parse_from_rfc3339(&msg.timestamp)
         .map_err(|e| Error::InternalError(format!("Failed to parse timestamp: {}", e)))?
         .timestamp_millis() as u64;
     
+    // Accounts on the new username system have discriminator "0"
+    let name = match (&msg.author.global_name, msg.author.discriminator.as_str()) {
+        (Some(global_name), _) => global_name.clone(),
+        (None, "" | "0") => msg.author.username.clone(),
+        (None, discriminator) => format!("{}#{}", msg.author.username, discriminator),
+    };
+    
     let sender = User {
         id: msg.author.id.clone(),
-        name: format!("{}#{}", msg.author.username, msg.author.discriminator),
+        name,
         platform: Platform::Discord,
         avatar_url: msg.author.avatar.map(|hash| {
             format!("https://cdn.discordapp.com/avatars/{}/{}.png", msg.author.id, hash)
@@ -131,18 +491,55 @@ fn discord_message_to_message(msg: DiscordMessage) -> Result<Message> {
     
     let edited = msg.edited_timestamp.is_some();
     
+    let mut attachments: Vec<Attachment> = msg.attachments.into_iter()
+        .map(|attachment| Attachment {
+            attachment_type: attachment_type(attachment.content_type.as_deref()),
+            url: Some(attachment.url),
+            name: Some(attachment.filename),
+            mime_type: attachment.content_type,
+            size: Some(attachment.size),
+            blob_hash: None,
+        })
+        .collect();
+    
+    // Link previews and bot embeds
+    attachments.extend(msg.embeds.into_iter()
+        .filter(|embed| embed.url.is_some() || embed.title.is_some())
+        .map(|embed| Attachment {
+            attachment_type: "embed".to_string(),
+            url: embed.url,
+            name: embed.title.or(embed.description),
+            mime_type: None,
+            size: None,
+            blob_hash: None,
+        }));
+    
+    // A message that started a thread belongs to it too
+    let thread_id = thread_id
+        .map(|id| id.to_string())
+        .or_else(|| msg.thread.map(|thread| thread.id));
+    
     Ok(Message {
         id: msg.id,
         platform: Platform::Discord,
-        conversation_id: msg.channel_id,
+        conversation_id: conversation_id.to_string(),
         sender,
         content: MessageContent {
             text: msg.content,
-            attachments: Vec::new(), // Would handle attachments here
+            attachments,
         },
         timestamp,
-        thread_id: None,
-        reply_to: msg.reference.and_then(|r| r.message_id),
+        thread_id,
+        reply_to: msg.message_reference.and_then(|r| r.message_id),
         edited,
     })
-}
\ No newline at end of file
+}
+
+fn attachment_type(content_type: Option<&str>) -> String {
+    match content_type.and_then(|t| t.split('/').next()) {
+        Some("image") => "image",
+        Some("video") => "video",
+        Some("audio") => "audio",
+        _ => "file",
+    }.to_string()
+}
@@ -1,322 +1,447 @@
 use crate::{
-    AuthConfig, Conversation, Message, MessageContent, User, 
+    AuthConfig, Conversation, Message, MessageContent, User,
     Attachment, Platform, Error, Result
 };
 use crate::auth::facebook;
-use crate::storage::{conversations, messages};
-use ic_cdk::api::time;
-use serde::{Deserialize, Serialize};
+use crate::storage::{connections, conversations, sync_state};
+use crate::storage::sync_state::{store_page, FeedCursor};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use super::http;
+use super::scope::{ConversationKind, RemoteConversation, SyncRules};
+
+const GRAPH_API_BASE: &str = "https://graph.facebook.com/v18.0";
+
+// Conversations and messages per page
+const CONVERSATION_PAGE_SIZE: usize = 25;
+const MESSAGE_PAGE_SIZE: usize = 50;
+
+// Conversation list pages read per sync, most recently active first
+const MAX_CONVERSATION_PAGES: usize = 4;
+
+// Message pages read per conversation in one sync; the cursor resumes there
+const MAX_MESSAGE_PAGES: usize = 4;
+
+const MAX_RESPONSE_BYTES: u64 = 1_000_000;
+
+// Graph error codes for app, user, page and Messenger rate limits
+const RATE_LIMIT_CODES: [i64; 5] = [4, 17, 32, 613, 80006];
+
+// How long to stop calling Graph after a rate-limit error
+const RATE_LIMIT_BACKOFF_NS: u64 = 10 * 60 * 1_000_000_000;
+
+const CONVERSATION_FIELDS: &str = "id,updated_time,link,name,participants";
+const MESSAGE_FIELDS: &str = "id,message,created_time,from,attachments{id,mime_type,name,size,image_data,video_data,file_url},shares{link,name},sticker";
+
+// Page inboxes read through the Graph API with a page access token
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inbox {
+    Messenger,
+    // Instagram Direct of the Instagram account linked to the page
+    Instagram,
+}
+
+impl Inbox {
+    fn platform(&self) -> Platform {
+        match self {
+            Inbox::Messenger => Platform::Facebook,
+            Inbox::Instagram => Platform::Instagram,
+        }
+    }
+    
+    // Value of the conversations edge's platform parameter
+    fn param(&self) -> &'static str {
+        match self {
+            Inbox::Messenger => "messenger",
+            Inbox::Instagram => "instagram",
+        }
+    }
+}
+
+thread_local! {
+    // End of a rate-limit back-off by connection, in nanoseconds
+    static THROTTLED_UNTIL: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+}
 
 // Initialize connection to Facebook Messenger
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
-    // Verify token validity by making a test API call
-    let page_info = get_page_info(auth_config).await?;
-    ic_cdk::println!("Connected to Facebook Page: {}", page_info.name);
+    init_inbox(auth_config, Inbox::Messenger).await
+}
+
+// Sync messages from Facebook Messenger
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    sync_inbox(auth_config, connection_id, rules, Inbox::Messenger).await
+}
+
+pub async fn list_remote_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    list_inbox_conversations(auth_config, connection_id, rules, Inbox::Messenger).await
+}
+
+// Verify the page token can read the inbox
+pub async fn init_inbox(auth_config: &AuthConfig, inbox: Inbox) -> Result<()> {
+    let page = get_page_info(auth_config, None).await?;
     
-    // Initialize conversations (Messenger threads)
-    let conversations = get_conversations(auth_config).await?;
-    store_conversations(auth_config, conversations).await?;
+    match inbox {
+        Inbox::Messenger => ic_cdk::println!("Connected to Facebook Page: {}", page.name),
+        Inbox::Instagram => {
+            let account = page.instagram_business_account.ok_or_else(|| Error::InvalidParameters(format!(
+                "Facebook Page {} has no linked Instagram professional account", page.name
+            )))?;
+            ic_cdk::println!(
+                "Connected to Instagram account: @{}",
+                account.username.unwrap_or(account.id)
+            );
+        },
+    }
     
     Ok(())
 }
 
-// Sync messages from Facebook Messenger
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
-    // Get all conversations
-    let user_conversations = conversations::get_user_conversations(
-        &ic_cdk::caller().to_string(), 
-        Some(Platform::Facebook)
-    );
+// Sync the page's most recently active conversations of an inbox. Returns
+// the number of messages stored.
+pub async fn sync_inbox(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules, inbox: Inbox) -> Result<u64> {
+    let caller = ic_cdk::caller().to_string();
+    let page = get_page_info(auth_config, Some(connection_id)).await?;
     
     let mut total_synced = 0;
+    let mut after: Option<String> = None;
     
-    // For each conversation, get recent messages
-    for conversation in user_conversations {
-        let fb_messages = get_conversation_messages(auth_config, &conversation.id).await?;
+    // Every listed page is read, so conversations whose earlier sync ran out
+    // of pages are picked up again even when nothing new arrived
+    for _ in 0..MAX_CONVERSATION_PAGES {
+        let mut params = vec![
+            ("platform", inbox.param().to_string()),
+            ("fields", CONVERSATION_FIELDS.to_string()),
+            ("limit", CONVERSATION_PAGE_SIZE.to_string()),
+        ];
+        if let Some(after) = &after {
+            params.push(("after", after.clone()));
+        }
+        
+        let list: GraphList<FacebookConversation> = graph_get(auth_config, Some(connection_id), &format!("/{}/conversations", page.id), &params).await?;
         
-        // Store messages
-        for msg in fb_messages {
-            let message = facebook_message_to_message(msg, &conversation.id)?;
-            messages::store_message(message)?;
-            total_synced += 1;
+        // Conversations outside the connection's scope are never stored
+        let scoped = list.data.iter()
+            .filter(|fb_conv| rules.allows(&remote_conversation(fb_conv, &page, inbox)));
+        
+        for fb_conv in scoped {
+            store_conversation(fb_conv, &page, &caller, connection_id, inbox)?;
+            
+            // Unchanged since a completed sync
+            let updated_key = format!("facebook:{}:updated", fb_conv.id);
+            let cursor = FeedCursor::load(&format!("facebook:{}", fb_conv.id));
+            if cursor.resume_token.is_none() && sync_state::get_cursor(&updated_key).as_deref() == Some(fb_conv.updated_time.as_str()) {
+                continue;
+            }
+            
+            match sync_conversation(auth_config, connection_id, &fb_conv.id, inbox).await {
+                Ok((count, finished)) => {
+                    total_synced += count;
+                    if finished {
+                        sync_state::set_cursor(&updated_key, &fb_conv.updated_time);
+                    }
+                },
+                // One unreadable conversation shouldn't stop the others
+                Err(e) => ic_cdk::println!("Failed to sync {:?} conversation {}: {:?}", inbox, fb_conv.id, e),
+            }
         }
         
-        // Update conversation with last message timestamp
-        if let Some(latest) = fb_messages.first() {
-            conversations::update_conversation_last_message(&conversation.id, latest.timestamp)?;
+        after = list.next_cursor();
+        if after.is_none() {
+            break;
         }
     }
     
     Ok(total_synced)
 }
 
-// Get page info
-async fn get_page_info(auth_config: &AuthConfig) -> Result<FacebookPage> {
-    // This would normally use HTTP outbound calls to the Facebook Graph API
-    // For demo purposes, we'll simulate the response
+// The inbox's most recently active conversations, and whether the
+// connection's rules sync them
+pub async fn list_inbox_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules, inbox: Inbox) -> Result<Vec<RemoteConversation>> {
+    let page = get_page_info(auth_config, Some(connection_id)).await?;
+    let mut remote = Vec::new();
+    let mut after: Option<String> = None;
     
-    Ok(FacebookPage {
-        id: "123456789012345".to_string(),
-        name: "Messagr Demo Page".to_string(),
-        category: "App Page".to_string(),
-        access_token: "PAGE_ACCESS_TOKEN".to_string(),
-        tasks: vec!["ANALYZE".to_string(), "ADVERTISE".to_string(), "MESSAGING".to_string()],
-        picture: Some(FacebookPicture {
-            url: "https://platform-lookaside.fbsbx.com/platform/profilepic/?id=123456789012345".to_string(),
-        }),
-    })
+    for _ in 0..MAX_CONVERSATION_PAGES {
+        let mut params = vec![
+            ("platform", inbox.param().to_string()),
+            ("fields", CONVERSATION_FIELDS.to_string()),
+            ("limit", CONVERSATION_PAGE_SIZE.to_string()),
+        ];
+        if let Some(after) = &after {
+            params.push(("after", after.clone()));
+        }
+        
+        let list: GraphList<FacebookConversation> = graph_get(auth_config, Some(connection_id), &format!("/{}/conversations", page.id), &params).await?;
+        remote.extend(list.data.iter().map(|fb_conv| remote_conversation(fb_conv, &page, inbox).scoped(rules)));
+        
+        after = list.next_cursor();
+        if after.is_none() {
+            break;
+        }
+    }
+    
+    Ok(remote)
 }
 
-// Get conversations (threads) from Facebook
-async fn get_conversations(auth_config: &AuthConfig) -> Result<Vec<FacebookConversation>> {
-    // This would normally use HTTP outbound calls to the Facebook Graph API
-    // For demo purposes, we'll simulate the response
+fn remote_conversation(fb_conv: &FacebookConversation, page: &FacebookPage, inbox: Inbox) -> RemoteConversation {
+    let kind = if other_participants(fb_conv, page).len() > 1 {
+        ConversationKind::Group
+    } else {
+        ConversationKind::Direct
+    };
     
-    Ok(vec![
-        FacebookConversation {
-            id: "t_123456789012345".to_string(),
-            updated_time: "2023-01-01T12:00:00+0000".to_string(),
-            link: "https://www.messenger.com/t/123456789012345".to_string(),
-            name: None,
-            message_count: 42,
-            participants: FacebookParticipantData {
-                data: vec![
-                    FacebookParticipant {
-                        id: "987654321098765".to_string(),
-                        name: "John Smith".to_string(),
-                        email: None,
-                        profile_pic: Some("https://platform-lookaside.fbsbx.com/platform/profilepic/?id=987654321098765".to_string()),
-                    },
-                    FacebookParticipant {
-                        id: "123456789012345".to_string(),
-                        name: "Messagr Demo Page".to_string(),
-                        email: None,
-                        profile_pic: Some("https://platform-lookaside.fbsbx.com/platform/profilepic/?id=123456789012345".to_string()),
-                    },
-                ],
-            },
-            scoped_thread_key: Some("t_123456789012345".to_string()),
-        },
-        FacebookConversation {
-            id: "t_567890123456789".to_string(),
-            updated_time: "2023-01-02T14:30:00+0000".to_string(),
-            link: "https://www.messenger.com/t/567890123456789".to_string(),
-            name: Some("Marketing Team".to_string()),
-            message_count: 128,
-            participants: FacebookParticipantData {
-                data: vec![
-                    FacebookParticipant {
-                        id: "111222333444555".to_string(),
-                        name: "Jane Doe".to_string(),
-                        email: None,
-                        profile_pic: Some("https://platform-lookaside.fbsbx.com/platform/profilepic/?id=111222333444555".to_string()),
-                    },
-                    FacebookParticipant {
-                        id: "555444333222111".to_string(),
-                        name: "Bob Johnson".to_string(),
-                        email: None,
-                        profile_pic: Some("https://platform-lookaside.fbsbx.com/platform/profilepic/?id=555444333222111".to_string()),
-                    },
-                    FacebookParticipant {
-                        id: "123456789012345".to_string(),
-                        name: "Messagr Demo Page".to_string(),
-                        email: None,
-                        profile_pic: Some("https://platform-lookaside.fbsbx.com/platform/profilepic/?id=123456789012345".to_string()),
-                    },
-                ],
-            },
-            scoped_thread_key: Some("t_567890123456789".to_string()),
-        },
-    ])
+    RemoteConversation::new(
+        fb_conv.id.clone(),
+        conversation_name(fb_conv, page, inbox),
+        kind,
+        false,
+        Some(fb_conv.participants.data.len() as u64),
+    )
 }
 
-// Store Facebook conversations
-async fn store_conversations(
-    auth_config: &AuthConfig,
-    fb_conversations: Vec<FacebookConversation>,
-) -> Result<()> {
-    let caller = ic_cdk::caller();
-    let page_info = get_page_info(auth_config).await?;
+// Fetch messages newer than the conversation's newest synced one. Returns
+// the number stored and whether the pass finished.
+async fn sync_conversation(auth_config: &AuthConfig, connection_id: &str, conversation_id: &str, inbox: Inbox) -> Result<(u64, bool)> {
+    let mut cursor = FeedCursor::load(&format!("facebook:{}", conversation_id));
+    let newest_synced: u64 = cursor.newest.as_deref().and_then(|t| t.parse().ok()).unwrap_or(0);
+    let mut after = cursor.resume_token.clone();
+    let mut synced = 0;
     
-    for fb_conv in fb_conversations {
-        // Create participants list
-        let mut participants = Vec::new();
-        
-        for participant in fb_conv.participants.data {
-            participants.push(User {
-                id: participant.id.clone(),
-                name: participant.name.clone(),
-                platform: Platform::Facebook,
-                avatar_url: participant.profile_pic,
-            });
+    for _ in 0..MAX_MESSAGE_PAGES {
+        let mut params = vec![
+            ("fields", MESSAGE_FIELDS.to_string()),
+            ("limit", MESSAGE_PAGE_SIZE.to_string()),
+        ];
+        if let Some(after) = &after {
+            params.push(("after", after.clone()));
         }
         
-        // Make sure page is included in participants
-        if !participants.iter().any(|p| p.id == page_info.id) {
-            participants.push(User {
-                id: page_info.id.clone(),
-                name: page_info.name.clone(),
-                platform: Platform::Facebook,
-                avatar_url: page_info.picture.as_ref().map(|p| p.url.clone()),
-            });
-        }
+        let list: GraphList<FacebookMessage> = graph_get(auth_config, Some(connection_id), &format!("/{}/messages", conversation_id), &params).await?;
+        let next = list.next_cursor();
         
-        // Parse the updated time to get a timestamp
-        // In a real implementation, we'd use a proper date parsing
-        // Here we're using a simple estimate
-        let timestamp = time();
+        // Graph only returns details of a conversation's most recent
+        // messages; older ones come back without fields and are skipped
+        let page: Vec<Message> = list.data.into_iter()
+            .filter_map(|msg| facebook_message_to_message(msg, conversation_id, inbox))
+            .collect();
+        let newest = page.iter().map(|m| m.timestamp).max();
+        cursor.start_page(newest.map(|t| t.to_string()).as_deref());
         
-        // Create conversation
-        let conversation = Conversation {
-            id: fb_conv.id.clone(),
-            platform: Platform::Facebook,
-            name: fb_conv.name.unwrap_or_else(|| {
-                // For direct conversations, use the other person's name
-                for participant in &fb_conv.participants.data {
-                    if participant.id != page_info.id {
-                        return format!("Chat with {}", participant.name);
-                    }
-                }
-                "Facebook Conversation".to_string()
-            }),
-            participants,
-            created_at: timestamp,
-            last_message_at: Some(timestamp),
-        };
+        let reached_synced = page.iter().any(|m| m.timestamp <= newest_synced);
+        synced += store_page(page.into_iter().filter(|m| m.timestamp > newest_synced), conversation_id)?;
         
-        // Store conversation
-        conversations::store_conversation(conversation)?;
+        after = if reached_synced { None } else { next };
+        if after.is_none() {
+            break;
+        }
     }
     
-    Ok(())
+    cursor.save(after.as_deref());
+    Ok((synced, after.is_none()))
 }
 
-// Get messages from a Facebook conversation
-async fn get_conversation_messages(
-    auth_config: &AuthConfig,
-    conversation_id: &str,
-) -> Result<Vec<FacebookMessage>> {
-    // This would normally use HTTP outbound calls to the Facebook Graph API
-    // For demo purposes, we'll simulate the response
+fn store_conversation(
+    fb_conv: &FacebookConversation,
+    page: &FacebookPage,
+    caller: &str,
+    connection_id: &str,
+    inbox: Inbox
+) -> Result<()> {
+    let platform = inbox.platform();
     
-    Ok(vec![
-        FacebookMessage {
-            id: "m_123456789012345".to_string(),
-            message: "Hello, this is a test message!".to_string(),
-            created_time: "2023-01-02T14:30:00+0000".to_string(),
-            timestamp: 1672670400000, // Jan 2, 2023, 14:30 UTC in milliseconds
-            from: FacebookMessageSender {
-                id: "987654321098765".to_string(),
-                name: "John Smith".to_string(),
-                email: None,
-            },
-            attachments: Some(FacebookAttachmentData {
-                data: vec![
-                    FacebookAttachment {
-                        id: "123456789".to_string(),
-                        mime_type: "image/jpeg".to_string(),
-                        name: Some("photo.jpg".to_string()),
-                        size: Some(12345),
-                        image_data: Some(FacebookImageData {
-                            width: 800,
-                            height: 600,
-                            render_as_sticker: false,
-                            url: "https://scontent.xx.fbcdn.net/v/image.jpg".to_string(),
-                        }),
-                        video_data: None,
-                        file_url: Some("https://scontent.xx.fbcdn.net/v/image.jpg".to_string()),
-                    },
-                ],
-            }),
-        },
-        FacebookMessage {
-            id: "m_987654321098765".to_string(),
-            message: "Thanks for your message! How can I help you today?".to_string(),
-            created_time: "2023-01-02T14:35:00+0000".to_string(),
-            timestamp: 1672670700000, // Jan 2, 2023, 14:35 UTC in milliseconds
-            from: FacebookMessageSender {
-                id: "123456789012345".to_string(),
-                name: "Messagr Demo Page".to_string(),
-                email: None,
-            },
-            attachments: None,
+    // Conversation ids are Graph's own, so other users' syncs of the same
+    // conversation keep their place among the participants
+    let mut participants = vec![User {
+        id: caller.to_string(),
+        name: "Current User".to_string(),
+        platform: platform.clone(),
+        avatar_url: None,
+    }];
+    participants.extend(fb_conv.participants.data.iter().map(|p| participant_user(p, inbox)));
+    
+    // Make sure the page is included in participants
+    if inbox == Inbox::Messenger && !participants.iter().any(|p| p.id == page.id) {
+        participants.push(User {
+            id: page.id.clone(),
+            name: page.name.clone(),
+            platform: platform.clone(),
+            avatar_url: Some(format!("{}/{}/picture", GRAPH_API_BASE, page.id)),
+        });
+    }
+    
+    let participants = conversations::merge_participants(&fb_conv.id, participants);
+    let existing = conversations::get_conversation(&fb_conv.id);
+    let updated_at = parse_time(&fb_conv.updated_time).unwrap_or_else(|| ic_cdk::api::time() / 1_000_000);
+    connections::tag_conversation(caller, connection_id, &fb_conv.id);
+    
+    conversations::store_conversation(Conversation {
+        id: fb_conv.id.clone(),
+        platform,
+        name: conversation_name(fb_conv, page, inbox),
+        participants,
+        // Graph gives no creation time, so the first sync's activity stands in
+        created_at: existing.as_ref().map(|c| c.created_at).unwrap_or(updated_at),
+        // Keep what earlier syncs learned
+        last_message_at: existing.and_then(|c| c.last_message_at),
+        connection_id: Some(connection_id.to_string()),
+    })
+}
+
+// Participants other than the page and its Instagram account
+fn other_participants<'a>(fb_conv: &'a FacebookConversation, page: &FacebookPage) -> Vec<&'a FacebookParticipant> {
+    fb_conv.participants.data.iter()
+        .filter(|p| p.id != page.id && Some(&p.id) != page.instagram_business_account.as_ref().map(|a| &a.id))
+        .collect()
+}
+
+// Direct conversations are named after the other person
+fn conversation_name(fb_conv: &FacebookConversation, page: &FacebookPage, inbox: Inbox) -> String {
+    fb_conv.name.clone().unwrap_or_else(|| match other_participants(fb_conv, page).first() {
+        Some(other) => format!("Chat with {}", participant_name(other)),
+        None => match inbox {
+            Inbox::Messenger => "Facebook Conversation".to_string(),
+            Inbox::Instagram => "Instagram Conversation".to_string(),
         },
-    ])
+    })
 }
 
-// Convert Facebook message to our domain model
-fn facebook_message_to_message(msg: FacebookMessage, conversation_id: &str) -> Result<Message> {
-    let sender = User {
-        id: msg.from.id.clone(),
-        name: msg.from.name.clone(),
-        platform: Platform::Facebook,
-        avatar_url: Some(format!("https://graph.facebook.com/{}/picture", msg.from.id)),
-    };
+// Get the page the token belongs to, with its linked Instagram account
+async fn get_page_info(auth_config: &AuthConfig, connection_id: Option<&str>) -> Result<FacebookPage> {
+    graph_get(auth_config, connection_id, "/me", &[("fields", "id,name,instagram_business_account{id,username}".to_string())]).await
+}
+
+// GET a Graph API path with the page token, signed with appsecret_proof.
+// Back-offs are tracked by connection; without one (while connecting) they
+// aren't tracked.
+async fn graph_get<T: DeserializeOwned>(auth_config: &AuthConfig, connection_id: Option<&str>, path: &str, params: &[(&str, String)]) -> Result<T> {
+    let now = ic_cdk::api::time();
     
-    let mut attachments = Vec::new();
+    // Don't spend an outcall while Graph is throttling this connection's token
+    let throttled_until = connection_id
+        .and_then(|id| THROTTLED_UNTIL.with(|throttled| throttled.borrow().get(id).copied()))
+        .unwrap_or(0);
+    if throttled_until > now {
+        return Err(rate_limited(throttled_until - now));
+    }
     
-    // Process attachments if available
-    if let Some(attachment_data) = msg.attachments {
-        for attachment in attachment_data.data {
-            let attachment_type = if attachment.mime_type.starts_with("image/") {
-                "image"
-            } else if attachment.mime_type.starts_with("video/") {
-                "video"
-            } else {
-                "file"
-            };
-            
-            attachments.push(Attachment {
-                attachment_type: attachment_type.to_string(),
-                url: attachment.file_url.clone(),
-                content: None,
-                name: attachment.name.clone(),
-            });
+    let proof = facebook::generate_app_proof(auth_config)?;
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params.iter().map(|(name, value)| (*name, value.as_str())))
+        .append_pair("appsecret_proof", &proof)
+        .finish();
+    let url = format!("{}{}?{}", GRAPH_API_BASE, path, query);
+    
+    // The token goes in a header so it stays out of URLs
+    let request = http::OutgoingRequest::get(&url)
+        .with_bearer_token(&auth_config.token)
+        .with_max_response_bytes(MAX_RESPONSE_BYTES);
+    let (status, body) = http::send(request).await?;
+    
+    if !(200..300).contains(&status) {
+        let error = serde_json::from_slice::<GraphErrorResponse>(&body).ok().map(|response| response.error);
+        if let Some(error) = &error {
+            if RATE_LIMIT_CODES.contains(&error.code) {
+                if let Some(id) = connection_id {
+                    THROTTLED_UNTIL.with(|throttled| {
+                        throttled.borrow_mut().insert(id.to_string(), now + RATE_LIMIT_BACKOFF_NS);
+                    });
+                }
+                return Err(rate_limited(RATE_LIMIT_BACKOFF_NS));
+            }
         }
+        
+        return Err(Error::PlatformError(match error {
+            Some(error) => format!("Graph API error {} on {}: {}", error.code, path, error.message),
+            None => format!("HTTP {} from Graph API {}", status, path),
+        }));
     }
     
-    Ok(Message {
-        id: msg.id.clone(),
-        platform: Platform::Facebook,
-        conversation_id: conversation_id.to_string(),
-        sender,
-        content: MessageContent {
-            text: msg.message,
-            attachments,
-        },
-        timestamp: msg.timestamp,
-        thread_id: None,
-        reply_to: None,
-        edited: false,
-    })
+    http::decode_json(&url, (status, body))
+}
+
+fn rate_limited(wait_ns: u64) -> Error {
+    Error::PlatformError(format!(
+        "Graph API rate limit reached; retry in {}s", (wait_ns + 999_999_999) / 1_000_000_000
+    ))
+}
+
+// Graph times look like 2023-01-02T14:30:00+0000
+fn parse_time(time: &str) -> Option<u64> {
+    chrono::DateTime::parse_from_str(time, "%Y-%m-%dT%H:%M:%S%z")
+        .ok()
+        .and_then(|time| u64::try_from(time.timestamp_millis()).ok())
 }
 
 // Facebook API response structures
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphList<T> {
+    #[serde(default = "Vec::new")]
+    data: Vec<T>,
+    #[serde(default)]
+    paging: Option<GraphPaging>,
+}
+
+impl<T> GraphList<T> {
+    // Cursor of the next page; Graph leaves out `next` on the last one
+    fn next_cursor(&self) -> Option<String> {
+        let paging = self.paging.as_ref()?;
+        paging.next.as_ref()?;
+        paging.cursors.as_ref()?.after.clone()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphPaging {
+    #[serde(default)]
+    cursors: Option<GraphCursors>,
+    #[serde(default)]
+    next: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphCursors {
+    #[serde(default)]
+    after: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphErrorResponse {
+    error: GraphError,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GraphError {
+    message: String,
+    #[serde(default)]
+    code: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct FacebookPage {
     id: String,
     name: String,
-    category: String,
-    access_token: String,
-    tasks: Vec<String>,
-    picture: Option<FacebookPicture>,
+    #[serde(default)]
+    instagram_business_account: Option<InstagramAccount>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct FacebookPicture {
-    url: String,
+struct InstagramAccount {
+    id: String,
+    #[serde(default)]
+    username: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct FacebookConversation {
     id: String,
     updated_time: String,
-    link: String,
+    #[serde(default)]
+    link: Option<String>,
+    #[serde(default)]
     name: Option<String>,
-    message_count: i32,
     participants: FacebookParticipantData,
-    scoped_thread_key: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -324,59 +449,157 @@ struct FacebookParticipantData {
     data: Vec<FacebookParticipant>,
 }
 
+// Messenger participants have a name; Instagram ones a username
 #[derive(Debug, Serialize, Deserialize)]
 struct FacebookParticipant {
     id: String,
-    name: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
     email: Option<String>,
-    profile_pic: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct FacebookMessage {
     id: String,
-    message: String,
-    created_time: String,
-    timestamp: u64,
-    from: FacebookMessageSender,
-    attachments: Option<FacebookAttachmentData>,
+    #[serde(default)]
+    message: Option<String>,
+    #[serde(default)]
+    created_time: Option<String>,
+    #[serde(default)]
+    from: Option<FacebookParticipant>,
+    #[serde(default)]
+    attachments: Option<GraphList<FacebookAttachment>>,
+    #[serde(default)]
+    shares: Option<GraphList<FacebookShare>>,
+    // URL of the sticker image
+    #[serde(default)]
+    sticker: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct FacebookMessageSender {
-    id: String,
-    name: String,
-    email: Option<String>,
+struct FacebookAttachment {
+    #[serde(default)]
+    id: Option<String>,
+    #[serde(default)]
+    mime_type: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    size: Option<u64>,
+    #[serde(default)]
+    image_data: Option<FacebookMediaData>,
+    #[serde(default)]
+    video_data: Option<FacebookMediaData>,
+    #[serde(default)]
+    file_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct FacebookAttachmentData {
-    data: Vec<FacebookAttachment>,
+struct FacebookMediaData {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    preview_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct FacebookAttachment {
-    id: String,
-    mime_type: String,
+struct FacebookShare {
+    #[serde(default)]
+    link: Option<String>,
+    #[serde(default)]
     name: Option<String>,
-    size: Option<i32>,
-    image_data: Option<FacebookImageData>,
-    video_data: Option<FacebookVideoData>,
-    file_url: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FacebookImageData {
-    width: i32,
-    height: i32,
-    render_as_sticker: bool,
-    url: String,
+// Convert Facebook entities to our domain model
+fn participant_name(participant: &FacebookParticipant) -> String {
+    match (&participant.name, &participant.username) {
+        (Some(name), _) => name.clone(),
+        (None, Some(username)) => format!("@{}", username),
+        (None, None) => format!("User {}", participant.id),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct FacebookVideoData {
-    width: i32,
-    height: i32,
-    url: String,
-    preview_url: String,
-}
\ No newline at end of file
+fn participant_user(participant: &FacebookParticipant, inbox: Inbox) -> User {
+    User {
+        id: participant.id.clone(),
+        name: participant_name(participant),
+        platform: inbox.platform(),
+        avatar_url: match inbox {
+            Inbox::Messenger => Some(format!("{}/{}/picture", GRAPH_API_BASE, participant.id)),
+            Inbox::Instagram => None,
+        },
+    }
+}
+
+fn facebook_message_to_message(msg: FacebookMessage, conversation_id: &str, inbox: Inbox) -> Option<Message> {
+    let timestamp = parse_time(msg.created_time.as_deref()?)?;
+    let sender = participant_user(msg.from.as_ref()?, inbox);
+    
+    let mut attachments: Vec<Attachment> = msg.attachments.map(|list| list.data).unwrap_or_default()
+        .into_iter()
+        .map(|attachment| {
+            let attachment_type = match attachment.mime_type.as_deref().and_then(|m| m.split('/').next()) {
+                Some("image") => "image",
+                Some("video") => "video",
+                Some("audio") => "audio",
+                _ if attachment.image_data.is_some() => "image",
+                _ if attachment.video_data.is_some() => "video",
+                _ => "file",
+            };
+            let media_url = attachment.image_data.as_ref()
+                .or(attachment.video_data.as_ref())
+                .and_then(|media| media.url.clone().or_else(|| media.preview_url.clone()));
+            
+            Attachment {
+                attachment_type: attachment_type.to_string(),
+                url: attachment.file_url.or(media_url),
+                name: attachment.name,
+                mime_type: attachment.mime_type,
+                size: attachment.size,
+                blob_hash: None,
+            }
+        })
+        .collect();
+    
+    // Shared links and posts
+    attachments.extend(msg.shares.map(|list| list.data).unwrap_or_default()
+        .into_iter()
+        .filter(|share| share.link.is_some() || share.name.is_some())
+        .map(|share| Attachment {
+            attachment_type: "share".to_string(),
+            url: share.link,
+            name: share.name,
+            mime_type: None,
+            size: None,
+            blob_hash: None,
+        }));
+    
+    if let Some(sticker) = msg.sticker {
+        attachments.push(Attachment {
+            attachment_type: "image".to_string(),
+            url: Some(sticker),
+            name: Some("Sticker".to_string()),
+            mime_type: None,
+            size: None,
+            blob_hash: None,
+        });
+    }
+    
+    Some(Message {
+        id: msg.id,
+        platform: inbox.platform(),
+        conversation_id: conversation_id.to_string(),
+        sender,
+        content: MessageContent {
+            text: msg.message.unwrap_or_default(),
+            attachments,
+        },
+        timestamp,
+        thread_id: None,
+        reply_to: None,
+        edited: false,
+    })
+}
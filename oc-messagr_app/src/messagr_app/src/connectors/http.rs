@@ -0,0 +1,220 @@
+use crate::{Error, Result};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse,
+    TransformArgs, TransformContext,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+// Name of the canister query used to make responses deterministic across replicas
+const TRANSFORM_METHOD: &str = "transform_http_response";
+
+// Default cap on response size (2MB is the protocol maximum)
+pub const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+// Number of nodes in a standard application subnet, used for cycle estimates
+const SUBNET_SIZE: u128 = 13;
+
+// Response headers that are safe to keep (identical on every replica)
+const KEPT_HEADERS: [&str; 2] = ["content-type", "content-length"];
+
+// Which limit a 429 response hit (Discord). Remaining counts and reset
+// timings differ between replicas, since each makes its own request, and no
+// rounding keeps values near a boundary together, so those are dropped and
+// connectors back off for a fixed time on 429 instead.
+const RATE_LIMIT_SCOPE_HEADERS: [&str; 2] = ["x-ratelimit-global", "x-ratelimit-scope"];
+
+// An outgoing HTTPS request
+pub struct OutgoingRequest {
+    pub url: String,
+    pub method: HttpMethod,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub max_response_bytes: u64,
+    // Canister query the response is passed through before consensus
+    pub transform: String,
+}
+
+impl OutgoingRequest {
+    pub fn get(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            method: HttpMethod::GET,
+            headers: Vec::new(),
+            body: None,
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            transform: TRANSFORM_METHOD.to_string(),
+        }
+    }
+    
+    pub fn post(url: &str, body: Vec<u8>) -> Self {
+        Self {
+            url: url.to_string(),
+            method: HttpMethod::POST,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: Some(body),
+            max_response_bytes: DEFAULT_MAX_RESPONSE_BYTES,
+            transform: TRANSFORM_METHOD.to_string(),
+        }
+    }
+    
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+    
+    pub fn with_bearer_token(self, token: &str) -> Self {
+        self.with_header("Authorization", &format!("Bearer {}", token))
+    }
+    
+    pub fn with_max_response_bytes(mut self, max_response_bytes: u64) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+    
+    pub fn with_transform(mut self, method: &str) -> Self {
+        self.transform = method.to_string();
+        self
+    }
+}
+
+// A response with the headers that survived the transform (lowercased names)
+pub struct IncomingResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl IncomingResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+// Send an HTTPS outcall and return the status code and body
+pub async fn send(request: OutgoingRequest) -> Result<(u16, Vec<u8>)> {
+    send_metered(request).await.0
+}
+
+// Like `send`, also returning the response headers
+pub async fn send_with_headers(request: OutgoingRequest) -> Result<IncomingResponse> {
+    send_outcall(request).await.0
+}
+
+// Like `send`, also returning the cycles the outcall consumed. Cycles are
+// charged for failed outcalls too, so the cost is returned either way.
+pub async fn send_metered(request: OutgoingRequest) -> (Result<(u16, Vec<u8>)>, u128) {
+    let (result, spent) = send_outcall(request).await;
+    (result.map(|response| (response.status, response.body)), spent)
+}
+
+async fn send_outcall(request: OutgoingRequest) -> (Result<IncomingResponse>, u128) {
+    let request_bytes = request.url.len()
+        + request.headers.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>()
+        + request.body.as_ref().map(|b| b.len()).unwrap_or(0);
+    let cycles = outcall_cycles(request_bytes as u64, request.max_response_bytes);
+    
+    let argument = CanisterHttpRequestArgument {
+        url: request.url.clone(),
+        max_response_bytes: Some(request.max_response_bytes),
+        method: request.method,
+        headers: request.headers.into_iter()
+            .map(|(name, value)| HttpHeader { name, value })
+            .collect(),
+        body: request.body,
+        transform: Some(TransformContext::from_name(request.transform, vec![])),
+    };
+    
+    let result = http_request(argument, cycles).await;
+    let spent = cycles.saturating_sub(ic_cdk::api::call::msg_cycles_refunded128());
+    
+    let response = match result {
+        Ok((response,)) => response,
+        Err((code, message)) => {
+            return (Err(Error::PlatformError(format!(
+                "HTTP request to {} failed: {:?} {}", request.url, code, message
+            ))), spent);
+        },
+    };
+    
+    let status = u16::try_from(response.status.0).unwrap_or(0);
+    let headers = response.headers.into_iter()
+        .map(|header| (header.name.to_lowercase(), header.value))
+        .collect();
+    
+    (Ok(IncomingResponse { status, headers, body: response.body }), spent)
+}
+
+// POST a JSON body and decode a JSON response
+pub async fn post_json<B: Serialize, T: DeserializeOwned>(
+    url: &str,
+    headers: &[(&str, &str)],
+    body: &B,
+) -> Result<T> {
+    let body = serde_json::to_vec(body)
+        .map_err(|e| Error::InternalError(format!("Failed to encode request body: {}", e)))?;
+    
+    let mut request = OutgoingRequest::post(url, body);
+    for (name, value) in headers {
+        request = request.with_header(name, value);
+    }
+    
+    decode_json(url, send(request).await?)
+}
+
+// GET a URL and decode a JSON response
+pub async fn get_json<T: DeserializeOwned>(url: &str, headers: &[(&str, &str)]) -> Result<T> {
+    let mut request = OutgoingRequest::get(url);
+    for (name, value) in headers {
+        request = request.with_header(name, value);
+    }
+    
+    decode_json(url, send(request).await?)
+}
+
+// Decode a JSON response body, treating non-2xx statuses as errors
+pub fn decode_json<T: DeserializeOwned>(url: &str, (status, body): (u16, Vec<u8>)) -> Result<T> {
+    if !(200..300).contains(&status) {
+        return Err(Error::PlatformError(format!(
+            "HTTP {} from {}: {}", status, url, String::from_utf8_lossy(&body)
+        )));
+    }
+    
+    serde_json::from_slice(&body)
+        .map_err(|e| Error::PlatformError(format!("Invalid JSON from {}: {}", url, e)))
+}
+
+// Strip headers that differ between replicas so consensus can be reached
+pub fn transform_response(args: TransformArgs) -> HttpResponse {
+    let rate_limited = u16::try_from(args.response.status.0.clone()).map_or(false, |status| status == 429);
+    
+    let headers = args.response.headers.into_iter()
+        .filter_map(|header| {
+            let name = header.name.to_lowercase();
+            if KEPT_HEADERS.contains(&name.as_str()) {
+                Some(HttpHeader { name, value: header.value })
+            } else if RATE_LIMIT_SCOPE_HEADERS.contains(&name.as_str()) {
+                Some(HttpHeader { name, value: header.value.trim().to_lowercase() })
+            } else {
+                None
+            }
+        })
+        .collect();
+    
+    // A 429 body repeats the timings (Discord's retry_after), so it goes too
+    HttpResponse {
+        status: args.response.status,
+        headers,
+        body: if rate_limited { Vec::new() } else { args.response.body },
+    }
+}
+
+// Cycles to attach to an outcall (see the HTTPS outcalls cost table)
+fn outcall_cycles(request_bytes: u64, max_response_bytes: u64) -> u128 {
+    let base = (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE;
+    let request_cost = 400 * SUBNET_SIZE * request_bytes as u128;
+    let response_cost = 800 * SUBNET_SIZE * max_response_bytes as u128;
+    
+    base + request_cost + response_cost
+}
@@ -0,0 +1,20 @@
+use crate::{AuthConfig, Result};
+use super::facebook::{self, Inbox};
+use super::scope::{RemoteConversation, SyncRules};
+
+// Instagram Direct is read through the Graph API with the token of the
+// Facebook page the Instagram account is linked to
+
+// Initialize connection to Instagram Direct
+pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
+    facebook::init_inbox(auth_config, Inbox::Instagram).await
+}
+
+// Sync messages from Instagram Direct
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    facebook::sync_inbox(auth_config, connection_id, rules, Inbox::Instagram).await
+}
+
+pub async fn list_remote_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    facebook::list_inbox_conversations(auth_config, connection_id, rules, Inbox::Instagram).await
+}
@@ -4,6 +4,9 @@ pub mod discord;
 pub mod twitter;
 pub mod facebook;
 pub mod whatsapp;
+pub mod instagram;
+pub mod http;
+pub mod scope;
 
 use crate::{AuthConfig, Conversation, Message, Error, Result, User};
 use ic_cdk::api::time;
@@ -0,0 +1,262 @@
+use crate::{Error, Result};
+use candid::{CandidType, Deserialize};
+
+// Which of a connection's conversations are synced. A conversation is synced
+// when no exclude rule matches it and, if there are include rules, one of
+// them does. Connections with rules but no include rules skip archived
+// conversations; connections without any rules sync everything.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SyncRules {
+    pub include: Vec<ScopeRule>,
+    pub exclude: Vec<ScopeRule>,
+}
+
+// A rule matches when every criterion it sets matches
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ScopeRule {
+    pub conversation_id: Option<String>,
+    // Case-insensitive glob over the conversation name; `*` and `?` wildcards
+    pub name_glob: Option<String>,
+    pub kind: Option<ConversationKind>,
+    pub archived: Option<bool>,
+    // Member bounds never match conversations whose size is unknown
+    pub min_members: Option<u64>,
+    pub max_members: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversationKind {
+    Direct,
+    Group,
+}
+
+// A conversation as it exists upstream, and whether the rules sync it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RemoteConversation {
+    pub id: String,
+    pub name: String,
+    pub kind: ConversationKind,
+    pub archived: bool,
+    pub member_count: Option<u64>,
+    pub included: bool,
+}
+
+impl RemoteConversation {
+    pub fn new(id: String, name: String, kind: ConversationKind, archived: bool, member_count: Option<u64>) -> Self {
+        Self { id, name, kind, archived, member_count, included: false }
+    }
+    
+    // Fill in `included` from the rules
+    pub fn scoped(mut self, rules: &SyncRules) -> Self {
+        self.included = rules.allows(&self);
+        self
+    }
+}
+
+impl SyncRules {
+    pub fn allows(&self, conversation: &RemoteConversation) -> bool {
+        if self.exclude.iter().any(|rule| rule.matches(conversation)) {
+            return false;
+        }
+        
+        if self.include.is_empty() {
+            self.exclude.is_empty() || !conversation.archived
+        } else {
+            self.include.iter().any(|rule| rule.matches(conversation))
+        }
+    }
+    
+    // Rules that can't match anything are rejected rather than stored
+    pub fn validate(&self) -> Result<()> {
+        for rule in self.include.iter().chain(self.exclude.iter()) {
+            if let (Some(min), Some(max)) = (rule.min_members, rule.max_members) {
+                if min > max {
+                    return Err(Error::InvalidParameters(format!(
+                        "min_members {} is greater than max_members {}", min, max
+                    )));
+                }
+            }
+            if rule.name_glob.as_deref().map_or(false, |glob| glob.is_empty()) {
+                return Err(Error::InvalidParameters("name_glob must not be empty".to_string()));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ScopeRule {
+    pub fn matches(&self, conversation: &RemoteConversation) -> bool {
+        if self.conversation_id.as_ref().map_or(false, |id| *id != conversation.id) {
+            return false;
+        }
+        if self.name_glob.as_ref().map_or(false, |glob| !glob_match(glob, &conversation.name)) {
+            return false;
+        }
+        if self.kind.map_or(false, |kind| kind != conversation.kind) {
+            return false;
+        }
+        if self.archived.map_or(false, |archived| archived != conversation.archived) {
+            return false;
+        }
+        
+        let members = conversation.member_count;
+        if self.min_members.map_or(false, |min| members.map_or(true, |count| count < min)) {
+            return false;
+        }
+        if self.max_members.map_or(false, |max| members.map_or(true, |count| count > max)) {
+            return false;
+        }
+        
+        true
+    }
+}
+
+// Case-insensitive glob with `*` (any run) and `?` (any one character)
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position it was tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+    
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p + 1, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            // Let the last `*` absorb one more character
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn conversation(name: &str, kind: ConversationKind, archived: bool, member_count: Option<u64>) -> RemoteConversation {
+        RemoteConversation::new(format!("id-{}", name), name.to_string(), kind, archived, member_count)
+    }
+    
+    fn name_rule(glob: &str) -> ScopeRule {
+        ScopeRule { name_glob: Some(glob.to_string()), ..Default::default() }
+    }
+    
+    #[test]
+    fn glob_matches_literals_case_insensitively() {
+        assert!(glob_match("general", "general"));
+        assert!(glob_match("General", "gENERAL"));
+        assert!(!glob_match("general", "generals"));
+        assert!(!glob_match("general", "genera"));
+    }
+    
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("#alerts-*", "#alerts-prod"));
+        assert!(glob_match("#alerts-*", "#alerts-"));
+        assert!(!glob_match("#alerts-*", "#alert-prod"));
+        assert!(glob_match("*-bot", "deploy-bot"));
+        assert!(glob_match("a*b*c", "axxbyybzc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("team-?", "team-a"));
+        assert!(!glob_match("team-?", "team-ab"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("**", "anything"));
+        assert!(!glob_match("?", ""));
+    }
+    
+    #[test]
+    fn glob_handles_non_ascii() {
+        assert!(glob_match("caf?", "café"));
+        assert!(glob_match("ÉQUIPE*", "équipe-nord"));
+    }
+    
+    #[test]
+    fn no_rules_sync_everything() {
+        let rules = SyncRules::default();
+        assert!(rules.allows(&conversation("general", ConversationKind::Group, false, None)));
+        assert!(rules.allows(&conversation("old", ConversationKind::Group, true, None)));
+    }
+    
+    #[test]
+    fn exclude_rules_skip_matches_and_archived() {
+        let rules = SyncRules { include: Vec::new(), exclude: vec![name_rule("#alerts-*")] };
+        assert!(!rules.allows(&conversation("#alerts-prod", ConversationKind::Group, false, None)));
+        assert!(rules.allows(&conversation("#general", ConversationKind::Group, false, None)));
+        assert!(!rules.allows(&conversation("#old", ConversationKind::Group, true, None)));
+    }
+    
+    #[test]
+    fn include_rules_select_conversations() {
+        let rules = SyncRules {
+            include: vec![
+                ScopeRule { kind: Some(ConversationKind::Direct), ..Default::default() },
+                ScopeRule { archived: Some(true), ..name_rule("#history") },
+            ],
+            exclude: Vec::new(),
+        };
+        assert!(rules.allows(&conversation("alice", ConversationKind::Direct, false, None)));
+        assert!(rules.allows(&conversation("#history", ConversationKind::Group, true, None)));
+        assert!(!rules.allows(&conversation("#history", ConversationKind::Group, false, None)));
+        assert!(!rules.allows(&conversation("#general", ConversationKind::Group, false, None)));
+    }
+    
+    #[test]
+    fn exclude_wins_over_include() {
+        let rules = SyncRules { include: vec![name_rule("#team-*")], exclude: vec![name_rule("#team-bots")] };
+        assert!(rules.allows(&conversation("#team-core", ConversationKind::Group, false, None)));
+        assert!(!rules.allows(&conversation("#team-bots", ConversationKind::Group, false, None)));
+    }
+    
+    #[test]
+    fn conversation_id_must_match_exactly() {
+        let rule = ScopeRule { conversation_id: Some("id-general".to_string()), ..Default::default() };
+        assert!(rule.matches(&conversation("general", ConversationKind::Group, false, None)));
+        assert!(!rule.matches(&conversation("random", ConversationKind::Group, false, None)));
+    }
+    
+    #[test]
+    fn member_bounds_skip_unknown_sizes() {
+        let rule = ScopeRule { min_members: Some(10), max_members: Some(500), ..Default::default() };
+        assert!(rule.matches(&conversation("a", ConversationKind::Group, false, Some(10))));
+        assert!(rule.matches(&conversation("b", ConversationKind::Group, false, Some(500))));
+        assert!(!rule.matches(&conversation("c", ConversationKind::Group, false, Some(9))));
+        assert!(!rule.matches(&conversation("d", ConversationKind::Group, false, Some(501))));
+        assert!(!rule.matches(&conversation("e", ConversationKind::Group, false, None)));
+        
+        let large = SyncRules { include: Vec::new(), exclude: vec![ScopeRule { min_members: Some(501), ..Default::default() }] };
+        assert!(large.allows(&conversation("f", ConversationKind::Group, false, None)));
+    }
+    
+    #[test]
+    fn scoped_fills_included() {
+        let rules = SyncRules { include: Vec::new(), exclude: vec![name_rule("#random")] };
+        assert!(conversation("#general", ConversationKind::Group, false, None).scoped(&rules).included);
+        assert!(!conversation("#random", ConversationKind::Group, false, None).scoped(&rules).included);
+    }
+    
+    #[test]
+    fn validate_rejects_unmatchable_rules() {
+        let inverted = SyncRules {
+            include: vec![ScopeRule { min_members: Some(10), max_members: Some(5), ..Default::default() }],
+            exclude: Vec::new(),
+        };
+        assert!(matches!(inverted.validate(), Err(Error::InvalidParameters(_))));
+        
+        let empty_glob = SyncRules { include: Vec::new(), exclude: vec![name_rule("")] };
+        assert!(matches!(empty_glob.validate(), Err(Error::InvalidParameters(_))));
+        
+        let fine = SyncRules { include: vec![name_rule("#team-*")], exclude: Vec::new() };
+        assert!(fine.validate().is_ok());
+    }
+}
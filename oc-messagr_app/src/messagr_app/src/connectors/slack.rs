@@ -1,214 +1,550 @@
 use crate::{
-    AuthConfig, Conversation, Message, MessageContent, User, 
+    AuthConfig, Conversation, Message, MessageContent, User,
     Attachment, Platform, Error, Result
 };
-use serde::{Deserialize, Serialize};
-use ic_cdk::api::time;
-use std::collections::HashMap;
-use crate::storage::{conversations, messages};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use crate::storage::{connections, conversations, directory, engagement, messages, sync_state};
+use crate::storage::sync_state::FeedCursor;
+use super::http;
+use super::scope::{ConversationKind, RemoteConversation, SyncRules};
+
+const API_BASE: &str = "https://slack.com/api";
+
+// Items per page for history, replies and lists
+const PAGE_SIZE: usize = 200;
+
+// Pages read per channel, thread or list in one sync; cursors resume there
+const MAX_PAGES: usize = 5;
+
+// Threads started this long ago are still checked for new replies
+const THREAD_WATCH_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+// Threads read per channel in one sync, new and rechecked together
+const MAX_THREADS_PER_CHANNEL: usize = 20;
+
+// Already read thread roots remembered per channel for rechecking, newest
+// kept. Roots not read yet are always kept.
+const MAX_WATCHED_THREADS: usize = 500;
+
+// Users looked up one by one per sync when missing from the directory
+const MAX_USER_LOOKUPS: usize = 20;
+
+// How often the user directory is reloaded from users.list
+const DIRECTORY_REFRESH_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+// How long a method is left alone after a 429. The transform drops Slack's
+// Retry-After, which differs between replicas.
+const RATE_LIMIT_BACKOFF_NS: u64 = 30 * 1_000_000_000;
+
+// Join and leave notices aren't conversation content
+const SKIPPED_SUBTYPES: [&str; 2] = ["channel_join", "channel_leave"];
+
+thread_local! {
+    // End of a rate limit by connection and API method, in nanoseconds
+    static RATE_LIMITS: RefCell<HashMap<(String, String), u64>> = RefCell::new(HashMap::new());
+}
+
+// What messages of one workspace are rendered against
+struct Workspace {
+    team_id: String,
+    // Channel names by id, for <#C123> mentions
+    channels: HashMap<String, String>,
+}
 
 // Initialize connection to Slack
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
     // Verify token validity by making a test API call
-    let user_info = get_user_info(auth_config).await?;
-    ic_cdk::println!("Connected to Slack as: {}", user_info.user.name);
-    
-    // Populate initial conversations
-    let channels = get_channels(auth_config).await?;
-    store_channels(auth_config, channels).await?;
+    let identity = auth_test(auth_config, None).await?;
+    ic_cdk::println!("Connected to Slack workspace {} as: {}", identity.team, identity.user);
     
     Ok(())
 }
 
-// Sync messages from Slack
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
-    // Get all channels/conversations
-    let user_conversations = get_user_conversations(auth_config).await?;
+// Sync the channels, DMs and threads the token can read. Returns the number
+// of messages stored.
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    let caller = ic_cdk::caller().to_string();
+    let identity = auth_test(auth_config, Some(connection_id)).await?;
+    
+    // A stale directory also refreshes channel members below
+    let refreshed = match refresh_directory(auth_config, connection_id, &identity.team_id).await {
+        Ok(refreshed) => refreshed,
+        Err(e) => {
+            ic_cdk::println!("Failed to refresh Slack user directory: {:?}", e);
+            false
+        },
+    };
+    
+    let channels = list_channels(auth_config, connection_id).await?;
+    let workspace = Workspace {
+        team_id: identity.team_id.clone(),
+        channels: channels.iter()
+            .filter_map(|c| c.name.as_ref().map(|name| (c.id.clone(), name.clone())))
+            .collect(),
+    };
     
     let mut total_synced = 0;
     
-    // For each conversation, get recent messages
-    for conversation in user_conversations {
-        let history = get_conversation_history(auth_config, &conversation.id).await?;
-        
-        // Store messages
-        for msg in history.messages {
-            let message = slack_message_to_message(msg, &conversation.id)?;
-            messages::store_message(message)?;
-            total_synced += 1;
+    // Channels outside the connection's scope are never stored
+    let scoped = channels.iter()
+        .filter(|channel| rules.allows(&remote_conversation(&identity.team_id, channel)));
+    
+    for channel in scoped {
+        if let Err(e) = store_channel(auth_config, &workspace, channel, &caller, connection_id, refreshed).await {
+            ic_cdk::println!("Failed to store Slack channel {}: {:?}", channel.id, e);
+            continue;
         }
         
-        // Update conversation with last message timestamp
-        if let Some(latest_ts) = history.messages.first().map(|m| m.ts.clone()) {
-            if let Ok(ts_millis) = parse_slack_timestamp(&latest_ts) {
-                conversations::update_conversation_last_message(&conversation.id, ts_millis)?;
-            }
+        match sync_channel(auth_config, connection_id, &workspace, &channel.id).await {
+            Ok(count) => total_synced += count,
+            // Rate-limited or unreadable channels are picked up on the next sync
+            Err(e) => ic_cdk::println!("Failed to sync Slack channel {}: {:?}", channel.id, e),
         }
     }
     
     Ok(total_synced)
 }
 
-// Get user info
-async fn get_user_info(auth_config: &AuthConfig) -> Result<SlackUserInfoResponse> {
-    // This would normally use HTTP outbound calls to the Slack API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(SlackUserInfoResponse {
-        ok: true,
-        user: SlackUser {
-            id: "U0123456789".to_string(),
-            name: "messagr_user".to_string(),
-            real_name: Some("Messagr User".to_string()),
-            profile: SlackUserProfile {
-                avatar_hash: "abc123".to_string(),
-                image_72: Some("https://avatars.slack-edge.com/abc123_72.png".to_string()),
-            },
-        },
-    })
+// Channels, private channels and DMs the token can read, and whether the
+// connection's rules sync them
+pub async fn list_remote_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    let identity = auth_test(auth_config, Some(connection_id)).await?;
+    let channels = list_channels(auth_config, connection_id).await?;
+    
+    Ok(channels.iter()
+        .map(|channel| remote_conversation(&identity.team_id, channel).scoped(rules))
+        .collect())
 }
 
-// Get channels
-async fn get_channels(auth_config: &AuthConfig) -> Result<SlackChannelsResponse> {
-    // This would normally use HTTP outbound calls to the Slack API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(SlackChannelsResponse {
-        ok: true,
-        channels: vec![
-            SlackChannel {
-                id: "C0123456789".to_string(),
-                name: "general".to_string(),
-                is_channel: true,
-                is_group: false,
-                is_im: false,
-                created: 1609459200, // January 1, 2021
-                creator: "U0123456789".to_string(),
-                is_archived: false,
-                is_general: true,
-                members: Some(vec!["U0123456789".to_string(), "U9876543210".to_string()]),
-                topic: SlackChannelTopic {
-                    value: "Company-wide announcements and work-based matters".to_string(),
-                    creator: "U0123456789".to_string(),
-                    last_set: 1609459200,
-                },
-                purpose: SlackChannelPurpose {
-                    value: "This channel is for company-wide communication".to_string(),
-                    creator: "U0123456789".to_string(),
-                    last_set: 1609459200,
-                },
-            },
-            SlackChannel {
-                id: "C9876543210".to_string(),
-                name: "random".to_string(),
-                is_channel: true,
-                is_group: false,
-                is_im: false,
-                created: 1609459200, // January 1, 2021
-                creator: "U0123456789".to_string(),
-                is_archived: false,
-                is_general: false,
-                members: Some(vec!["U0123456789".to_string(), "U9876543210".to_string()]),
-                topic: SlackChannelTopic {
-                    value: "Non-work banter and water cooler conversation".to_string(),
-                    creator: "U0123456789".to_string(),
-                    last_set: 1609459200,
-                },
-                purpose: SlackChannelPurpose {
-                    value: "A place for non-work-related flimflam".to_string(),
-                    creator: "U0123456789".to_string(),
-                    last_set: 1609459200,
-                },
+fn remote_conversation(team_id: &str, channel: &SlackChannel) -> RemoteConversation {
+    let kind = if channel.is_im.unwrap_or(false) {
+        ConversationKind::Direct
+    } else {
+        ConversationKind::Group
+    };
+    
+    RemoteConversation::new(
+        channel.id.clone(),
+        channel_name(team_id, channel),
+        kind,
+        channel.is_archived.unwrap_or(false),
+        // DMs always have two members; other channels report theirs
+        if channel.is_im.unwrap_or(false) { Some(2) } else { channel.num_members },
+    )
+}
+
+fn channel_name(team_id: &str, channel: &SlackChannel) -> String {
+    match (&channel.user, &channel.name) {
+        (Some(user_id), _) => format!("DM: {}", directory_user(team_id, user_id).name),
+        (None, Some(name)) if channel.is_mpim.unwrap_or(false) => format!("Group DM: {}", name),
+        (None, Some(name)) => format!("#{}", name),
+        (None, None) => channel.id.clone(),
+    }
+}
+
+// Fetch a channel's new messages, then new replies of its recent threads
+async fn sync_channel(auth_config: &AuthConfig, connection_id: &str, workspace: &Workspace, channel_id: &str) -> Result<u64> {
+    let mut cursor = FeedCursor::load(&format!("slack:{}", channel_id));
+    let mut next_cursor = cursor.resume_token.clone();
+    let mut threads: Vec<String> = Vec::new();
+    let mut synced = 0;
+    
+    for _ in 0..MAX_PAGES {
+        let mut params = vec![
+            ("channel", channel_id.to_string()),
+            ("limit", PAGE_SIZE.to_string()),
+        ];
+        // Exclusive, so only messages after the newest synced one
+        if let Some(oldest) = &cursor.newest {
+            params.push(("oldest", oldest.clone()));
+        }
+        if let Some(next) = &next_cursor {
+            params.push(("cursor", next.clone()));
+        }
+        
+        let page: SlackHistoryResponse = api_get(auth_config, Some(connection_id), "conversations.history", &params).await?;
+        cursor.start_page(page.messages.iter().map(|m| m.ts.as_str()).max_by(|a, b| compare_ts(a, b)));
+        
+        threads.extend(page.messages.iter()
+            .filter(|m| m.reply_count.unwrap_or(0) > 0)
+            .map(|m| m.ts.clone()));
+        synced += store_page(auth_config, connection_id, workspace, page.messages, channel_id).await?;
+        
+        next_cursor = page.response_metadata.and_then(|m| m.next_cursor).filter(|c| !c.is_empty());
+        if next_cursor.is_none() {
+            break;
+        }
+    }
+    
+    cursor.save(next_cursor.as_deref());
+    
+    // Replies don't show up in history, so threads are read separately, a
+    // capped number per sync. Roots never read come first and stay watched
+    // until they are, whatever their age; read ones are rechecked while recent.
+    let mut candidates: Vec<String> = threads.into_iter().chain(watched_threads(channel_id)).collect();
+    candidates.sort_by(|a, b| compare_ts(b, a));
+    candidates.dedup();
+    let read_before: HashSet<String> = candidates.iter()
+        .filter(|ts| sync_state::get_cursor(&thread_newest_key(channel_id, ts)).is_some())
+        .cloned()
+        .collect();
+    // Stable, so each group stays newest first
+    candidates.sort_by_key(|ts| read_before.contains(ts));
+    
+    let mut read_now: HashSet<String> = HashSet::new();
+    for thread_ts in candidates.iter().take(MAX_THREADS_PER_CHANNEL) {
+        match sync_thread(auth_config, connection_id, workspace, channel_id, thread_ts).await {
+            Ok(count) => {
+                synced += count;
+                read_now.insert(thread_ts.clone());
             },
-        ],
-    })
+            Err(e) => ic_cdk::println!("Failed to sync Slack thread {} in {}: {:?}", thread_ts, channel_id, e),
+        }
+    }
+    
+    let watch_from = (ic_cdk::api::time() / 1_000_000).saturating_sub(THREAD_WATCH_MS);
+    let (mut still_watched, mut recent): (Vec<String>, Vec<String>) = candidates.into_iter()
+        .partition(|ts| !read_before.contains(ts) && !read_now.contains(ts));
+    recent.retain(|ts| parse_slack_timestamp(ts).unwrap_or(0) >= watch_from);
+    recent.truncate(MAX_WATCHED_THREADS);
+    still_watched.extend(recent);
+    still_watched.sort_by(|a, b| compare_ts(b, a));
+    sync_state::set_cursor(&watched_key(channel_id), &still_watched.join(","));
+    
+    Ok(synced)
+}
+
+fn watched_key(channel_id: &str) -> String {
+    format!("slack:{}:threads", channel_id)
 }
 
-// Store channels as conversations
-async fn store_channels(auth_config: &AuthConfig, channels_response: SlackChannelsResponse) -> Result<()> {
-    let caller = ic_cdk::caller();
+// Newest reply synced from a thread; absent until the thread has been read
+fn thread_newest_key(channel_id: &str, thread_ts: &str) -> String {
+    format!("slack:{}:{}:newest", channel_id, thread_ts)
+}
+
+// Roots of the channel's threads seen by earlier syncs, newest first. Kept
+// in the sync state so finding them doesn't read the channel's messages.
+fn watched_threads(channel_id: &str) -> Vec<String> {
+    sync_state::get_cursor(&watched_key(channel_id))
+        .map(|threads| threads.split(',').filter(|ts| !ts.is_empty()).map(|ts| ts.to_string()).collect())
+        .unwrap_or_default()
+}
+
+// Fetch replies of a thread after the newest synced one. Replies come oldest
+// first, so the cursor moves forward page by page.
+async fn sync_thread(auth_config: &AuthConfig, connection_id: &str, workspace: &Workspace, channel_id: &str, thread_ts: &str) -> Result<u64> {
+    let newest_key = thread_newest_key(channel_id, thread_ts);
+    // Fixed for the whole pass so the paging cursor stays valid
+    let newest = sync_state::get_cursor(&newest_key).unwrap_or_else(|| thread_ts.to_string());
+    let mut next_cursor: Option<String> = None;
+    let mut synced = 0;
     
-    for channel in channels_response.channels {
-        // Get channel members (in a real implementation, we'd fetch user details)
-        let members = channel.members.unwrap_or_default();
-        let mut participants = Vec::new();
+    for _ in 0..MAX_PAGES {
+        let mut params = vec![
+            ("channel", channel_id.to_string()),
+            ("ts", thread_ts.to_string()),
+            ("oldest", newest.clone()),
+            ("limit", PAGE_SIZE.to_string()),
+        ];
+        if let Some(next) = &next_cursor {
+            params.push(("cursor", next.clone()));
+        }
         
-        for member_id in members {
-            participants.push(User {
-                id: member_id.clone(),
-                name: format!("User {}", member_id),
-                platform: Platform::Slack,
-                avatar_url: None,
-            });
+        let page: SlackHistoryResponse = api_get(auth_config, Some(connection_id), "conversations.replies", &params).await?;
+        
+        // The thread's parent is always included
+        let replies: Vec<SlackMessage> = page.messages.into_iter()
+            .filter(|m| m.ts != thread_ts && compare_ts(&m.ts, &newest).is_gt())
+            .collect();
+        let latest = replies.iter().map(|m| m.ts.clone()).max_by(|a, b| compare_ts(a, b));
+        synced += store_page(auth_config, connection_id, workspace, replies, channel_id).await?;
+        if let Some(latest) = latest {
+            sync_state::set_cursor(&newest_key, &latest);
         }
         
-        // Add the current user if not already in participants
-        if !participants.iter().any(|p| p.id.starts_with(&caller.to_string())) {
-            participants.push(User {
-                id: caller.to_string(),
-                name: "Current User".to_string(),
-                platform: Platform::Slack,
-                avatar_url: None,
-            });
+        next_cursor = page.response_metadata.and_then(|m| m.next_cursor).filter(|c| !c.is_empty());
+        if next_cursor.is_none() {
+            break;
+        }
+    }
+    
+    // Marks the thread read even when it had no new replies
+    if sync_state::get_cursor(&newest_key).is_none() {
+        sync_state::set_cursor(&newest_key, &newest);
+    }
+    
+    Ok(synced)
+}
+
+// Store a page of messages and move the conversation's last message time forward
+async fn store_page(
+    auth_config: &AuthConfig,
+    connection_id: &str,
+    workspace: &Workspace,
+    page: Vec<SlackMessage>,
+    channel_id: &str
+) -> Result<u64> {
+    // Authors and mentioned users missing from the directory
+    let unknown: HashSet<String> = page.iter()
+        .flat_map(|m| m.user.iter().cloned().chain(mentioned_users(&m.text)))
+        .filter(|user_id| directory::get_user(&directory_key(&workspace.team_id, user_id)).is_none())
+        .collect();
+    for user_id in unknown.into_iter().take(MAX_USER_LOOKUPS) {
+        if let Err(e) = lookup_user(auth_config, connection_id, &workspace.team_id, &user_id).await {
+            ic_cdk::println!("Failed to look up Slack user {}: {:?}", user_id, e);
+        }
+    }
+    
+    let mut stored = 0;
+    let mut latest = 0;
+    
+    for msg in page {
+        if msg.subtype.as_deref().map(|s| SKIPPED_SUBTYPES.contains(&s)).unwrap_or(false) {
+            continue;
         }
         
-        // Create conversation
-        let conversation = Conversation {
-            id: channel.id.clone(),
-            platform: Platform::Slack,
-            name: format!("#{}", channel.name),
-            participants,
-            created_at: (channel.created as u64) * 1000, // Convert to milliseconds
-            last_message_at: None,
-        };
+        let reply_count = msg.reply_count.unwrap_or(0);
+        let reaction_count: u32 = msg.reactions.as_ref()
+            .map(|reactions| reactions.iter().map(|r| r.count).sum())
+            .unwrap_or(0);
+        
+        let message = slack_message_to_message(msg, channel_id, workspace)?;
+        let message_id = message.id.clone();
+        latest = latest.max(message.timestamp);
+        messages::store_message(message)?;
+        
+        // Keep engagement counts reported by Slack for ranking
+        engagement::record_platform_counts(&message_id, reply_count, reaction_count);
+        stored += 1;
+    }
+    
+    let previous = conversations::get_conversation(channel_id).and_then(|c| c.last_message_at);
+    if stored > 0 && previous.map(|p| latest > p).unwrap_or(true) {
+        conversations::update_conversation_last_message(channel_id, latest)?;
+    }
+    
+    Ok(stored)
+}
+
+// Channels, private channels and DMs the token is a member of, archived
+// ones included so the sync rules can decide on them
+async fn list_channels(auth_config: &AuthConfig, connection_id: &str) -> Result<Vec<SlackChannel>> {
+    let mut channels = Vec::new();
+    let mut next_cursor: Option<String> = None;
+    
+    for _ in 0..MAX_PAGES {
+        let mut params = vec![
+            ("types", "public_channel,private_channel,mpim,im".to_string()),
+            ("exclude_archived", "false".to_string()),
+            ("limit", PAGE_SIZE.to_string()),
+        ];
+        if let Some(next) = &next_cursor {
+            params.push(("cursor", next.clone()));
+        }
+        
+        let page: SlackChannelsResponse = api_get(auth_config, Some(connection_id), "conversations.list", &params).await?;
+        // History of public channels can only be read by members
+        channels.extend(page.channels.into_iter().filter(|c| c.is_member.unwrap_or(true)));
+        
+        next_cursor = page.response_metadata.and_then(|m| m.next_cursor).filter(|c| !c.is_empty());
+        if next_cursor.is_none() {
+            break;
+        }
+    }
+    
+    Ok(channels)
+}
+
+// Store a channel as a conversation. Members are fetched for new channels
+// and whenever the directory was refreshed.
+async fn store_channel(
+    auth_config: &AuthConfig,
+    workspace: &Workspace,
+    channel: &SlackChannel,
+    caller: &str,
+    connection_id: &str,
+    refresh_members: bool
+) -> Result<()> {
+    let existing = conversations::get_conversation(&channel.id);
+    
+    let mut participants = vec![User {
+        id: caller.to_string(),
+        name: "Current User".to_string(),
+        platform: Platform::Slack,
+        avatar_url: None,
+    }];
+    
+    let member_ids: Option<Vec<String>> = if let Some(user_id) = &channel.user {
+        // The other side of a DM
+        Some(vec![user_id.clone()])
+    } else if existing.is_none() || refresh_members {
+        Some(list_members(auth_config, connection_id, &channel.id).await?)
+    } else {
+        None
+    };
+    
+    if let Some(member_ids) = member_ids {
+        participants.extend(member_ids.iter().map(|id| directory_user(&workspace.team_id, id)));
+    }
+    // Channel ids are Slack's own, so other users' syncs of the channel
+    // keep their place among the participants
+    let participants = conversations::merge_participants(&channel.id, participants);
+    connections::tag_conversation(caller, connection_id, &channel.id);
+    
+    conversations::store_conversation(Conversation {
+        id: channel.id.clone(),
+        platform: Platform::Slack,
+        name: channel_name(&workspace.team_id, channel),
+        participants,
+        created_at: channel.created * 1000, // Convert to milliseconds
+        // Keep what earlier syncs learned
+        last_message_at: existing.and_then(|c| c.last_message_at),
+        connection_id: Some(connection_id.to_string()),
+    })
+}
+
+// Member ids of a channel, a page at a time
+async fn list_members(auth_config: &AuthConfig, connection_id: &str, channel_id: &str) -> Result<Vec<String>> {
+    let mut members = Vec::new();
+    let mut next_cursor: Option<String> = None;
+    
+    for _ in 0..MAX_PAGES {
+        let mut params = vec![
+            ("channel", channel_id.to_string()),
+            ("limit", PAGE_SIZE.to_string()),
+        ];
+        if let Some(next) = &next_cursor {
+            params.push(("cursor", next.clone()));
+        }
         
-        // Store conversation
-        conversations::store_conversation(conversation)?;
+        let page: SlackMembersResponse = api_get(auth_config, Some(connection_id), "conversations.members", &params).await?;
+        members.extend(page.members);
+        
+        next_cursor = page.response_metadata.and_then(|m| m.next_cursor).filter(|c| !c.is_empty());
+        if next_cursor.is_none() {
+            break;
+        }
+    }
+    
+    Ok(members)
+}
+
+// Reload the workspace's users when the directory is older than a day.
+// Returns whether it was refreshed.
+async fn refresh_directory(auth_config: &AuthConfig, connection_id: &str, team_id: &str) -> Result<bool> {
+    let refreshed_key = format!("slack:{}:directory:refreshed", team_id);
+    let resume_key = format!("slack:{}:directory:resume", team_id);
+    let now = ic_cdk::api::time();
+    
+    let refreshed_at: u64 = sync_state::get_cursor(&refreshed_key).and_then(|t| t.parse().ok()).unwrap_or(0);
+    let mut next_cursor = sync_state::get_cursor(&resume_key).filter(|c| !c.is_empty());
+    if next_cursor.is_none() && now.saturating_sub(refreshed_at) < DIRECTORY_REFRESH_NS {
+        return Ok(false);
+    }
+    
+    for _ in 0..MAX_PAGES {
+        let mut params = vec![("limit", PAGE_SIZE.to_string())];
+        if let Some(next) = &next_cursor {
+            params.push(("cursor", next.clone()));
+        }
+        
+        let page: SlackUsersResponse = api_get(auth_config, Some(connection_id), "users.list", &params).await?;
+        for user in &page.members {
+            directory::store_user(&directory_key(team_id, &user.id), slack_user(user));
+        }
+        
+        next_cursor = page.response_metadata.and_then(|m| m.next_cursor).filter(|c| !c.is_empty());
+        if next_cursor.is_none() {
+            break;
+        }
+    }
+    
+    // Large workspaces finish loading on later syncs
+    sync_state::set_cursor(&resume_key, next_cursor.as_deref().unwrap_or(""));
+    if next_cursor.is_none() {
+        sync_state::set_cursor(&refreshed_key, &now.to_string());
     }
     
+    Ok(true)
+}
+
+async fn lookup_user(auth_config: &AuthConfig, connection_id: &str, team_id: &str, user_id: &str) -> Result<()> {
+    let response: SlackUserInfoResponse = api_get(auth_config, Some(connection_id), "users.info", &[("user", user_id.to_string())]).await?;
+    directory::store_user(&directory_key(team_id, user_id), slack_user(&response.user));
     Ok(())
 }
 
-// Get conversation history
-async fn get_conversation_history(auth_config: &AuthConfig, channel_id: &str) -> Result<SlackHistoryResponse> {
-    // This would normally use HTTP outbound calls to the Slack API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(SlackHistoryResponse {
-        ok: true,
-        messages: vec![
-            SlackMessage {
-                type_field: "message".to_string(),
-                user: Some("U0123456789".to_string()),
-                text: "Hello world!".to_string(),
-                ts: "1609459200.000100".to_string(),
-                thread_ts: None,
-                reply_count: None,
-                replies: None,
-                attachments: None,
-            },
-            SlackMessage {
-                type_field: "message".to_string(),
-                user: Some("U9876543210".to_string()),
-                text: "Hi there!".to_string(),
-                ts: "1609459300.000200".to_string(),
-                thread_ts: None,
-                reply_count: None,
-                replies: None,
-                attachments: None,
-            },
-        ],
-        has_more: false,
+fn directory_key(team_id: &str, user_id: &str) -> String {
+    format!("slack:{}:{}", team_id, user_id)
+}
+
+// A user from the directory, or a placeholder until they've been looked up
+fn directory_user(team_id: &str, user_id: &str) -> User {
+    directory::get_user(&directory_key(team_id, user_id)).unwrap_or_else(|| User {
+        id: user_id.to_string(),
+        name: format!("User {}", user_id),
+        platform: Platform::Slack,
+        avatar_url: None,
     })
 }
 
-// Get all conversations/channels the user is part of
-async fn get_user_conversations(auth_config: &AuthConfig) -> Result<Vec<Conversation>> {
-    // In a real implementation, we'd make API calls
-    // For demo purposes, we'll use our stored conversations
+async fn auth_test(auth_config: &AuthConfig, connection_id: Option<&str>) -> Result<SlackAuthTestResponse> {
+    api_get(auth_config, connection_id, "auth.test", &[]).await
+}
+
+// Call a Web API method, honouring Retry-After from the connection's earlier
+// rate limits. Without a connection (while connecting) limits aren't tracked.
+async fn api_get<T: DeserializeOwned>(auth_config: &AuthConfig, connection_id: Option<&str>, method: &str, params: &[(&str, String)]) -> Result<T> {
+    let limit_key = connection_id.map(|id| (id.to_string(), method.to_string()));
+    let now = ic_cdk::api::time();
+    
+    // Don't spend an outcall on a request Slack would reject
+    let reset_at = limit_key.as_ref()
+        .and_then(|key| RATE_LIMITS.with(|limits| limits.borrow().get(key).copied()))
+        .unwrap_or(0);
+    if reset_at > now {
+        return Err(rate_limited(method, reset_at - now));
+    }
+    
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params.iter().map(|(name, value)| (*name, value.as_str())))
+        .finish();
+    let url = format!("{}/{}?{}", API_BASE, method, query);
+    
+    let request = http::OutgoingRequest::get(&url)
+        .with_bearer_token(&auth_config.token)
+        .with_max_response_bytes(MAX_RESPONSE_BYTES);
+    let response = http::send_with_headers(request).await?;
     
-    let caller = ic_cdk::caller();
-    Ok(conversations::get_user_conversations(&caller.to_string(), Some(Platform::Slack)))
+    if response.status == 429 {
+        if let Some(key) = limit_key {
+            RATE_LIMITS.with(|limits| {
+                limits.borrow_mut().insert(key, now + RATE_LIMIT_BACKOFF_NS);
+            });
+        }
+        return Err(rate_limited(method, RATE_LIMIT_BACKOFF_NS));
+    }
+    
+    // Errors come back as HTTP 200 with ok: false
+    let status: SlackStatus = http::decode_json(&url, (response.status, response.body.clone()))?;
+    if !status.ok {
+        return Err(Error::PlatformError(format!(
+            "Slack {} failed: {}", method, status.error.unwrap_or_else(|| "unknown error".to_string())
+        )));
+    }
+    
+    http::decode_json(&url, (response.status, response.body))
+}
+
+fn rate_limited(method: &str, wait_ns: u64) -> Error {
+    Error::PlatformError(format!(
+        "Slack rate limit reached for {}; retry in {}s", method, (wait_ns + 999_999_999) / 1_000_000_000
+    ))
 }
 
 // Parse Slack timestamp (e.g., "1609459200.000100") to milliseconds
@@ -227,136 +563,407 @@ fn parse_slack_timestamp(ts: &str) -> Result<u64> {
     Ok(seconds * 1000 + microseconds / 1000)
 }
 
-// Convert Slack message to our domain model
-fn slack_message_to_message(msg: SlackMessage, channel_id: &str) -> Result<Message> {
-    let user_id = msg.user.clone().unwrap_or_else(|| "unknown".to_string());
-    
-    let message_id = msg.ts.clone();
-    let timestamp = parse_slack_timestamp(&msg.ts)?;
+// Order Slack timestamps numerically; their fractions are fixed width
+fn compare_ts(a: &str, b: &str) -> std::cmp::Ordering {
+    let split = |ts: &str| {
+        let (seconds, fraction) = ts.split_once('.').unwrap_or((ts, ""));
+        (seconds.parse::<u64>().unwrap_or(0), fraction.parse::<u64>().unwrap_or(0))
+    };
+    split(a).cmp(&split(b))
+}
+
+// Message ids are only unique within a channel
+fn message_id(channel_id: &str, ts: &str) -> String {
+    format!("{}:{}", channel_id, ts)
+}
+
+// Users referenced by <@U123> mentions
+fn mentioned_users(text: &str) -> Vec<String> {
+    text.split("<@")
+        .skip(1)
+        .filter_map(|rest| rest.split(|c| c == '>' || c == '|').next())
+        .map(|id| id.to_string())
+        .collect()
+}
+
+// Render Slack mrkdwn references as plain text: <@U123> and <#C123> become
+// names, <!here> becomes @here and <url|label> becomes "label (url)"
+fn render_mrkdwn(text: &str, workspace: &Workspace) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
     
-    let mut attachments = Vec::new();
-    if let Some(slack_attachments) = msg.attachments {
-        for attachment in slack_attachments {
-            attachments.push(Attachment {
-                attachment_type: "link".to_string(),
-                url: Some(attachment.fallback),
-                content: None,
-                name: Some(attachment.title.unwrap_or_default()),
-            });
-        }
+    while let Some(open) = rest.find('<') {
+        let close = match rest[open..].find('>') {
+            Some(close) => open + close,
+            None => break,
+        };
+        out.push_str(&rest[..open]);
+        
+        let inner = &rest[open + 1..close];
+        let (target, label) = match inner.split_once('|') {
+            Some((target, label)) => (target, Some(label)),
+            None => (inner, None),
+        };
+        
+        let rendered = if let Some(user_id) = target.strip_prefix('@') {
+            // The directory has the current name; labels can be outdated
+            let name = directory::get_user(&directory_key(&workspace.team_id, user_id))
+                .map(|user| user.name)
+                .or_else(|| label.map(|label| label.to_string()))
+                .unwrap_or_else(|| user_id.to_string());
+            format!("@{}", name)
+        } else if let Some(channel_id) = target.strip_prefix('#') {
+            let name = label.map(|label| label.to_string())
+                .or_else(|| workspace.channels.get(channel_id).cloned())
+                .unwrap_or_else(|| channel_id.to_string());
+            format!("#{}", name)
+        } else if let Some(command) = target.strip_prefix('!') {
+            // <!here>, <!channel>, <!subteam^S123|@team>, <!date^...|fallback>
+            match label {
+                Some(label) => label.to_string(),
+                None => format!("@{}", command.split('^').next().unwrap_or(command)),
+            }
+        } else {
+            match label {
+                Some(label) if label != target => format!("{} ({})", label, target),
+                _ => target.to_string(),
+            }
+        };
+        
+        out.push_str(&rendered);
+        rest = &rest[close + 1..];
     }
+    out.push_str(rest);
     
-    let sender = User {
-        id: user_id.clone(),
-        name: format!("User {}", user_id),
+    // Slack escapes these three characters in message text
+    out.replace("&lt;", "<").replace("&gt;", ">").replace("&amp;", "&")
+}
+
+// Convert Slack entities to our domain model
+fn slack_user(user: &SlackUser) -> User {
+    let display_name = user.profile.display_name.as_deref().filter(|name| !name.is_empty());
+    let real_name = user.real_name.as_deref().filter(|name| !name.is_empty());
+    
+    User {
+        id: user.id.clone(),
+        name: display_name.or(real_name).unwrap_or(&user.name).to_string(),
         platform: Platform::Slack,
-        avatar_url: None,
+        avatar_url: user.profile.image_72.clone(),
+    }
+}
+
+fn slack_message_to_message(msg: SlackMessage, channel_id: &str, workspace: &Workspace) -> Result<Message> {
+    let timestamp = parse_slack_timestamp(&msg.ts)?;
+    
+    let sender = match (&msg.user, &msg.bot_profile) {
+        (Some(user_id), _) => directory_user(&workspace.team_id, user_id),
+        (None, Some(bot)) => User {
+            id: msg.bot_id.clone().unwrap_or_default(),
+            name: bot.name.clone(),
+            platform: Platform::Slack,
+            avatar_url: bot.icons.as_ref().and_then(|icons| icons.image_72.clone()),
+        },
+        (None, None) => User {
+            id: msg.bot_id.clone().unwrap_or_else(|| "unknown".to_string()),
+            name: msg.username.clone().unwrap_or_else(|| "Slack".to_string()),
+            platform: Platform::Slack,
+            avatar_url: None,
+        },
     };
     
+    // Files removed or hidden by plan limits have no content to link to
+    let mut attachments: Vec<Attachment> = msg.files.unwrap_or_default()
+        .into_iter()
+        .filter(|file| !matches!(file.mode.as_deref(), Some("tombstone") | Some("hidden_by_limit")))
+        .map(|file| Attachment {
+            attachment_type: match file.mimetype.as_deref().and_then(|m| m.split('/').next()) {
+                Some("image") => "image",
+                Some("video") => "video",
+                Some("audio") => "audio",
+                _ => "file",
+            }.to_string(),
+            url: file.permalink.or(file.url_private),
+            name: file.name.or(file.title),
+            mime_type: file.mimetype,
+            size: file.size,
+            blob_hash: None,
+        })
+        .collect();
+    
+    // Link unfurls and legacy attachments
+    attachments.extend(msg.attachments.unwrap_or_default()
+        .into_iter()
+        .map(|attachment| Attachment {
+            attachment_type: "link".to_string(),
+            url: attachment.from_url.or(attachment.title_link).or(attachment.image_url),
+            name: attachment.title.or(attachment.fallback),
+            mime_type: None,
+            size: None,
+            blob_hash: None,
+        }));
+    
+    // Parents and replies alike belong to the thread
+    let thread_id = msg.thread_ts.as_deref().map(|ts| message_id(channel_id, ts));
+    let reply_to = msg.thread_ts.as_deref()
+        .filter(|thread_ts| *thread_ts != msg.ts)
+        .map(|thread_ts| message_id(channel_id, thread_ts));
+    
     Ok(Message {
-        id: message_id,
+        id: message_id(channel_id, &msg.ts),
         platform: Platform::Slack,
         conversation_id: channel_id.to_string(),
         sender,
         content: MessageContent {
-            text: msg.text,
+            text: render_mrkdwn(&msg.text, workspace),
             attachments,
         },
         timestamp,
-        thread_id: msg.thread_ts,
-        reply_to: None, // Would need to parse replies in a real implementation
-        edited: false,
+        thread_id,
+        reply_to,
+        edited: msg.edited.is_some(),
     })
 }
 
 // Slack API response structures
 #[derive(Debug, Serialize, Deserialize)]
-struct SlackUserInfoResponse {
+struct SlackStatus {
     ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SlackResponseMetadata {
+    #[serde(default)]
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackAuthTestResponse {
+    team_id: String,
+    team: String,
+    user: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackUserInfoResponse {
     user: SlackUser,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackUsersResponse {
+    members: Vec<SlackUser>,
+    #[serde(default)]
+    response_metadata: Option<SlackResponseMetadata>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackUser {
     id: String,
     name: String,
+    #[serde(default)]
     real_name: Option<String>,
     profile: SlackUserProfile,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackUserProfile {
-    avatar_hash: String,
+    #[serde(default)]
+    display_name: Option<String>,
+    #[serde(default)]
     image_72: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackChannelsResponse {
-    ok: bool,
     channels: Vec<SlackChannel>,
+    #[serde(default)]
+    response_metadata: Option<SlackResponseMetadata>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackChannel {
     id: String,
-    name: String,
-    is_channel: bool,
-    is_group: bool,
-    is_im: bool,
-    created: u32,
-    creator: String,
-    is_archived: bool,
-    is_general: bool,
-    members: Option<Vec<String>>,
-    topic: SlackChannelTopic,
-    purpose: SlackChannelPurpose,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-struct SlackChannelTopic {
-    value: String,
-    creator: String,
-    last_set: u32,
+    // DMs have no name
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    is_mpim: Option<bool>,
+    #[serde(default)]
+    is_im: Option<bool>,
+    #[serde(default)]
+    is_member: Option<bool>,
+    #[serde(default)]
+    is_archived: Option<bool>,
+    // Not reported for DMs
+    #[serde(default)]
+    num_members: Option<u64>,
+    // The other user of a DM
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    created: u64,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SlackChannelPurpose {
-    value: String,
-    creator: String,
-    last_set: u32,
+struct SlackMembersResponse {
+    members: Vec<String>,
+    #[serde(default)]
+    response_metadata: Option<SlackResponseMetadata>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackHistoryResponse {
-    ok: bool,
     messages: Vec<SlackMessage>,
-    has_more: bool,
+    #[serde(default)]
+    response_metadata: Option<SlackResponseMetadata>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackMessage {
-    #[serde(rename = "type")]
-    type_field: String,
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
     user: Option<String>,
+    #[serde(default)]
+    bot_id: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    bot_profile: Option<SlackBotProfile>,
+    #[serde(default)]
     text: String,
     ts: String,
+    #[serde(default)]
     thread_ts: Option<String>,
+    #[serde(default)]
     reply_count: Option<u32>,
-    replies: Option<Vec<SlackReply>>,
+    #[serde(default)]
+    reactions: Option<Vec<SlackReaction>>,
+    #[serde(default)]
     attachments: Option<Vec<SlackAttachment>>,
+    #[serde(default)]
+    files: Option<Vec<SlackFile>>,
+    #[serde(default)]
+    edited: Option<SlackEdited>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct SlackReply {
-    user: String,
+struct SlackBotProfile {
+    name: String,
+    #[serde(default)]
+    icons: Option<SlackBotIcons>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackBotIcons {
+    #[serde(default)]
+    image_72: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackEdited {
     ts: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackReaction {
+    name: String,
+    count: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct SlackAttachment {
-    fallback: String,
+    #[serde(default)]
+    fallback: Option<String>,
+    #[serde(default)]
     title: Option<String>,
-    text: Option<String>,
+    #[serde(default)]
+    title_link: Option<String>,
+    #[serde(default)]
+    from_url: Option<String>,
+    #[serde(default)]
     image_url: Option<String>,
-    thumb_url: Option<String>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SlackFile {
+    id: String,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    mimetype: Option<String>,
+    #[serde(default)]
+    size: Option<u64>,
+    // Needs the token to download; the permalink opens it in Slack
+    #[serde(default)]
+    url_private: Option<String>,
+    #[serde(default)]
+    permalink: Option<String>,
+    #[serde(default)]
+    mode: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn workspace() -> Workspace {
+        Workspace {
+            team_id: "T1".to_string(),
+            channels: HashMap::from([("C1".to_string(), "general".to_string())]),
+        }
+    }
+    
+    #[test]
+    fn renders_user_mentions_from_the_directory() {
+        directory::store_user(&directory_key("T1", "U1"), User {
+            id: "U1".to_string(),
+            name: "Ada".to_string(),
+            platform: Platform::Slack,
+            avatar_url: None,
+        });
+        
+        assert_eq!(render_mrkdwn("hi <@U1|old name> and <@U2|bob>, <@U3>", &workspace()), "hi @Ada and @bob, @U3");
+    }
+    
+    #[test]
+    fn renders_channels() {
+        assert_eq!(render_mrkdwn("see <#C1> and <#C2|random> or <#C3>", &workspace()), "see #general and #random or #C3");
+    }
+    
+    #[test]
+    fn renders_special_mentions() {
+        assert_eq!(
+            render_mrkdwn("<!here> <!channel> <!subteam^S1|@design> <!date^1700000000^{date}|Nov 14>", &workspace()),
+            "@here @channel @design Nov 14"
+        );
+    }
+    
+    #[test]
+    fn renders_links() {
+        assert_eq!(
+            render_mrkdwn("<https://example.com|the docs>, <https://example.com> and <mailto:a@b.co|mailto:a@b.co>", &workspace()),
+            "the docs (https://example.com), https://example.com and mailto:a@b.co"
+        );
+    }
+    
+    #[test]
+    fn unescapes_text_and_keeps_unclosed_brackets() {
+        assert_eq!(render_mrkdwn("a &lt; b &amp;&amp; c &gt; d <unclosed", &workspace()), "a < b && c > d <unclosed");
+    }
+    
+    #[test]
+    fn finds_mentioned_users() {
+        assert_eq!(mentioned_users("<@U1> and <@U2|bob> but not <#C1>"), vec!["U1".to_string(), "U2".to_string()]);
+    }
+    
+    #[test]
+    fn orders_timestamps_numerically() {
+        assert!(compare_ts("1700000000.000200", "999999999.999999").is_gt());
+        assert!(compare_ts("1700000000.000100", "1700000000.000200").is_lt());
+        assert_eq!(parse_slack_timestamp("1700000000.123456").unwrap(), 1_700_000_000_123);
+    }
+}
@@ -5,6 +5,7 @@ use crate::{
 use ic_cdk::api::time;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use super::scope::{RemoteConversation, SyncRules};
 
 // Initialize connection to Telegram
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
@@ -16,7 +17,7 @@ pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
 }
 
 // Sync messages from Telegram
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
+pub async fn sync_messages(_auth_config: &AuthConfig, _connection_id: &str, _rules: &SyncRules) -> Result<u64> {
     // In a real implementation, we would:
     // 1. Fetch updates or use webhooks (would need outbound HTTP requests)
     // 2. Process messages and store them
@@ -29,6 +30,13 @@ pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
     Ok(0)
 }
 
+// Chats the bot is in, and whether the connection's rules sync them. The Bot
+// API has no chat listing; chats would be learned from updates once they are
+// fetched, so there is nothing to preview yet.
+pub async fn list_remote_conversations(_auth_config: &AuthConfig, _rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    Ok(Vec::new())
+}
+
 // Get bot information
 async fn get_bot_info(auth_config: &AuthConfig) -> Result<BotInfo> {
     // This would normally use HTTP outbound calls to the Telegram API
@@ -97,7 +105,7 @@ struct TelegramMessage {
 }
 
 // Convert Telegram entities to our domain model
-fn telegram_chat_to_conversation(chat: TelegramChat, participants: Vec<User>) -> Conversation {
+fn telegram_chat_to_conversation(chat: TelegramChat, participants: Vec<User>, connection_id: &str) -> Conversation {
     Conversation {
         id: chat.id.to_string(),
         platform: Platform::Telegram,
@@ -112,6 +120,7 @@ fn telegram_chat_to_conversation(chat: TelegramChat, participants: Vec<User>) ->
         participants,
         created_at: time(),
         last_message_at: None,
+        connection_id: Some(connection_id.to_string()),
     }
 }
 
@@ -133,7 +142,7 @@ fn telegram_message_to_message(msg: TelegramMessage) -> Message {
             avatar_url: None,
         }
     };
-
+    
     Message {
         id: msg.message_id.to_string(),
         platform: Platform::Telegram,
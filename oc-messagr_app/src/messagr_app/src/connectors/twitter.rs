@@ -1,416 +1,802 @@
 use crate::{
-    AuthConfig, Conversation, Message, MessageContent, User, 
+    AuthConfig, Conversation, Message, MessageContent, User,
     Attachment, Platform, Error, Result
 };
 use crate::auth::twitter;
-use crate::storage::{conversations, messages};
-use ic_cdk::api::time;
-use serde::{Deserialize, Serialize};
+use crate::storage::{connections, conversations, messages, sync_state};
+use crate::storage::sync_state::{store_page, FeedCursor};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
 use std::collections::HashMap;
+use super::http;
+use super::scope::{ConversationKind, RemoteConversation, SyncRules};
+
+const API_BASE: &str = "https://api.twitter.com/2";
+
+// Results per page (the API maximum)
+const PAGE_SIZE: usize = 100;
+
+// Pages fetched per feed in one sync; an unfinished pass resumes next time
+const MAX_PAGES_PER_FEED: usize = 5;
+
+const MAX_RESPONSE_BYTES: u64 = 1_000_000;
+
+// Twitter's snowflake epoch (2010-11-04) in Unix milliseconds
+const TWITTER_EPOCH_MS: u64 = 1_288_834_974_657;
+
+// Longest query recent search accepts
+const MAX_SEARCH_QUERY_LEN: usize = 512;
+
+// Saved search conversations are named after their query
+const SEARCH_NAME_PREFIX: &str = "Search: ";
+
+// Recent search only reaches back 7 days and rejects an older since_id. An
+// hour's margin keeps a cursor from expiring between the check and the request.
+const RECENT_SEARCH_WINDOW_MS: u64 = (7 * 24 - 1) * 60 * 60 * 1000;
+
+// Synced tweets deleted per remove_search call
+const REMOVE_BATCH_SIZE: usize = 500;
+
+// Fields and expansions requested alongside tweets and DM events, so authors,
+// referenced tweets and media arrive in the same response
+const TWEET_FIELDS: &str = "created_at,conversation_id,author_id,in_reply_to_user_id,referenced_tweets,attachments,note_tweet";
+const TWEET_EXPANSIONS: &str = "author_id,attachments.media_keys,referenced_tweets.id,referenced_tweets.id.author_id";
+const DM_EVENT_FIELDS: &str = "id,text,event_type,created_at,sender_id,dm_conversation_id,attachments,referenced_tweets";
+const DM_EXPANSIONS: &str = "sender_id,attachments.media_keys,referenced_tweets.id";
+const MEDIA_FIELDS: &str = "url,preview_image_url,type,alt_text";
+const USER_FIELDS: &str = "name,username,profile_image_url";
+
+// How long a route is left alone after a 429: one rate-limit window. The
+// transform drops Twitter's reset time, which differs between replicas.
+const RATE_LIMIT_WINDOW_NS: u64 = 15 * 60 * 1_000_000_000;
+
+thread_local! {
+    // End of a rate limit by connection and route, in nanoseconds; limits
+    // apply per access token
+    static RATE_LIMITS: RefCell<HashMap<(String, String), u64>> = RefCell::new(HashMap::new());
+}
 
 // Initialize connection to Twitter
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
-    // Verify token validity by making a test API call
-    let user_info = get_user_info(auth_config).await?;
-    ic_cdk::println!("Connected to Twitter as: @{}", user_info.screen_name);
-    
-    // Initialize conversations (direct messages)
-    let dms = get_direct_messages(auth_config).await?;
-    store_direct_messages(auth_config, dms).await?;
+    // Verify token validity by looking up the authenticated user
+    let me = get_me(auth_config, None).await?;
+    ic_cdk::println!("Connected to Twitter as: @{}", me.username);
     
     Ok(())
 }
 
-// Sync messages from Twitter
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
-    // Get all conversations
-    let user_conversations = conversations::get_user_conversations(
-        &ic_cdk::caller().to_string(), 
-        Some(Platform::Twitter)
-    );
+// Sync mentions, bookmarks, DM events and saved searches. Returns the number
+// of messages stored.
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    let caller = ic_cdk::caller().to_string();
+    let me = get_me(auth_config, Some(connection_id)).await?;
     
     let mut total_synced = 0;
     
-    // For each conversation, get recent messages
-    for conversation in user_conversations {
-        let timeline = match conversation.name.starts_with("DM:") {
-            true => get_direct_message_events(auth_config, &conversation.id).await?,
-            false => get_timeline_tweets(auth_config, &conversation.id).await?,
-        };
-        
-        // Store messages
-        for tweet in timeline {
-            let message = twitter_message_to_message(tweet, &conversation.id)?;
-            messages::store_message(message)?;
-            total_synced += 1;
+    // Feeds outside the connection's scope are never stored
+    if rules.allows(&feed_remote(connection_id, "mentions", "Mentions")) {
+        let mentions = store_feed(&caller, connection_id, &me, "mentions", "Mentions")?;
+        match sync_tweets(auth_config, connection_id, &format!("/users/{}/mentions", me.id), None, &mentions).await {
+            Ok(count) => total_synced += count,
+            Err(e) => ic_cdk::println!("Failed to sync Twitter mentions: {:?}", e),
         }
-        
-        // Update conversation with last message timestamp if available
-        if let Some(latest) = timeline.first() {
-            if let Some(timestamp) = latest.created_at_millis {
-                conversations::update_conversation_last_message(&conversation.id, timestamp)?;
-            }
+    }
+    
+    // Bookmarks can only be read with an OAuth 2.0 user token
+    if twitter::uses_bearer_token(auth_config) && rules.allows(&feed_remote(connection_id, "bookmarks", "Bookmarks")) {
+        let bookmarks = store_feed(&caller, connection_id, &me, "bookmarks", "Bookmarks")?;
+        match sync_bookmarks(auth_config, connection_id, &me.id, &bookmarks).await {
+            Ok(count) => total_synced += count,
+            Err(e) => ic_cdk::println!("Failed to sync Twitter bookmarks: {:?}", e),
+        }
+    }
+    
+    match sync_direct_messages(auth_config, &caller, connection_id, rules, &me).await {
+        Ok(count) => total_synced += count,
+        Err(e) => ic_cdk::println!("Failed to sync Twitter direct messages: {:?}", e),
+    }
+    
+    let searches = saved_searches(&caller, connection_id).into_iter()
+        .filter(|search| rules.allows(&stored_remote(search, ConversationKind::Group)));
+    for search in searches {
+        let query = search.name.strip_prefix(SEARCH_NAME_PREFIX).unwrap_or(&search.name).to_string();
+        match sync_tweets(auth_config, connection_id, "/tweets/search/recent", Some(&query), &search.id).await {
+            Ok(count) => total_synced += count,
+            // Rate-limited feeds are picked up on the next sync
+            Err(e) => ic_cdk::println!("Failed to sync Twitter search {:?}: {:?}", query, e),
         }
     }
     
     Ok(total_synced)
 }
 
-// Get user info from Twitter
-async fn get_user_info(auth_config: &AuthConfig) -> Result<TwitterUserResponse> {
-    // This would normally use HTTP outbound calls to the Twitter API with OAuth 1.0a
-    // For demo purposes, we'll simulate the response
-    
-    Ok(TwitterUserResponse {
-        id: 12345678,
-        id_str: "12345678".to_string(),
-        name: "Messagr User".to_string(),
-        screen_name: "messagr_user".to_string(),
-        location: Some("Internet Computer".to_string()),
-        description: Some("Testing the Messagr app for ICP".to_string()),
-        verified: false,
-        followers_count: 42,
-        friends_count: 100,
-        profile_image_url_https: Some("https://pbs.twimg.com/profile_images/default_image.png".to_string()),
-    })
-}
-
-// Get direct messages
-async fn get_direct_messages(auth_config: &AuthConfig) -> Result<Vec<TwitterDirectMessageEvent>> {
-    // This would normally use HTTP outbound calls to the Twitter API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(vec![
-        TwitterDirectMessageEvent {
-            id: "1234567890".to_string(),
-            created_timestamp: "1609459200000".to_string(),
-            created_at_millis: Some(1609459200000),
-            message_create: TwitterDirectMessageCreate {
-                target: TwitterMessageTarget {
-                    recipient_id: "987654321".to_string(),
-                },
-                sender_id: "12345678".to_string(),
-                message_data: TwitterMessageData {
-                    text: "Hello, this is a direct message!".to_string(),
-                    entities: None,
-                    attachment: None,
-                },
-            },
-        },
-    ])
+// Feeds and saved searches of the account, plus the DM conversations synced
+// so far (DMs are only discovered through their events), and whether the
+// connection's rules sync them
+pub fn list_remote_conversations(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Vec<RemoteConversation> {
+    let caller = ic_cdk::caller().to_string();
+    let mut remote = vec![feed_remote(connection_id, "mentions", "Mentions")];
+    
+    if twitter::uses_bearer_token(auth_config) {
+        remote.push(feed_remote(connection_id, "bookmarks", "Bookmarks"));
+    }
+    
+    remote.extend(saved_searches(&caller, connection_id).iter()
+        .map(|search| stored_remote(search, ConversationKind::Group)));
+    
+    let dm_prefix = format!("{}:", feed_id(connection_id, "dm"));
+    remote.extend(conversations::get_user_conversations(&caller, Some(Platform::Twitter)).iter()
+        .filter_map(|conversation| {
+            let dm_conversation_id = conversation.id.strip_prefix(&dm_prefix)?;
+            Some(stored_remote(conversation, dm_kind(dm_conversation_id)))
+        }));
+    
+    remote.into_iter().map(|conversation| conversation.scoped(rules)).collect()
 }
 
-// Store direct messages as conversations
-async fn store_direct_messages(
-    auth_config: &AuthConfig,
-    dms: Vec<TwitterDirectMessageEvent>,
-) -> Result<()> {
-    let caller = ic_cdk::caller();
-    let caller_info = get_user_info(auth_config).await?;
-    
-    // Extract unique conversation partners
-    let mut conversation_partners = HashMap::new();
-    for dm in &dms {
-        let partner_id = if dm.message_create.sender_id == caller_info.id_str {
-            dm.message_create.target.recipient_id.clone()
-        } else {
-            dm.message_create.sender_id.clone()
-        };
-        
-        // In a real implementation, we'd fetch user details for each partner
-        // For now, just use IDs with a placeholder name
-        conversation_partners.insert(partner_id.clone(), format!("User {}", partner_id));
+fn feed_remote(connection_id: &str, feed: &str, name: &str) -> RemoteConversation {
+    RemoteConversation::new(feed_id(connection_id, feed), name.to_string(), ConversationKind::Group, false, None)
+}
+
+// Sizes of feeds and group DMs aren't known; one-to-one DMs have two members
+fn stored_remote(conversation: &Conversation, kind: ConversationKind) -> RemoteConversation {
+    let members = match kind {
+        ConversationKind::Direct => Some(2),
+        ConversationKind::Group => None,
+    };
+    RemoteConversation::new(conversation.id.clone(), conversation.name.clone(), kind, false, members)
+}
+
+// One-to-one conversation ids are the two user ids joined by a dash
+fn dm_kind(dm_conversation_id: &str) -> ConversationKind {
+    if dm_conversation_id.contains('-') {
+        ConversationKind::Direct
+    } else {
+        ConversationKind::Group
+    }
+}
+
+// Save a recent-search query for a connection; its results are synced into
+// a conversation of their own
+pub fn save_search(caller: &str, connection_id: &str, query: &str) -> Result<Conversation> {
+    let query = query.trim();
+    if query.is_empty() || query.len() > MAX_SEARCH_QUERY_LEN {
+        return Err(Error::InvalidParameters(format!(
+            "Search query must be between 1 and {} characters", MAX_SEARCH_QUERY_LEN
+        )));
     }
     
-    // Create conversations for each partner
-    for (partner_id, partner_name) in conversation_partners {
-        // Create conversation
-        let conversation_id = format!("dm-{}-{}", caller_info.id_str, partner_id);
-        let conversation = Conversation {
-            id: conversation_id,
-            platform: Platform::Twitter,
-            name: format!("DM: @{}", partner_name),
-            participants: vec![
-                User {
-                    id: caller_info.id_str.clone(),
-                    name: caller_info.name.clone(),
-                    platform: Platform::Twitter,
-                    avatar_url: caller_info.profile_image_url_https.clone(),
-                },
-                User {
-                    id: partner_id.clone(),
-                    name: partner_name,
-                    platform: Platform::Twitter,
-                    avatar_url: None,
-                },
-            ],
-            created_at: time(),
-            last_message_at: None,
-        };
-        
-        // Store conversation
-        conversations::store_conversation(conversation)?;
+    let hash: String = Sha256::digest(query.as_bytes())[..8].iter().map(|b| format!("{:02x}", b)).collect();
+    let conversation = Conversation {
+        id: format!("{}:{}", feed_id(connection_id, "search"), hash),
+        platform: Platform::Twitter,
+        name: format!("{}{}", SEARCH_NAME_PREFIX, query),
+        participants: vec![caller_participant(caller)],
+        created_at: ic_cdk::api::time() / 1_000_000,
+        last_message_at: None,
+        connection_id: Some(connection_id.to_string()),
+    };
+    
+    if conversations::get_conversation(&conversation.id).is_none() {
+        conversations::store_conversation(conversation.clone())?;
     }
+    connections::tag_conversation(caller, connection_id, &conversation.id);
+    Ok(conversation)
+}
+
+// Remove a saved search, then the results synced for it in batches. The
+// first call stops the search syncing; call again while tweets remain.
+// Returns the number of tweets still to delete.
+pub fn remove_search(caller: &str, conversation_id: &str) -> Result<u64> {
+    let removing_key = format!("{}:removing", conversation_id);
+    let is_saved_search = connections::list_connections(caller, Some(&Platform::Twitter))
+        .iter()
+        .any(|connection| saved_searches(caller, &connection.id).iter().any(|search| search.id == conversation_id));
     
-    Ok(())
+    if is_saved_search {
+        conversations::delete_conversation(conversation_id)?;
+        FeedCursor::load(conversation_id).reset();
+        sync_state::set_cursor(&removing_key, caller);
+    } else if sync_state::get_cursor(&removing_key).as_deref() != Some(caller) {
+        return Err(Error::InvalidParameters(format!("No saved search {}", conversation_id)));
+    }
+    
+    let message_ids = messages::get_conversation_message_ids(conversation_id);
+    for message_id in message_ids.iter().take(REMOVE_BATCH_SIZE) {
+        messages::delete_message(message_id)?;
+    }
+    
+    let remaining = message_ids.len().saturating_sub(REMOVE_BATCH_SIZE) as u64;
+    if remaining == 0 {
+        sync_state::set_cursor(&removing_key, "");
+    }
+    Ok(remaining)
 }
 
-// Get timeline tweets
-async fn get_timeline_tweets(auth_config: &AuthConfig, timeline_id: &str) -> Result<Vec<TwitterMessage>> {
-    // This would normally use HTTP outbound calls to the Twitter API
-    // For demo purposes, we'll simulate the response
-    
-    Ok(vec![
-        TwitterMessage {
-            id: "1234567890".to_string(),
-            id_str: "1234567890".to_string(),
-            text: "Hello, this is a tweet!".to_string(),
-            created_at: "Wed Apr 15 00:00:00 +0000 2020".to_string(),
-            created_at_millis: Some(1586908800000),
-            user: Some(TwitterUser {
-                id: 12345678,
-                id_str: "12345678".to_string(),
-                name: "Messagr User".to_string(),
-                screen_name: "messagr_user".to_string(),
-                profile_image_url_https: Some("https://pbs.twimg.com/profile_images/default_image.png".to_string()),
-            }),
-            in_reply_to_status_id_str: None,
-            entities: None,
-            extended_entities: None,
-        },
-    ])
+fn saved_searches(caller: &str, connection_id: &str) -> Vec<Conversation> {
+    let prefix = format!("{}:", feed_id(connection_id, "search"));
+    conversations::get_user_conversations(caller, Some(Platform::Twitter))
+        .into_iter()
+        .filter(|conversation| conversation.id.starts_with(&prefix))
+        .collect()
+}
+
+// Conversation ids are scoped to the connection, since two users (or two
+// accounts of one user) can share a DM conversation or follow the same search
+fn feed_id(connection_id: &str, feed: &str) -> String {
+    format!("twitter:{}:{}", connection_id, feed)
+}
+
+fn caller_participant(caller: &str) -> User {
+    User {
+        id: caller.to_string(),
+        name: "Current User".to_string(),
+        platform: Platform::Twitter,
+        avatar_url: None,
+    }
+}
+
+// Store the conversation a feed syncs into, returning its id
+fn store_feed(caller: &str, connection_id: &str, me: &TwitterUser, feed: &str, name: &str) -> Result<String> {
+    let id = feed_id(connection_id, feed);
+    let existing = conversations::get_conversation(&id);
+    connections::tag_conversation(caller, connection_id, &id);
+    
+    conversations::store_conversation(Conversation {
+        id: id.clone(),
+        platform: Platform::Twitter,
+        name: name.to_string(),
+        participants: vec![caller_participant(caller), twitter_user(me)],
+        created_at: existing.as_ref().map(|c| c.created_at).unwrap_or_else(|| ic_cdk::api::time() / 1_000_000),
+        // Keep what earlier syncs learned
+        last_message_at: existing.and_then(|c| c.last_message_at),
+        connection_id: Some(connection_id.to_string()),
+    })?;
+    
+    Ok(id)
 }
 
-// Get direct message events
-async fn get_direct_message_events(
-    auth_config: &AuthConfig, 
+// Sync a tweet timeline (mentions or recent search) using since_id, so each
+// pass only returns tweets newer than the last one. A search whose newest
+// tweet is older than the search window starts over without since_id.
+async fn sync_tweets(
+    auth_config: &AuthConfig,
+    connection_id: &str,
+    path: &str,
+    search_query: Option<&str>,
     conversation_id: &str
-) -> Result<Vec<TwitterMessage>> {
-    // This would fetch DMs and convert them to our common format
-    let dm_events = get_direct_messages(auth_config).await?;
+) -> Result<u64> {
+    let mut cursor = FeedCursor::load(conversation_id);
+    let mut next_token = cursor.resume_token.clone();
+    let mut synced = 0;
     
-    // Convert DM events to the common TwitterMessage format
-    let messages = dm_events
-        .into_iter()
-        .filter(|dm| {
-            // Extract conversation ID format: "dm-{user1}-{user2}"
-            let parts: Vec<&str> = conversation_id.split('-').collect();
-            if parts.len() != 3 {
-                return false;
-            }
-            
-            let user1 = parts[1];
-            let user2 = parts[2];
-            
-            // Check if this DM is part of the conversation
-            (dm.message_create.sender_id == user1 && dm.message_create.target.recipient_id == user2) ||
-            (dm.message_create.sender_id == user2 && dm.message_create.target.recipient_id == user1)
-        })
-        .map(|dm| {
-            // We would normally fetch user info for the sender
-            // Here using placeholder data
-            let timestamp = dm.created_timestamp.parse::<u64>().unwrap_or(0);
-            
-            TwitterMessage {
-                id: dm.id.clone(),
-                id_str: dm.id,
-                text: dm.message_create.message_data.text,
-                created_at: "".to_string(), // Not used for DMs
-                created_at_millis: Some(timestamp),
-                user: Some(TwitterUser {
-                    id: 0,
-                    id_str: dm.message_create.sender_id,
-                    name: "Twitter User".to_string(),
-                    screen_name: "twitter_user".to_string(),
-                    profile_image_url_https: None,
-                }),
-                in_reply_to_status_id_str: None,
-                entities: None,
-                extended_entities: None,
-            }
-        })
-        .collect();
+    let now_ms = ic_cdk::api::time() / 1_000_000;
+    let since_id = cursor.newest.clone()
+        .filter(|id| search_query.is_none() || snowflake_time(id) + RECENT_SEARCH_WINDOW_MS > now_ms);
     
-    Ok(messages)
+    for _ in 0..MAX_PAGES_PER_FEED {
+        let mut params = tweet_params();
+        if let Some(query) = search_query {
+            params.push(("query", query.to_string()));
+        }
+        if let Some(since_id) = &since_id {
+            params.push(("since_id", since_id.clone()));
+        }
+        if let Some(token) = &next_token {
+            params.push((pagination_param(path), token.clone()));
+        }
+        
+        let page: TwitterPage<Vec<TwitterTweet>> = api_get(auth_config, Some(connection_id), path, &params).await?;
+        cursor.start_page(page.meta.newest_id.as_deref());
+        
+        let includes = Includes::new(page.includes);
+        let tweets = page.data.unwrap_or_default();
+        synced += store_page(tweets.iter().map(|tweet| tweet_to_message(tweet, &includes, conversation_id)), conversation_id)?;
+        
+        next_token = page.meta.next_token;
+        if next_token.is_none() {
+            break;
+        }
+    }
+    
+    cursor.save(next_token.as_deref());
+    Ok(synced)
 }
 
-// Convert Twitter message to our domain model
-fn twitter_message_to_message(tweet: TwitterMessage, conversation_id: &str) -> Result<Message> {
-    let user = tweet.user.ok_or_else(|| {
-        Error::InternalError("Tweet missing user information".to_string())
-    })?;
+// Bookmarks come in the order they were bookmarked and take no since_id, so
+// a pass stops at the first bookmark of the last completed one
+async fn sync_bookmarks(auth_config: &AuthConfig, connection_id: &str, user_id: &str, conversation_id: &str) -> Result<u64> {
+    let path = format!("/users/{}/bookmarks", user_id);
+    let mut cursor = FeedCursor::load(conversation_id);
+    let mut next_token = cursor.resume_token.clone();
+    let mut synced = 0;
+    
+    for _ in 0..MAX_PAGES_PER_FEED {
+        let mut params = tweet_params();
+        if let Some(token) = &next_token {
+            params.push(("pagination_token", token.clone()));
+        }
+        
+        let page: TwitterPage<Vec<TwitterTweet>> = api_get(auth_config, Some(connection_id), &path, &params).await?;
+        let tweets = page.data.unwrap_or_default();
+        cursor.start_page(tweets.first().map(|tweet| tweet.id.as_str()));
+        
+        let seen = tweets.iter().position(|tweet| Some(&tweet.id) == cursor.newest.as_ref());
+        let new_tweets = &tweets[..seen.unwrap_or(tweets.len())];
+        
+        let includes = Includes::new(page.includes);
+        synced += store_page(new_tweets.iter().map(|tweet| tweet_to_message(tweet, &includes, conversation_id)), conversation_id)?;
+        
+        next_token = if seen.is_some() { None } else { page.meta.next_token };
+        if next_token.is_none() {
+            break;
+        }
+    }
     
-    let mut attachments = Vec::new();
-    
-    // Process media entities if available
-    if let Some(entities) = &tweet.extended_entities {
-        if let Some(media) = &entities.media {
-            for m in media {
-                attachments.push(Attachment {
-                    attachment_type: "image".to_string(),
-                    url: Some(m.media_url_https.clone()),
-                    content: None,
-                    name: Some(format!("Media {}", m.id_str)),
-                });
+    cursor.save(next_token.as_deref());
+    Ok(synced)
+}
+
+// DM events of every conversation come newest first in one feed; each
+// dm_conversation_id becomes a conversation of its own
+async fn sync_direct_messages(
+    auth_config: &AuthConfig,
+    caller: &str,
+    connection_id: &str,
+    rules: &SyncRules,
+    me: &TwitterUser
+) -> Result<u64> {
+    let dm_feed = feed_id(connection_id, "dm");
+    let mut cursor = FeedCursor::load(&dm_feed);
+    let mut next_token = cursor.resume_token.clone();
+    let mut synced = 0;
+    
+    for _ in 0..MAX_PAGES_PER_FEED {
+        let mut params = vec![
+            ("dm_event.fields", DM_EVENT_FIELDS.to_string()),
+            ("event_types", "MessageCreate".to_string()),
+            ("expansions", DM_EXPANSIONS.to_string()),
+            ("media.fields", MEDIA_FIELDS.to_string()),
+            ("user.fields", USER_FIELDS.to_string()),
+            ("tweet.fields", TWEET_FIELDS.to_string()),
+            ("max_results", PAGE_SIZE.to_string()),
+        ];
+        if let Some(token) = &next_token {
+            params.push(("pagination_token", token.clone()));
+        }
+        
+        let page: TwitterPage<Vec<TwitterDmEvent>> = api_get(auth_config, Some(connection_id), "/dm_events", &params).await?;
+        let events = page.data.unwrap_or_default();
+        cursor.start_page(events.iter().map(|event| event.id.as_str()).max_by_key(|id| snowflake(id)));
+        
+        let newest_synced = cursor.newest.as_deref().map(snowflake).unwrap_or(0);
+        let reached_synced = events.iter().any(|event| snowflake(&event.id) <= newest_synced);
+        
+        let includes = Includes::new(page.includes);
+        let mut by_conversation: HashMap<&str, Vec<&TwitterDmEvent>> = HashMap::new();
+        for event in events.iter().filter(|event| snowflake(&event.id) > newest_synced) {
+            if let Some(dm_conversation_id) = event.dm_conversation_id.as_deref() {
+                by_conversation.entry(dm_conversation_id).or_default().push(event);
+            }
+        }
+        
+        for (dm_conversation_id, events) in by_conversation {
+            // Out-of-scope conversations are passed over; the feed cursor
+            // still moves past their events
+            let conversation = dm_conversation(caller, connection_id, me, dm_conversation_id, &includes);
+            if !rules.allows(&stored_remote(&conversation, dm_kind(dm_conversation_id))) {
+                continue;
             }
+            let conversation_id = conversation.id.clone();
+            connections::tag_conversation(caller, connection_id, &conversation_id);
+            conversations::store_conversation(conversation)?;
+            synced += store_page(events.into_iter().map(|event| dm_event_to_message(event, &includes, &conversation_id)), &conversation_id)?;
+        }
+        
+        next_token = if reached_synced { None } else { page.meta.next_token };
+        if next_token.is_none() {
+            break;
         }
     }
     
-    // Get timestamp
-    let timestamp = tweet.created_at_millis.unwrap_or_else(|| {
-        // Parse Twitter's date format if milliseconds not available
-        // In a real implementation we'd parse the date string properly
-        ic_cdk::api::time() // Fallback to current time
-    });
+    cursor.save(next_token.as_deref());
+    Ok(synced)
+}
+
+// The conversation a DM thread is stored as, named after the other person
+fn dm_conversation(
+    caller: &str,
+    connection_id: &str,
+    me: &TwitterUser,
+    dm_conversation_id: &str,
+    includes: &Includes
+) -> Conversation {
+    let id = format!("{}:{}", feed_id(connection_id, "dm"), dm_conversation_id);
+    let existing = conversations::get_conversation(&id);
     
-    let sender = User {
-        id: user.id_str.clone(),
-        name: format!("{} (@{})", user.name, user.screen_name),
-        platform: Platform::Twitter,
-        avatar_url: user.profile_image_url_https,
+    let partner_id = dm_conversation_id.split('-')
+        .find(|user_id| *user_id != me.id)
+        .filter(|_| dm_conversation_id.contains('-'));
+    let partner_user = partner_id.and_then(|user_id| includes.users.get(user_id));
+    
+    let mut participants = vec![caller_participant(caller), twitter_user(me)];
+    let name = match (partner_user, partner_id) {
+        (Some(user), _) => {
+            participants.push(twitter_user(user));
+            format!("DM: @{}", user.username)
+        },
+        // Pages only expand the senders they contain; keep the earlier details
+        (None, Some(user_id)) => match &existing {
+            Some(existing) => {
+                participants = existing.participants.clone();
+                existing.name.clone()
+            },
+            None => format!("DM: User {}", user_id),
+        },
+        (None, None) => existing.as_ref().map(|c| c.name.clone()).unwrap_or_else(|| "Group DM".to_string()),
     };
     
-    Ok(Message {
-        id: tweet.id_str.clone(),
+    Conversation {
+        id,
         platform: Platform::Twitter,
-        conversation_id: conversation_id.to_string(),
-        sender,
-        content: MessageContent {
-            text: tweet.text,
-            attachments,
-        },
-        timestamp,
-        thread_id: None,
-        reply_to: tweet.in_reply_to_status_id_str,
-        edited: false,
-    })
+        name,
+        participants,
+        created_at: existing.as_ref().map(|c| c.created_at).unwrap_or_else(|| ic_cdk::api::time() / 1_000_000),
+        last_message_at: existing.and_then(|c| c.last_message_at),
+        connection_id: Some(connection_id.to_string()),
+    }
 }
 
-// Twitter API response structures
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterUserResponse {
-    id: i64,
-    id_str: String,
-    name: String,
-    screen_name: String,
-    location: Option<String>,
-    description: Option<String>,
-    verified: bool,
-    followers_count: i32,
-    friends_count: i32,
-    profile_image_url_https: Option<String>,
+fn tweet_params() -> Vec<(&'static str, String)> {
+    vec![
+        ("tweet.fields", TWEET_FIELDS.to_string()),
+        ("expansions", TWEET_EXPANSIONS.to_string()),
+        ("media.fields", MEDIA_FIELDS.to_string()),
+        ("user.fields", USER_FIELDS.to_string()),
+        ("max_results", PAGE_SIZE.to_string()),
+    ]
+}
+
+// Recent search pages with next_token; user timelines with pagination_token
+fn pagination_param(path: &str) -> &'static str {
+    if path.starts_with("/tweets/search/") {
+        "next_token"
+    } else {
+        "pagination_token"
+    }
 }
 
+async fn get_me(auth_config: &AuthConfig, connection_id: Option<&str>) -> Result<TwitterUser> {
+    let page: TwitterPage<TwitterUser> = api_get(auth_config, connection_id, "/users/me", &[("user.fields", USER_FIELDS.to_string())]).await?;
+    page.data.ok_or_else(|| Error::PlatformError("Twitter returned no user for the token".to_string()))
+}
+
+// GET an API path as the connected user, honouring the connection's rate
+// limits from earlier responses. Without a connection (while connecting)
+// limits aren't tracked.
+async fn api_get<T: DeserializeOwned>(auth_config: &AuthConfig, connection_id: Option<&str>, path: &str, params: &[(&str, String)]) -> Result<T> {
+    let route = connection_id.map(|id| (id.to_string(), path.to_string()));
+    let now = ic_cdk::api::time();
+    
+    // Don't spend an outcall on a request Twitter would reject
+    let reset_at = route.as_ref()
+        .and_then(|route| RATE_LIMITS.with(|limits| limits.borrow().get(route).copied()))
+        .unwrap_or(0);
+    if reset_at > now {
+        return Err(rate_limited(path, reset_at - now));
+    }
+    
+    let url = format!("{}{}", API_BASE, path);
+    let params: Vec<(String, String)> = params.iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect();
+    let query = params.iter()
+        .map(|(name, value)| format!("{}={}", twitter::url_encode(name), twitter::url_encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let full_url = if query.is_empty() { url.clone() } else { format!("{}?{}", url, query) };
+    
+    let authorization = twitter::authorization_header(auth_config, "GET", &url, &params)?;
+    let request = http::OutgoingRequest::get(&full_url)
+        .with_header("Authorization", &authorization)
+        .with_max_response_bytes(MAX_RESPONSE_BYTES);
+    let response = http::send_with_headers(request).await?;
+    
+    if response.status == 429 {
+        if let Some(route) = route {
+            RATE_LIMITS.with(|limits| {
+                limits.borrow_mut().insert(route, now + RATE_LIMIT_WINDOW_NS);
+            });
+        }
+        return Err(rate_limited(path, RATE_LIMIT_WINDOW_NS));
+    }
+    
+    http::decode_json(&full_url, (response.status, response.body))
+}
+
+fn rate_limited(path: &str, wait_ns: u64) -> Error {
+    Error::PlatformError(format!(
+        "Twitter rate limit reached for {}; retry in {}s", path, (wait_ns + 999_999_999) / 1_000_000_000
+    ))
+}
+
+// Snowflake IDs grow with time, so they order tweets and DM events
+fn snowflake(id: &str) -> u64 {
+    id.parse().unwrap_or(0)
+}
+
+// created_at is RFC 3339; a snowflake ID also carries its creation time
+fn timestamp_ms(created_at: Option<&str>, id: &str) -> u64 {
+    created_at
+        .and_then(|created_at| chrono::DateTime::parse_from_rfc3339(created_at).ok())
+        .and_then(|time| u64::try_from(time.timestamp_millis()).ok())
+        .unwrap_or_else(|| snowflake_time(id))
+}
+
+// Creation time carried by a snowflake ID, in Unix milliseconds
+fn snowflake_time(id: &str) -> u64 {
+    (snowflake(id) >> 22) + TWITTER_EPOCH_MS
+}
+
+// Twitter API v2 response structures
 #[derive(Debug, Serialize, Deserialize)]
+struct TwitterPage<T> {
+    // Absent when a page has no results
+    #[serde(default)]
+    data: Option<T>,
+    #[serde(default)]
+    includes: TwitterIncludes,
+    #[serde(default)]
+    meta: TwitterMeta,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TwitterIncludes {
+    #[serde(default)]
+    users: Vec<TwitterUser>,
+    #[serde(default)]
+    tweets: Vec<TwitterTweet>,
+    #[serde(default)]
+    media: Vec<TwitterMedia>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TwitterMeta {
+    #[serde(default)]
+    newest_id: Option<String>,
+    #[serde(default)]
+    next_token: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct TwitterUser {
-    id: i64,
-    id_str: String,
+    id: String,
     name: String,
-    screen_name: String,
-    profile_image_url_https: Option<String>,
+    username: String,
+    #[serde(default)]
+    profile_image_url: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TwitterEntities {
-    hashtags: Option<Vec<TwitterHashtag>>,
-    urls: Option<Vec<TwitterUrl>>,
-    user_mentions: Option<Vec<TwitterMention>>,
-    media: Option<Vec<TwitterMedia>>,
+struct TwitterTweet {
+    id: String,
+    text: String,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    author_id: Option<String>,
+    // Id of the tweet that started the thread
+    #[serde(default)]
+    conversation_id: Option<String>,
+    #[serde(default)]
+    referenced_tweets: Vec<TwitterReference>,
+    #[serde(default)]
+    attachments: Option<TwitterAttachments>,
+    // Full text of tweets longer than 280 characters
+    #[serde(default)]
+    note_tweet: Option<TwitterNoteTweet>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TwitterHashtag {
-    text: String,
-    indices: Vec<i32>,
+struct TwitterReference {
+    // "replied_to", "quoted" or "retweeted"; DM events give only the id
+    #[serde(rename = "type", default)]
+    reference_type: String,
+    id: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TwitterUrl {
-    url: String,
-    expanded_url: String,
-    display_url: String,
-    indices: Vec<i32>,
+struct TwitterAttachments {
+    #[serde(default)]
+    media_keys: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TwitterMention {
-    screen_name: String,
-    name: String,
-    id: i64,
-    id_str: String,
-    indices: Vec<i32>,
+struct TwitterNoteTweet {
+    text: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct TwitterMedia {
-    id: i64,
-    id_str: String,
-    indices: Vec<i32>,
-    media_url: String,
-    media_url_https: String,
-    url: String,
-    display_url: String,
-    expanded_url: String,
+    media_key: String,
+    // "photo", "video" or "animated_gif"
     #[serde(rename = "type")]
     media_type: String,
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default)]
+    preview_image_url: Option<String>,
+    #[serde(default)]
+    alt_text: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct TwitterMessage {
+struct TwitterDmEvent {
     id: String,
-    id_str: String,
-    text: String,
-    created_at: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    created_at_millis: Option<u64>,
-    user: Option<TwitterUser>,
-    in_reply_to_status_id_str: Option<String>,
-    entities: Option<TwitterEntities>,
-    extended_entities: Option<TwitterEntities>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    created_at: Option<String>,
+    #[serde(default)]
+    sender_id: Option<String>,
+    #[serde(default)]
+    dm_conversation_id: Option<String>,
+    #[serde(default)]
+    attachments: Option<TwitterAttachments>,
+    #[serde(default)]
+    referenced_tweets: Vec<TwitterReference>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterDirectMessageEvent {
-    id: String,
-    created_timestamp: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    created_at_millis: Option<u64>,
-    message_create: TwitterDirectMessageCreate,
+// A page's expansions, by id
+struct Includes {
+    users: HashMap<String, TwitterUser>,
+    tweets: HashMap<String, TwitterTweet>,
+    media: HashMap<String, TwitterMedia>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterDirectMessageCreate {
-    target: TwitterMessageTarget,
-    sender_id: String,
-    message_data: TwitterMessageData,
+impl Includes {
+    fn new(includes: TwitterIncludes) -> Self {
+        Self {
+            users: includes.users.into_iter().map(|user| (user.id.clone(), user)).collect(),
+            tweets: includes.tweets.into_iter().map(|tweet| (tweet.id.clone(), tweet)).collect(),
+            media: includes.media.into_iter().map(|media| (media.media_key.clone(), media)).collect(),
+        }
+    }
+    
+    fn author(&self, user_id: Option<&str>) -> User {
+        match user_id.and_then(|id| self.users.get(id)) {
+            Some(user) => twitter_user(user),
+            None => User {
+                id: user_id.unwrap_or_default().to_string(),
+                name: "Twitter User".to_string(),
+                platform: Platform::Twitter,
+                avatar_url: None,
+            },
+        }
+    }
+    
+    fn attachments(&self, attachments: Option<&TwitterAttachments>) -> Vec<Attachment> {
+        attachments.map(|a| a.media_keys.as_slice()).unwrap_or_default()
+            .iter()
+            .filter_map(|key| self.media.get(key))
+            .map(|media| Attachment {
+                attachment_type: match media.media_type.as_str() {
+                    "photo" => "image",
+                    _ => "video",
+                }.to_string(),
+                // Videos only expose a preview image
+                url: media.url.clone().or_else(|| media.preview_image_url.clone()),
+                name: media.alt_text.clone(),
+                mime_type: None,
+                size: None,
+                blob_hash: None,
+            })
+            .collect()
+    }
+    
+    // Quoted and shared tweets, as links carrying their author and text
+    fn tweet_links<'a>(&self, tweet_ids: impl Iterator<Item = &'a str>) -> Vec<Attachment> {
+        tweet_ids
+            .map(|tweet_id| {
+                let tweet = self.tweets.get(tweet_id);
+                let username = tweet
+                    .and_then(|tweet| tweet.author_id.as_deref())
+                    .and_then(|id| self.users.get(id))
+                    .map(|user| user.username.as_str())
+                    .unwrap_or("i");
+                
+                Attachment {
+                    attachment_type: "tweet".to_string(),
+                    url: Some(format!("https://twitter.com/{}/status/{}", username, tweet_id)),
+                    name: tweet.map(|tweet| format!("@{}: {}", username, full_text(tweet))),
+                    mime_type: None,
+                    size: None,
+                    blob_hash: None,
+                }
+            })
+            .collect()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterMessageTarget {
-    recipient_id: String,
+fn full_text(tweet: &TwitterTweet) -> &str {
+    tweet.note_tweet.as_ref().map(|note| note.text.as_str()).unwrap_or(&tweet.text)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterMessageData {
-    text: String,
-    entities: Option<TwitterEntities>,
-    attachment: Option<TwitterMessageAttachment>,
+// Convert Twitter entities to our domain model
+fn twitter_user(user: &TwitterUser) -> User {
+    User {
+        id: user.id.clone(),
+        name: format!("{} (@{})", user.name, user.username),
+        platform: Platform::Twitter,
+        avatar_url: user.profile_image_url.clone(),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterMessageAttachment {
-    #[serde(rename = "type")]
-    attachment_type: String,
-    media: Option<TwitterAttachmentMedia>,
+// Message ids are scoped to their conversation, since the same tweet can
+// turn up in mentions, bookmarks and several searches
+fn message_id(conversation_id: &str, tweet_id: &str) -> String {
+    format!("{}/{}", conversation_id, tweet_id)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct TwitterAttachmentMedia {
-    id: i64,
-    id_str: String,
-    media_url: String,
-    media_url_https: String,
-    url: String,
-    #[serde(rename = "type")]
-    media_type: String,
-}
\ No newline at end of file
+fn tweet_to_message(tweet: &TwitterTweet, includes: &Includes, conversation_id: &str) -> Message {
+    let mut text = full_text(tweet).to_string();
+    
+    // Retweet text is truncated; use the original's in full
+    let retweeted = tweet.referenced_tweets.iter()
+        .find(|reference| reference.reference_type == "retweeted")
+        .and_then(|reference| includes.tweets.get(&reference.id));
+    if let Some(original) = retweeted {
+        let author = original.author_id.as_deref()
+            .and_then(|id| includes.users.get(id))
+            .map(|user| user.username.clone())
+            .unwrap_or_default();
+        text = format!("RT @{}: {}", author, full_text(original));
+    }
+    
+    let mut attachments = includes.attachments(tweet.attachments.as_ref());
+    attachments.extend(includes.tweet_links(tweet.referenced_tweets.iter()
+        .filter(|reference| reference.reference_type == "quoted")
+        .map(|reference| reference.id.as_str())));
+    
+    let reply_to = tweet.referenced_tweets.iter()
+        .find(|reference| reference.reference_type == "replied_to")
+        .map(|reference| message_id(conversation_id, &reference.id));
+    
+    Message {
+        id: message_id(conversation_id, &tweet.id),
+        platform: Platform::Twitter,
+        conversation_id: conversation_id.to_string(),
+        sender: includes.author(tweet.author_id.as_deref()),
+        content: MessageContent {
+            text,
+            attachments,
+        },
+        timestamp: timestamp_ms(tweet.created_at.as_deref(), &tweet.id),
+        // The root tweet's message id, so thread lookups match message ids
+        thread_id: tweet.conversation_id.as_deref().map(|root| message_id(conversation_id, root)),
+        reply_to,
+        edited: false,
+    }
+}
+
+fn dm_event_to_message(event: &TwitterDmEvent, includes: &Includes, conversation_id: &str) -> Message {
+    let mut attachments = includes.attachments(event.attachments.as_ref());
+    // Tweets shared into the conversation
+    attachments.extend(includes.tweet_links(event.referenced_tweets.iter().map(|reference| reference.id.as_str())));
+    
+    Message {
+        id: message_id(conversation_id, &event.id),
+        platform: Platform::Twitter,
+        conversation_id: conversation_id.to_string(),
+        sender: includes.author(event.sender_id.as_deref()),
+        content: MessageContent {
+            text: event.text.clone().unwrap_or_default(),
+            attachments,
+        },
+        timestamp: timestamp_ms(event.created_at.as_deref(), &event.id),
+        thread_id: None,
+        reply_to: None,
+        edited: false,
+    }
+}
@@ -3,10 +3,20 @@ use crate::{
     Attachment, Platform, Error, Result
 };
 use crate::auth::whatsapp;
-use crate::storage::{conversations, messages};
+use crate::storage::{blobs, connections, conversations, messages};
 use ic_cdk::api::time;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use super::http;
+use super::scope::{ConversationKind, RemoteConversation, SyncRules};
+
+const GRAPH_API_BASE: &str = "https://graph.facebook.com/v18.0";
+
+// Largest media downloaded at ingest
+const MAX_MEDIA_BYTES: u64 = 16_000_000;
+
+// Bytes requested per outcall, leaving room for headers under the response limit
+const MEDIA_CHUNK_BYTES: u64 = 1_900_000;
 
 // Initialize connection to WhatsApp
 pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
@@ -14,43 +24,89 @@ pub async fn init_connection(auth_config: &AuthConfig) -> Result<()> {
     let business_profile = get_business_profile(auth_config).await?;
     ic_cdk::println!("Connected to WhatsApp Business: {}", business_profile.name);
     
-    // Initialize conversations
-    let contacts = get_contacts(auth_config).await?;
-    store_contacts(auth_config, contacts).await?;
-    
     Ok(())
 }
 
 // Sync messages from WhatsApp
-pub async fn sync_messages(auth_config: &AuthConfig) -> Result<u64> {
-    // Get all conversations
-    let user_conversations = conversations::get_user_conversations(
-        &ic_cdk::caller().to_string(), 
-        Some(Platform::WhatsApp)
-    );
+pub async fn sync_messages(auth_config: &AuthConfig, connection_id: &str, rules: &SyncRules) -> Result<u64> {
+    let caller = ic_cdk::caller().to_string();
+    
+    // Downloaded media is linked with URLs signed by the caller's key
+    blobs::ensure_url_key(&caller).await?;
+    
+    // Contacts in the connection's scope become conversations, tagged with
+    // the number they belong to
+    let contacts = get_contacts(auth_config).await?;
+    let conversation_ids = store_contacts(auth_config, contacts, connection_id, rules).await?;
     
     let mut total_synced = 0;
     
     // For each conversation, get recent messages
-    for conversation in user_conversations {
-        let wa_messages = get_conversation_messages(auth_config, &conversation.id).await?;
+    for conversation_id in conversation_ids {
+        let wa_messages = get_conversation_messages(auth_config, &conversation_id).await?;
+        
+        let latest = wa_messages.iter().map(|m| m.timestamp).max();
         
         // Store messages
         for msg in wa_messages {
-            let message = whatsapp_message_to_message(msg, &conversation.id)?;
+            // Media URLs expire within minutes, so the content is fetched now
+            let blob_hash = match media_reference(&msg) {
+                Some(media) => match download_media(auth_config, &media, &caller).await {
+                    Ok(hash) => Some(hash),
+                    Err(e) => {
+                        ic_cdk::println!("Failed to download WhatsApp media {}: {:?}", media.id, e);
+                        None
+                    },
+                },
+                None => None,
+            };
+            
+            let message = whatsapp_message_to_message(msg, &conversation_id, blob_hash, &caller)?;
             messages::store_message(message)?;
             total_synced += 1;
         }
         
         // Update conversation with last message timestamp
-        if let Some(latest) = wa_messages.first() {
-            conversations::update_conversation_last_message(&conversation.id, latest.timestamp)?;
+        if let Some(latest) = latest {
+            conversations::update_conversation_last_message(&conversation_id, latest)?;
         }
     }
     
     Ok(total_synced)
 }
 
+// Contacts of the business number, and whether the connection's rules sync
+// them
+pub async fn list_remote_conversations(auth_config: &AuthConfig, rules: &SyncRules) -> Result<Vec<RemoteConversation>> {
+    let business_profile = get_business_profile(auth_config).await?;
+    let contacts = get_contacts(auth_config).await?;
+    
+    Ok(contacts.iter()
+        .map(|contact| remote_conversation(&business_profile, contact).scoped(rules))
+        .collect())
+}
+
+fn remote_conversation(business_profile: &WhatsAppBusinessProfile, contact: &WhatsAppContact) -> RemoteConversation {
+    RemoteConversation::new(
+        contact_conversation_id(business_profile, contact),
+        format!("Chat with {}", contact_name(contact)),
+        ConversationKind::Direct,
+        false,
+        Some(2),
+    )
+}
+
+// Conversation ID format: wa_<business id>_<contact id>
+fn contact_conversation_id(business_profile: &WhatsAppBusinessProfile, contact: &WhatsAppContact) -> String {
+    format!("wa_{}_{}", business_profile.id, contact.wa_id)
+}
+
+fn contact_name(contact: &WhatsAppContact) -> String {
+    contact.name.as_ref()
+        .map(|n| n.formatted_name.clone())
+        .unwrap_or_else(|| contact.phone_number.clone())
+}
+
 // Get business profile
 async fn get_business_profile(auth_config: &AuthConfig) -> Result<WhatsAppBusinessProfile> {
     // This would normally use HTTP outbound calls to the WhatsApp Business API
@@ -107,15 +163,22 @@ async fn get_contacts(auth_config: &AuthConfig) -> Result<Vec<WhatsAppContact>>
     ])
 }
 
-// Store WhatsApp contacts as conversations
+// Store the WhatsApp contacts the rules allow as conversations, returning
+// their conversation ids
 async fn store_contacts(
     auth_config: &AuthConfig,
     contacts: Vec<WhatsAppContact>,
-) -> Result<()> {
+    connection_id: &str,
+    rules: &SyncRules,
+) -> Result<Vec<String>> {
     let caller = ic_cdk::caller();
     let business_profile = get_business_profile(auth_config).await?;
+    let mut conversation_ids = Vec::new();
+    
+    let scoped = contacts.into_iter()
+        .filter(|contact| rules.allows(&remote_conversation(&business_profile, contact)));
     
-    for contact in contacts {
+    for contact in scoped {
         // Create participants list
         let mut participants = Vec::new();
         
@@ -128,9 +191,7 @@ async fn store_contacts(
         });
         
         // Add the contact
-        let contact_name = contact.name.as_ref()
-            .map(|n| n.formatted_name.clone())
-            .unwrap_or_else(|| contact.phone_number.clone());
+        let contact_name = contact_name(&contact);
         
         participants.push(User {
             id: contact.wa_id.clone(),
@@ -139,24 +200,27 @@ async fn store_contacts(
             avatar_url: contact.profile.as_ref().and_then(|p| p.picture_url.clone()),
         });
         
-        // Create conversation ID (format: whatsapp_business_id_contact_id)
-        let conversation_id = format!("wa_{}_{}", business_profile.id, contact.wa_id);
+        let conversation_id = contact_conversation_id(&business_profile, &contact);
         
-        // Create conversation
+        // Create conversation, keeping what earlier syncs learned
+        let existing = conversations::get_conversation(&conversation_id);
         let conversation = Conversation {
-            id: conversation_id,
+            id: conversation_id.clone(),
             platform: Platform::WhatsApp,
             name: format!("Chat with {}", contact_name),
             participants,
-            created_at: time(),
-            last_message_at: None,
+            created_at: existing.as_ref().map(|c| c.created_at).unwrap_or_else(time),
+            last_message_at: existing.and_then(|c| c.last_message_at),
+            connection_id: Some(connection_id.to_string()),
         };
         
         // Store conversation
+        connections::tag_conversation(&caller.to_string(), connection_id, &conversation_id);
         conversations::store_conversation(conversation)?;
+        conversation_ids.push(conversation_id);
     }
     
-    Ok(())
+    Ok(conversation_ids)
 }
 
 // Get messages from a WhatsApp conversation
@@ -216,7 +280,7 @@ async fn get_conversation_messages(
             type_field: "image".to_string(),
             text: None,
             image: Some(WhatsAppMedia {
-                id: "1234567890",
+                id: "1234567890".to_string(),
                 mime_type: "image/jpeg".to_string(),
                 sha256: "abcdef1234567890".to_string(),
                 caption: Some("Here's a screenshot of my order confirmation".to_string()),
@@ -232,8 +296,113 @@ async fn get_conversation_messages(
     ])
 }
 
-// Convert WhatsApp message to our domain model
-fn whatsapp_message_to_message(msg: WhatsAppMessage, conversation_id: &str) -> Result<Message> {
+// Media attached to a message, as WhatsApp identifies it
+struct MediaReference {
+    id: String,
+    // Hex SHA-256 of the content, when WhatsApp reports one
+    sha256: Option<String>,
+}
+
+fn media_reference(msg: &WhatsAppMessage) -> Option<MediaReference> {
+    let (id, sha256) = match msg.type_field.as_str() {
+        "image" => msg.image.as_ref().map(|m| (&m.id, &m.sha256))?,
+        "audio" => msg.audio.as_ref().map(|m| (&m.id, &m.sha256))?,
+        "video" => msg.video.as_ref().map(|m| (&m.id, &m.sha256))?,
+        "document" => msg.document.as_ref().map(|m| (&m.id, &m.sha256))?,
+        _ => return None,
+    };
+    
+    let sha256 = sha256.to_lowercase();
+    Some(MediaReference {
+        id: id.clone(),
+        sha256: if sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit()) { Some(sha256) } else { None },
+    })
+}
+
+// Resolve a media ID to its short-lived URL and download the content in
+// ranged chunks into the blob store. Returns the blob hash.
+async fn download_media(auth_config: &AuthConfig, media: &MediaReference, owner: &str) -> Result<String> {
+    // WhatsApp reports the content hash, so media already stored isn't fetched again
+    if let Some(sha256) = &media.sha256 {
+        if let Some(blob) = blobs::get_blob_info(sha256) {
+            if !blobs::is_owner(sha256, owner) {
+                blobs::check_quota(owner, blob.size)?;
+                blobs::add_owner(sha256, owner);
+            }
+            return Ok(sha256.clone());
+        }
+    }
+    
+    let authorization = format!("Bearer {}", auth_config.token);
+    let info: WhatsAppMediaUrl = http::get_json(
+        &format!("{}/{}", GRAPH_API_BASE, media.id),
+        &[("Authorization", &authorization)],
+    ).await?;
+    
+    if info.file_size > MAX_MEDIA_BYTES {
+        return Err(Error::PlatformError(format!(
+            "WhatsApp media {} is {} bytes, over the {} byte limit", media.id, info.file_size, MAX_MEDIA_BYTES
+        )));
+    }
+    // Downloads count against the same quota as uploads
+    blobs::check_quota(owner, info.file_size)?;
+    
+    let mut data: Vec<u8> = Vec::with_capacity(info.file_size as usize);
+    while (data.len() as u64) < info.file_size {
+        let start = data.len() as u64;
+        let end = (start + MEDIA_CHUNK_BYTES).min(info.file_size) - 1;
+        let request = http::OutgoingRequest::get(&info.url)
+            .with_bearer_token(&auth_config.token)
+            .with_header("Range", &format!("bytes={}-{}", start, end));
+        let (status, body) = http::send(request).await?;
+        
+        match status {
+            206 if !body.is_empty() => data.extend_from_slice(&body),
+            // The server ignored the range and sent the whole file
+            200 if start == 0 => {
+                data = body;
+                break;
+            },
+            _ => return Err(Error::PlatformError(format!(
+                "HTTP {} downloading WhatsApp media {}", status, media.id
+            ))),
+        }
+    }
+    
+    if data.len() as u64 != info.file_size {
+        return Err(Error::PlatformError(format!(
+            "WhatsApp media {} is {} bytes, expected {}", media.id, data.len(), info.file_size
+        )));
+    }
+    
+    let hash = blobs::sha256_hex(&data);
+    if media.sha256.as_ref().map(|expected| *expected != hash).unwrap_or(false) {
+        return Err(Error::PlatformError(format!("WhatsApp media {} failed its SHA-256 check", media.id)));
+    }
+    
+    let mime_type = blobs::allowed_mime_type(&info.mime_type)
+        .unwrap_or_else(|| blobs::FALLBACK_MIME_TYPE.to_string());
+    Ok(blobs::store_blob(&data, &mime_type, owner))
+}
+
+// Attachment for downloaded media, with its size and type from the blob store
+fn media_attachment(attachment_type: &str, name: Option<String>, blob_hash: &Option<String>, owner: &str) -> Attachment {
+    let blob = blob_hash.as_deref().and_then(blobs::get_blob_info);
+    
+    Attachment {
+        attachment_type: attachment_type.to_string(),
+        url: blob_hash.as_deref().and_then(|hash| blobs::blob_url(hash, owner)),
+        name,
+        mime_type: blob.as_ref().map(|blob| blob.mime_type.clone()),
+        size: blob.as_ref().map(|blob| blob.size),
+        blob_hash: blob_hash.clone(),
+    }
+}
+
+// Convert WhatsApp message to our domain model. Media attachments reference
+// their downloaded blob, served from the canister's /blobs/ route with a URL
+// signed for the syncing user.
+fn whatsapp_message_to_message(msg: WhatsAppMessage, conversation_id: &str, blob_hash: Option<String>, owner: &str) -> Result<Message> {
     // Parse conversation ID to get business ID
     // Format: wa_business_id_contact_id
     let parts: Vec<&str> = conversation_id.split('_').collect();
@@ -271,44 +440,24 @@ fn whatsapp_message_to_message(msg: WhatsAppMessage, conversation_id: &str) -> R
         "image" => {
             if let Some(image) = &msg.image {
                 message_text = image.caption.clone().unwrap_or_default();
-                attachments.push(Attachment {
-                    attachment_type: "image".to_string(),
-                    url: image.url.clone(),
-                    content: None,
-                    name: Some("Image".to_string()),
-                });
+                attachments.push(media_attachment("image", Some("Image".to_string()), &blob_hash, owner));
             }
         },
         "audio" => {
-            if let Some(audio) = &msg.audio {
-                attachments.push(Attachment {
-                    attachment_type: "audio".to_string(),
-                    url: audio.url.clone(),
-                    content: None,
-                    name: Some("Audio".to_string()),
-                });
+            if msg.audio.is_some() {
+                attachments.push(media_attachment("audio", Some("Audio".to_string()), &blob_hash, owner));
             }
         },
         "document" => {
             if let Some(document) = &msg.document {
                 message_text = document.caption.clone().unwrap_or_default();
-                attachments.push(Attachment {
-                    attachment_type: "file".to_string(),
-                    url: document.url.clone(),
-                    content: None,
-                    name: document.filename.clone(),
-                });
+                attachments.push(media_attachment("file", document.filename.clone(), &blob_hash, owner));
             }
         },
         "video" => {
             if let Some(video) = &msg.video {
                 message_text = video.caption.clone().unwrap_or_default();
-                attachments.push(Attachment {
-                    attachment_type: "video".to_string(),
-                    url: video.url.clone(),
-                    content: None,
-                    name: Some("Video".to_string()),
-                });
+                attachments.push(media_attachment("video", Some("Video".to_string()), &blob_hash, owner));
             }
         },
         "location" => {
@@ -394,6 +543,15 @@ struct WhatsAppMessage {
     interactive: Option<WhatsAppInteractive>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+struct WhatsAppMediaUrl {
+    id: String,
+    url: String,
+    mime_type: String,
+    sha256: String,
+    file_size: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct WhatsAppText {
     body: String,
@@ -0,0 +1,337 @@
+use crate::{Attachment, Message, Error, Result};
+use tantivy::{Index, IndexWriter, Document, Term, query::QueryParser, collector::TopDocs, SnippetGenerator};
+use tantivy::query::{AllQuery, Query, BooleanQuery, Occur, TermQuery, RangeQuery};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::directory::MmapDirectory;
+use std::collections::HashMap;
+use std::path::Path;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use super::extract;
+use super::schema::{create_attachment_schema, register_tokenizers, platform_to_string, FIELD_ID, FIELD_ATTACHMENT_TYPE, FIELD_ATTACHMENT_NAME, FIELD_ATTACHMENT_URL, FIELD_ATTACHMENT_HASH, FIELD_ATTACHMENT_TEXT, FIELD_CONVERSATION_ID, FIELD_PLATFORM, FIELD_TIMESTAMP};
+use super::search::SearchFilters;
+
+// In-memory buffer size
+const MEMORY_BUFFER_SIZE: usize = 20_000_000; // 20MB
+
+// Path for attachment index storage
+const INDEX_PATH: &str = "stable_memory/attachment_index";
+
+// Maximum length of a passage returned from an attachment
+const SNIPPET_MAX_CHARS: usize = 160;
+
+// Boost for a match in the file name relative to the extracted text
+const NAME_BOOST: f32 = 0.8;
+
+// Flag for operating in test mode
+static IN_TEST_MODE: AtomicBool = AtomicBool::new(false);
+
+// A hit inside one of a message's attachments, with the passage that matched
+#[derive(Debug, Clone)]
+pub struct AttachmentMatch {
+    // Score normalized to the 0.0-1.0 range; 0.0 when there was no query text
+    pub score: f32,
+    pub attachment_name: Option<String>,
+    pub blob_hash: Option<String>,
+    pub fragment: String,
+    // Byte ranges of the matched terms within the fragment
+    pub highlights: Vec<(usize, usize)>,
+}
+
+pub struct AttachmentIndexer {
+    index: Index,
+    writer: IndexWriter,
+    fields: HashMap<String, Field>,
+}
+
+impl AttachmentIndexer {
+    pub fn new() -> Self {
+        // Set up the schema
+        let (schema, fields) = create_attachment_schema();
+        
+        // Create or open the index
+        let index = if IN_TEST_MODE.load(Ordering::Relaxed) {
+            // In-memory index for testing
+            Index::create_in_ram(schema)
+        } else {
+            // Create directory if it doesn't exist
+            if !Path::new(INDEX_PATH).exists() {
+                fs::create_dir_all(INDEX_PATH).unwrap_or_else(|e| {
+                    ic_cdk::println!("Error creating attachment index directory: {}", e);
+                });
+            }
+            
+            // Persistent index
+            let dir = MmapDirectory::open(Path::new(INDEX_PATH))
+                .unwrap_or_else(|e| {
+                    ic_cdk::println!("Error opening attachment index directory: {}", e);
+                    // Fallback to in-memory
+                    MmapDirectory::create_from_tempdir().unwrap()
+                });
+            
+            Index::open_or_create(dir, schema).unwrap_or_else(|e| {
+                ic_cdk::println!("Error opening attachment index: {}", e);
+                // Fallback to in-memory
+                Index::create_in_ram(schema)
+            })
+        };
+        
+        // Extracted text uses the same analyzers as message content
+        register_tokenizers(&index);
+        
+        // Create a writer with a memory buffer
+        let writer = index.writer(MEMORY_BUFFER_SIZE)
+            .unwrap_or_else(|e| {
+                ic_cdk::println!("Error creating attachment index writer: {}", e);
+                // Fallback with smaller buffer
+                index.writer(1_000_000).unwrap()
+            });
+        
+        Self {
+            index,
+            writer,
+            fields,
+        }
+    }
+    
+    // Index each of a message's attachments as its own document
+    pub fn index_message(&mut self, message: &Message) -> Result<()> {
+        // Replace the documents from an earlier version of the message
+        self.writer.delete_term(Term::from_field_text(self.fields[FIELD_ID], &message.id));
+        
+        for attachment in &message.content.attachments {
+            let mut doc = Document::new();
+            
+            doc.add_text(self.fields[FIELD_ID], &message.id);
+            doc.add_text(self.fields[FIELD_ATTACHMENT_TYPE], &attachment.attachment_type);
+            doc.add_text(self.fields[FIELD_CONVERSATION_ID], &message.conversation_id);
+            doc.add_text(self.fields[FIELD_PLATFORM], &platform_to_string(&message.platform));
+            doc.add_u64(self.fields[FIELD_TIMESTAMP], message.timestamp);
+            
+            if let Some(name) = &attachment.name {
+                doc.add_text(self.fields[FIELD_ATTACHMENT_NAME], name);
+            }
+            if let Some(url) = &attachment.url {
+                doc.add_text(self.fields[FIELD_ATTACHMENT_URL], url);
+            }
+            if let Some(hash) = &attachment.blob_hash {
+                doc.add_text(self.fields[FIELD_ATTACHMENT_HASH], hash);
+            }
+            
+            let text = attachment_text(attachment, &message.id);
+            if !text.is_empty() {
+                doc.add_text(self.fields[FIELD_ATTACHMENT_TEXT], &text);
+            }
+            
+            self.writer.add_document(doc).map_err(|e| Error::InternalError(format!("Failed to index attachment: {}", e)))?;
+        }
+        
+        // Commit periodically
+        if message.id.ends_with("00") {
+            self.commit()?;
+        }
+        
+        Ok(())
+    }
+    
+    // Commit changes to the index
+    fn commit(&mut self) -> Result<()> {
+        self.writer.commit().map_err(|e| Error::InternalError(format!("Failed to commit attachment index: {}", e)))?;
+        Ok(())
+    }
+    
+    // Optimize the index
+    pub fn optimize(&mut self) -> Result<()> {
+        // First commit any pending changes
+        self.commit()?;
+        
+        // Then merge segments
+        self.writer.merge(&[]).map_err(|e| Error::InternalError(format!("Failed to optimize attachment index: {}", e)))?;
+        Ok(())
+    }
+    
+    // Delete a message's attachments from the index
+    pub fn delete_message(&mut self, message_id: &str) -> Result<()> {
+        let term = Term::from_field_text(self.fields[FIELD_ID], message_id);
+        self.writer.delete_term(term);
+        self.commit()?;
+        Ok(())
+    }
+    
+    // Clear the entire index
+    pub fn clear(&mut self) -> Result<()> {
+        self.writer.delete_all_documents().map_err(|e| Error::InternalError(format!("Failed to clear attachment index: {}", e)))?;
+        self.commit()?;
+        Ok(())
+    }
+    
+    // Search attachment names and extracted text, keeping the best matching
+    // attachment of each message
+    pub fn search(&self, query_text: &str, filters: &SearchFilters, limit: usize) -> Result<HashMap<String, AttachmentMatch>> {
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        
+        if !query_text.trim().is_empty() {
+            let mut query_parser = QueryParser::for_index(
+                &self.index,
+                vec![self.fields[FIELD_ATTACHMENT_TEXT], self.fields[FIELD_ATTACHMENT_NAME]]
+            );
+            query_parser.set_field_boost(self.fields[FIELD_ATTACHMENT_NAME], NAME_BOOST);
+            query_parser.set_conjunction_by_default();
+            
+            let text_query = query_parser.parse_query(query_text)
+                .map_err(|e| Error::QueryError(format!("Failed to parse attachment query: {}", e)))?;
+            clauses.push((Occur::Must, text_query));
+        }
+        
+        // Attachment type filter
+        if let Some(attachment_type) = &filters.attachment_type {
+            let type_term = Term::from_field_text(self.fields[FIELD_ATTACHMENT_TYPE], attachment_type);
+            clauses.push((Occur::Must, Box::new(TermQuery::new(type_term, IndexRecordOption::Basic))));
+        }
+        
+        // Platform, time and conversation filters, as in the metadata index
+        if let Some(platform) = &filters.platform {
+            let platform_term = Term::from_field_text(self.fields[FIELD_PLATFORM], &platform_to_string(platform));
+            clauses.push((Occur::Must, Box::new(TermQuery::new(platform_term, IndexRecordOption::Basic))));
+        }
+        
+        if let Some(start_time) = filters.start_time {
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_u64(self.fields[FIELD_TIMESTAMP], start_time..=u64::MAX))));
+        }
+        
+        if let Some(end_time) = filters.end_time {
+            clauses.push((Occur::Must, Box::new(RangeQuery::new_u64(self.fields[FIELD_TIMESTAMP], 0..=end_time))));
+        }
+        
+        if let Some(conversation_id) = &filters.conversation_id {
+            let conversation_term = Term::from_field_text(self.fields[FIELD_CONVERSATION_ID], conversation_id);
+            clauses.push((Occur::Must, Box::new(TermQuery::new(conversation_term, IndexRecordOption::Basic))));
+        }
+        
+        // Only the caller's conversations, before the limit is applied
+        if let Some(scope) = filters.conversation_scope(self.fields[FIELD_CONVERSATION_ID]) {
+            clauses.push((Occur::Must, scope));
+        }
+        
+        let query: Box<dyn Query> = if clauses.is_empty() {
+            Box::new(AllQuery)
+        } else {
+            Box::new(BooleanQuery::new(clauses))
+        };
+        
+        let searcher = self.index.reader()
+            .map_err(|e| Error::InternalError(format!("Failed to get attachment index reader: {}", e)))?
+            .searcher();
+        
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))
+            .map_err(|e| Error::InternalError(format!("Failed to execute attachment search: {}", e)))?;
+        
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &*query, self.fields[FIELD_ATTACHMENT_TEXT])
+            .map_err(|e| Error::InternalError(format!("Failed to create snippet generator: {}", e)))?;
+        snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+        
+        // Without query text every document scores the same and nothing is highlighted
+        let has_query = !query_text.trim().is_empty();
+        let max_score = top_docs.iter()
+            .map(|(score, _)| score)
+            .fold(0.0, |max, &score| if score > max { score } else { max });
+        
+        let mut results: HashMap<String, AttachmentMatch> = HashMap::new();
+        
+        // Documents come best first, so the first per message is kept
+        for (score, doc_address) in top_docs {
+            let retrieved_doc = searcher.doc(doc_address)
+                .map_err(|e| Error::InternalError(format!("Failed to retrieve attachment document: {}", e)))?;
+            
+            let text_field = |name: &str| {
+                retrieved_doc.get_first(self.fields[name])
+                    .and_then(|v| v.as_text())
+                    .map(|v| v.to_string())
+            };
+            
+            let message_id = match text_field(FIELD_ID) {
+                Some(message_id) => message_id,
+                None => continue,
+            };
+            if results.contains_key(&message_id) {
+                continue;
+            }
+            
+            let attachment_name = text_field(FIELD_ATTACHMENT_NAME);
+            let snippet = snippet_generator.snippet_from_doc(&retrieved_doc);
+            let (fragment, highlights) = if has_query && !snippet.fragment().is_empty() {
+                (
+                    snippet.fragment().to_string(),
+                    snippet.highlighted().iter().map(|range| (range.start, range.end)).collect(),
+                )
+            } else {
+                // Matched on the name (or no query), so the name stands in for a passage
+                (attachment_name.clone().unwrap_or_default(), Vec::new())
+            };
+            
+            results.insert(message_id, AttachmentMatch {
+                score: if has_query && max_score > 0.0 { score / max_score } else { 0.0 },
+                attachment_name,
+                blob_hash: text_field(FIELD_ATTACHMENT_HASH),
+                fragment,
+                highlights,
+            });
+        }
+        
+        Ok(results)
+    }
+}
+
+// Cached text of a stored attachment. Documents not yet read are queued
+// for extraction, which runs in its own call (see
+// indexing::process_pending_extractions), and indexed by name until then.
+fn attachment_text(attachment: &Attachment, message_id: &str) -> String {
+    let hash = match &attachment.blob_hash {
+        Some(hash) => hash,
+        None => return String::new(),
+    };
+    if let Some(text) = crate::storage::blobs::get_blob_text(hash) {
+        return text;
+    }
+    
+    let blob = match crate::storage::blobs::get_blob_info(hash) {
+        Some(blob) => blob,
+        None => return String::new(),
+    };
+    if blob.size <= extract::MAX_EXTRACT_INPUT_BYTES && extract::has_text(&blob.mime_type, attachment.name.as_deref()) {
+        crate::storage::blobs::enqueue_extraction(hash, message_id);
+    } else {
+        // Content that is too large or not a document is cached as empty
+        crate::storage::blobs::store_blob_text(hash, String::new());
+    }
+    String::new()
+}
+
+// Extract and cache the text of a queued blob. Content that yields none is
+// cached as empty.
+pub fn extract_blob_text(hash: &str, name: Option<&str>) -> String {
+    let extracted = crate::storage::blobs::get_blob_info(hash)
+        .filter(|blob| blob.size <= extract::MAX_EXTRACT_INPUT_BYTES)
+        .and_then(|blob| {
+            crate::storage::blobs::get_blob_data(hash)
+                .and_then(|data| extract::extract_text(&data, &blob.mime_type, name))
+        });
+    
+    let text = match extracted {
+        Some(extracted) => {
+            if extracted.truncated {
+                ic_cdk::println!("Text extraction from blob {} stopped early at {} chars", hash, extracted.text.len());
+            }
+            extracted.text
+        },
+        None => String::new(),
+    };
+    
+    crate::storage::blobs::store_blob_text(hash, text.clone());
+    text
+}
+
+// For testing
+pub fn set_test_mode(enabled: bool) {
+    IN_TEST_MODE.store(enabled, Ordering::Relaxed);
+}
@@ -0,0 +1,134 @@
+use crate::Message;
+use crate::openchat::embeddings::fnv_hash;
+use crate::storage::duplicates;
+
+// Normalized text shorter than this is too generic to call a duplicate ("ok", "thanks!")
+const MIN_DEDUP_CHARS: usize = 24;
+
+// Words per shingle fed into the fingerprint
+const SHINGLE_SIZE: usize = 3;
+
+// Maximum differing fingerprint bits for two messages to be near-duplicates
+const MAX_HAMMING_DISTANCE: u32 = 3;
+
+// The 64-bit fingerprint is split into this many bands for candidate lookup.
+// With 4 bands of 16 bits, any pair within 3 bits shares at least one band.
+pub const BAND_COUNT: u8 = 4;
+const BAND_BITS: u32 = 16;
+
+// Lowercase, drop URLs, mentions and punctuation, collapse whitespace. Cross-posts
+// often differ only in links, @handles or platform formatting.
+pub fn normalize_text(text: &str) -> String {
+    text.split_whitespace()
+        .filter(|word| !word.starts_with("http://") && !word.starts_with("https://") && !word.starts_with('@'))
+        .map(|word| {
+            word.chars()
+                .filter(|c| c.is_alphanumeric())
+                .flat_map(|c| c.to_lowercase())
+                .collect::<String>()
+        })
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// 64-bit SimHash over word shingles of normalized text
+pub fn simhash(normalized: &str) -> u64 {
+    let words: Vec<&str> = normalized.split(' ').collect();
+    let mut weights = [0i32; 64];
+    
+    let shingles: Vec<String> = if words.len() < SHINGLE_SIZE {
+        vec![words.join(" ")]
+    } else {
+        words.windows(SHINGLE_SIZE).map(|w| w.join(" ")).collect()
+    };
+    
+    for shingle in shingles {
+        let hash = fnv_hash(&shingle);
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash & (1 << bit) != 0 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    
+    weights.iter().enumerate()
+        .filter(|(_, &weight)| weight > 0)
+        .fold(0u64, |fingerprint, (bit, _)| fingerprint | (1 << bit))
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// Value of one band of a fingerprint
+pub fn band(fingerprint: u64, band: u8) -> u16 {
+    ((fingerprint >> (band as u32 * BAND_BITS)) & 0xFFFF) as u16
+}
+
+// Fingerprint a message and attach it to the cluster of any near-duplicate
+pub fn index_message(message: &Message) {
+    // Edits may change the fingerprint, so start from a clean slate
+    remove_message(&message.id);
+    
+    let normalized = normalize_text(&message.content.text);
+    if normalized.chars().count() < MIN_DEDUP_CHARS {
+        return;
+    }
+    
+    let fingerprint = simhash(&normalized);
+    
+    // Closest already-clustered candidate sharing a band
+    let mut best: Option<(u32, String)> = None;
+    for band_index in 0..BAND_COUNT {
+        for candidate_id in duplicates::get_band_members(band_index, band(fingerprint, band_index)) {
+            if let Some(candidate) = duplicates::get_fingerprint(&candidate_id) {
+                let distance = hamming_distance(fingerprint, candidate);
+                let is_better = match &best {
+                    Some((best_distance, _)) => distance < *best_distance,
+                    None => true,
+                };
+                
+                if distance <= MAX_HAMMING_DISTANCE && is_better {
+                    best = Some((distance, candidate_id));
+                }
+            }
+        }
+    }
+    
+    duplicates::store_fingerprint(&message.id, fingerprint);
+    for band_index in 0..BAND_COUNT {
+        duplicates::add_band_member(band_index, band(fingerprint, band_index), &message.id);
+    }
+    
+    let cluster_id = best
+        .and_then(|(_, candidate_id)| duplicates::get_cluster_id(&candidate_id))
+        .unwrap_or_else(|| message.id.clone());
+    duplicates::add_to_cluster(&cluster_id, &message.id);
+}
+
+// Forget a message's fingerprint and cluster membership
+pub fn remove_message(message_id: &str) {
+    if let Some(fingerprint) = duplicates::delete_fingerprint(message_id) {
+        for band_index in 0..BAND_COUNT {
+            duplicates::remove_band_member(band_index, band(fingerprint, band_index), message_id);
+        }
+    }
+    
+    duplicates::remove_from_cluster(message_id);
+}
+
+// IDs of the messages in the same duplicate cluster (including the message itself)
+pub fn get_duplicates(message_id: &str) -> Vec<String> {
+    match duplicates::get_cluster_id(message_id) {
+        Some(cluster_id) => duplicates::get_cluster_members(&cluster_id),
+        None => vec![message_id.to_string()],
+    }
+}
+
+// Cluster a message belongs to; messages without duplicates are their own cluster
+pub fn cluster_key(message_id: &str) -> String {
+    duplicates::get_cluster_id(message_id).unwrap_or_else(|| message_id.to_string())
+}
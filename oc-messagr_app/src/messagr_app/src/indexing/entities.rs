@@ -0,0 +1,859 @@
+use crate::{Message, User};
+use crate::storage::entities::{self as entity_store, EntityRecord};
+use candid::{CandidType, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+// Rule- and gazetteer-based named-entity extraction. Patterns catch structured
+// values (emails, URLs, money, dates, phone numbers, code); people are matched
+// against the users we know, organisations and locations against word lists.
+// Capitalisation alone never makes an entity.
+
+// Longest gazetteer phrase tried at each position, in words
+const MAX_PHRASE_WORDS: usize = 4;
+
+// Phone numbers have between 7 and 15 digits (E.164)
+const MIN_PHONE_DIGITS: usize = 7;
+const MAX_PHONE_DIGITS: usize = 15;
+
+// Longer inline code spans are snippets rather than identifiers
+const MAX_CODE_CHARS: usize = 80;
+
+// Co-occurring entities kept for each entity
+const MAX_RELATED: usize = 5;
+
+// Message IDs returned with each entity, most recent first
+const MAX_MESSAGE_IDS: usize = 50;
+
+const ORGANIZATIONS: &[&str] = &[
+    "google", "alphabet", "microsoft", "apple", "amazon", "aws", "meta", "facebook",
+    "netflix", "openai", "anthropic", "nvidia", "intel", "amd", "ibm", "oracle",
+    "salesforce", "adobe", "tesla", "spacex", "twitter", "slack", "discord", "telegram",
+    "whatsapp", "instagram", "github", "gitlab", "atlassian", "stripe", "paypal", "shopify",
+    "uber", "airbnb", "spotify", "samsung", "sony", "dfinity", "internet computer",
+    "openchat", "coinbase", "binance", "ethereum foundation", "mozilla", "wikipedia",
+    "united nations", "european union", "nasa", "unicef", "world bank",
+];
+
+// Words that mark the capitalised words before them as an organisation ("Acme Corp")
+const ORGANIZATION_SUFFIXES: &[&str] = &[
+    "inc", "ltd", "llc", "llp", "plc", "corp", "corporation", "company", "co", "gmbh",
+    "ag", "sa", "foundation", "university", "college", "bank", "labs", "group",
+    "institute", "association", "agency", "ministry", "capital", "ventures", "partners",
+];
+
+const LOCATIONS: &[&str] = &[
+    // Countries
+    "united states", "usa", "us", "canada", "mexico", "brazil", "argentina", "chile",
+    "colombia", "peru", "united kingdom", "uk", "england", "scotland", "wales", "ireland",
+    "france", "germany", "spain", "portugal", "italy", "netherlands", "belgium",
+    "switzerland", "austria", "sweden", "norway", "denmark", "finland", "poland",
+    "ukraine", "russia", "turkey", "greece", "israel", "egypt", "nigeria", "kenya",
+    "south africa", "india", "pakistan", "china", "japan", "korea", "south korea",
+    "singapore", "indonesia", "vietnam", "thailand", "philippines", "australia",
+    "new zealand", "uae", "saudi arabia",
+    // Cities
+    "new york", "los angeles", "san francisco", "seattle", "chicago", "boston", "austin",
+    "miami", "toronto", "vancouver", "london", "paris", "berlin", "munich", "zurich",
+    "geneva", "madrid", "barcelona", "lisbon", "rome", "milan", "amsterdam", "brussels",
+    "stockholm", "oslo", "copenhagen", "dublin", "vienna", "prague", "warsaw", "kyiv",
+    "istanbul", "dubai", "tel aviv", "cairo", "lagos", "nairobi", "mumbai", "delhi",
+    "new delhi", "bangalore", "beijing", "shanghai", "hong kong", "tokyo", "osaka",
+    "seoul", "sydney", "melbourne", "sao paulo", "buenos aires", "mexico city",
+];
+
+const MONTHS: &[&str] = &[
+    "january", "february", "march", "april", "may", "june", "july", "august",
+    "september", "october", "november", "december", "jan", "feb", "mar", "apr",
+    "jun", "jul", "aug", "sep", "sept", "oct", "nov", "dec",
+];
+
+const WEEKDAYS: &[&str] = &[
+    "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday",
+];
+
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥', '₹'];
+
+const CURRENCY_WORDS: &[&str] = &[
+    "usd", "eur", "gbp", "jpy", "chf", "cad", "aud", "inr", "icp", "btc", "eth",
+    "dollar", "dollars", "euro", "euros", "pound", "pounds", "bucks",
+];
+
+// Capitalised at the start of a sentence or greeting, never an entity on their own
+const COMMON_WORDS: &[&str] = &[
+    "the", "a", "an", "and", "or", "but", "if", "so", "hi", "hey", "hello", "thanks",
+    "thank", "yes", "no", "ok", "okay", "i", "we", "you", "he", "she", "they", "it",
+    "this", "that", "what", "when", "where", "who", "why", "how", "will", "can", "may",
+    "in", "on", "at", "to", "for", "from", "with", "of", "by", "about",
+];
+
+// Kinds of entity the extractor recognises
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EntityKind {
+    Person,
+    Organization,
+    Location,
+    Date,
+    Money,
+    Email,
+    Phone,
+    Url,
+    Code,
+}
+
+impl EntityKind {
+    pub const ALL: [EntityKind; 9] = [
+        EntityKind::Person,
+        EntityKind::Organization,
+        EntityKind::Location,
+        EntityKind::Date,
+        EntityKind::Money,
+        EntityKind::Email,
+        EntityKind::Phone,
+        EntityKind::Url,
+        EntityKind::Code,
+    ];
+    
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntityKind::Person => "person",
+            EntityKind::Organization => "organization",
+            EntityKind::Location => "location",
+            EntityKind::Date => "date",
+            EntityKind::Money => "money",
+            EntityKind::Email => "email",
+            EntityKind::Phone => "phone",
+            EntityKind::Url => "url",
+            EntityKind::Code => "code",
+        }
+    }
+}
+
+// An entity found in a piece of text
+#[derive(Debug, Clone)]
+pub struct ExtractedEntity {
+    pub kind: EntityKind,
+    // As written in the text
+    pub name: String,
+    pub normalized: String,
+}
+
+impl ExtractedEntity {
+    fn new(kind: EntityKind, name: &str, normalized: String) -> Self {
+        Self {
+            kind,
+            name: name.to_string(),
+            normalized,
+        }
+    }
+    
+    pub fn key(&self) -> String {
+        entity_key(self.kind, &self.normalized)
+    }
+}
+
+// An entity aggregated over a set of messages
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct EntityDetails {
+    pub key: String,
+    pub name: String,
+    pub kind: EntityKind,
+    pub mentions: u64,
+    pub message_count: u64,
+    pub first_seen: u64,
+    pub last_seen: u64,
+    pub related: Vec<String>,
+    pub message_ids: Vec<String>,
+}
+
+// Index key of an entity
+pub fn entity_key(kind: EntityKind, normalized: &str) -> String {
+    format!("{}:{}", kind.as_str(), normalized)
+}
+
+// Lowercase and collapse whitespace
+pub fn normalize_name(name: &str) -> String {
+    name.split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// A whitespace-separated token with its surrounding punctuation handled
+struct Token<'a> {
+    // Without leading brackets/quotes or trailing punctuation
+    text: &'a str,
+    // Alphanumeric core, used for word and gazetteer matching
+    word: &'a str,
+    lower: String,
+    // Followed by punctuation that ends a phrase
+    ends_clause: bool,
+}
+
+impl<'a> Token<'a> {
+    fn new(raw: &'a str) -> Self {
+        let unquoted = raw.trim_start_matches(|c: char| "([{\"'".contains(c));
+        // Calls like run() keep their parentheses
+        let text = match unquoted.trim_end_matches(|c: char| ".,;:!?".contains(c)) {
+            call if call.ends_with("()") => call,
+            _ => unquoted.trim_end_matches(|c: char| ".,;:!?)]}\"'".contains(c)),
+        };
+        let word = raw.trim_matches(|c: char| !c.is_alphanumeric());
+        let ends_clause = raw
+            .trim_end_matches(|c: char| ")]}\"'".contains(c))
+            .ends_with(|c: char| ".,;:!?".contains(c));
+        
+        Self {
+            text,
+            word,
+            lower: word.to_lowercase(),
+            ends_clause,
+        }
+    }
+    
+    fn is_capitalized(&self) -> bool {
+        self.word.chars().next().map(|c| c.is_uppercase()).unwrap_or(false)
+    }
+}
+
+// People known to the extractor, by lowercased name words
+struct Gazetteer {
+    people: Vec<(Vec<String>, String)>,
+    handles: HashMap<String, String>,
+}
+
+impl Gazetteer {
+    fn new(people: &[User]) -> Self {
+        let mut names: Vec<(Vec<String>, String)> = Vec::new();
+        let mut handles = HashMap::new();
+        let mut first_names: HashMap<String, Vec<String>> = HashMap::new();
+        
+        for user in people {
+            let words: Vec<String> = user.name.split_whitespace().map(|w| w.to_lowercase()).collect();
+            if words.is_empty() || names.iter().any(|(_, name)| *name == user.name) {
+                continue;
+            }
+            
+            handles.insert(user.id.to_lowercase(), user.name.clone());
+            handles.insert(words.concat(), user.name.clone());
+            if words.len() > 1 {
+                first_names.entry(words[0].clone()).or_default().push(user.name.clone());
+            }
+            names.push((words, user.name.clone()));
+        }
+        
+        // First names alone only when they identify a single person
+        for (first_name, matches) in first_names {
+            if matches.len() == 1 && first_name.chars().count() >= 3 && !COMMON_WORDS.contains(&first_name.as_str()) {
+                handles.entry(first_name.clone()).or_insert_with(|| matches[0].clone());
+                names.push((vec![first_name], matches[0].clone()));
+            }
+        }
+        
+        Self { people: names, handles }
+    }
+}
+
+// Extract entities from text, matching people against the given users
+pub fn extract(text: &str, people: &[User]) -> Vec<ExtractedEntity> {
+    let gazetteer = Gazetteer::new(people);
+    let mut entities = Vec::new();
+    
+    // Backtick spans are code; the rest is tokenised
+    let mut plain = String::with_capacity(text.len());
+    let segments: Vec<&str> = text.split('`').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        let is_code = i % 2 == 1 && i + 1 < segments.len();
+        let code = segment.trim();
+        if is_code && !code.is_empty() && code.chars().count() <= MAX_CODE_CHARS && !code.contains('\n') {
+            entities.push(ExtractedEntity::new(EntityKind::Code, code, code.to_lowercase()));
+        } else if !is_code {
+            plain.push_str(segment);
+        }
+        plain.push(' ');
+    }
+    
+    let tokens: Vec<Token> = plain.split_whitespace().map(Token::new).collect();
+    let mut i = 0;
+    
+    while i < tokens.len() {
+        match match_at(&tokens, i, &gazetteer) {
+            Some((entity, consumed)) => {
+                entities.push(entity);
+                i += consumed;
+            },
+            None => i += 1,
+        }
+    }
+    
+    entities
+}
+
+// The entity starting at token i and the number of tokens it spans
+fn match_at(tokens: &[Token], i: usize, gazetteer: &Gazetteer) -> Option<(ExtractedEntity, usize)> {
+    let token = &tokens[i];
+    if token.text.is_empty() {
+        return None;
+    }
+    
+    match_url(token)
+        .or_else(|| match_email(token))
+        .map(|entity| (entity, 1))
+        .or_else(|| match_mention(token, gazetteer).map(|entity| (entity, 1)))
+        .or_else(|| match_money(tokens, i))
+        .or_else(|| match_date(tokens, i))
+        .or_else(|| match_phone(tokens, i))
+        .or_else(|| match_code(token).map(|entity| (entity, 1)))
+        .or_else(|| match_gazetteers(tokens, i, gazetteer))
+}
+
+fn match_url(token: &Token) -> Option<ExtractedEntity> {
+    let lower = token.text.to_lowercase();
+    if (lower.starts_with("http://") || lower.starts_with("https://") || lower.starts_with("www.")) && lower.len() > 8 {
+        Some(ExtractedEntity::new(EntityKind::Url, token.text, lower.trim_end_matches('/').to_string()))
+    } else {
+        None
+    }
+}
+
+fn match_email(token: &Token) -> Option<ExtractedEntity> {
+    let (local, domain) = token.text.split_once('@')?;
+    let valid_chars = token.text.chars().all(|c| c.is_alphanumeric() || "._%+-@".contains(c));
+    
+    if !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && valid_chars
+    {
+        Some(ExtractedEntity::new(EntityKind::Email, token.text, token.text.to_lowercase()))
+    } else {
+        None
+    }
+}
+
+// @handle of a known person
+fn match_mention(token: &Token, gazetteer: &Gazetteer) -> Option<ExtractedEntity> {
+    let handle = token.text.strip_prefix('@')?.to_lowercase();
+    let name = gazetteer.handles.get(&handle)?;
+    Some(ExtractedEntity::new(EntityKind::Person, name, normalize_name(name)))
+}
+
+// "$1,200", "€5.50", "20k USD", "300 dollars"
+fn match_money(tokens: &[Token], i: usize) -> Option<(ExtractedEntity, usize)> {
+    let token = &tokens[i];
+    
+    if let Some(amount) = token.text.strip_prefix(CURRENCY_SYMBOLS) {
+        if is_amount(amount) {
+            return Some((ExtractedEntity::new(EntityKind::Money, token.text, token.text.to_lowercase()), 1));
+        }
+    }
+    
+    if is_amount(token.text) && !token.ends_clause {
+        let next = tokens.get(i + 1)?;
+        if CURRENCY_WORDS.contains(&next.lower.as_str()) {
+            let name = format!("{} {}", token.text, next.word);
+            let normalized = normalize_name(&name);
+            return Some((ExtractedEntity::new(EntityKind::Money, &name, normalized), 2));
+        }
+    }
+    
+    None
+}
+
+// Digits with thousands separators, decimals and an optional k/m/bn multiplier
+fn is_amount(text: &str) -> bool {
+    let lower = text.to_lowercase();
+    let number = lower.trim_end_matches("bn").trim_end_matches(['k', 'm']);
+    number.starts_with(|c: char| c.is_ascii_digit())
+        && number.ends_with(|c: char| c.is_ascii_digit())
+        && number.chars().all(|c| c.is_ascii_digit() || c == ',' || c == '.')
+}
+
+// "2024-05-01", "5/1/24", "March 3", "3rd March 2024", "Monday"
+fn match_date(tokens: &[Token], i: usize) -> Option<(ExtractedEntity, usize)> {
+    let token = &tokens[i];
+    
+    if is_iso_date(token.text) || is_slash_date(token.text) {
+        return Some((ExtractedEntity::new(EntityKind::Date, token.text, token.text.to_string()), 1));
+    }
+    
+    if token.is_capitalized() && WEEKDAYS.contains(&token.lower.as_str()) {
+        return Some((ExtractedEntity::new(EntityKind::Date, token.word, token.lower.clone()), 1));
+    }
+    
+    // Month then day and/or year
+    if is_month(token) && !token.ends_clause {
+        let next = tokens.get(i + 1)?;
+        if day_number(next.word).is_some() || is_year(next.word) {
+            let mut span = 2;
+            if day_number(next.word).is_some() && !next.ends_clause {
+                if let Some(year) = tokens.get(i + 2) {
+                    if is_year(year.word) {
+                        span = 3;
+                    }
+                }
+            }
+            return Some(date_span(tokens, i, span));
+        }
+    }
+    
+    // Day then month, optionally a year
+    if day_number(token.word).is_some() && !token.ends_clause {
+        let next = tokens.get(i + 1)?;
+        if is_month(next) {
+            let mut span = 2;
+            if !next.ends_clause {
+                if let Some(year) = tokens.get(i + 2) {
+                    if is_year(year.word) {
+                        span = 3;
+                    }
+                }
+            }
+            return Some(date_span(tokens, i, span));
+        }
+    }
+    
+    None
+}
+
+fn date_span(tokens: &[Token], i: usize, span: usize) -> (ExtractedEntity, usize) {
+    let name = tokens[i..i + span].iter().map(|t| t.word).collect::<Vec<&str>>().join(" ");
+    let normalized = normalize_name(&name);
+    (ExtractedEntity::new(EntityKind::Date, &name, normalized), span)
+}
+
+// Month names must be capitalised; "may" and "mar" are common words otherwise
+fn is_month(token: &Token) -> bool {
+    token.is_capitalized() && MONTHS.contains(&token.lower.as_str())
+}
+
+fn day_number(word: &str) -> Option<u32> {
+    let digits = word.trim_end_matches(|c: char| c.is_alphabetic());
+    let suffix = &word[digits.len()..].to_lowercase();
+    if !suffix.is_empty() && !["st", "nd", "rd", "th"].contains(&suffix.as_str()) {
+        return None;
+    }
+    
+    digits.parse::<u32>().ok().filter(|day| (1..=31).contains(day))
+}
+
+fn is_year(word: &str) -> bool {
+    word.len() == 4 && word.parse::<u32>().map(|year| (1900..=2100).contains(&year)).unwrap_or(false)
+}
+
+fn is_iso_date(text: &str) -> bool {
+    let parts: Vec<&str> = text.split('-').collect();
+    parts.len() == 3
+        && is_year(parts[0])
+        && parts[1].len() == 2 && parts[1].parse::<u32>().map(|m| (1..=12).contains(&m)).unwrap_or(false)
+        && parts[2].len() == 2 && parts[2].parse::<u32>().map(|d| (1..=31).contains(&d)).unwrap_or(false)
+}
+
+fn is_slash_date(text: &str) -> bool {
+    let parts: Vec<&str> = text.split('/').collect();
+    parts.len() == 3
+        && parts[..2].iter().all(|p| (1..=2).contains(&p.len()) && p.parse::<u32>().map(|n| (1..=31).contains(&n)).unwrap_or(false))
+        && (parts[2].len() == 2 || parts[2].len() == 4)
+        && parts[2].chars().all(|c| c.is_ascii_digit())
+}
+
+// A run of digit groups like "+1 (555) 123-4567"
+fn match_phone(tokens: &[Token], i: usize) -> Option<(ExtractedEntity, usize)> {
+    let is_phone_part = |text: &str| {
+        !text.is_empty()
+            && text.chars().all(|c| c.is_ascii_digit() || "+-().".contains(c))
+            && text.chars().any(|c| c.is_ascii_digit())
+    };
+    
+    let mut digits = String::new();
+    let mut span = 0;
+    let mut has_separator = false;
+    
+    while let Some(token) = tokens.get(i + span) {
+        if !is_phone_part(token.text) {
+            break;
+        }
+        
+        let token_digits: String = token.text.chars().filter(|c| c.is_ascii_digit()).collect();
+        if digits.len() + token_digits.len() > MAX_PHONE_DIGITS {
+            break;
+        }
+        
+        digits.push_str(&token_digits);
+        has_separator |= token.text.contains(['-', '(', ')', '.']);
+        span += 1;
+        
+        if token.ends_clause {
+            break;
+        }
+    }
+    
+    let international = tokens[i].text.starts_with('+');
+    if digits.len() < MIN_PHONE_DIGITS || !(international || has_separator || span > 1) {
+        return None;
+    }
+    
+    let name = tokens[i..i + span].iter().map(|t| t.text).collect::<Vec<&str>>().join(" ");
+    let normalized = if international { format!("+{}", digits) } else { digits };
+    Some((ExtractedEntity::new(EntityKind::Phone, &name, normalized), span))
+}
+
+// snake_case, camelCase, paths like Foo::bar and calls like run()
+fn match_code(token: &Token) -> Option<ExtractedEntity> {
+    let text = token.text;
+    if text.chars().count() > MAX_CODE_CHARS || !text.chars().any(|c| c.is_alphabetic()) {
+        return None;
+    }
+    
+    let identifier_chars = |s: &str| s.chars().all(|c| c.is_alphanumeric() || c == '_');
+    
+    let is_path = text.contains("::") && text.split("::").all(|part| !part.is_empty() && identifier_chars(part.trim_end_matches("()")));
+    let is_call = text.ends_with("()") && text.len() > 2 && identifier_chars(text.trim_end_matches("()").replace('.', "_").as_str());
+    let is_snake = text.contains('_')
+        && identifier_chars(text)
+        && !text.starts_with('_')
+        && !text.ends_with('_');
+    
+    // camelCase needs at least two lower-case letters before the first hump,
+    // so "iPhone" and "eBay" don't count
+    let leading_lower = text.chars().take_while(|c| c.is_lowercase()).count();
+    let is_camel = leading_lower >= 2
+        && text.chars().all(|c| c.is_alphanumeric())
+        && text.chars().skip(leading_lower).any(|c| c.is_uppercase());
+    
+    if is_path || is_call || is_snake || is_camel {
+        Some(ExtractedEntity::new(EntityKind::Code, text, text.to_lowercase()))
+    } else {
+        None
+    }
+}
+
+// People, organisations and locations from the word lists, longest phrase first
+fn match_gazetteers(tokens: &[Token], i: usize, gazetteer: &Gazetteer) -> Option<(ExtractedEntity, usize)> {
+    if !tokens[i].is_capitalized() {
+        return None;
+    }
+    
+    for len in (1..=MAX_PHRASE_WORDS).rev() {
+        let phrase = match phrase_at(tokens, i, len) {
+            Some(phrase) => phrase,
+            None => continue,
+        };
+        let name = tokens[i..i + len].iter().map(|t| t.word).collect::<Vec<&str>>().join(" ");
+        
+        if let Some((_, person)) = gazetteer.people.iter().find(|(words, _)| words.join(" ") == phrase) {
+            return Some((ExtractedEntity::new(EntityKind::Person, person, normalize_name(person)), len));
+        }
+        
+        if ORGANIZATIONS.contains(&phrase.as_str()) {
+            return Some((ExtractedEntity::new(EntityKind::Organization, &name, phrase), len));
+        }
+        
+        if LOCATIONS.contains(&phrase.as_str()) {
+            // Two-letter codes only count in capitals ("US", not "us")
+            if phrase.len() <= 3 && tokens[i].word.chars().any(|c| c.is_lowercase()) {
+                continue;
+            }
+            return Some((ExtractedEntity::new(EntityKind::Location, &name, phrase), len));
+        }
+    }
+    
+    match_organization_suffix(tokens, i)
+}
+
+// Capitalised words followed by an organisation suffix ("Acme Corp", "Stanford University")
+fn match_organization_suffix(tokens: &[Token], i: usize) -> Option<(ExtractedEntity, usize)> {
+    if COMMON_WORDS.contains(&tokens[i].lower.as_str()) {
+        return None;
+    }
+    
+    for len in 1..MAX_PHRASE_WORDS {
+        let words = tokens.get(i..i + len + 1)?;
+        if !words.iter().all(|t| t.is_capitalized()) || words[..len].iter().any(|t| t.ends_clause) {
+            return None;
+        }
+        
+        if ORGANIZATION_SUFFIXES.contains(&words[len].lower.as_str()) {
+            let name = words.iter().map(|t| t.word).collect::<Vec<&str>>().join(" ");
+            let normalized = normalize_name(&name);
+            return Some((ExtractedEntity::new(EntityKind::Organization, &name, normalized), len + 1));
+        }
+    }
+    
+    None
+}
+
+// Lowercased words i..i+len, unless punctuation breaks the phrase
+fn phrase_at(tokens: &[Token], i: usize, len: usize) -> Option<String> {
+    let words = tokens.get(i..i + len)?;
+    if words[..len - 1].iter().any(|t| t.ends_clause) || words.iter().any(|t| t.word.is_empty()) {
+        return None;
+    }
+    
+    Some(words.iter().map(|t| t.lower.as_str()).collect::<Vec<&str>>().join(" "))
+}
+
+// Extract a message's entities and add them to the entity index
+pub fn index_message(message: &Message) {
+    // Edits may change the entities, so start from a clean slate
+    remove_message(message);
+    
+    // People are matched against the conversation's participants
+    let mut people: Vec<User> = crate::storage::conversations::get_conversation(&message.conversation_id)
+        .map(|c| c.participants)
+        .unwrap_or_default();
+    people.push(message.sender.clone());
+    
+    let mut counts: HashMap<String, (EntityRecord, u32)> = HashMap::new();
+    for entity in extract(&message.content.text, &people) {
+        let key = entity.key();
+        counts.entry(key.clone())
+            .or_insert_with(|| (EntityRecord { key, name: entity.name, kind: entity.kind }, 0))
+            .1 += 1;
+    }
+    
+    let mut entities: Vec<(EntityRecord, u32)> = counts.into_values().collect();
+    entities.sort_by(|(a, _), (b, _)| a.key.cmp(&b.key));
+    entity_store::store_message_entities(&message.id, &message.conversation_id, entities);
+}
+
+// Remove a message from the entity index
+pub fn remove_message(message: &Message) {
+    entity_store::remove_message_entities(&message.id, &message.conversation_id);
+}
+
+// Entities in a query: ones the extractor recognises plus indexed entities
+// whose names appear in it
+pub fn query_entities(query: &str) -> Vec<EntityRecord> {
+    let mut found: Vec<EntityRecord> = Vec::new();
+    let mut seen = HashSet::new();
+    
+    for entity in extract(query, &[]) {
+        let key = entity.key();
+        if seen.insert(key.clone()) {
+            found.push(entity_store::get_entity(&key).unwrap_or(EntityRecord {
+                key,
+                name: entity.name,
+                kind: entity.kind,
+            }));
+        }
+    }
+    
+    let words: Vec<String> = query.split_whitespace()
+        .map(|w| w.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect();
+    
+    for len in (1..=MAX_PHRASE_WORDS.min(words.len())).rev() {
+        for window in words.windows(len) {
+            if len == 1 && COMMON_WORDS.contains(&window[0].as_str()) {
+                continue;
+            }
+            
+            let phrase = window.join(" ");
+            for kind in EntityKind::ALL {
+                let key = entity_key(kind, &phrase);
+                if seen.contains(&key) {
+                    continue;
+                }
+                if let Some(record) = entity_store::get_entity(&key) {
+                    seen.insert(key);
+                    found.push(record);
+                }
+            }
+        }
+    }
+    
+    found
+}
+
+// Aggregate the entities of a set of messages, with the entities that most
+// often appear alongside each one
+pub fn aggregate(messages: &[&Message]) -> Vec<EntityDetails> {
+    let mut details: HashMap<String, EntityDetails> = HashMap::new();
+    let mut co_occurrence: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let mut message_times: HashMap<String, Vec<(u64, String)>> = HashMap::new();
+    
+    for message in messages {
+        let entities = entity_store::get_message_entities(&message.id);
+        
+        for (key, mentions) in &entities {
+            let record = match entity_store::get_entity(key) {
+                Some(record) => record,
+                None => continue,
+            };
+            
+            let entry = details.entry(key.clone()).or_insert_with(|| EntityDetails {
+                key: key.clone(),
+                name: record.name,
+                kind: record.kind,
+                mentions: 0,
+                message_count: 0,
+                first_seen: message.timestamp,
+                last_seen: message.timestamp,
+                related: Vec::new(),
+                message_ids: Vec::new(),
+            });
+            entry.mentions += *mentions as u64;
+            entry.message_count += 1;
+            entry.first_seen = entry.first_seen.min(message.timestamp);
+            entry.last_seen = entry.last_seen.max(message.timestamp);
+            message_times.entry(key.clone()).or_default().push((message.timestamp, message.id.clone()));
+            
+            for (other, _) in &entities {
+                if other != key {
+                    *co_occurrence.entry(key.clone()).or_default().entry(other.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+    
+    let names: HashMap<String, String> = details.iter().map(|(key, d)| (key.clone(), d.name.clone())).collect();
+    
+    let mut result: Vec<EntityDetails> = details.into_values()
+        .map(|mut entity| {
+            let mut related: Vec<(String, u64)> = co_occurrence.remove(&entity.key)
+                .unwrap_or_default()
+                .into_iter()
+                .collect();
+            related.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+            entity.related = related.into_iter()
+                .filter_map(|(key, _)| names.get(&key).cloned())
+                .take(MAX_RELATED)
+                .collect();
+            
+            let mut times = message_times.remove(&entity.key).unwrap_or_default();
+            times.sort_by(|a, b| b.cmp(a));
+            entity.message_ids = times.into_iter().take(MAX_MESSAGE_IDS).map(|(_, id)| id).collect();
+            
+            entity
+        })
+        .collect();
+    
+    result.sort_by(|a, b| b.mentions.cmp(&a.mentions).then_with(|| a.key.cmp(&b.key)));
+    result
+}
+
+// Look up an entity by name among the messages in the given conversations
+pub fn lookup(name: &str, conversation_ids: &HashSet<String>) -> Vec<EntityDetails> {
+    let normalized = normalize_name(name);
+    let keys: Vec<String> = EntityKind::ALL.iter()
+        .map(|kind| entity_key(*kind, &normalized))
+        .filter(|key| entity_store::get_entity(key).is_some())
+        .collect();
+    
+    // Only mentions in the given conversations are loaded
+    let mut message_ids: Vec<String> = keys.iter()
+        .flat_map(|key| entity_store::get_entity_messages(key, conversation_ids))
+        .collect();
+    message_ids.sort();
+    message_ids.dedup();
+    
+    let messages: Vec<Message> = message_ids.iter()
+        .filter_map(|id| crate::storage::messages::get_message(id))
+        .collect();
+    let message_refs: Vec<&Message> = messages.iter().collect();
+    
+    aggregate(&message_refs).into_iter()
+        .filter(|entity| keys.contains(&entity.key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Platform;
+    
+    fn user(id: &str, name: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: name.to_string(),
+            platform: Platform::Slack,
+            avatar_url: None,
+        }
+    }
+    
+    fn found(text: &str, people: &[User]) -> Vec<(EntityKind, String)> {
+        extract(text, people).into_iter().map(|e| (e.kind, e.normalized)).collect()
+    }
+    
+    #[test]
+    fn extracts_urls_and_emails() {
+        assert_eq!(found("See https://example.com/docs/ or mail Bob@Example.com.", &[]), vec![
+            (EntityKind::Url, "https://example.com/docs".to_string()),
+            (EntityKind::Email, "bob@example.com".to_string()),
+        ]);
+    }
+    
+    #[test]
+    fn extracts_money() {
+        assert_eq!(found("It costs $1,200 or 300 dollars", &[]), vec![
+            (EntityKind::Money, "$1,200".to_string()),
+            (EntityKind::Money, "300 dollars".to_string()),
+        ]);
+    }
+    
+    #[test]
+    fn extracts_dates() {
+        assert_eq!(found("ship on March 3rd 2024, 2024-05-01 or Monday", &[]), vec![
+            (EntityKind::Date, "march 3rd 2024".to_string()),
+            (EntityKind::Date, "2024-05-01".to_string()),
+            (EntityKind::Date, "monday".to_string()),
+        ]);
+        // Lower-case month names are ordinary words
+        assert!(found("we may ship it", &[]).is_empty());
+    }
+    
+    #[test]
+    fn extracts_phone_numbers() {
+        assert_eq!(found("call +1 (555) 123-4567 today", &[]), vec![
+            (EntityKind::Phone, "+15551234567".to_string()),
+        ]);
+    }
+    
+    #[test]
+    fn extracts_code() {
+        assert_eq!(found("run `cargo build` then parse_message() in fooBar", &[]), vec![
+            (EntityKind::Code, "cargo build".to_string()),
+            (EntityKind::Code, "parse_message()".to_string()),
+            (EntityKind::Code, "foobar".to_string()),
+        ]);
+        // Brand names aren't camelCase
+        assert!(found("my iPhone", &[]).is_empty());
+    }
+    
+    #[test]
+    fn matches_known_people_by_name_first_name_and_handle() {
+        let people = [user("U1", "Alice Smith"), user("U2", "Bob Jones")];
+        assert_eq!(found("Alice Smith met Bob yesterday, cc @bob", &people), vec![
+            (EntityKind::Person, "alice smith".to_string()),
+            (EntityKind::Person, "bob jones".to_string()),
+            (EntityKind::Person, "bob jones".to_string()),
+        ]);
+    }
+    
+    #[test]
+    fn ambiguous_first_names_are_not_people() {
+        let people = [user("U1", "Alice Smith"), user("U2", "Alice Brown")];
+        assert!(found("Alice said hi", &people).is_empty());
+    }
+    
+    #[test]
+    fn extracts_organizations_and_locations() {
+        assert_eq!(found("Google opened an office in New York with Acme Corp", &[]), vec![
+            (EntityKind::Organization, "google".to_string()),
+            (EntityKind::Location, "new york".to_string()),
+            (EntityKind::Organization, "acme corp".to_string()),
+        ]);
+        // Country codes only count in capitals
+        assert_eq!(found("tell us about the US", &[]), vec![
+            (EntityKind::Location, "us".to_string()),
+        ]);
+    }
+    
+    #[test]
+    fn capitalisation_alone_is_not_an_entity() {
+        assert!(found("Yesterday Zorblax Quux arrived", &[]).is_empty());
+    }
+}
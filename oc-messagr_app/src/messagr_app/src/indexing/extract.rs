@@ -0,0 +1,466 @@
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::io::{Cursor, Read};
+
+// Larger attachments are indexed by name only
+pub const MAX_EXTRACT_INPUT_BYTES: u64 = 8 * 1024 * 1024;
+
+// Extracted text beyond this is dropped
+pub const MAX_EXTRACTED_CHARS: usize = 100_000;
+
+// Instructions one extraction may use between pages or parts. Parsing the
+// document and reading a single page can't be interrupted, so the caller
+// leaves headroom below the 40B message limit (see EXTRACTION_CALL_BUDGET).
+const EXTRACT_INSTRUCTION_BUDGET: u64 = 5_000_000_000;
+
+// No further extraction is started once a call has used this many
+// instructions, leaving the rest of the limit for the one in progress
+pub const EXTRACTION_CALL_BUDGET: u64 = 15_000_000_000;
+
+// Cap on a single decompressed OOXML part, against zip bombs
+const MAX_XML_PART_BYTES: u64 = 16 * 1024 * 1024;
+
+// PDF pages read before giving up on the rest
+const MAX_PDF_PAGES: u32 = 200;
+
+// Text pulled out of a document
+#[derive(Debug, Clone)]
+pub struct Extracted {
+    pub text: String,
+    // A size or instruction guard stopped extraction early
+    pub truncated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DocumentKind {
+    Pdf,
+    Docx,
+    Xlsx,
+    Pptx,
+    Html,
+    Json,
+    // Plain text, Markdown and CSV are indexed as written
+    Text,
+}
+
+// Extract searchable text from an attachment's content. Returns None for
+// types that hold no text (images, audio, archives...).
+pub fn extract_text(data: &[u8], mime_type: &str, name: Option<&str>) -> Option<Extracted> {
+    let kind = document_kind(mime_type, name)?;
+    if data.len() as u64 > MAX_EXTRACT_INPUT_BYTES {
+        return Some(Extracted { text: String::new(), truncated: true });
+    }
+    
+    let mut budget = Budget::start();
+    let text = match kind {
+        DocumentKind::Pdf => extract_pdf(data, &mut budget),
+        DocumentKind::Docx => extract_ooxml(data, &["word/document.xml"], None, &mut budget),
+        DocumentKind::Pptx => extract_ooxml(data, &[], Some("ppt/slides/slide"), &mut budget),
+        DocumentKind::Xlsx => extract_ooxml(data, &["xl/sharedStrings.xml"], Some("xl/worksheets/sheet"), &mut budget),
+        DocumentKind::Html => Some(strip_html(&String::from_utf8_lossy(data))),
+        DocumentKind::Json => Some(json_strings(data)),
+        DocumentKind::Text => Some(String::from_utf8_lossy(data).into_owned()),
+    }?;
+    
+    let (text, cut) = truncate_chars(normalize_whitespace(&text), MAX_EXTRACTED_CHARS);
+    Some(Extracted { text, truncated: cut || budget.exhausted })
+}
+
+// Whether an attachment is a document text can be extracted from
+pub fn has_text(mime_type: &str, name: Option<&str>) -> bool {
+    document_kind(mime_type, name).is_some()
+}
+
+// Decide how to read an attachment from its MIME type, or its file extension
+// when the platform only reports a generic type
+fn document_kind(mime_type: &str, name: Option<&str>) -> Option<DocumentKind> {
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    let by_mime = match mime_type.as_str() {
+        "application/pdf" => Some(DocumentKind::Pdf),
+        "application/vnd.openxmlformats-officedocument.wordprocessingml.document" => Some(DocumentKind::Docx),
+        "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet" => Some(DocumentKind::Xlsx),
+        "application/vnd.openxmlformats-officedocument.presentationml.presentation" => Some(DocumentKind::Pptx),
+        "text/html" | "application/xhtml+xml" => Some(DocumentKind::Html),
+        "application/json" => Some(DocumentKind::Json),
+        "text/plain" | "text/markdown" | "text/csv" | "text/tab-separated-values" => Some(DocumentKind::Text),
+        _ => None,
+    };
+    if by_mime.is_some() {
+        return by_mime;
+    }
+    
+    let extension = name?.rsplit_once('.')?.1.to_lowercase();
+    match extension.as_str() {
+        "pdf" => Some(DocumentKind::Pdf),
+        "docx" => Some(DocumentKind::Docx),
+        "xlsx" => Some(DocumentKind::Xlsx),
+        "pptx" => Some(DocumentKind::Pptx),
+        "html" | "htm" => Some(DocumentKind::Html),
+        "json" => Some(DocumentKind::Json),
+        "txt" | "md" | "markdown" | "csv" | "tsv" | "log" => Some(DocumentKind::Text),
+        _ if mime_type.starts_with("text/") => Some(DocumentKind::Text),
+        _ => None,
+    }
+}
+
+// Tracks instructions used by one extraction
+struct Budget {
+    start: u64,
+    exhausted: bool,
+}
+
+impl Budget {
+    fn start() -> Self {
+        Self { start: instruction_counter(), exhausted: false }
+    }
+    
+    // True once the extraction should stop
+    fn spent(&mut self) -> bool {
+        if instruction_counter().saturating_sub(self.start) > EXTRACT_INSTRUCTION_BUDGET {
+            self.exhausted = true;
+        }
+        self.exhausted
+    }
+}
+
+#[cfg(not(test))]
+fn instruction_counter() -> u64 {
+    ic_cdk::api::instruction_counter()
+}
+
+// Unit tests run outside a canister, where there is no counter
+#[cfg(test)]
+fn instruction_counter() -> u64 {
+    0
+}
+
+// Text of each page in order. Encrypted or malformed PDFs yield nothing.
+fn extract_pdf(data: &[u8], budget: &mut Budget) -> Option<String> {
+    let document = lopdf::Document::load_mem(data).ok()?;
+    if document.is_encrypted() {
+        return None;
+    }
+    
+    let mut text = String::new();
+    for (number, _) in document.get_pages() {
+        if number > MAX_PDF_PAGES || text.len() > MAX_EXTRACTED_CHARS || budget.spent() {
+            budget.exhausted = true;
+            break;
+        }
+        if let Ok(page_text) = document.extract_text(&[number]) {
+            text.push_str(&page_text);
+            text.push('\n');
+        }
+    }
+    Some(text)
+}
+
+// Text runs of the named parts of an Office Open XML package, followed by
+// every part numbered after `numbered_prefix` (slides, sheets) in order
+fn extract_ooxml(data: &[u8], parts: &[&str], numbered_prefix: Option<&str>, budget: &mut Budget) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data)).ok()?;
+    
+    let mut numbered: Vec<(u32, String)> = archive.file_names()
+        .filter_map(|name| {
+            let number = name.strip_prefix(numbered_prefix?)?.strip_suffix(".xml")?.parse().ok()?;
+            Some((number, name.to_string()))
+        })
+        .collect();
+    numbered.sort();
+    
+    let names = parts.iter()
+        .map(|name| name.to_string())
+        .chain(numbered.into_iter().map(|(_, name)| name));
+    
+    let mut text = String::new();
+    for name in names {
+        if text.len() > MAX_EXTRACTED_CHARS || budget.spent() {
+            budget.exhausted = true;
+            break;
+        }
+        
+        let part = match archive.by_name(&name) {
+            Ok(part) => part,
+            Err(_) => continue,
+        };
+        let mut xml = String::new();
+        if part.take(MAX_XML_PART_BYTES).read_to_string(&mut xml).is_err() {
+            continue;
+        }
+        xml_text(&xml, &mut text);
+    }
+    Some(text)
+}
+
+// Append the text runs (<w:t>, <a:t>, <t>) of an OOXML part, with a line
+// break after each paragraph, shared string or row
+fn xml_text(xml: &str, out: &mut String) {
+    let mut reader = Reader::from_str(xml);
+    let mut in_text = false;
+    
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                in_text = element.local_name().as_ref() == b"t";
+            },
+            Ok(Event::Text(text)) if in_text => {
+                if let Ok(text) = text.unescape() {
+                    out.push_str(&text);
+                }
+            },
+            Ok(Event::Empty(element)) => {
+                // Tabs and breaks inside a run
+                if matches!(element.local_name().as_ref(), b"tab" | b"br") {
+                    out.push(' ');
+                }
+            },
+            Ok(Event::End(element)) => {
+                in_text = false;
+                match element.local_name().as_ref() {
+                    b"p" | b"si" | b"row" => out.push('\n'),
+                    // Cells of a row stay on one line
+                    b"c" => out.push(' '),
+                    _ => {},
+                }
+            },
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {},
+        }
+    }
+}
+
+// Visible text of an HTML document, without scripts, styles or markup
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+    
+    while let Some(open) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..open]));
+        rest = &rest[open..];
+        
+        let close = match rest.find('>') {
+            Some(close) => close,
+            None => break,
+        };
+        let is_closing = rest[1..close].starts_with('/');
+        let tag = rest[1..close].trim_start_matches('/').to_lowercase();
+        let tag_name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("");
+        rest = &rest[close + 1..];
+        
+        // Skip the contents of non-visible elements
+        if !is_closing && (tag_name == "script" || tag_name == "style") {
+            let end_tag = format!("</{}", tag_name);
+            rest = match find_ignore_ascii_case(rest, &end_tag) {
+                Some(end) => &rest[end..],
+                None => "",
+            };
+            continue;
+        }
+        
+        // Block elements separate words
+        text.push(' ');
+    }
+    text.push_str(&decode_entities(rest));
+    text
+}
+
+fn find_ignore_ascii_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack.as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn decode_entities(text: &str) -> String {
+    if !text.contains('&') {
+        return text.to_string();
+    }
+    
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// String values (not keys) of a JSON document, one per line
+fn json_strings(data: &[u8]) -> String {
+    fn collect(value: &serde_json::Value, out: &mut String) {
+        match value {
+            serde_json::Value::String(s) => {
+                out.push_str(s);
+                out.push('\n');
+            },
+            serde_json::Value::Array(items) => items.iter().for_each(|item| collect(item, out)),
+            serde_json::Value::Object(map) => map.values().for_each(|item| collect(item, out)),
+            _ => {},
+        }
+    }
+    
+    match serde_json::from_slice::<serde_json::Value>(data) {
+        Ok(value) => {
+            let mut out = String::new();
+            collect(&value, &mut out);
+            out
+        },
+        // Not valid JSON, so index it as text
+        Err(_) => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+// Collapse runs of spaces and blank lines
+fn normalize_whitespace(text: &str) -> String {
+    text.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn truncate_chars(text: String, max_chars: usize) -> (String, bool) {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => (text[..end].to_string(), true),
+        None => (text, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use lopdf::content::{Content, Operation};
+    use lopdf::{dictionary, Document, Object, Stream};
+    
+    const DOCX: &str = "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+    
+    fn zip_of(parts: &[(&str, &str)]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in parts {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+    
+    // A PDF with one page of Helvetica text per entry
+    fn pdf_of(pages: &[&str]) -> Vec<u8> {
+        let mut doc = Document::with_version("1.5");
+        let pages_id = doc.new_object_id();
+        let font_id = doc.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Helvetica",
+        });
+        let resources_id = doc.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+        
+        let kids: Vec<Object> = pages.iter()
+            .map(|text| {
+                let content = Content {
+                    operations: vec![
+                        Operation::new("BT", vec![]),
+                        Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                        Operation::new("Td", vec![72.into(), 720.into()]),
+                        Operation::new("Tj", vec![Object::string_literal(*text)]),
+                        Operation::new("ET", vec![]),
+                    ],
+                };
+                let content_id = doc.add_object(Stream::new(dictionary! {}, content.encode().unwrap()));
+                doc.add_object(dictionary! {
+                    "Type" => "Page",
+                    "Parent" => pages_id,
+                    "Contents" => content_id,
+                }).into()
+            })
+            .collect();
+        
+        doc.objects.insert(pages_id, Object::Dictionary(dictionary! {
+            "Type" => "Pages",
+            "Count" => kids.len() as i64,
+            "Kids" => kids,
+            "Resources" => resources_id,
+            "MediaBox" => vec![0.into(), 0.into(), 612.into(), 792.into()],
+        }));
+        let catalog_id = doc.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        doc.trailer.set("Root", catalog_id);
+        
+        let mut data = Vec::new();
+        doc.save_to(&mut data).unwrap();
+        data
+    }
+    
+    fn text_of(data: &[u8], mime_type: &str, name: Option<&str>) -> Option<String> {
+        extract_text(data, mime_type, name).map(|extracted| extracted.text)
+    }
+    
+    #[test]
+    fn detects_documents_by_type_or_extension() {
+        assert_eq!(document_kind("application/pdf", None), Some(DocumentKind::Pdf));
+        assert_eq!(document_kind("text/HTML; charset=utf-8", None), Some(DocumentKind::Html));
+        assert_eq!(document_kind("application/octet-stream", Some("Report.DOCX")), Some(DocumentKind::Docx));
+        assert_eq!(document_kind("text/x-rust", Some("main.rs")), Some(DocumentKind::Text));
+        assert!(!has_text("image/png", Some("photo.png")));
+        assert!(!has_text("application/octet-stream", None));
+    }
+    
+    #[test]
+    fn reads_pdf_pages_in_order() {
+        let text = text_of(&pdf_of(&["First page", "Second page"]), "application/pdf", None).unwrap();
+        assert_eq!(text, "First page\nSecond page");
+    }
+    
+    #[test]
+    fn malformed_pdf_yields_nothing() {
+        assert_eq!(text_of(b"%PDF-1.5 not really", "application/pdf", None), None);
+    }
+    
+    #[test]
+    fn reads_docx_paragraphs() {
+        let document = r#"<w:document xmlns:w="w"><w:body>
+            <w:p><w:r><w:t>Quarterly</w:t></w:r><w:r><w:tab/><w:t>plan</w:t></w:r></w:p>
+            <w:p><w:r><w:t>R&amp;D budget</w:t></w:r></w:p>
+        </w:body></w:document>"#;
+        let data = zip_of(&[("word/document.xml", document), ("word/styles.xml", "<w:t>ignored</w:t>")]);
+        
+        assert_eq!(text_of(&data, DOCX, None).unwrap(), "Quarterly plan\nR&D budget");
+    }
+    
+    #[test]
+    fn reads_pptx_slides_in_numeric_order() {
+        let slide = |text: &str| format!("<p:sld><a:p><a:r><a:t>{}</a:t></a:r></a:p></p:sld>", text);
+        let (two, ten) = (slide("two"), slide("ten"));
+        let data = zip_of(&[("ppt/slides/slide10.xml", &ten), ("ppt/slides/slide2.xml", &two)]);
+        
+        assert_eq!(text_of(&data, "application/octet-stream", Some("deck.pptx")).unwrap(), "two\nten");
+    }
+    
+    #[test]
+    fn invalid_ooxml_yields_nothing() {
+        assert_eq!(text_of(b"not a zip", DOCX, None), None);
+    }
+    
+    #[test]
+    fn strips_html_markup_scripts_and_entities() {
+        let html = "<p>Fish &amp; chips</p><SCRIPT>alert(1)</script><style>p {}</style><b>tonight</b>";
+        assert_eq!(text_of(html.as_bytes(), "text/html", None).unwrap(), "Fish & chips tonight");
+    }
+    
+    #[test]
+    fn keeps_json_values_not_keys() {
+        let json = br#"{"title": ["Launch", 3, {"note": "alpha"}]}"#;
+        assert_eq!(text_of(json, "application/json", None).unwrap(), "Launch\nalpha");
+    }
+    
+    #[test]
+    fn truncates_long_text() {
+        let data = "a".repeat(MAX_EXTRACTED_CHARS + 10);
+        let extracted = extract_text(data.as_bytes(), "text/plain", None).unwrap();
+        assert!(extracted.truncated);
+        assert_eq!(extracted.text.chars().count(), MAX_EXTRACTED_CHARS);
+    }
+}
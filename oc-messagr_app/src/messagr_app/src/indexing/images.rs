@@ -0,0 +1,395 @@
+use crate::storage::{blobs, media};
+use crate::storage::media::ImageInfo;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GenericImageView};
+use std::io::Cursor;
+
+// Larger images get no thumbnail, though their location data is still removed
+const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+// Decoding cost grows with pixels, not file size
+const MAX_IMAGE_PIXELS: u64 = 24_000_000;
+
+// Longest side of a thumbnail
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+const THUMBNAIL_QUALITY: u8 = 75;
+
+// No further image is started once a call has used this many instructions
+const PROCESS_INSTRUCTION_BUDGET: u64 = 15_000_000_000;
+
+// Instructions an update may use
+const MESSAGE_INSTRUCTION_LIMIT: u64 = 40_000_000_000;
+
+// Rough cost of decoding and downscaling one pixel, so an image is only
+// decoded when the call has room left for it
+const DECODE_INSTRUCTIONS_PER_PIXEL: u64 = 600;
+
+// Cost of everything after decoding (encoding the thumbnail, the clean copy)
+const DECODE_RESERVE: u64 = 2_000_000_000;
+
+// Process up to `limit` of the queued image blobs a user holds: dimensions,
+// EXIF capture time and camera, a JPEG thumbnail, and a copy without location
+// metadata to serve in place of the original. The derived blobs are charged to
+// that user. Images too large to decode in what is left of the call stay
+// queued. Returns the number processed.
+pub fn process_pending(owner: &str, limit: usize) -> u64 {
+    let mut processed = 0;
+    
+    for hash in media::pending_for(owner, limit) {
+        if ic_cdk::api::instruction_counter() > PROCESS_INSTRUCTION_BUDGET {
+            break;
+        }
+        
+        match process_image(&hash, owner) {
+            Some(info) => media::store_image(info),
+            None => break,
+        }
+        media::remove_pending(&hash);
+        processed += 1;
+    }
+    
+    processed
+}
+
+// None when the image can't be decoded within the call's remaining budget
+fn process_image(hash: &str, owner: &str) -> Option<ImageInfo> {
+    let mut info = ImageInfo {
+        hash: hash.to_string(),
+        width: 0,
+        height: 0,
+        taken_at: None,
+        camera: None,
+        thumbnail_hash: None,
+        had_location: false,
+        error: None,
+        processed_at: ic_cdk::api::time(),
+    };
+    
+    let blob = match blobs::get_blob_info(hash) {
+        Some(blob) => blob,
+        None => {
+            info.error = Some("Blob not found".to_string());
+            return Some(info);
+        },
+    };
+    let data = match blobs::get_blob_data(hash) {
+        Some(data) => data,
+        None => {
+            info.error = Some("Blob is missing chunks".to_string());
+            return Some(info);
+        },
+    };
+    
+    let exif = read_exif(&data);
+    let orientation = exif.as_ref().map(|e| e.orientation).unwrap_or(1);
+    if let Some(exif) = &exif {
+        info.taken_at = exif.taken_at;
+        info.camera = exif.camera.clone();
+        info.had_location = exif.has_gps;
+    }
+    // XMP packets can repeat the location outside EXIF
+    info.had_location |= xmp_has_location(&data);
+    
+    // Read the header first so oversized images are never decoded
+    let reader = match image::io::Reader::new(Cursor::new(&data)).with_guessed_format() {
+        Ok(reader) => reader,
+        Err(e) => {
+            info.error = Some(format!("Unreadable image: {}", e));
+            return Some(withhold_location(info, &data, &blob.mime_type, owner));
+        },
+    };
+    match reader.into_dimensions() {
+        Ok((width, height)) => {
+            info.width = width;
+            info.height = height;
+        },
+        Err(e) => {
+            info.error = Some(format!("Unsupported image: {}", e));
+            return Some(withhold_location(info, &data, &blob.mime_type, owner));
+        },
+    }
+    // Quarter turns swap the displayed dimensions
+    if orientation >= 5 {
+        std::mem::swap(&mut info.width, &mut info.height);
+    }
+    
+    if blob.size > MAX_IMAGE_BYTES {
+        info.error = Some(format!("Image is over {} bytes", MAX_IMAGE_BYTES));
+        return Some(withhold_location(info, &data, &blob.mime_type, owner));
+    }
+    if info.width as u64 * info.height as u64 > MAX_IMAGE_PIXELS {
+        info.error = Some(format!("Image is over {} pixels", MAX_IMAGE_PIXELS));
+        return Some(withhold_location(info, &data, &blob.mime_type, owner));
+    }
+    
+    // Decoding can't be interrupted, so it waits for a call with room for it
+    let decode_cost = info.width as u64 * info.height as u64 * DECODE_INSTRUCTIONS_PER_PIXEL + DECODE_RESERVE;
+    if ic_cdk::api::instruction_counter() + decode_cost > MESSAGE_INSTRUCTION_LIMIT {
+        return None;
+    }
+    
+    match image::load_from_memory(&data) {
+        Ok(image) => {
+            let thumbnail = orient(image, orientation).thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+            match encode_jpeg(&thumbnail) {
+                Some(jpeg) => info.thumbnail_hash = Some(blobs::store_derived_blob(&jpeg, "image/jpeg", owner)),
+                None => info.error = Some("Failed to encode thumbnail".to_string()),
+            }
+        },
+        Err(e) => info.error = Some(format!("Failed to decode image: {}", e)),
+    }
+    
+    Some(withhold_location(info, &data, &blob.mime_type, owner))
+}
+
+// Serve a copy without EXIF and XMP when the original has location data.
+// Formats whose metadata can't be removed losslessly are served as their thumbnail instead,
+// or not at all.
+fn withhold_location(info: ImageInfo, data: &[u8], mime_type: &str, owner: &str) -> ImageInfo {
+    if !info.had_location {
+        return info;
+    }
+    
+    match strip_metadata(data) {
+        Some(clean) => {
+            let clean_hash = blobs::store_derived_blob(&clean, mime_type, owner);
+            blobs::serve_as(&info.hash, &clean_hash);
+        },
+        None => {
+            let replacement = info.thumbnail_hash.clone()
+                .unwrap_or_else(|| blobs::store_derived_blob(&[], "application/octet-stream", owner));
+            blobs::serve_as(&info.hash, &replacement);
+        },
+    }
+    
+    info
+}
+
+struct ExifSummary {
+    taken_at: Option<u64>,
+    camera: Option<String>,
+    orientation: u32,
+    has_gps: bool,
+}
+
+fn read_exif(data: &[u8]) -> Option<ExifSummary> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(data))
+        .ok()?;
+    
+    let ascii = |tag: exif::Tag| {
+        exif.get_field(tag, exif::In::PRIMARY).and_then(|field| match &field.value {
+            exif::Value::Ascii(values) => values.first()
+                .map(|v| String::from_utf8_lossy(v).trim().to_string())
+                .filter(|v| !v.is_empty()),
+            _ => None,
+        })
+    };
+    
+    let taken_at = ascii(exif::Tag::DateTimeOriginal)
+        .or_else(|| ascii(exif::Tag::DateTime))
+        .and_then(|value| exif::DateTime::from_ascii(value.as_bytes()).ok())
+        .and_then(|time| {
+            let date = chrono::NaiveDate::from_ymd_opt(time.year as i32, time.month as u32, time.day as u32)?;
+            let local = date.and_hms_opt(time.hour as u32, time.minute as u32, time.second as u32)?;
+            // EXIF offsets are in minutes east of UTC
+            let utc = local - chrono::Duration::minutes(time.offset.unwrap_or(0) as i64);
+            u64::try_from(utc.timestamp_millis()).ok()
+        });
+    
+    // "Canon Canon EOS R6" reads better as "Canon EOS R6"
+    let camera = match (ascii(exif::Tag::Make), ascii(exif::Tag::Model)) {
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => make.or(model),
+    };
+    
+    let orientation = exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .unwrap_or(1);
+    
+    let has_gps = exif.fields().any(|field| field.tag.context() == exif::Context::Gps);
+    
+    Some(ExifSummary { taken_at, camera, orientation, has_gps })
+}
+
+// Apply the EXIF orientation so thumbnails display upright
+fn orient(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Option<Vec<u8>> {
+    let mut jpeg = Vec::new();
+    let rgb = image.to_rgb8();
+    let (width, height) = image.dimensions();
+    JpegEncoder::new_with_quality(&mut jpeg, THUMBNAIL_QUALITY)
+        .encode(&rgb, width, height, image::ColorType::Rgb8)
+        .ok()?;
+    Some(jpeg)
+}
+
+// Namespaces of XMP packets: JPEG APP1 segments start with one, and PNG
+// iTXt chunks carry them under the keyword
+const XMP_JPEG_HEADERS: [&[u8]; 2] = [b"http://ns.adobe.com/xap/1.0/\0", b"http://ns.adobe.com/xmp/extension/\0"];
+const XMP_PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp\0";
+
+// XMP property of a GPS position, as written in the packet's text
+const XMP_GPS_PROPERTY: &[u8] = b"exif:GPSLatitude";
+
+// Whether an XMP packet in the file holds GPS coordinates
+fn xmp_has_location(data: &[u8]) -> bool {
+    data.windows(XMP_GPS_PROPERTY.len()).any(|window| window == XMP_GPS_PROPERTY)
+}
+
+// Remove the EXIF and XMP metadata from a JPEG (APP1 segments) or PNG (eXIf
+// and XMP iTXt chunks) without re-encoding. Other formats return None.
+fn strip_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        strip_jpeg_metadata(data)
+    } else if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        strip_png_metadata(data)
+    } else {
+        None
+    }
+}
+
+fn strip_jpeg_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut pos = 2;
+    
+    loop {
+        if data.get(pos)? != &0xFF {
+            return None;
+        }
+        let marker = *data.get(pos + 1)?;
+        
+        // Start of scan: the rest is image data
+        if marker == 0xDA || marker == 0xD9 {
+            out.extend_from_slice(&data[pos..]);
+            return Some(out);
+        }
+        
+        let length = u16::from_be_bytes([*data.get(pos + 2)?, *data.get(pos + 3)?]) as usize;
+        let end = pos + 2 + length;
+        let segment = data.get(pos..end)?;
+        
+        let payload = segment.get(4..).unwrap_or_default();
+        let is_metadata = marker == 0xE1 && (payload.starts_with(b"Exif\0\0")
+            || XMP_JPEG_HEADERS.iter().any(|header| payload.starts_with(header)));
+        if !is_metadata {
+            out.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+fn strip_png_metadata(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..8]);
+    let mut pos = 8;
+    
+    while pos < data.len() {
+        let length = u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        // Length, type, data and CRC
+        let end = pos + 12 + length;
+        let chunk = data.get(pos..end)?;
+        
+        let is_xmp = &chunk[4..8] == b"iTXt" && chunk[8..].starts_with(XMP_PNG_KEYWORD);
+        if &chunk[4..8] != b"eXIf" && !is_xmp {
+            out.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+    
+    // CRCs aren't checked when stripping, so they are left zero
+    fn png_chunk(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let mut chunk = (payload.len() as u32).to_be_bytes().to_vec();
+        chunk.extend_from_slice(kind);
+        chunk.extend_from_slice(payload);
+        chunk.extend_from_slice(&[0; 4]);
+        chunk
+    }
+    
+    fn xmp_packet() -> Vec<u8> {
+        b"<x:xmpmeta><rdf:Description exif:GPSLatitude=\"52,31.2N\"/></x:xmpmeta>".to_vec()
+    }
+    
+    #[test]
+    fn strips_exif_and_xmp_from_jpeg() {
+        let jfif = jpeg_segment(0xE0, b"JFIF\0\x01\x02");
+        let exif = jpeg_segment(0xE1, b"Exif\0\0MM\0*");
+        let xmp = jpeg_segment(0xE1, &[XMP_JPEG_HEADERS[0], &xmp_packet()[..]].concat());
+        let other_app1 = jpeg_segment(0xE1, b"urn:example\0data");
+        let quantization = jpeg_segment(0xDB, &[0; 5]);
+        let scan = [jpeg_segment(0xDA, &[1, 2, 3]), vec![0x12, 0x34, 0xFF, 0xD9]].concat();
+        
+        let data = [vec![0xFF, 0xD8], jfif.clone(), exif, xmp, other_app1.clone(), quantization.clone(), scan.clone()].concat();
+        let expected = [vec![0xFF, 0xD8], jfif, other_app1, quantization, scan].concat();
+        
+        assert_eq!(strip_metadata(&data), Some(expected));
+    }
+    
+    #[test]
+    fn rejects_truncated_jpeg() {
+        let data = [vec![0xFF, 0xD8], jpeg_segment(0xE1, b"Exif\0\0MM")[..6].to_vec()].concat();
+        assert_eq!(strip_metadata(&data), None);
+    }
+    
+    #[test]
+    fn strips_exif_and_xmp_from_png() {
+        let signature = b"\x89PNG\r\n\x1a\n".to_vec();
+        let header = png_chunk(b"IHDR", &[0; 13]);
+        let text = png_chunk(b"tEXt", b"Title\0Beach");
+        let end = png_chunk(b"IEND", &[]);
+        
+        let data = [
+            signature.clone(),
+            header.clone(),
+            png_chunk(b"eXIf", b"MM\0*"),
+            png_chunk(b"iTXt", &[XMP_PNG_KEYWORD, &xmp_packet()[..]].concat()),
+            text.clone(),
+            end.clone(),
+        ].concat();
+        
+        assert_eq!(strip_metadata(&data), Some([signature, header, text, end].concat()));
+    }
+    
+    #[test]
+    fn leaves_other_formats_alone() {
+        assert_eq!(strip_metadata(b"GIF89a\x01\x00\x01\x00"), None);
+    }
+    
+    #[test]
+    fn finds_location_in_xmp() {
+        let data = [vec![0xFF, 0xD8], jpeg_segment(0xE1, &[XMP_JPEG_HEADERS[0], &xmp_packet()[..]].concat())].concat();
+        assert!(xmp_has_location(&data));
+        
+        let without = b"<x:xmpmeta><rdf:Description exif:DateTimeOriginal=\"2024\"/></x:xmpmeta>";
+        assert!(!xmp_has_location(without));
+    }
+}
@@ -0,0 +1,235 @@
+use tantivy::tokenizer::{BoxTokenStream, Token, TokenStream, Tokenizer};
+use whatlang::Lang;
+use super::schema::{FIELD_CONTENT, FIELD_CONTENT_CJK, FIELD_CONTENT_DE, FIELD_CONTENT_ES};
+
+// Minimum detector confidence before we trust a non-English result
+const MIN_DETECTION_CONFIDENCE: f64 = 0.5;
+
+// Share of CJK characters above which text is treated as CJK
+const CJK_RATIO_THRESHOLD: f32 = 0.3;
+
+// Languages with a dedicated analyzer in the text index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageLanguage {
+    English,
+    Spanish,
+    German,
+    Japanese,
+    Chinese,
+    Korean,
+    Other,
+}
+
+impl MessageLanguage {
+    // ISO 639-1 code stored in the language field
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageLanguage::English => "en",
+            MessageLanguage::Spanish => "es",
+            MessageLanguage::German => "de",
+            MessageLanguage::Japanese => "ja",
+            MessageLanguage::Chinese => "zh",
+            MessageLanguage::Korean => "ko",
+            MessageLanguage::Other => "other",
+        }
+    }
+    
+    // Language-specific content field, if the language has its own analyzer
+    pub fn content_field(&self) -> Option<&'static str> {
+        match self {
+            MessageLanguage::Spanish => Some(FIELD_CONTENT_ES),
+            MessageLanguage::German => Some(FIELD_CONTENT_DE),
+            MessageLanguage::Japanese | MessageLanguage::Chinese | MessageLanguage::Korean => Some(FIELD_CONTENT_CJK),
+            // English (and anything we can't analyze better) uses the main content field
+            MessageLanguage::English | MessageLanguage::Other => None,
+        }
+    }
+    
+    // Field to search with the highest boost for this language
+    pub fn primary_field(&self) -> &'static str {
+        self.content_field().unwrap_or(FIELD_CONTENT)
+    }
+    
+    pub fn is_cjk(&self) -> bool {
+        matches!(self, MessageLanguage::Japanese | MessageLanguage::Chinese | MessageLanguage::Korean)
+    }
+}
+
+// Detect the language of a message
+pub fn detect_language(text: &str) -> MessageLanguage {
+    // Script detection is more reliable than n-gram statistics for CJK
+    if let Some(language) = detect_cjk_language(text) {
+        return language;
+    }
+    
+    match whatlang::detect(text) {
+        Some(info) if info.confidence() >= MIN_DETECTION_CONFIDENCE => match info.lang() {
+            Lang::Eng => MessageLanguage::English,
+            Lang::Spa => MessageLanguage::Spanish,
+            Lang::Deu => MessageLanguage::German,
+            Lang::Jpn => MessageLanguage::Japanese,
+            Lang::Cmn => MessageLanguage::Chinese,
+            Lang::Kor => MessageLanguage::Korean,
+            _ => MessageLanguage::Other,
+        },
+        // Short or ambiguous messages fall back to English, our most common language
+        _ => MessageLanguage::English,
+    }
+}
+
+// Detect CJK languages from the scripts used in the text
+fn detect_cjk_language(text: &str) -> Option<MessageLanguage> {
+    let mut letters = 0;
+    let mut kana = 0;
+    let mut hangul = 0;
+    let mut han = 0;
+    
+    for c in text.chars().filter(|c| c.is_alphanumeric()) {
+        letters += 1;
+        if is_kana(c) {
+            kana += 1;
+        } else if is_hangul(c) {
+            hangul += 1;
+        } else if is_han(c) {
+            han += 1;
+        }
+    }
+    
+    if letters == 0 || ((kana + hangul + han) as f32 / letters as f32) < CJK_RATIO_THRESHOLD {
+        return None;
+    }
+    
+    // Kana only appears in Japanese; Han alone is most likely Chinese
+    if kana > 0 {
+        Some(MessageLanguage::Japanese)
+    } else if hangul >= han {
+        Some(MessageLanguage::Korean)
+    } else {
+        Some(MessageLanguage::Chinese)
+    }
+}
+
+// Check whether text contains any CJK characters
+pub fn contains_cjk(text: &str) -> bool {
+    text.chars().any(is_cjk_char)
+}
+
+fn is_cjk_char(c: char) -> bool {
+    is_han(c) || is_kana(c) || is_hangul(c)
+}
+
+fn is_han(c: char) -> bool {
+    matches!(c as u32,
+        0x4E00..=0x9FFF     // CJK Unified Ideographs
+        | 0x3400..=0x4DBF   // Extension A
+        | 0x20000..=0x2A6DF // Extension B
+        | 0xF900..=0xFAFF   // Compatibility Ideographs
+    )
+}
+
+fn is_kana(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x309F     // Hiragana
+        | 0x30A0..=0x30FF   // Katakana
+        | 0x31F0..=0x31FF   // Katakana extensions
+        | 0xFF66..=0xFF9F   // Half-width Katakana
+    )
+}
+
+fn is_hangul(c: char) -> bool {
+    matches!(c as u32,
+        0xAC00..=0xD7AF     // Hangul syllables
+        | 0x1100..=0x11FF   // Hangul Jamo
+        | 0x3130..=0x318F   // Compatibility Jamo
+    )
+}
+
+// Tokenizer emitting overlapping bigrams for runs of CJK characters and
+// whole words for everything else. CJK text has no spaces between words,
+// so bigrams give reasonable recall without a dictionary-based segmenter.
+#[derive(Clone)]
+pub struct CjkBigramTokenizer;
+
+pub struct CjkBigramTokenStream {
+    tokens: Vec<Token>,
+    cursor: usize,
+}
+
+impl Tokenizer for CjkBigramTokenizer {
+    fn token_stream<'a>(&self, text: &'a str) -> BoxTokenStream<'a> {
+        let mut tokens = Vec::new();
+        let mut cjk_run: Vec<(usize, char)> = Vec::new();
+        let mut word_start: Option<usize> = None;
+        
+        for (offset, c) in text.char_indices() {
+            if is_cjk_char(c) {
+                if let Some(start) = word_start.take() {
+                    push_token(&mut tokens, text, start, offset);
+                }
+                cjk_run.push((offset, c));
+            } else {
+                flush_cjk_run(&mut tokens, text, &mut cjk_run);
+                
+                if c.is_alphanumeric() {
+                    if word_start.is_none() {
+                        word_start = Some(offset);
+                    }
+                } else if let Some(start) = word_start.take() {
+                    push_token(&mut tokens, text, start, offset);
+                }
+            }
+        }
+        
+        flush_cjk_run(&mut tokens, text, &mut cjk_run);
+        if let Some(start) = word_start {
+            push_token(&mut tokens, text, start, text.len());
+        }
+        
+        BoxTokenStream::from(CjkBigramTokenStream { tokens, cursor: 0 })
+    }
+}
+
+// Emit bigrams (or a single unigram for one-character runs) for a CJK run
+fn flush_cjk_run(tokens: &mut Vec<Token>, text: &str, run: &mut Vec<(usize, char)>) {
+    if run.len() == 1 {
+        let (start, c) = run[0];
+        push_token(tokens, text, start, start + c.len_utf8());
+    } else {
+        for pair in run.windows(2) {
+            let (start, _) = pair[0];
+            let (second_start, second) = pair[1];
+            push_token(tokens, text, start, second_start + second.len_utf8());
+        }
+    }
+    run.clear();
+}
+
+fn push_token(tokens: &mut Vec<Token>, text: &str, start: usize, end: usize) {
+    let position = tokens.len();
+    tokens.push(Token {
+        offset_from: start,
+        offset_to: end,
+        position,
+        text: text[start..end].to_string(),
+        position_length: 1,
+    });
+}
+
+impl TokenStream for CjkBigramTokenStream {
+    fn advance(&mut self) -> bool {
+        if self.cursor < self.tokens.len() {
+            self.cursor += 1;
+            true
+        } else {
+            false
+        }
+    }
+    
+    fn token(&self) -> &Token {
+        &self.tokens[self.cursor - 1]
+    }
+    
+    fn token_mut(&mut self) -> &mut Token {
+        &mut self.tokens[self.cursor - 1]
+    }
+}
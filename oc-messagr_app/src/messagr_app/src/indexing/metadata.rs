@@ -1,13 +1,14 @@
 use crate::{Message, Error, Result};
 use tantivy::{Index, IndexWriter, Document, Term, query::QueryParser, collector::TopDocs};
 use tantivy::query::{Query, BooleanQuery, Occur, TermQuery, RangeQuery};
-use tantivy::schema::{Field, Value};
+use tantivy::schema::{Field, IndexRecordOption, Value};
 use tantivy::directory::MmapDirectory;
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
 use std::fs;
 use std::sync::atomic::{AtomicBool, Ordering};
-use super::schema::{create_metadata_schema, platform_to_string, FIELD_ID, FIELD_CONVERSATION_ID, FIELD_PLATFORM, FIELD_TIMESTAMP};
+use super::schema::{create_metadata_schema, platform_to_string, FIELD_ID, FIELD_CONVERSATION_ID, FIELD_PLATFORM, FIELD_TIMESTAMP, FIELD_SENTIMENT, FIELD_SENTIMENT_LABEL};
+use super::sentiment::{self, SentimentLabel};
 use super::search::SearchFilters;
 
 // In-memory buffer size
@@ -91,6 +92,11 @@ impl MetadataIndexer {
         // Is edited flag
         doc.add_bool(self.fields["is_edited"], message.edited);
         
+        // Sentiment score and label
+        let scores = sentiment::analyze_message(message);
+        doc.add_f64(self.fields[FIELD_SENTIMENT], scores.compound as f64);
+        doc.add_text(self.fields[FIELD_SENTIMENT_LABEL], scores.label().as_str());
+        
         // Add the document to the index
         self.writer.add_document(doc).map_err(|e| Error::InternalError(format!("Failed to index message metadata: {}", e)))?;
         
@@ -177,6 +183,11 @@ impl MetadataIndexer {
             clauses.push((Occur::Must, Box::new(TermQuery::new(conv_term, 1.0))));
         }
         
+        // Only the caller's conversations
+        if let Some(scope) = filters.conversation_scope(self.fields[FIELD_CONVERSATION_ID]) {
+            clauses.push((Occur::Must, scope));
+        }
+        
         // Sender ID filter
         if let Some(sender_id) = &filters.sender_id {
             let sender_term = Term::from_field_text(
@@ -204,6 +215,15 @@ impl MetadataIndexer {
             clauses.push((Occur::Must, Box::new(TermQuery::new(edited_term, 1.0))));
         }
         
+        // Sentiment filter
+        if let Some(label) = &filters.sentiment {
+            let sentiment_term = Term::from_field_text(
+                self.fields[FIELD_SENTIMENT_LABEL],
+                label.as_str()
+            );
+            clauses.push((Occur::Must, Box::new(TermQuery::new(sentiment_term, 1.0))));
+        }
+        
         // Create the final query
         let boolean_query = if clauses.is_empty() {
             // Match all documents if no filters are specified
@@ -240,6 +260,40 @@ impl MetadataIndexer {
         
         Ok(results)
     }
+    
+    // Sentiment labels stored when the messages were indexed; messages not in
+    // the index are left out
+    pub fn sentiment_labels<'a, I>(&self, message_ids: I) -> Result<HashMap<String, SentimentLabel>>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        let searcher = self.index.reader()
+            .map_err(|e| Error::InternalError(format!("Failed to get metadata index reader: {}", e)))?
+            .searcher();
+        
+        let mut labels = HashMap::new();
+        for message_id in message_ids {
+            let query = TermQuery::new(
+                Term::from_field_text(self.fields[FIELD_ID], message_id),
+                IndexRecordOption::Basic,
+            );
+            let top_docs = searcher.search(&query, &TopDocs::with_limit(1))
+                .map_err(|e| Error::InternalError(format!("Failed to look up message {}: {}", message_id, e)))?;
+            
+            if let Some((_, doc_address)) = top_docs.into_iter().next() {
+                let doc = searcher.doc(doc_address)
+                    .map_err(|e| Error::InternalError(format!("Failed to retrieve metadata document: {}", e)))?;
+                let label = doc.get_first(self.fields[FIELD_SENTIMENT_LABEL])
+                    .and_then(|value| value.as_text())
+                    .and_then(SentimentLabel::parse);
+                if let Some(label) = label {
+                    labels.insert(message_id.clone(), label);
+                }
+            }
+        }
+        
+        Ok(labels)
+    }
 }
 
 // For testing
@@ -1,8 +1,15 @@
 pub mod schema;
 pub mod search;
 pub mod text;
+pub mod language;
+pub mod dedup;
+pub mod ranking;
 pub mod metadata;
 pub mod attachments;
+pub mod extract;
+pub mod images;
+pub mod sentiment;
+pub mod entities;
 
 use crate::{Message, Conversation, Error, Result, Platform};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
@@ -13,6 +20,9 @@ use std::collections::{HashSet, HashMap};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// Weight of a match in attachment text relative to one in the message itself
+const ATTACHMENT_TEXT_WEIGHT: f32 = 0.8;
+
 // IndexManager is responsible for coordinating all indexing operations
 pub struct IndexManager {
     text_indexer: text::TextIndexer,
@@ -28,7 +38,7 @@ impl IndexManager {
             attachment_indexer: attachments::AttachmentIndexer::new(),
         }
     }
-
+    
     // Index a new message
     pub fn index_message(&mut self, message: &Message) -> Result<()> {
         // Index text content
@@ -37,10 +47,15 @@ impl IndexManager {
         // Index metadata (sender, timestamp, platform, etc.)
         self.metadata_indexer.index_message(message)?;
         
-        // Index attachments
-        if !message.content.attachments.is_empty() {
-            self.attachment_indexer.index_message(message)?;
-        }
+        // Index attachments, extracting text from stored documents. Runs
+        // for every message so an edit that drops attachments clears them.
+        self.attachment_indexer.index_message(message)?;
+        
+        // Fingerprint for near-duplicate detection
+        dedup::index_message(message);
+        
+        // Named entities for entity lookup and co-occurrence
+        entities::index_message(message);
         
         Ok(())
     }
@@ -61,60 +76,144 @@ impl IndexManager {
     }
     
     // Search across all indices
-    pub fn search(&self, query: &str, filters: &search::SearchFilters, limit: usize) -> Result<Vec<String>> {
+    pub fn search(&self, query: &str, filters: &search::SearchFilters, limit: usize, ranking: &ranking::RankingContext) -> Result<Vec<String>> {
+        let hits = self.search_hits(query, filters, limit, ranking)?;
+        Ok(hits.into_iter().map(|hit| hit.message_id).collect())
+    }
+    
+    // Search across all indices, keeping scores and highlighted fragments
+    pub fn search_hits(&self, query: &str, filters: &search::SearchFilters, limit: usize, ranking: &ranking::RankingContext) -> Result<Vec<search::SearchHit>> {
         // Collect results from each indexer
-        let text_results = self.text_indexer.search(query, limit * 2)?;
+        let mut text_results = self.text_indexer.search(query, filters, limit * 2)?;
         let metadata_results = self.metadata_indexer.filter(&filters, limit * 2)?;
-        let attachment_results = if filters.has_attachments {
+        // Attachment names and contents are searched whenever there is query text
+        let mut attachment_results = if filters.has_attachments || !query.trim().is_empty() {
             self.attachment_indexer.search(query, filters, limit * 2)?
         } else {
-            HashSet::new()
+            HashMap::new()
         };
         
         // Combine and rank results
-        let combined_results = self.rank_results(text_results, metadata_results, attachment_results, query);
+        let text_scores: HashMap<String, f32> = text_results.iter()
+            .map(|(id, text_match)| (id.clone(), text_match.score))
+            .collect();
+        let attachment_scores: HashMap<String, f32> = attachment_results.iter()
+            .map(|(id, attachment_match)| (id.clone(), attachment_match.score))
+            .collect();
+        let combined_results = self.rank_results(text_scores, metadata_results, attachment_scores, query, filters, ranking)?;
         
-        // Apply limit
-        let limited_results: Vec<String> = combined_results
+        // Apply limit and attach the text and attachment matches for each hit
+        let hits = combined_results
             .into_iter()
             .take(limit)
+            .map(|(message_id, breakdown)| {
+                let text_match = text_results.remove(&message_id);
+                let attachment_match = attachment_results.remove(&message_id);
+                search::SearchHit {
+                    message_id,
+                    score: breakdown.total,
+                    text_match,
+                    attachment_match,
+                    breakdown,
+                }
+            })
             .collect();
         
-        Ok(limited_results)
+        Ok(hits)
     }
     
-    // Rank and combine results from different indices
+    // Suggest a corrected query when nothing matched the text index
+    pub fn suggest(&self, query: &str) -> Result<Option<String>> {
+        self.text_indexer.suggest(query)
+    }
+    
+    // Rank and combine results from different indices using BM25, recency,
+    // engagement, conversation affinity and exact-phrase signals
     fn rank_results(
         &self, 
         text_results: HashMap<String, f32>, 
         metadata_results: HashSet<String>,
-        attachment_results: HashSet<String>,
-        query: &str
-    ) -> Vec<String> {
-        // Create a score map for all results
-        let mut scores: HashMap<String, f32> = HashMap::new();
-        
-        // Add text scores (already normalized 0.0-1.0)
-        for (id, score) in text_results {
-            scores.insert(id, score);
-        }
+        attachment_results: HashMap<String, f32>,
+        query: &str,
+        filters: &search::SearchFilters,
+        ranking: &ranking::RankingContext
+    ) -> Result<Vec<(String, ranking::ScoreBreakdown)>> {
+        // Every message that matched any index is a candidate
+        let mut candidates: HashSet<String> = text_results.keys().cloned().collect();
+        candidates.extend(metadata_results.iter().cloned());
+        candidates.extend(attachment_results.keys().cloned());
         
-        // Add metadata bonus (0.2)
-        for id in metadata_results {
-            *scores.entry(id).or_insert(0.0) += 0.2;
+        // A topic filter restricts candidates to the topic's members, and
+        // supplies them directly when there is no query text. Only the
+        // caller's own topics can be named.
+        if let Some(topic_id) = &filters.topic_id {
+            let members: HashSet<String> = crate::storage::topics::get_topic(&ranking.user_id, topic_id)
+                .map(|topic| topic.message_ids.into_iter().collect())
+                .ok_or_else(|| Error::InvalidParameters(format!("Unknown topic: {}", topic_id)))?;
+            if query.trim().is_empty() {
+                candidates.extend(members.iter().cloned());
+            }
+            candidates.retain(|id| members.contains(id));
         }
         
-        // Add attachment bonus (0.1)
-        for id in attachment_results {
-            *scores.entry(id).or_insert(0.0) += 0.1;
-        }
+        // Metadata results are merged rather than intersected, so the
+        // sentiment filter is checked against each candidate's stored label
+        let sentiment_labels = match filters.sentiment {
+            Some(_) => self.metadata_indexer.sentiment_labels(&candidates)?,
+            None => HashMap::new(),
+        };
         
-        // Convert to vec and sort by score
-        let mut ranked_results: Vec<(String, f32)> = scores.into_iter().collect();
-        ranked_results.sort_by(|(_, score_a), (_, score_b)| score_b.partial_cmp(score_a).unwrap());
+        let accessible: Option<HashSet<&String>> = filters.conversation_ids.as_ref()
+            .map(|ids| ids.iter().collect());
         
-        // Return just the message IDs
-        ranked_results.into_iter().map(|(id, _)| id).collect()
+        // Conversations are shared, so a connection filter goes by the
+        // caller's record of what each connection synced
+        let synced_by: Option<HashSet<String>> = filters.connection_id.as_ref()
+            .map(|connection_id| crate::storage::connections::conversation_ids(&ranking.user_id, connection_id));
+        
+        let mut ranked_results: Vec<(String, ranking::ScoreBreakdown)> = candidates.into_iter()
+            .filter_map(|id| {
+                let message = crate::storage::messages::get_message(&id);
+                
+                if let Some(label) = filters.sentiment {
+                    if sentiment_labels.get(&id) != Some(&label) {
+                        return None;
+                    }
+                }
+                
+                // Topic members aren't scoped by an index
+                if let (Some(accessible), Some(message)) = (&accessible, &message) {
+                    if !accessible.contains(&message.conversation_id) {
+                        return None;
+                    }
+                }
+                
+                if let Some(synced_by) = &synced_by {
+                    if !message.as_ref().map_or(false, |m| synced_by.contains(&m.conversation_id)) {
+                        return None;
+                    }
+                }
+                
+                // Text found only in an attachment counts, discounted, as a text match
+                let text_score = text_results.get(&id).copied().unwrap_or(0.0)
+                    .max(attachment_results.get(&id).copied().unwrap_or(0.0) * ATTACHMENT_TEXT_WEIGHT);
+                let breakdown = ranking.score(
+                    message.as_ref(),
+                    text_score,
+                    query,
+                    metadata_results.contains(&id),
+                    attachment_results.contains_key(&id),
+                );
+                Some((id, breakdown))
+            })
+            .collect();
+        
+        // Sort by score (NaN-safe), then by ID so ties are stable
+        ranked_results.sort_by(|(id_a, a), (id_b, b)| {
+            ranking::compare_scores(a.total, b.total).then_with(|| id_a.cmp(id_b))
+        });
+        
+        Ok(ranked_results)
     }
     
     // Optimize indices for better performance
@@ -125,11 +224,18 @@ impl IndexManager {
         Ok(())
     }
     
+    // Index a message's attachments again, e.g. once their text is extracted
+    pub fn reindex_attachments(&mut self, message: &Message) -> Result<()> {
+        self.attachment_indexer.index_message(message)
+    }
+    
     // Delete a message from all indices
-    pub fn delete_message(&mut self, message_id: &str) -> Result<()> {
-        self.text_indexer.delete_message(message_id)?;
-        self.metadata_indexer.delete_message(message_id)?;
-        self.attachment_indexer.delete_message(message_id)?;
+    pub fn delete_message(&mut self, message: &Message) -> Result<()> {
+        self.text_indexer.delete_message(&message.id)?;
+        self.metadata_indexer.delete_message(&message.id)?;
+        self.attachment_indexer.delete_message(&message.id)?;
+        dedup::remove_message(&message.id);
+        entities::remove_message(message);
         Ok(())
     }
 }
@@ -146,15 +252,58 @@ pub fn index_message(message: &Message) -> Result<()> {
     })
 }
 
-pub fn search(query: &str, filters: &search::SearchFilters, limit: usize) -> Result<Vec<String>> {
+pub fn search(query: &str, filters: &search::SearchFilters, limit: usize, ranking: &ranking::RankingContext) -> Result<Vec<String>> {
+    INDEX_MANAGER.with(|manager| {
+        manager.borrow().search(query, filters, limit, ranking)
+    })
+}
+
+pub fn search_hits(query: &str, filters: &search::SearchFilters, limit: usize, ranking: &ranking::RankingContext) -> Result<Vec<search::SearchHit>> {
     INDEX_MANAGER.with(|manager| {
-        manager.borrow().search(query, filters, limit)
+        manager.borrow().search_hits(query, filters, limit, ranking)
     })
 }
 
-pub fn delete_message(message_id: &str) -> Result<()> {
+pub fn suggest(query: &str) -> Result<Option<String>> {
+    INDEX_MANAGER.with(|manager| {
+        manager.borrow().suggest(query)
+    })
+}
+
+// Extract text from up to `limit` queued document blobs the owner holds and
+// reindex the attachments of the messages waiting on them. Returns the
+// number of blobs read; the rest stay queued.
+pub fn process_pending_extractions(owner: &str, limit: usize) -> Result<u64> {
+    let mut processed = 0;
+    
+    for hash in crate::storage::blobs::pending_extractions(owner, limit) {
+        if ic_cdk::api::instruction_counter() > extract::EXTRACTION_CALL_BUDGET {
+            break;
+        }
+        
+        let messages: Vec<Message> = crate::storage::blobs::take_extraction(&hash).iter()
+            .filter_map(|id| crate::storage::messages::get_message(id))
+            .collect();
+        // File names help tell documents apart when the type is generic
+        let name = messages.iter()
+            .flat_map(|m| m.content.attachments.iter())
+            .find(|a| a.blob_hash.as_deref() == Some(hash.as_str()))
+            .and_then(|a| a.name.clone());
+        
+        attachments::extract_blob_text(&hash, name.as_deref());
+        INDEX_MANAGER.with(|manager| {
+            let mut manager = manager.borrow_mut();
+            messages.iter().try_for_each(|message| manager.reindex_attachments(message))
+        })?;
+        processed += 1;
+    }
+    
+    Ok(processed)
+}
+
+pub fn delete_message(message: &Message) -> Result<()> {
     INDEX_MANAGER.with(|manager| {
-        manager.borrow_mut().delete_message(message_id)
+        manager.borrow_mut().delete_message(message)
     })
 }
 
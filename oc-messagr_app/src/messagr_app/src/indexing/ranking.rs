@@ -0,0 +1,217 @@
+use candid::{CandidType, Deserialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::{Message, Error, Result};
+use crate::storage::{engagement, preferences};
+
+// Default half-life of the recency signal (one week, in milliseconds)
+const DEFAULT_RECENCY_HALF_LIFE_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+// Engagement (replies + weighted reactions) at which the signal saturates
+const ENGAGEMENT_SATURATION: f32 = 50.0;
+
+// A reaction counts for less than a reply
+const REACTION_WEIGHT: f32 = 0.5;
+
+// Upper bound for any single weight, to keep scores comparable
+const MAX_WEIGHT: f32 = 10.0;
+
+// Weights of each ranking signal; tunable per user
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RankingWeights {
+    // Normalized BM25 score from the text index
+    pub text: f32,
+    
+    // Exponential time decay
+    pub recency: f32,
+    pub recency_half_life_ms: u64,
+    
+    // Reply and reaction counts
+    pub engagement: f32,
+    
+    // How often the caller reads the message's conversation
+    pub affinity: f32,
+    
+    // Message contains the whole query as a phrase
+    pub exact_phrase: f32,
+    
+    // Matched the structured filters / attachment index
+    pub metadata: f32,
+    pub attachment: f32,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        Self {
+            text: 1.0,
+            recency: 0.3,
+            recency_half_life_ms: DEFAULT_RECENCY_HALF_LIFE_MS,
+            engagement: 0.2,
+            affinity: 0.2,
+            exact_phrase: 0.3,
+            metadata: 0.1,
+            attachment: 0.05,
+        }
+    }
+}
+
+impl RankingWeights {
+    // Reject weights that would produce meaningless scores
+    pub fn validate(&self) -> Result<()> {
+        let weights = [
+            ("text", self.text),
+            ("recency", self.recency),
+            ("engagement", self.engagement),
+            ("affinity", self.affinity),
+            ("exact_phrase", self.exact_phrase),
+            ("metadata", self.metadata),
+            ("attachment", self.attachment),
+        ];
+        
+        for (name, weight) in weights {
+            if !weight.is_finite() || weight < 0.0 || weight > MAX_WEIGHT {
+                return Err(Error::InvalidParameters(format!(
+                    "Ranking weight '{}' must be between 0 and {}", name, MAX_WEIGHT
+                )));
+            }
+        }
+        
+        if self.recency_half_life_ms == 0 {
+            return Err(Error::InvalidParameters("Recency half-life must be greater than zero".to_string()));
+        }
+        
+        Ok(())
+    }
+}
+
+// Per-signal contributions to a hit's final score (already weighted)
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ScoreBreakdown {
+    pub text: f32,
+    pub recency: f32,
+    pub engagement: f32,
+    pub affinity: f32,
+    pub exact_phrase: f32,
+    pub metadata: f32,
+    pub attachment: f32,
+    pub total: f32,
+}
+
+// Everything needed to rank results for a particular caller
+pub struct RankingContext {
+    pub user_id: String,
+    pub weights: RankingWeights,
+    
+    // Conversation ID -> number of times the caller has read it
+    pub conversation_reads: HashMap<String, u32>,
+    max_reads: u32,
+    
+    // Current time in milliseconds
+    pub now: u64,
+}
+
+impl RankingContext {
+    // Load the caller's weights and reading history
+    pub fn for_user(user_id: &str) -> Self {
+        let conversation_reads = engagement::get_conversation_reads(user_id);
+        let max_reads = conversation_reads.values().copied().max().unwrap_or(0);
+        
+        Self {
+            user_id: user_id.to_string(),
+            weights: preferences::get_ranking_weights(user_id),
+            conversation_reads,
+            max_reads,
+            now: ic_cdk::api::time() / 1_000_000,
+        }
+    }
+    
+    // Score a candidate message from its raw signals
+    pub fn score(
+        &self,
+        message: Option<&Message>,
+        text_score: f32,
+        query: &str,
+        metadata_match: bool,
+        attachment_match: bool,
+    ) -> ScoreBreakdown {
+        let weights = &self.weights;
+        
+        let (recency, engagement, affinity, exact_phrase) = match message {
+            Some(message) => (
+                self.recency(message.timestamp),
+                engagement_signal(&message.id),
+                self.affinity(&message.conversation_id),
+                if contains_phrase(&message.content.text, query) { 1.0 } else { 0.0 },
+            ),
+            None => (0.0, 0.0, 0.0, 0.0),
+        };
+        
+        let mut breakdown = ScoreBreakdown {
+            text: weights.text * sanitize(text_score),
+            recency: weights.recency * recency,
+            engagement: weights.engagement * engagement,
+            affinity: weights.affinity * affinity,
+            exact_phrase: weights.exact_phrase * exact_phrase,
+            metadata: if metadata_match { weights.metadata } else { 0.0 },
+            attachment: if attachment_match { weights.attachment } else { 0.0 },
+            total: 0.0,
+        };
+        
+        breakdown.total = sanitize(
+            breakdown.text
+                + breakdown.recency
+                + breakdown.engagement
+                + breakdown.affinity
+                + breakdown.exact_phrase
+                + breakdown.metadata
+                + breakdown.attachment
+        );
+        
+        breakdown
+    }
+    
+    // Exponential decay: 1.0 for a message sent now, 0.5 after one half-life
+    fn recency(&self, timestamp: u64) -> f32 {
+        let age = self.now.saturating_sub(timestamp) as f64;
+        let half_life = self.weights.recency_half_life_ms.max(1) as f64;
+        sanitize(0.5f64.powf(age / half_life) as f32)
+    }
+    
+    // Reads of this conversation relative to the caller's most-read conversation
+    fn affinity(&self, conversation_id: &str) -> f32 {
+        if self.max_reads == 0 {
+            return 0.0;
+        }
+        
+        let reads = self.conversation_reads.get(conversation_id).copied().unwrap_or(0);
+        reads as f32 / self.max_reads as f32
+    }
+}
+
+// Log-scaled engagement in the 0.0-1.0 range
+fn engagement_signal(message_id: &str) -> f32 {
+    let counts = engagement::get_engagement(message_id);
+    let raw = counts.reply_count as f32 + counts.reaction_count as f32 * REACTION_WEIGHT;
+    
+    sanitize(((1.0 + raw).ln() / (1.0 + ENGAGEMENT_SATURATION).ln()).min(1.0))
+}
+
+// Whether a multi-word query appears verbatim (case-insensitive) in the text
+fn contains_phrase(text: &str, query: &str) -> bool {
+    let phrase = query.trim().to_lowercase();
+    if phrase.split_whitespace().count() < 2 {
+        return false;
+    }
+    
+    text.to_lowercase().contains(&phrase)
+}
+
+// Replace NaN/infinite values so they can't poison the ordering
+fn sanitize(value: f32) -> f32 {
+    if value.is_finite() { value } else { 0.0 }
+}
+
+// Order scores from highest to lowest, NaN-safe
+pub fn compare_scores(a: f32, b: f32) -> Ordering {
+    sanitize(b).total_cmp(&sanitize(a))
+}
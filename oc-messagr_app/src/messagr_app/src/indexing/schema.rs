@@ -1,11 +1,19 @@
 use tantivy::schema::{Schema, STORED, INDEXED, TEXT, STRING, FAST, SchemaBuilder, Field, TextOptions, TextFieldIndexing};
 use tantivy::tokenizer::{NgramTokenizer, TextAnalyzer, SimpleTokenizer, Language, LowerCaser, StopWordFilter, Stemmer, RemoveLongFilter};
+use tantivy::Index;
 use std::collections::HashMap;
 use crate::Platform;
+use super::language::CjkBigramTokenizer;
 
 // Field names for text index
 pub const FIELD_ID: &str = "id";
 pub const FIELD_CONTENT: &str = "content";
+pub const FIELD_CONTENT_TERMS: &str = "content_terms";
+pub const FIELD_CONTENT_NGRAM: &str = "content_ngram";
+pub const FIELD_CONTENT_ES: &str = "content_es";
+pub const FIELD_CONTENT_DE: &str = "content_de";
+pub const FIELD_CONTENT_CJK: &str = "content_cjk";
+pub const FIELD_LANGUAGE: &str = "language";
 pub const FIELD_SENDER_NAME: &str = "sender_name";
 pub const FIELD_CONVERSATION_ID: &str = "conversation_id";
 pub const FIELD_PLATFORM: &str = "platform";
@@ -17,6 +25,12 @@ pub const FIELD_REPLY_TO: &str = "reply_to";
 pub const FIELD_ATTACHMENT_TYPE: &str = "attachment_type";
 pub const FIELD_ATTACHMENT_NAME: &str = "attachment_name";
 pub const FIELD_ATTACHMENT_URL: &str = "attachment_url";
+pub const FIELD_ATTACHMENT_HASH: &str = "attachment_hash";
+pub const FIELD_ATTACHMENT_TEXT: &str = "attachment_text";
+
+// Sentiment field names
+pub const FIELD_SENTIMENT: &str = "sentiment";
+pub const FIELD_SENTIMENT_LABEL: &str = "sentiment_label";
 
 // Create the text schema for message content
 pub fn create_text_schema() -> (Schema, HashMap<String, Field>) {
@@ -25,13 +39,39 @@ pub fn create_text_schema() -> (Schema, HashMap<String, Field>) {
     
     // Configure text field indexing with advanced analysis
     let text_indexing = TextFieldIndexing::default()
-        .set_tokenizer("en_ngram") // Custom tokenizer
+        .set_tokenizer("en_analyzer") // Stemmed English analysis
         .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions);
     
     let text_options = TextOptions::default()
         .set_indexing_options(text_indexing)
         .set_stored();
     
+    // Unstemmed lowercase words, used for fuzzy/prefix matching and suggestions
+    let terms_options = TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("en_terms")
+                .set_index_option(tantivy::schema::IndexRecordOption::WithFreqs)
+        );
+    
+    // Character n-grams, used for partial-word matching
+    let ngram_options = TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("en_ngram")
+                .set_index_option(tantivy::schema::IndexRecordOption::WithFreqs)
+        );
+    
+    // Per-language analysis of the content, searched alongside the main field
+    let language_options = |tokenizer: &str| {
+        TextOptions::default()
+            .set_indexing_options(
+                TextFieldIndexing::default()
+                    .set_tokenizer(tokenizer)
+                    .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions)
+            )
+    };
+    
     // ID field - stored but not tokenized
     fields.insert(
         FIELD_ID.to_string(),
@@ -44,6 +84,40 @@ pub fn create_text_schema() -> (Schema, HashMap<String, Field>) {
         schema_builder.add_text_field(FIELD_CONTENT, text_options)
     );
     
+    // Content copies for typo-tolerant and partial-word matching - indexed only
+    fields.insert(
+        FIELD_CONTENT_TERMS.to_string(),
+        schema_builder.add_text_field(FIELD_CONTENT_TERMS, terms_options)
+    );
+    
+    fields.insert(
+        FIELD_CONTENT_NGRAM.to_string(),
+        schema_builder.add_text_field(FIELD_CONTENT_NGRAM, ngram_options)
+    );
+    
+    // Language-specific content copies - only populated for messages in that language
+    fields.insert(
+        FIELD_CONTENT_ES.to_string(),
+        schema_builder.add_text_field(FIELD_CONTENT_ES, language_options("es_analyzer"))
+    );
+    
+    fields.insert(
+        FIELD_CONTENT_DE.to_string(),
+        schema_builder.add_text_field(FIELD_CONTENT_DE, language_options("de_analyzer"))
+    );
+    
+    // CJK content is stored so snippets can be highlighted with bigram offsets
+    fields.insert(
+        FIELD_CONTENT_CJK.to_string(),
+        schema_builder.add_text_field(FIELD_CONTENT_CJK, language_options("cjk_bigram").set_stored())
+    );
+    
+    // Detected language (ISO 639-1 code)
+    fields.insert(
+        FIELD_LANGUAGE.to_string(),
+        schema_builder.add_text_field(FIELD_LANGUAGE, STRING | STORED)
+    );
+    
     // Sender name - indexed and stored
     fields.insert(
         FIELD_SENDER_NAME.to_string(),
@@ -121,6 +195,27 @@ pub fn create_attachment_schema() -> (Schema, HashMap<String, Field>) {
         schema_builder.add_text_field(FIELD_ATTACHMENT_URL, STRING | STORED)
     );
     
+    // Blob hash, identifying which attachment of the message matched
+    fields.insert(
+        FIELD_ATTACHMENT_HASH.to_string(),
+        schema_builder.add_text_field(FIELD_ATTACHMENT_HASH, STRING | STORED)
+    );
+    
+    // Text extracted from document attachments - analyzed like message
+    // content and stored for snippets
+    let content_options = TextOptions::default()
+        .set_indexing_options(
+            TextFieldIndexing::default()
+                .set_tokenizer("en_analyzer")
+                .set_index_option(tantivy::schema::IndexRecordOption::WithFreqsAndPositions)
+        )
+        .set_stored();
+    
+    fields.insert(
+        FIELD_ATTACHMENT_TEXT.to_string(),
+        schema_builder.add_text_field(FIELD_ATTACHMENT_TEXT, content_options)
+    );
+    
     // Add common fields
     fields.insert(
         FIELD_TIMESTAMP.to_string(),
@@ -188,12 +283,25 @@ pub fn create_metadata_schema() -> (Schema, HashMap<String, Field>) {
         schema_builder.add_bool_field("is_edited", STORED | INDEXED)
     );
     
+    // Lexicon sentiment: compound score and its label
+    fields.insert(
+        FIELD_SENTIMENT.to_string(),
+        schema_builder.add_f64_field(FIELD_SENTIMENT, STORED | INDEXED | FAST)
+    );
+    
+    fields.insert(
+        FIELD_SENTIMENT_LABEL.to_string(),
+        schema_builder.add_text_field(FIELD_SENTIMENT_LABEL, STRING | STORED)
+    );
+    
     let schema = schema_builder.build();
     (schema, fields)
 }
 
-// Register custom tokenizers
-pub fn register_tokenizers() {
+// Register custom tokenizers on an index
+pub fn register_tokenizers(index: &Index) {
+    let tokenizers = index.tokenizers();
+    
     // English analyzer with stemming and stopwords
     let en_analyzer = TextAnalyzer::from(SimpleTokenizer)
         .filter(RemoveLongFilter::limit(40))
@@ -201,15 +309,43 @@ pub fn register_tokenizers() {
         .filter(StopWordFilter::new(Language::English))
         .filter(Stemmer::new(Language::English));
     
-    tantivy::tokenizer::TokenizerManager::register("en_analyzer", en_analyzer);
+    tokenizers.register("en_analyzer", en_analyzer);
     
-    // N-gram tokenizer for partial matching and typo tolerance
-    let ngram = TextAnalyzer::from(SimpleTokenizer)
+    // Spanish and German analyzers
+    let es_analyzer = TextAnalyzer::from(SimpleTokenizer)
         .filter(RemoveLongFilter::limit(40))
         .filter(LowerCaser)
-        .filter(NgramTokenizer::new(2, 4, false).unwrap());
+        .filter(StopWordFilter::new(Language::Spanish))
+        .filter(Stemmer::new(Language::Spanish));
+    
+    tokenizers.register("es_analyzer", es_analyzer);
+    
+    let de_analyzer = TextAnalyzer::from(SimpleTokenizer)
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser)
+        .filter(StopWordFilter::new(Language::German))
+        .filter(Stemmer::new(Language::German));
+    
+    tokenizers.register("de_analyzer", de_analyzer);
+    
+    // Overlapping bigrams for Chinese, Japanese and Korean text
+    let cjk_bigram = TextAnalyzer::from(CjkBigramTokenizer)
+        .filter(LowerCaser);
+    
+    tokenizers.register("cjk_bigram", cjk_bigram);
+    
+    // Plain lowercase words without stemming, for fuzzy and prefix queries
+    let en_terms = TextAnalyzer::from(SimpleTokenizer)
+        .filter(RemoveLongFilter::limit(40))
+        .filter(LowerCaser);
+    
+    tokenizers.register("en_terms", en_terms);
+    
+    // N-gram tokenizer for partial matching and typo tolerance
+    let ngram = TextAnalyzer::from(NgramTokenizer::new(2, 4, false).unwrap())
+        .filter(LowerCaser);
     
-    tantivy::tokenizer::TokenizerManager::register("en_ngram", ngram);
+    tokenizers.register("en_ngram", ngram);
 }
 
 // Convert Platform enum to string for storage
@@ -221,5 +357,6 @@ pub fn platform_to_string(platform: &Platform) -> String {
         Platform::Twitter => "twitter".to_string(),
         Platform::Facebook => "facebook".to_string(),
         Platform::WhatsApp => "whatsapp".to_string(),
+        Platform::Instagram => "instagram".to_string(),
     }
 }
\ No newline at end of file
@@ -1,5 +1,12 @@
 use crate::Platform;
 use serde::{Deserialize, Serialize};
+use super::text::TextMatch;
+use super::attachments::AttachmentMatch;
+use super::ranking::ScoreBreakdown;
+use super::sentiment::SentimentLabel;
+use tantivy::query::{Query, TermSetQuery};
+use tantivy::schema::Field;
+use tantivy::Term;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SearchFilters {
@@ -10,9 +17,16 @@ pub struct SearchFilters {
     pub start_time: Option<u64>,
     pub end_time: Option<u64>,
     
+    // Connected account or workspace filter
+    pub connection_id: Option<String>,
+    
     // Conversation filters
     pub conversation_id: Option<String>,
     
+    // Conversations the caller can read. Each index applies it before its
+    // limit, so other users' messages never take up candidate slots.
+    pub conversation_ids: Option<Vec<String>>,
+    
     // Sender filters
     pub sender_id: Option<String>,
     pub sender_name: Option<String>,
@@ -26,6 +40,12 @@ pub struct SearchFilters {
     pub in_thread: bool,
     pub is_edited: bool,
     
+    // Sentiment filter
+    pub sentiment: Option<SentimentLabel>,
+    
+    // Discovered topic filter
+    pub topic_id: Option<String>,
+    
     // Sort options
     pub sort_by: SortField,
     pub sort_direction: SortDirection,
@@ -48,13 +68,26 @@ pub enum SortDirection {
     Descending,
 }
 
+// A ranked search hit with the text match that produced it (if any)
+#[derive(Debug, Clone)]
+pub struct SearchHit {
+    pub message_id: String,
+    pub score: f32,
+    pub text_match: Option<TextMatch>,
+    // Passage of a matching attachment, when the message matched through one
+    pub attachment_match: Option<AttachmentMatch>,
+    pub breakdown: ScoreBreakdown,
+}
+
 impl Default for SearchFilters {
     fn default() -> Self {
         Self {
             platform: None,
             start_time: None,
             end_time: None,
+            connection_id: None,
             conversation_id: None,
+            conversation_ids: None,
             sender_id: None,
             sender_name: None,
             has_attachments: false,
@@ -62,6 +95,8 @@ impl Default for SearchFilters {
             is_reply: false,
             in_thread: false,
             is_edited: false,
+            sentiment: None,
+            topic_id: None,
             sort_by: SortField::Relevance,
             sort_direction: SortDirection::Descending,
             offset: 0,
@@ -83,11 +118,24 @@ impl SearchFilters {
         self
     }
     
+    pub fn with_connection(mut self, connection_id: String) -> Self {
+        self.connection_id = Some(connection_id);
+        self
+    }
+    
     pub fn with_conversation(mut self, conversation_id: String) -> Self {
         self.conversation_id = Some(conversation_id);
         self
     }
     
+    // Query clause restricting an index's conversation_id field to the
+    // accessible conversations, when they are set
+    pub fn conversation_scope(&self, field: Field) -> Option<Box<dyn Query>> {
+        let conversation_ids = self.conversation_ids.as_ref()?;
+        let terms = conversation_ids.iter().map(|id| Term::from_field_text(field, id));
+        Some(Box::new(TermSetQuery::new(terms)))
+    }
+    
     pub fn with_sender_id(mut self, sender_id: String) -> Self {
         self.sender_id = Some(sender_id);
         self
@@ -124,6 +172,16 @@ impl SearchFilters {
         self
     }
     
+    pub fn with_sentiment(mut self, sentiment: SentimentLabel) -> Self {
+        self.sentiment = Some(sentiment);
+        self
+    }
+    
+    pub fn with_topic(mut self, topic_id: String) -> Self {
+        self.topic_id = Some(topic_id);
+        self
+    }
+    
     pub fn sort_by(mut self, field: SortField, direction: SortDirection) -> Self {
         self.sort_by = field;
         self.sort_direction = direction;
@@ -160,6 +218,8 @@ impl SearchFilters {
                 filters.platform = Some(Platform::Facebook);
             } else if word == "whatsapp" || word == "from:whatsapp" {
                 filters.platform = Some(Platform::WhatsApp);
+            } else if word == "instagram" || word == "from:instagram" {
+                filters.platform = Some(Platform::Instagram);
             }
             // Time-based filters
             else if word == "today" {
@@ -217,6 +277,28 @@ impl SearchFilters {
             } else if word == "edited" {
                 filters.is_edited = true;
             }
+            // Sentiment filter
+            else if let Some(label) = word.strip_prefix("sentiment:").and_then(SentimentLabel::parse) {
+                filters.sentiment = Some(label);
+            }
+            // Topic filter
+            else if word.starts_with("topic:") {
+                let topic = word.strip_prefix("topic:").unwrap_or("");
+                if !topic.is_empty() {
+                    filters.topic_id = Some(topic.to_string());
+                }
+            }
+            // Connected account, by id or label ("account:acme-slack")
+            else if word.starts_with("account:") {
+                let account = word.strip_prefix("account:").unwrap_or("");
+                if !account.is_empty() {
+                    // Unknown accounts are kept as given and match nothing
+                    let connection_id = crate::storage::connections::resolve(&ic_cdk::caller().to_string(), account)
+                        .map(|connection| connection.id)
+                        .unwrap_or_else(|| account.to_string());
+                    filters.connection_id = Some(connection_id);
+                }
+            }
             // From specific senders
             else if word.starts_with("from:") {
                 let sender = word.strip_prefix("from:").unwrap_or("");
@@ -279,6 +361,7 @@ impl SearchFilters {
                 Platform::Twitter => "twitter",
                 Platform::Facebook => "facebook",
                 Platform::WhatsApp => "whatsapp",
+                Platform::Instagram => "instagram",
             };
             query_parts.push(format!("platform:{}", platform_str));
         }
@@ -315,6 +398,10 @@ impl SearchFilters {
             query_parts.push("is_edited:true".to_string());
         }
         
+        if let Some(sentiment) = &self.sentiment {
+            query_parts.push(format!("sentiment_label:{}", sentiment.as_str()));
+        }
+        
         // Attachments filter
         if self.has_attachments {
             query_parts.push("has_attachments:true".to_string());
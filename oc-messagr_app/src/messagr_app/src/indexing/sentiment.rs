@@ -0,0 +1,518 @@
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use crate::Message;
+
+// VADER-style rule-based sentiment scoring. Runs entirely on-canister and is
+// deterministic, so every replica produces the same score for a message.
+
+// Compound scores beyond these thresholds are positive / negative
+const POSITIVE_THRESHOLD: f32 = 0.05;
+const NEGATIVE_THRESHOLD: f32 = -0.05;
+
+// Valence change from a booster word ("very good", "barely works")
+const BOOSTER_INCREMENT: f32 = 0.293;
+const BOOSTER_DECREMENT: f32 = -0.293;
+
+// Extra valence for an ALL CAPS word in otherwise mixed-case text
+const CAPS_INCREMENT: f32 = 0.733;
+
+// Multiplier for a negated word ("not good")
+const NEGATION_SCALAR: f32 = -0.74;
+
+// Words before and after "but" are weighted down and up
+const BUT_BEFORE_WEIGHT: f32 = 0.5;
+const BUT_AFTER_WEIGHT: f32 = 1.5;
+
+// Emphasis per exclamation mark (counted up to 4) and per question mark (up to 3)
+const EXCLAMATION_INCREMENT: f32 = 0.292;
+const MAX_EXCLAMATIONS: usize = 4;
+const QUESTION_INCREMENT: f32 = 0.18;
+const MAX_QUESTION_EMPHASIS: f32 = 0.96;
+
+// Normalization constant approximating the maximum expected valence sum
+const NORMALIZATION_ALPHA: f32 = 15.0;
+
+// Words (and emoji / emoticons) with their valence, from -4 to 4
+const LEXICON: &[(&str, f32)] = &[
+    // Positive
+    ("good", 1.9), ("great", 3.1), ("excellent", 2.7), ("amazing", 2.8), ("awesome", 3.1),
+    ("love", 3.2), ("loved", 2.9), ("loves", 2.7), ("lovely", 2.8), ("nice", 1.8),
+    ("happy", 2.7), ("glad", 2.0), ("thanks", 1.9), ("thank", 1.5), ("thx", 1.5),
+    ("perfect", 2.7), ("best", 3.2), ("better", 1.9), ("wonderful", 2.7), ("fantastic", 2.6),
+    ("fun", 2.3), ("cool", 1.3), ("yay", 2.4), ("congrats", 2.4), ("congratulations", 2.9),
+    ("win", 2.8), ("won", 2.7), ("success", 2.7), ("successful", 2.8), ("agree", 1.5),
+    ("helpful", 1.8), ("beautiful", 2.9), ("brilliant", 2.8), ("enjoy", 2.2), ("enjoyed", 2.3),
+    ("excited", 1.4), ("exciting", 2.2), ("impressive", 2.3), ("ok", 1.2), ("okay", 0.9),
+    ("yes", 1.7), ("sure", 1.3), ("lol", 1.8), ("haha", 2.0), ("pleased", 1.9),
+    ("appreciate", 1.7), ("appreciated", 2.3), ("welcome", 2.0), ("support", 1.7), ("smart", 1.7),
+    ("easy", 1.9), ("fine", 0.8), ("solved", 1.1), ("like", 1.5), ("liked", 1.8),
+    ("recommend", 1.5), ("useful", 1.9), ("wow", 2.8), ("proud", 2.1), ("relieved", 1.5),
+    // Negative
+    ("bad", -2.5), ("terrible", -2.1), ("awful", -2.0), ("horrible", -2.5), ("hate", -2.7),
+    ("hated", -3.2), ("worst", -3.1), ("worse", -2.1), ("sad", -2.1), ("angry", -2.3),
+    ("annoyed", -1.6), ("annoying", -1.7), ("broken", -2.1), ("fail", -2.5), ("failed", -2.3),
+    ("failure", -2.3), ("failing", -2.3), ("error", -1.7), ("errors", -1.4), ("problem", -1.7),
+    ("problems", -1.7), ("wrong", -2.1), ("sorry", -0.3), ("unfortunately", -1.5), ("disappointed", -1.9),
+    ("disappointing", -2.2), ("frustrated", -2.2), ("frustrating", -2.0), ("upset", -1.6), ("hurt", -2.4),
+    ("pain", -2.3), ("crash", -1.7), ("stupid", -2.4), ("useless", -1.8), ("ugly", -2.3),
+    ("boring", -1.3), ("worried", -1.2), ("worry", -1.9), ("fear", -2.2), ("scared", -1.9),
+    ("lost", -1.3), ("lose", -1.7), ("loss", -1.3), ("damn", -1.7), ("sucks", -1.5),
+    ("died", -2.6), ("dead", -3.3), ("cry", -2.1), ("crying", -2.1), ("delay", -1.3),
+    ("delayed", -0.9), ("missed", -1.2), ("confused", -1.3), ("confusing", -0.9), ("blocked", -1.3),
+    ("ugh", -1.8), ("hard", -0.4), ("difficult", -1.5), ("sick", -2.0), ("tired", -1.9),
+    ("mess", -1.5), ("outage", -1.7), ("risk", -1.1), ("urgent", -0.6), ("complaint", -1.5),
+    // Emoticons
+    (":)", 2.0), (":-)", 2.0), (":d", 2.3), (":-d", 2.3), (";)", 1.5), ("<3", 1.9),
+    (":(", -1.9), (":-(", -1.9), (":/", -1.4), (":'(", -2.2),
+    // Emoji
+    ("😀", 2.0), ("😃", 2.0), ("😄", 2.2), ("😁", 2.0), ("😂", 1.8), ("🤣", 1.8),
+    ("😊", 2.2), ("🙂", 1.5), ("😍", 2.8), ("🥰", 2.8), ("❤", 3.0), ("💯", 2.0),
+    ("👍", 1.8), ("👏", 2.0), ("🎉", 2.4), ("🙏", 1.5), ("🔥", 1.2), ("✅", 1.2),
+    ("😢", -2.1), ("😭", -2.3), ("😞", -2.0), ("😡", -2.9), ("😠", -2.5), ("👎", -1.8),
+    ("💔", -2.6), ("😕", -1.2), ("🙁", -1.6), ("☹", -1.8), ("😤", -1.8), ("😩", -2.0),
+];
+
+// Words that flip the valence of what follows
+const NEGATIONS: &[&str] = &[
+    "not", "no", "never", "none", "nothing", "nowhere", "neither", "nor", "nope",
+    "without", "rarely", "seldom", "cannot", "cant", "dont", "doesnt", "didnt",
+    "isnt", "wasnt", "arent", "werent", "wont", "wouldnt", "shouldnt", "couldnt",
+];
+
+// Words that intensify what follows
+const BOOSTERS_UP: &[&str] = &[
+    "absolutely", "amazingly", "completely", "deeply", "especially", "extremely",
+    "fully", "greatly", "highly", "hugely", "incredibly", "most", "much", "really",
+    "remarkably", "so", "super", "totally", "truly", "utterly", "very",
+];
+
+// Words that dampen what follows
+const BOOSTERS_DOWN: &[&str] = &[
+    "almost", "barely", "hardly", "kinda", "less", "little", "marginally",
+    "occasionally", "partly", "scarcely", "slightly", "somewhat",
+];
+
+thread_local! {
+    static LEXICON_MAP: RefCell<HashMap<&'static str, f32>> = RefCell::new(LEXICON.iter().copied().collect());
+}
+
+// Sentiment class used for filtering and aggregation
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SentimentLabel {
+    Positive,
+    Neutral,
+    Negative,
+}
+
+impl SentimentLabel {
+    // Value stored in the metadata index
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SentimentLabel::Positive => "positive",
+            SentimentLabel::Neutral => "neutral",
+            SentimentLabel::Negative => "negative",
+        }
+    }
+    
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "positive" | "pos" => Some(SentimentLabel::Positive),
+            "neutral" | "neu" => Some(SentimentLabel::Neutral),
+            "negative" | "neg" => Some(SentimentLabel::Negative),
+            _ => None,
+        }
+    }
+}
+
+// Scores for one text: compound in [-1, 1] and the positive / neutral /
+// negative shares, which add up to 1
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, Default)]
+pub struct SentimentScores {
+    pub compound: f32,
+    pub positive: f32,
+    pub neutral: f32,
+    pub negative: f32,
+}
+
+impl SentimentScores {
+    pub fn label(&self) -> SentimentLabel {
+        if self.compound >= POSITIVE_THRESHOLD {
+            SentimentLabel::Positive
+        } else if self.compound <= NEGATIVE_THRESHOLD {
+            SentimentLabel::Negative
+        } else {
+            SentimentLabel::Neutral
+        }
+    }
+}
+
+// A token and whether it was written in ALL CAPS
+struct SentimentToken {
+    text: String,
+    shouting: bool,
+}
+
+// Score a piece of text
+pub fn analyze(text: &str) -> SentimentScores {
+    let tokens = tokenize(text);
+    if tokens.is_empty() {
+        return SentimentScores { compound: 0.0, positive: 0.0, neutral: 1.0, negative: 0.0 };
+    }
+    
+    // Caps only count as emphasis when the text also has lower-case words
+    let caps_differential = tokens.iter().any(|t| t.shouting) && tokens.iter().any(|t| !t.shouting && t.text.chars().any(char::is_alphabetic));
+    
+    let mut valences: Vec<f32> = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        valences.push(token_valence(&tokens, i, token, caps_differential));
+    }
+    
+    // "but" shifts the weight to the clause after it
+    if let Some(but_index) = tokens.iter().position(|t| t.text == "but") {
+        for (i, valence) in valences.iter_mut().enumerate() {
+            if i < but_index {
+                *valence *= BUT_BEFORE_WEIGHT;
+            } else if i > but_index {
+                *valence *= BUT_AFTER_WEIGHT;
+            }
+        }
+    }
+    
+    let sum: f32 = valences.iter().sum();
+    let emphasis = punctuation_emphasis(text);
+    let emphasized_sum = if sum > 0.0 {
+        sum + emphasis
+    } else if sum < 0.0 {
+        sum - emphasis
+    } else {
+        sum
+    };
+    
+    let compound = (emphasized_sum / (emphasized_sum * emphasized_sum + NORMALIZATION_ALPHA).sqrt()).clamp(-1.0, 1.0);
+    
+    // Shares of positive, negative and neutral content
+    let mut positive_sum: f32 = valences.iter().filter(|v| **v > 0.0).map(|v| v + 1.0).sum();
+    let mut negative_sum: f32 = valences.iter().filter(|v| **v < 0.0).map(|v| v - 1.0).sum();
+    let neutral_count = valences.iter().filter(|v| **v == 0.0).count() as f32;
+    
+    if positive_sum > negative_sum.abs() {
+        positive_sum += emphasis;
+    } else if positive_sum < negative_sum.abs() {
+        negative_sum -= emphasis;
+    }
+    
+    let total = positive_sum + negative_sum.abs() + neutral_count;
+    if total == 0.0 {
+        return SentimentScores { compound, positive: 0.0, neutral: 1.0, negative: 0.0 };
+    }
+    
+    SentimentScores {
+        compound,
+        positive: positive_sum / total,
+        neutral: neutral_count / total,
+        negative: negative_sum.abs() / total,
+    }
+}
+
+// Score a message's text
+pub fn analyze_message(message: &Message) -> SentimentScores {
+    analyze(&message.content.text)
+}
+
+// Valence of one token given the tokens before it
+fn token_valence(tokens: &[SentimentToken], index: usize, token: &SentimentToken, caps_differential: bool) -> f32 {
+    // Boosters modify other words and carry no sentiment of their own
+    if booster_scalar(&token.text).is_some() {
+        return 0.0;
+    }
+    
+    let mut valence = match LEXICON_MAP.with(|lexicon| lexicon.borrow().get(token.text.as_str()).copied()) {
+        Some(valence) => valence,
+        None => return 0.0,
+    };
+    
+    if token.shouting && caps_differential {
+        valence += CAPS_INCREMENT * valence.signum();
+    }
+    
+    // Look back up to three words for boosters and negations
+    for distance in 1..=3 {
+        if distance > index {
+            break;
+        }
+        let previous = &tokens[index - distance];
+        
+        if let Some(mut scalar) = booster_scalar(&previous.text) {
+            if previous.shouting && caps_differential {
+                scalar += CAPS_INCREMENT * scalar.signum();
+            }
+            // Boosters further away have less effect
+            let damping = match distance {
+                1 => 1.0,
+                2 => 0.95,
+                _ => 0.9,
+            };
+            valence += scalar * damping * valence.signum();
+        }
+        
+        if is_negation(&previous.text) {
+            valence *= NEGATION_SCALAR;
+        }
+    }
+    
+    valence
+}
+
+fn booster_scalar(word: &str) -> Option<f32> {
+    if BOOSTERS_UP.contains(&word) {
+        Some(BOOSTER_INCREMENT)
+    } else if BOOSTERS_DOWN.contains(&word) {
+        Some(BOOSTER_DECREMENT)
+    } else {
+        None
+    }
+}
+
+fn is_negation(word: &str) -> bool {
+    NEGATIONS.contains(&word) || word.ends_with("n't")
+}
+
+// Emphasis from exclamation and question marks
+fn punctuation_emphasis(text: &str) -> f32 {
+    let exclamations = text.matches('!').count().min(MAX_EXCLAMATIONS);
+    let questions = text.matches('?').count();
+    
+    let question_emphasis = if questions > 1 {
+        (questions as f32 * QUESTION_INCREMENT).min(MAX_QUESTION_EMPHASIS)
+    } else {
+        0.0
+    };
+    
+    exclamations as f32 * EXCLAMATION_INCREMENT + question_emphasis
+}
+
+// Split text into lower-cased words, emoticons and individual emoji
+fn tokenize(text: &str) -> Vec<SentimentToken> {
+    let mut tokens = Vec::new();
+    
+    for raw in text.split_whitespace() {
+        // Emoticons are matched before punctuation is stripped
+        let lowered = raw.to_lowercase();
+        if LEXICON_MAP.with(|lexicon| lexicon.borrow().contains_key(lowered.as_str())) && !lowered.starts_with(char::is_alphanumeric) {
+            tokens.push(SentimentToken { text: lowered, shouting: false });
+            continue;
+        }
+        
+        let mut word = String::new();
+        for c in raw.chars() {
+            if c.is_alphanumeric() || c == '\'' || c == '\u{2019}' {
+                word.push(if c == '\u{2019}' { '\'' } else { c });
+            } else {
+                push_word(&mut tokens, &mut word);
+                
+                // Emoji stand alone even when glued to a word
+                let symbol = c.to_string();
+                if LEXICON_MAP.with(|lexicon| lexicon.borrow().contains_key(symbol.as_str())) {
+                    tokens.push(SentimentToken { text: symbol, shouting: false });
+                }
+            }
+        }
+        push_word(&mut tokens, &mut word);
+    }
+    
+    tokens
+}
+
+fn push_word(tokens: &mut Vec<SentimentToken>, word: &mut String) {
+    let trimmed = word.trim_matches('\'');
+    if !trimmed.is_empty() {
+        let shouting = trimmed.chars().count() > 1
+            && trimmed.chars().any(char::is_alphabetic)
+            && !trimmed.chars().any(char::is_lowercase);
+        tokens.push(SentimentToken { text: trimmed.to_lowercase(), shouting });
+    }
+    word.clear();
+}
+
+// Time bucket for sentiment over time
+#[derive(CandidType, Deserialize, Debug, Clone, Copy)]
+pub enum TimeBucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl TimeBucket {
+    fn millis(&self) -> u64 {
+        match self {
+            TimeBucket::Hour => 60 * 60 * 1000,
+            TimeBucket::Day => 24 * 60 * 60 * 1000,
+            TimeBucket::Week => 7 * 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+// Aggregate sentiment of a set of messages
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct SentimentStats {
+    pub message_count: u64,
+    pub mean_compound: f32,
+    pub positive_count: u64,
+    pub neutral_count: u64,
+    pub negative_count: u64,
+}
+
+impl SentimentStats {
+    fn add(&mut self, scores: &SentimentScores) {
+        let count = self.message_count as f32;
+        self.mean_compound = (self.mean_compound * count + scores.compound) / (count + 1.0);
+        self.message_count += 1;
+        
+        match scores.label() {
+            SentimentLabel::Positive => self.positive_count += 1,
+            SentimentLabel::Neutral => self.neutral_count += 1,
+            SentimentLabel::Negative => self.negative_count += 1,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SenderSentiment {
+    pub sender_id: String,
+    pub sender_name: String,
+    pub stats: SentimentStats,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BucketSentiment {
+    // Start of the bucket (milliseconds since the epoch)
+    pub start: u64,
+    pub stats: SentimentStats,
+}
+
+// Sentiment of a conversation overall, per sender and over time
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct SentimentSummary {
+    pub overall: SentimentStats,
+    pub by_sender: Vec<SenderSentiment>,
+    pub over_time: Vec<BucketSentiment>,
+}
+
+// Aggregate message sentiment overall, per sender and per time bucket
+pub fn summarize(messages: &[Message], bucket: TimeBucket) -> SentimentSummary {
+    let mut overall = SentimentStats::default();
+    let mut senders: HashMap<String, SenderSentiment> = HashMap::new();
+    let mut buckets: BTreeMap<u64, SentimentStats> = BTreeMap::new();
+    let bucket_size = bucket.millis();
+    
+    for message in messages {
+        let scores = analyze_message(message);
+        overall.add(&scores);
+        
+        senders.entry(message.sender.id.clone())
+            .or_insert_with(|| SenderSentiment {
+                sender_id: message.sender.id.clone(),
+                sender_name: message.sender.name.clone(),
+                stats: SentimentStats::default(),
+            })
+            .stats
+            .add(&scores);
+        
+        buckets.entry(message.timestamp - message.timestamp % bucket_size)
+            .or_default()
+            .add(&scores);
+    }
+    
+    // Most active senders first
+    let mut by_sender: Vec<SenderSentiment> = senders.into_values().collect();
+    by_sender.sort_by(|a, b| {
+        b.stats.message_count.cmp(&a.stats.message_count).then_with(|| a.sender_id.cmp(&b.sender_id))
+    });
+    
+    SentimentSummary {
+        overall,
+        by_sender,
+        over_time: buckets.into_iter()
+            .map(|(start, stats)| BucketSentiment { start, stats })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn labels_plain_positive_and_negative_text() {
+        assert_eq!(analyze("This release is great").label(), SentimentLabel::Positive);
+        assert_eq!(analyze("The build is broken again").label(), SentimentLabel::Negative);
+        assert_eq!(analyze("The meeting moved to Tuesday").label(), SentimentLabel::Neutral);
+    }
+    
+    #[test]
+    fn empty_text_is_neutral() {
+        let scores = analyze("   ");
+        assert_eq!(scores.compound, 0.0);
+        assert_eq!(scores.neutral, 1.0);
+    }
+    
+    #[test]
+    fn negation_flips_valence() {
+        assert!(analyze("good").compound > 0.0);
+        assert!(analyze("not good").compound < 0.0);
+        assert!(analyze("this isn't bad").compound > 0.0);
+    }
+    
+    #[test]
+    fn boosters_and_dampeners_scale_valence() {
+        let plain = analyze("good").compound;
+        assert!(analyze("very good").compound > plain);
+        assert!(analyze("barely good").compound < plain);
+    }
+    
+    #[test]
+    fn caps_emphasis_needs_mixed_case_text() {
+        let plain = analyze("the demo was good").compound;
+        assert!(analyze("the demo was GOOD").compound > plain);
+        // All caps everywhere is not emphasis
+        assert_eq!(analyze("THE DEMO WAS GOOD").compound, plain);
+    }
+    
+    #[test]
+    fn exclamations_add_emphasis_up_to_a_cap() {
+        let plain = analyze("good").compound;
+        assert!(analyze("good!").compound > plain);
+        assert_eq!(analyze("good!!!!").compound, analyze("good!!!!!!!").compound);
+    }
+    
+    #[test]
+    fn clause_after_but_dominates() {
+        assert!(analyze("The food was good but the service was terrible").compound < 0.0);
+        assert!(analyze("The service was terrible but the food was great").compound > 0.0);
+    }
+    
+    #[test]
+    fn scores_emoji_and_emoticons() {
+        assert_eq!(analyze("shipped 🎉").label(), SentimentLabel::Positive);
+        assert_eq!(analyze("missed the deadline :(").label(), SentimentLabel::Negative);
+        // Emoji glued to a word still count
+        assert_eq!(analyze("thanks👍").label(), SentimentLabel::Positive);
+    }
+    
+    #[test]
+    fn shares_add_up_to_one() {
+        let scores = analyze("Great work on the launch, but the docs are confusing!");
+        let total = scores.positive + scores.neutral + scores.negative;
+        assert!((total - 1.0).abs() < 1e-4);
+        assert!((-1.0..=1.0).contains(&scores.compound));
+    }
+    
+    #[test]
+    fn parses_labels() {
+        assert_eq!(SentimentLabel::parse("NEG"), Some(SentimentLabel::Negative));
+        assert_eq!(SentimentLabel::parse("positive"), Some(SentimentLabel::Positive));
+        assert_eq!(SentimentLabel::parse("mixed"), None);
+    }
+}
@@ -1,12 +1,18 @@
 use crate::{Message, Error, Result};
-use tantivy::{Index, IndexWriter, Document, Term, query::QueryParser, collector::TopDocs, TantivyError};
-use tantivy::schema::Field;
+use tantivy::{Index, IndexWriter, Document, Term, query::QueryParser, collector::TopDocs, TantivyError, SnippetGenerator};
+use tantivy::query::{Query, BooleanQuery, BoostQuery, FuzzyTermQuery, Occur, TermQuery};
+use tantivy::schema::{Field, IndexRecordOption};
 use tantivy::directory::MmapDirectory;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::Path;
 use std::fs;
 use std::sync::atomic::{AtomicBool, Ordering};
-use super::schema::{create_text_schema, register_tokenizers, platform_to_string, FIELD_ID, FIELD_CONTENT, FIELD_SENDER_NAME, FIELD_CONVERSATION_ID, FIELD_PLATFORM, FIELD_TIMESTAMP, FIELD_THREAD_ID, FIELD_REPLY_TO};
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
+use tantivy_fst::Automaton;
+use super::language::{detect_language, contains_cjk};
+use super::search::SearchFilters;
+use super::schema::{create_text_schema, register_tokenizers, platform_to_string, FIELD_ID, FIELD_CONTENT, FIELD_CONTENT_TERMS, FIELD_CONTENT_NGRAM, FIELD_CONTENT_ES, FIELD_CONTENT_DE, FIELD_CONTENT_CJK, FIELD_LANGUAGE, FIELD_SENDER_NAME, FIELD_CONVERSATION_ID, FIELD_PLATFORM, FIELD_TIMESTAMP, FIELD_THREAD_ID, FIELD_REPLY_TO};
 
 // In-memory buffer before writing to stable storage
 const MEMORY_BUFFER_SIZE: usize = 50_000_000; // 50MB
@@ -14,9 +20,80 @@ const MEMORY_BUFFER_SIZE: usize = 50_000_000; // 50MB
 // Path for index storage (would need to be adapted for canister stable memory)
 const INDEX_PATH: &str = "stable_memory/text_index";
 
+// Maximum length of a highlighted fragment returned with a hit
+const SNIPPET_MAX_CHARS: usize = 160;
+
+// Boosts for approximate matches relative to an exact (analyzed) match
+const FUZZY_BOOST: f32 = 0.6;
+const PREFIX_BOOST: f32 = 0.8;
+const NGRAM_BOOST: f32 = 0.4;
+
+// Boost for the content field matching the language of the query
+const QUERY_LANGUAGE_BOOST: f32 = 1.5;
+
+// Shortest term that is treated as a prefix (search-as-you-type)
+const MIN_PREFIX_LENGTH: usize = 2;
+
+// Shortest term that is matched as a partial word through the n-gram field
+const MIN_NGRAM_LENGTH: usize = 4;
+
 // Flag for operating in test/production mode
 static IN_TEST_MODE: AtomicBool = AtomicBool::new(false);
 
+thread_local! {
+    // Levenshtein automaton builders by distance; building one is costly
+    static LEVENSHTEIN_BUILDERS: RefCell<HashMap<u8, LevenshteinAutomatonBuilder>> = RefCell::new(HashMap::new());
+}
+
+// A Levenshtein DFA driving a term dictionary stream, so only terms within
+// the distance are visited
+struct LevenshteinDfa(DFA);
+
+impl Automaton for LevenshteinDfa {
+    type State = u32;
+    
+    fn start(&self) -> u32 {
+        self.0.initial_state()
+    }
+    
+    fn is_match(&self, state: &u32) -> bool {
+        matches!(self.0.distance(*state), Distance::Exact(_))
+    }
+    
+    fn can_match(&self, state: &u32) -> bool {
+        *state != SINK_STATE
+    }
+    
+    fn accept(&self, state: &u32, byte: u8) -> u32 {
+        self.0.transition(*state, byte)
+    }
+}
+
+fn levenshtein_dfa(term: &str, distance: u8) -> LevenshteinDfa {
+    LEVENSHTEIN_BUILDERS.with(|builders| {
+        let mut builders = builders.borrow_mut();
+        let builder = builders.entry(distance)
+            .or_insert_with(|| LevenshteinAutomatonBuilder::new(distance, true));
+        LevenshteinDfa(builder.build_dfa(term))
+    })
+}
+
+// A single text hit with the fragment that explains why it matched
+#[derive(Debug, Clone)]
+pub struct TextMatch {
+    // Score normalized to the 0.0-1.0 range
+    pub score: f32,
+    
+    // Best matching fragment of the message content
+    pub fragment: String,
+    
+    // Byte ranges of the matched terms within the fragment
+    pub highlights: Vec<(usize, usize)>,
+    
+    // Distinct terms (lowercased) that were highlighted
+    pub matched_terms: Vec<String>,
+}
+
 pub struct TextIndexer {
     index: Index,
     writer: IndexWriter,
@@ -28,9 +105,6 @@ impl TextIndexer {
         // Set up the schema
         let (schema, fields) = create_text_schema();
         
-        // Register custom tokenizers
-        register_tokenizers();
-        
         // Create or open the index
         let index = if IN_TEST_MODE.load(Ordering::Relaxed) {
             // In-memory index for testing
@@ -58,6 +132,9 @@ impl TextIndexer {
             })
         };
         
+        // Register custom tokenizers
+        register_tokenizers(&index);
+        
         // Create a writer with a memory buffer
         let writer = index.writer(MEMORY_BUFFER_SIZE)
             .unwrap_or_else(|e| {
@@ -80,6 +157,16 @@ impl TextIndexer {
         // Add all fields to the document
         doc.add_text(self.fields[FIELD_ID], &message.id);
         doc.add_text(self.fields[FIELD_CONTENT], &message.content.text);
+        doc.add_text(self.fields[FIELD_CONTENT_TERMS], &message.content.text);
+        doc.add_text(self.fields[FIELD_CONTENT_NGRAM], &message.content.text);
+        
+        // Detect the language and add a copy analyzed for that language
+        let language = detect_language(&message.content.text);
+        doc.add_text(self.fields[FIELD_LANGUAGE], language.code());
+        if let Some(language_field) = language.content_field() {
+            doc.add_text(self.fields[language_field], &message.content.text);
+        }
+        
         doc.add_text(self.fields[FIELD_SENDER_NAME], &message.sender.name);
         doc.add_text(self.fields[FIELD_CONVERSATION_ID], &message.conversation_id);
         doc.add_text(self.fields[FIELD_PLATFORM], &platform_to_string(&message.platform));
@@ -137,23 +224,14 @@ impl TextIndexer {
         Ok(())
     }
     
-    // Search for messages
-    pub fn search(&self, query_text: &str, limit: usize) -> Result<HashMap<String, f32>> {
-        // Create a query parser that searches in multiple fields with different boosts
-        let mut query_parser = QueryParser::for_index(
-            &self.index, 
-            vec![
-                (self.fields[FIELD_CONTENT], 1.0),      // Normal boost for content
-                (self.fields[FIELD_SENDER_NAME], 0.5),  // Lower boost for sender
-            ]
-        );
-        
-        // Set default conjunctive operator (AND)
-        query_parser.set_conjunction_by_default();
-        
-        // Parse the query
-        let query = query_parser.parse_query(query_text)
-            .map_err(|e| Error::QueryError(format!("Failed to parse query: {}", e)))?;
+    // Search for messages in the filters' accessible conversations, returning
+    // a highlighted fragment for each hit
+    pub fn search(&self, query_text: &str, filters: &SearchFilters, limit: usize) -> Result<HashMap<String, TextMatch>> {
+        let query = self.build_query(query_text)?;
+        let query: Box<dyn Query> = match filters.conversation_scope(self.fields[FIELD_CONVERSATION_ID]) {
+            Some(scope) => Box::new(BooleanQuery::new(vec![(Occur::Must, query), (Occur::Must, scope)])),
+            None => query,
+        };
         
         // Execute the search
         let searcher = self.index.reader()
@@ -163,7 +241,17 @@ impl TextIndexer {
         let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))
             .map_err(|e| Error::InternalError(format!("Failed to execute search: {}", e)))?;
         
-        // Convert results to ID -> score map
+        // Snippets are generated from the content field, or the bigram field
+        // for CJK messages since the main analyzer can't split those
+        let mut snippet_generator = SnippetGenerator::create(&searcher, &*query, self.fields[FIELD_CONTENT])
+            .map_err(|e| Error::InternalError(format!("Failed to create snippet generator: {}", e)))?;
+        snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+        
+        let mut cjk_snippet_generator = SnippetGenerator::create(&searcher, &*query, self.fields[FIELD_CONTENT_CJK])
+            .map_err(|e| Error::InternalError(format!("Failed to create snippet generator: {}", e)))?;
+        cjk_snippet_generator.set_max_num_chars(SNIPPET_MAX_CHARS);
+        
+        // Convert results to ID -> match map
         let mut results = HashMap::new();
         
         // Find the maximum score for normalization
@@ -181,15 +269,259 @@ impl TextIndexer {
             
             // Extract the message ID
             let id_field = self.fields[FIELD_ID];
-            if let Some(id_value) = retrieved_doc.get_first(id_field) {
-                if let Some(id_text) = id_value.as_text() {
-                    results.insert(id_text.to_string(), normalized_score);
+            let id_text = match retrieved_doc.get_first(id_field).and_then(|v| v.as_text()) {
+                Some(id_text) => id_text.to_string(),
+                None => continue,
+            };
+            
+            // Build the highlighted fragment for this hit
+            let is_cjk = retrieved_doc.get_first(self.fields[FIELD_CONTENT_CJK]).is_some();
+            let snippet = if is_cjk {
+                cjk_snippet_generator.snippet_from_doc(&retrieved_doc)
+            } else {
+                snippet_generator.snippet_from_doc(&retrieved_doc)
+            };
+            let fragment = snippet.fragment().to_string();
+            let highlights: Vec<(usize, usize)> = snippet.highlighted().iter()
+                .map(|range| (range.start, range.end))
+                .collect();
+            
+            let mut matched_terms: Vec<String> = Vec::new();
+            for (start, end) in &highlights {
+                if let Some(term) = fragment.get(*start..*end) {
+                    let term = term.to_lowercase();
+                    if !matched_terms.contains(&term) {
+                        matched_terms.push(term);
+                    }
                 }
             }
+            
+            results.insert(id_text, TextMatch {
+                score: normalized_score,
+                fragment,
+                highlights,
+                matched_terms,
+            });
         }
         
         Ok(results)
     }
+    
+    // Build a typo-tolerant query: every term must match exactly, fuzzily,
+    // as a partial word, or (for the last term) as a prefix
+    fn build_query(&self, query_text: &str) -> Result<Box<dyn Query>> {
+        // Favour the content field analyzed in the language of the query
+        let query_language = detect_language(query_text);
+        let content_boost = |field_name: &str| {
+            if field_name == query_language.primary_field() { QUERY_LANGUAGE_BOOST } else { 1.0 }
+        };
+        
+        // Create a query parser that searches in multiple fields with different boosts
+        let mut query_parser = QueryParser::for_index(
+            &self.index, 
+            vec![
+                (self.fields[FIELD_CONTENT], content_boost(FIELD_CONTENT)),         // Normal boost for content
+                (self.fields[FIELD_CONTENT_ES], content_boost(FIELD_CONTENT_ES)),   // Spanish analysis
+                (self.fields[FIELD_CONTENT_DE], content_boost(FIELD_CONTENT_DE)),   // German analysis
+                (self.fields[FIELD_CONTENT_CJK], content_boost(FIELD_CONTENT_CJK)), // CJK bigrams
+                (self.fields[FIELD_SENDER_NAME], 0.5),                              // Lower boost for sender
+            ]
+        );
+        
+        // Set default conjunctive operator (AND)
+        query_parser.set_conjunction_by_default();
+        
+        let terms = self.analyze(FIELD_CONTENT_TERMS, query_text)?;
+        
+        // Nothing to make approximate (e.g. "*" or only punctuation)
+        if terms.is_empty() {
+            return query_parser.parse_query(query_text)
+                .map_err(|e| Error::QueryError(format!("Failed to parse query: {}", e)));
+        }
+        
+        let terms_field = self.fields[FIELD_CONTENT_TERMS];
+        let mut clauses: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+        
+        for (i, term) in terms.iter().enumerate() {
+            let mut alternatives: Vec<(Occur, Box<dyn Query>)> = Vec::new();
+            
+            // Exact match on the analyzed content and sender name
+            let exact = query_parser.parse_query(term)
+                .map_err(|e| Error::QueryError(format!("Failed to parse query: {}", e)))?;
+            alternatives.push((Occur::Should, exact));
+            
+            // Exact match on the unstemmed word (keeps stopwords searchable)
+            alternatives.push((Occur::Should, Box::new(TermQuery::new(
+                Term::from_field_text(terms_field, term),
+                IndexRecordOption::WithFreqs,
+            ))));
+            
+            // CJK runs are matched as bigram phrases only; edit distance and
+            // n-grams over ideographs produce mostly noise
+            if contains_cjk(term) {
+                clauses.push((Occur::Must, Box::new(BooleanQuery::new(alternatives))));
+                continue;
+            }
+            
+            // Levenshtein match, allowing more edits for longer words
+            let distance = fuzzy_distance(term);
+            if distance > 0 {
+                let fuzzy = FuzzyTermQuery::new(Term::from_field_text(terms_field, term), distance, true);
+                alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(fuzzy), FUZZY_BOOST))));
+            }
+            
+            // Prefix match on the term being typed
+            if i == terms.len() - 1 && term.chars().count() >= MIN_PREFIX_LENGTH {
+                let prefix = FuzzyTermQuery::new_prefix(Term::from_field_text(terms_field, term), 0, true);
+                alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(prefix), PREFIX_BOOST))));
+            }
+            
+            // Partial-word match: every n-gram of the term must be present
+            if term.chars().count() >= MIN_NGRAM_LENGTH {
+                let ngram_field = self.fields[FIELD_CONTENT_NGRAM];
+                let grams: Vec<(Occur, Box<dyn Query>)> = self.analyze(FIELD_CONTENT_NGRAM, term)?
+                    .into_iter()
+                    .map(|gram| {
+                        let query: Box<dyn Query> = Box::new(TermQuery::new(
+                            Term::from_field_text(ngram_field, &gram),
+                            IndexRecordOption::WithFreqs,
+                        ));
+                        (Occur::Must, query)
+                    })
+                    .collect();
+                
+                if !grams.is_empty() {
+                    let partial = BooleanQuery::new(grams);
+                    alternatives.push((Occur::Should, Box::new(BoostQuery::new(Box::new(partial), NGRAM_BOOST))));
+                }
+            }
+            
+            clauses.push((Occur::Must, Box::new(BooleanQuery::new(alternatives))));
+        }
+        
+        Ok(Box::new(BooleanQuery::new(clauses)))
+    }
+    
+    // Run text through the analyzer of a field and collect the distinct tokens
+    fn analyze(&self, field_name: &str, text: &str) -> Result<Vec<String>> {
+        let analyzer = self.index.tokenizer_for_field(self.fields[field_name])
+            .map_err(|e| Error::InternalError(format!("Failed to get tokenizer: {}", e)))?;
+        
+        let mut tokens: Vec<String> = Vec::new();
+        let mut stream = analyzer.token_stream(text);
+        while stream.advance() {
+            let token = stream.token().text.clone();
+            if !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+        
+        Ok(tokens)
+    }
+    
+    // Suggest a corrected query ("did you mean") using the indexed vocabulary
+    pub fn suggest(&self, query_text: &str) -> Result<Option<String>> {
+        let terms = self.analyze(FIELD_CONTENT_TERMS, query_text)?;
+        if terms.is_empty() {
+            return Ok(None);
+        }
+        
+        let searcher = self.index.reader()
+            .map_err(|e| Error::InternalError(format!("Failed to get index reader: {}", e)))?
+            .searcher();
+        
+        let terms_field = self.fields[FIELD_CONTENT_TERMS];
+        let mut corrected = false;
+        let mut suggestion = Vec::new();
+        
+        for term in terms {
+            let doc_freq = searcher.doc_freq(&Term::from_field_text(terms_field, &term))
+                .map_err(|e| Error::InternalError(format!("Failed to read term frequency: {}", e)))?;
+            
+            // Known words (and CJK, which has no useful edit distance) are kept as typed
+            let max_distance = fuzzy_distance(&term);
+            if doc_freq > 0 || max_distance == 0 || contains_cjk(&term) {
+                suggestion.push(term);
+                continue;
+            }
+            
+            // Pick the closest indexed word, preferring the most frequent on
+            // ties. The automaton walks the dictionary's FST, so only words
+            // within the distance are read.
+            let mut best: Option<(usize, u32, String)> = None;
+            for segment_reader in searcher.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(terms_field)
+                    .map_err(|e| Error::InternalError(format!("Failed to read term dictionary: {}", e)))?;
+                let mut stream = inverted_index.terms().search(levenshtein_dfa(&term, max_distance)).into_stream()
+                    .map_err(|e| Error::InternalError(format!("Failed to stream term dictionary: {}", e)))?;
+                
+                while stream.advance() {
+                    let candidate = match std::str::from_utf8(stream.key()) {
+                        Ok(candidate) => candidate,
+                        Err(_) => continue,
+                    };
+                    
+                    // Ranked by plain edit distance; the automaton also admits transpositions
+                    let distance = edit_distance(&term, candidate);
+                    
+                    let candidate_freq = stream.value().doc_freq;
+                    let is_better = match &best {
+                        Some((best_distance, best_freq, _)) => {
+                            distance < *best_distance || (distance == *best_distance && candidate_freq > *best_freq)
+                        },
+                        None => true,
+                    };
+                    
+                    if is_better {
+                        best = Some((distance, candidate_freq, candidate.to_string()));
+                    }
+                }
+            }
+            
+            match best {
+                Some((_, _, candidate)) => {
+                    corrected = true;
+                    suggestion.push(candidate);
+                },
+                None => suggestion.push(term),
+            }
+        }
+        
+        if corrected {
+            Ok(Some(suggestion.join(" ")))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+// Allowed Levenshtein distance for a term, based on its length
+fn fuzzy_distance(term: &str) -> u8 {
+    match term.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+// Levenshtein distance between two strings (character based)
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b_chars.len()).collect();
+    let mut current = vec![0; b_chars.len() + 1];
+    
+    for (i, a_char) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, b_char) in b_chars.iter().enumerate() {
+            let substitution = if a_char == *b_char { 0 } else { 1 };
+            current[j + 1] = (previous[j] + substitution)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    
+    previous[b_chars.len()]
 }
 
 // For testing
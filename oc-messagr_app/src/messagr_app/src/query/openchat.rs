@@ -146,5 +146,6 @@ fn platform_to_string(platform: &Platform) -> String {
         Platform::Twitter => "Twitter".to_string(),
         Platform::Facebook => "Facebook".to_string(),
         Platform::WhatsApp => "WhatsApp".to_string(),
+        Platform::Instagram => "Instagram".to_string(),
     }
 }
\ No newline at end of file
@@ -52,6 +52,10 @@ pub fn parse_query(query_text: &str) -> Result<QueryRequest> {
         filters.push(FilterType::Platform(Platform::WhatsApp));
         cleaned_query = cleaned_query.replace("whatsapp", "").replace("WhatsApp", "");
     }
+    if query_text.to_lowercase().contains("instagram") {
+        filters.push(FilterType::Platform(Platform::Instagram));
+        cleaned_query = cleaned_query.replace("instagram", "").replace("Instagram", "");
+    }
     
     // Check for time-based filters
     if query_text.to_lowercase().contains("yesterday") {
@@ -0,0 +1,734 @@
+use candid::{CandidType, Deserialize};
+use ic_certified_map::{labeled, labeled_hash, AsHashTree, Hash, RbTree};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use base64::{Engine as _, engine::general_purpose};
+use hmac::{Hmac, Mac};
+use crate::{Error, Result};
+
+type HmacSha256 = Hmac<Sha256>;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Blobs are stored and served in chunks of this size
+pub const BLOB_CHUNK_SIZE: usize = 1_000_000;
+
+// Largest attachment accepted through uploads
+pub const MAX_UPLOAD_BYTES: u64 = 32 * 1024 * 1024;
+
+// Bytes each user may hold across their blobs and uploads in progress
+pub const USER_QUOTA_BYTES: u64 = 256 * 1024 * 1024;
+
+// Uploads not committed within a day are discarded
+const UPLOAD_TTL_NS: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Longest client-chosen upload ID
+const MAX_UPLOAD_ID_LEN: usize = 64;
+
+// Types blobs are stored with. Anything else (HTML and SVG included) could
+// run script when opened from the canister's origin.
+const ALLOWED_MIME_TYPES: &[&str] = &[
+    "image/jpeg", "image/png", "image/gif", "image/webp", "image/heic",
+    "video/mp4", "video/3gpp", "video/webm", "video/quicktime",
+    "audio/mpeg", "audio/ogg", "audio/aac", "audio/mp4", "audio/amr", "audio/webm", "audio/wav",
+    "application/pdf", "application/zip", "application/json", "application/octet-stream",
+    "application/msword", "application/vnd.ms-excel", "application/vnd.ms-powerpoint",
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+    "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation",
+    "text/plain", "text/markdown", "text/csv", "text/tab-separated-values",
+];
+
+// Stored for types off the allowlist
+pub const FALLBACK_MIME_TYPE: &str = "application/octet-stream";
+
+// Label of the asset tree in the canister's certified data
+const ASSETS_LABEL: &[u8] = b"http_assets";
+
+// Stored content, addressed by the SHA-256 of its bytes
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlobInfo {
+    pub hash: String,
+    pub mime_type: String,
+    pub size: u64,
+    pub chunk_count: u32,
+    pub created_at: u64,
+}
+
+// An upload in progress
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingUpload {
+    pub size: u64,
+    pub chunk_count: u32,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlobQuota {
+    pub used: u64,
+    pub pending: u64,
+    pub limit: u64,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Blob metadata by hex SHA-256
+    static BLOBS: RefCell<StableBTreeMap<String, BlobInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+        )
+    );
+    
+    // Blob contents by (hash, chunk index)
+    static BLOB_CHUNKS: RefCell<StableBTreeMap<(String, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))),
+        )
+    );
+    
+    // Users holding each blob, keyed (hash, user), with the bytes charged to them
+    static BLOB_OWNERS: RefCell<StableBTreeMap<(String, String), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        )
+    );
+    
+    // Bytes charged to each user for the blobs they hold
+    static USAGE: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+        )
+    );
+    
+    // Uploads in progress by "<user>:<upload id>"
+    static UPLOADS: RefCell<StableBTreeMap<String, PendingUpload, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+        )
+    );
+    
+    // Uploaded chunks by (upload key, chunk index)
+    static UPLOAD_CHUNKS: RefCell<StableBTreeMap<(String, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))),
+        )
+    );
+    
+    // Searchable text extracted from each document blob
+    static BLOB_TEXT: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+        )
+    );
+    
+    // Content served in place of a blob, by hash (an image with its GPS
+    // tags removed)
+    static SERVED_AS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+        )
+    );
+    
+    // Document blobs waiting for text extraction, keyed (hash, message ID) so
+    // the messages attaching them are reindexed once the text is known
+    static PENDING_EXTRACTIONS: RefCell<StableBTreeMap<(String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))),
+        )
+    );
+    
+    // The same queue by (owner, hash), so each holder of a queued blob can
+    // find the ones they may trigger extraction for
+    static EXTRACTION_QUEUE: RefCell<StableBTreeMap<(String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+        )
+    );
+    
+    // Secret each user's blob URLs are signed with, by user
+    static URL_KEYS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))),
+        )
+    );
+    
+    // Certified body hash of each served path. Rebuilt from BLOBS after an upgrade.
+    static ASSET_HASHES: RefCell<RbTree<Vec<u8>, Hash>> = RefCell::new(RbTree::new());
+}
+
+// Hex SHA-256 of some bytes
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Path the blob is served from by `http_request`
+pub fn blob_path(hash: &str) -> String {
+    format!("/blobs/{}", hash)
+}
+
+// URL through which an owner shares a blob. Content hashes aren't secret, so
+// the path alone grants nothing; the token proves the owner handed it out.
+// None until the owner's key exists or when they don't hold the blob.
+pub fn blob_url(hash: &str, owner: &str) -> Option<String> {
+    if !is_owner(hash, owner) {
+        return None;
+    }
+    let key = URL_KEYS.with(|keys| keys.borrow().get(&owner.to_string()))?;
+    Some(format!("{}?token={}", blob_path(hash), url_token(&key, hash)))
+}
+
+// Create the key a user's blob URLs are signed with, once
+pub async fn ensure_url_key(owner: &str) -> Result<()> {
+    if URL_KEYS.with(|keys| keys.borrow().contains_key(&owner.to_string())) {
+        return Ok(());
+    }
+    
+    let (key,) = ic_cdk::api::management_canister::main::raw_rand().await
+        .map_err(|(code, message)| Error::InternalError(format!("Failed to get randomness: {:?} {}", code, message)))?;
+    
+    // Another call may have created it while this one waited
+    URL_KEYS.with(|keys| {
+        let mut keys = keys.borrow_mut();
+        if !keys.contains_key(&owner.to_string()) {
+            keys.insert(owner.to_string(), key);
+        }
+    });
+    Ok(())
+}
+
+// Whether a URL token was issued for the blob by one of its current owners.
+// Removing a blob from an owner revokes the URLs they shared.
+pub fn url_token_valid(hash: &str, token: &str) -> bool {
+    let token = match hash_bytes(token) {
+        Some(token) => token,
+        None => return false,
+    };
+    
+    let owners: Vec<String> = BLOB_OWNERS.with(|owners| {
+        owners.borrow()
+            .range((hash.to_string(), String::new())..)
+            .take_while(|((h, _), _)| h == hash)
+            .map(|((_, owner), _)| owner)
+            .collect()
+    });
+    
+    owners.iter().any(|owner| {
+        URL_KEYS.with(|keys| keys.borrow().get(owner))
+            .and_then(|key| HmacSha256::new_from_slice(&key).ok())
+            .map(|mut mac| {
+                mac.update(hash.as_bytes());
+                mac.verify_slice(&token).is_ok()
+            })
+            .unwrap_or(false)
+    })
+}
+
+fn url_token(key: &[u8], hash: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(hash.as_bytes());
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// A MIME type on the allowlist, lowercased and without parameters
+pub fn allowed_mime_type(mime_type: &str) -> Option<String> {
+    let mime_type = mime_type.split(';').next().unwrap_or("").trim().to_lowercase();
+    ALLOWED_MIME_TYPES.contains(&mime_type.as_str()).then_some(mime_type)
+}
+
+// Images on the allowlist are shown in the browser; everything else downloads
+pub fn is_inline_image(mime_type: &str) -> bool {
+    allowed_mime_type(mime_type).map(|m| m.starts_with("image/")).unwrap_or(false)
+}
+
+// Store content unless a blob with the same hash exists, and charge it to
+// the owner. New images are queued for thumbnails and metadata. Returns the hash.
+pub fn store_blob(data: &[u8], mime_type: &str, owner: &str) -> String {
+    let (hash, is_new) = insert_blob(data, mime_type, owner);
+    if is_new && mime_type.starts_with("image/") {
+        super::media::enqueue(&hash, owner);
+    }
+    hash
+}
+
+// Store content generated from another blob (thumbnails, cleaned copies),
+// which is not processed again
+pub fn store_derived_blob(data: &[u8], mime_type: &str, owner: &str) -> String {
+    insert_blob(data, mime_type, owner).0
+}
+
+fn insert_blob(data: &[u8], mime_type: &str, owner: &str) -> (String, bool) {
+    let hash = sha256_hex(data);
+    if get_blob_info(&hash).is_some() {
+        add_owner(&hash, owner);
+        return (hash, false);
+    }
+    
+    let mut chunk_count = 0;
+    BLOB_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for (index, chunk) in data.chunks(BLOB_CHUNK_SIZE).enumerate() {
+            chunks.insert((hash.clone(), index as u32), chunk.to_vec());
+            chunk_count += 1;
+        }
+    });
+    
+    BLOBS.with(|blobs| {
+        blobs.borrow_mut().insert(hash.clone(), BlobInfo {
+            hash: hash.clone(),
+            mime_type: mime_type.to_string(),
+            size: data.len() as u64,
+            chunk_count,
+            created_at: ic_cdk::api::time(),
+        });
+    });
+    
+    certify(&hash);
+    add_owner(&hash, owner);
+    (hash, true)
+}
+
+// Charge a stored blob to a user, once however often they store it
+pub fn add_owner(hash: &str, owner: &str) {
+    let size = match get_blob_info(hash) {
+        Some(blob) => blob.size,
+        None => return,
+    };
+    
+    let key = (hash.to_string(), owner.to_string());
+    let added = BLOB_OWNERS.with(|owners| {
+        let mut owners = owners.borrow_mut();
+        if owners.contains_key(&key) {
+            false
+        } else {
+            owners.insert(key, size);
+            true
+        }
+    });
+    
+    if added {
+        USAGE.with(|usage| {
+            let mut usage = usage.borrow_mut();
+            let used = usage.get(&owner.to_string()).unwrap_or(0);
+            usage.insert(owner.to_string(), used + size);
+        });
+        
+        // New holders of a queued image can trigger its processing too
+        if super::media::is_pending(hash) {
+            super::media::add_holder(hash, owner);
+        }
+        
+        // New holders of a queued document can trigger its extraction too
+        if is_extraction_pending(hash) {
+            EXTRACTION_QUEUE.with(|queue| {
+                queue.borrow_mut().insert((owner.to_string(), hash.to_string()), ());
+            });
+        }
+    }
+}
+
+// Fail when charging `size` more bytes would take a user over their quota
+pub fn check_quota(user: &str, size: u64) -> Result<()> {
+    let quota = get_quota(user);
+    if quota.used + quota.pending + size > quota.limit {
+        return Err(Error::InvalidParameters(format!(
+            "Storage quota of {} bytes exceeded", quota.limit
+        )));
+    }
+    Ok(())
+}
+
+// Stop charging a blob to a user and refund its size. Blobs nobody holds
+// any more are deleted along with the thumbnail and cleaned copy made from
+// them. Returns false when the user didn't hold the blob.
+pub fn remove_owner(hash: &str, owner: &str) -> bool {
+    let charged = BLOB_OWNERS.with(|owners| {
+        owners.borrow_mut().remove(&(hash.to_string(), owner.to_string()))
+    });
+    let charged = match charged {
+        Some(charged) => charged,
+        None => return false,
+    };
+    
+    USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let used = usage.get(&owner.to_string()).unwrap_or(0);
+        usage.insert(owner.to_string(), used.saturating_sub(charged));
+    });
+    
+    EXTRACTION_QUEUE.with(|queue| {
+        queue.borrow_mut().remove(&(owner.to_string(), hash.to_string()));
+    });
+    super::media::remove_holder(hash, owner);
+    
+    if !has_owners(hash) {
+        let mut derived: Vec<String> = super::media::get_image(hash)
+            .and_then(|image| image.thumbnail_hash)
+            .into_iter()
+            .collect();
+        derived.push(served_hash(hash));
+        
+        delete_blob(hash);
+        for derived_hash in derived.iter().filter(|h| h.as_str() != hash) {
+            remove_owner(derived_hash, owner);
+        }
+    }
+    
+    true
+}
+
+// Everyone holding a blob
+pub fn owners(hash: &str) -> Vec<String> {
+    BLOB_OWNERS.with(|owners| {
+        owners.borrow()
+            .range((hash.to_string(), String::new())..)
+            .take_while(|((h, _), _)| h == hash)
+            .map(|((_, owner), _)| owner)
+            .collect()
+    })
+}
+
+fn has_owners(hash: &str) -> bool {
+    BLOB_OWNERS.with(|owners| {
+        owners.borrow()
+            .range((hash.to_string(), String::new())..)
+            .next()
+            .map(|((h, _), _)| h == hash)
+            .unwrap_or(false)
+    })
+}
+
+// Remove a blob's content, metadata and certified path
+fn delete_blob(hash: &str) {
+    let chunk_count = get_blob_info(hash).map(|blob| blob.chunk_count).unwrap_or(0);
+    BLOB_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..chunk_count {
+            chunks.remove(&(hash.to_string(), index));
+        }
+    });
+    BLOBS.with(|blobs| {
+        blobs.borrow_mut().remove(&hash.to_string());
+    });
+    BLOB_TEXT.with(|text| {
+        text.borrow_mut().remove(&hash.to_string());
+    });
+    SERVED_AS.with(|served| {
+        served.borrow_mut().remove(&hash.to_string());
+    });
+    super::media::remove_image(hash);
+    take_extraction(hash);
+    
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.delete(blob_path(hash).as_bytes());
+        ic_cdk::api::set_certified_data(&labeled_hash(ASSETS_LABEL, &tree.root_hash()));
+    });
+}
+
+pub fn is_owner(hash: &str, user: &str) -> bool {
+    BLOB_OWNERS.with(|owners| {
+        owners.borrow().contains_key(&(hash.to_string(), user.to_string()))
+    })
+}
+
+pub fn get_quota(user: &str) -> BlobQuota {
+    BlobQuota {
+        used: USAGE.with(|usage| usage.borrow().get(&user.to_string()).unwrap_or(0)),
+        pending: user_uploads(user).iter().map(|(_, upload)| upload.size).sum(),
+        limit: USER_QUOTA_BYTES,
+    }
+}
+
+// Store one chunk of an upload. Chunks may arrive in any order and a chunk
+// sent again replaces the earlier one. Returns the bytes received so far.
+pub fn upload_chunk(user: &str, upload_id: &str, index: u32, data: Vec<u8>) -> Result<u64> {
+    if upload_id.is_empty()
+        || upload_id.len() > MAX_UPLOAD_ID_LEN
+        || !upload_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(Error::InvalidParameters(
+            "Upload IDs are 1-64 letters, digits, '-' or '_'".to_string()
+        ));
+    }
+    if data.is_empty() || data.len() > BLOB_CHUNK_SIZE {
+        return Err(Error::InvalidParameters(format!(
+            "Chunks must be 1 to {} bytes", BLOB_CHUNK_SIZE
+        )));
+    }
+    
+    let now = ic_cdk::api::time();
+    expire_uploads(user, now);
+    
+    let key = upload_key(user, upload_id);
+    let mut upload = UPLOADS.with(|uploads| uploads.borrow().get(&key))
+        .unwrap_or(PendingUpload { size: 0, chunk_count: 0, updated_at: now });
+    
+    let replaced = UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow().get(&(key.clone(), index)).map(|chunk| chunk.len() as u64)
+    });
+    let size = upload.size - replaced.unwrap_or(0) + data.len() as u64;
+    if size > MAX_UPLOAD_BYTES {
+        return Err(Error::InvalidParameters(format!(
+            "Attachments are limited to {} bytes", MAX_UPLOAD_BYTES
+        )));
+    }
+    
+    check_quota(user, size.saturating_sub(upload.size))?;
+    
+    UPLOAD_CHUNKS.with(|chunks| {
+        chunks.borrow_mut().insert((key.clone(), index), data);
+    });
+    
+    upload.size = size;
+    if replaced.is_none() {
+        upload.chunk_count += 1;
+    }
+    upload.updated_at = now;
+    UPLOADS.with(|uploads| {
+        uploads.borrow_mut().insert(key, upload);
+    });
+    
+    Ok(size)
+}
+
+// Assemble an upload's chunks, in index order, into a blob. The chunks must
+// be numbered from 0 without gaps, and the type must be on the allowlist.
+pub fn commit_upload(user: &str, upload_id: &str, mime_type: &str) -> Result<BlobInfo> {
+    let mime_type = allowed_mime_type(mime_type)
+        .ok_or_else(|| Error::InvalidParameters(format!("Attachments of type {} are not accepted", mime_type)))?;
+    let key = upload_key(user, upload_id);
+    let upload = UPLOADS.with(|uploads| uploads.borrow().get(&key))
+        .ok_or_else(|| Error::InvalidParameters(format!("No upload {}", upload_id)))?;
+    
+    let mut data = Vec::with_capacity(upload.size as usize);
+    for index in 0..upload.chunk_count {
+        let chunk = UPLOAD_CHUNKS.with(|chunks| chunks.borrow().get(&(key.clone(), index)))
+            .ok_or_else(|| Error::InvalidParameters(format!(
+                "Upload {} is missing chunk {}", upload_id, index
+            )))?;
+        data.extend_from_slice(&chunk);
+    }
+    
+    remove_upload(&key);
+    
+    let hash = store_blob(&data, &mime_type, user);
+    get_blob_info(&hash)
+        .ok_or_else(|| Error::InternalError(format!("Blob {} was not stored", hash)))
+}
+
+fn upload_key(user: &str, upload_id: &str) -> String {
+    format!("{}:{}", user, upload_id)
+}
+
+fn user_uploads(user: &str) -> Vec<(String, PendingUpload)> {
+    let prefix = format!("{}:", user);
+    UPLOADS.with(|uploads| {
+        uploads.borrow()
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .collect()
+    })
+}
+
+// Discard a user's uploads that stopped receiving chunks
+fn expire_uploads(user: &str, now: u64) {
+    for (key, upload) in user_uploads(user) {
+        if now.saturating_sub(upload.updated_at) > UPLOAD_TTL_NS {
+            remove_upload(&key);
+        }
+    }
+}
+
+fn remove_upload(key: &str) {
+    UPLOAD_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        let indices: Vec<u32> = chunks.range((key.to_string(), 0)..)
+            .take_while(|((k, _), _)| k == key)
+            .map(|((_, index), _)| index)
+            .collect();
+        for index in indices {
+            chunks.remove(&(key.to_string(), index));
+        }
+    });
+    UPLOADS.with(|uploads| {
+        uploads.borrow_mut().remove(&key.to_string());
+    });
+}
+
+pub fn get_blob_info(hash: &str) -> Option<BlobInfo> {
+    BLOBS.with(|blobs| {
+        blobs.borrow().get(hash)
+    })
+}
+
+pub fn get_chunk(hash: &str, index: u32) -> Option<Vec<u8>> {
+    BLOB_CHUNKS.with(|chunks| {
+        chunks.borrow().get(&(hash.to_string(), index))
+    })
+}
+
+// Whole content of a blob, reassembled from its chunks
+pub fn get_blob_data(hash: &str) -> Option<Vec<u8>> {
+    let blob = get_blob_info(hash)?;
+    let mut data = Vec::with_capacity(blob.size as usize);
+    for index in 0..blob.chunk_count {
+        data.extend_from_slice(&get_chunk(hash, index)?);
+    }
+    Some(data)
+}
+
+// Extracted text, cached per blob so shared files are only read once.
+// An empty string records that the blob has no extractable text.
+pub fn get_blob_text(hash: &str) -> Option<String> {
+    BLOB_TEXT.with(|text| {
+        text.borrow().get(&hash.to_string())
+    })
+}
+
+pub fn store_blob_text(hash: &str, text: String) {
+    BLOB_TEXT.with(|cache| {
+        cache.borrow_mut().insert(hash.to_string(), text);
+    });
+}
+
+// Queue a blob for text extraction on behalf of a message attaching it
+pub fn enqueue_extraction(hash: &str, message_id: &str) {
+    PENDING_EXTRACTIONS.with(|queue| {
+        queue.borrow_mut().insert((hash.to_string(), message_id.to_string()), ());
+    });
+    EXTRACTION_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        for owner in owners(hash) {
+            queue.insert((owner, hash.to_string()), ());
+        }
+    });
+}
+
+fn is_extraction_pending(hash: &str) -> bool {
+    PENDING_EXTRACTIONS.with(|queue| {
+        queue.borrow()
+            .range((hash.to_string(), String::new())..)
+            .next()
+            .map(|((h, _), _)| h == hash)
+            .unwrap_or(false)
+    })
+}
+
+// Up to `limit` queued blobs held by a user, without removing them
+pub fn pending_extractions(owner: &str, limit: usize) -> Vec<String> {
+    EXTRACTION_QUEUE.with(|queue| {
+        queue.borrow()
+            .range((owner.to_string(), String::new())..)
+            .take_while(|((o, _), _)| o == owner)
+            .take(limit)
+            .map(|((_, hash), _)| hash)
+            .collect()
+    })
+}
+
+// Remove a blob from the extraction queue, returning the messages that waited on it
+pub fn take_extraction(hash: &str) -> Vec<String> {
+    EXTRACTION_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        for owner in owners(hash) {
+            queue.remove(&(owner, hash.to_string()));
+        }
+    });
+    PENDING_EXTRACTIONS.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let keys: Vec<(String, String)> = queue.range((hash.to_string(), String::new())..)
+            .take_while(|((h, _), _)| h == hash)
+            .map(|(key, _)| key)
+            .collect();
+        for key in &keys {
+            queue.remove(key);
+        }
+        keys.into_iter().map(|(_, message_id)| message_id).collect()
+    })
+}
+
+// Serve another blob's content at a blob's path, e.g. a copy without
+// location data. The original stays stored but is no longer served.
+pub fn serve_as(hash: &str, replacement: &str) {
+    SERVED_AS.with(|served| {
+        served.borrow_mut().insert(hash.to_string(), replacement.to_string());
+    });
+    certify(hash);
+}
+
+// Hash of the content actually served for a blob
+pub fn served_hash(hash: &str) -> String {
+    SERVED_AS.with(|served| served.borrow().get(&hash.to_string()))
+        .unwrap_or_else(|| hash.to_string())
+}
+
+// Add a blob's path to the certified asset tree
+fn certify(hash: &str) {
+    let body_hash = match hash_bytes(&served_hash(hash)) {
+        Some(body_hash) => body_hash,
+        None => return,
+    };
+    
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        tree.insert(blob_path(hash).into_bytes(), body_hash);
+        ic_cdk::api::set_certified_data(&labeled_hash(ASSETS_LABEL, &tree.root_hash()));
+    });
+}
+
+// Re-certify every stored blob (the tree lives on the heap, so after an upgrade)
+pub fn certify_all() {
+    let hashes: Vec<String> = BLOBS.with(|blobs| {
+        blobs.borrow().iter().map(|(hash, _)| hash).collect()
+    });
+    
+    ASSET_HASHES.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        for hash in &hashes {
+            if let Some(body_hash) = hash_bytes(&served_hash(hash)) {
+                tree.insert(blob_path(hash).into_bytes(), body_hash);
+            }
+        }
+        ic_cdk::api::set_certified_data(&labeled_hash(ASSETS_LABEL, &tree.root_hash()));
+    });
+}
+
+// `IC-Certificate` header value proving a path's body hash
+pub fn certificate_header(path: &str) -> Option<String> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    
+    // The witness borrows the tree, so it is encoded while the tree is borrowed
+    let witness = ASSET_HASHES.with(|tree| {
+        let tree = tree.borrow();
+        let witness = labeled(ASSETS_LABEL, tree.witness(path.as_bytes()));
+        
+        let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+        serializer.self_describe().ok()?;
+        witness.serialize(&mut serializer).ok()?;
+        Some(serializer.into_inner())
+    })?;
+    
+    Some(format!(
+        "certificate=:{}:, tree=:{}:",
+        general_purpose::STANDARD.encode(certificate),
+        general_purpose::STANDARD.encode(witness)
+    ))
+}
+
+// Bytes of a hex SHA-256 (content hashes, which are also the certified body
+// hashes, and URL tokens)
+fn hash_bytes(hash: &str) -> Option<Hash> {
+    if hash.len() != 64 {
+        return None;
+    }
+    
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hash.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
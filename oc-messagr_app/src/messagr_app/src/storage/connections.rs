@@ -0,0 +1,204 @@
+use crate::{Connection, Platform};
+use crate::connectors::scope::SyncRules;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Longest label accepted for a connection
+pub const MAX_LABEL_LEN: usize = 64;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Platform accounts and workspaces, keyed "<principal>:<connection id>"
+    static CONNECTIONS: RefCell<StableBTreeMap<String, Connection, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))),
+        )
+    );
+    
+    // Sync scope rules, keyed like the connection they belong to
+    static SYNC_RULES: RefCell<StableBTreeMap<String, SyncRules, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+        )
+    );
+    
+    // Conversations each connection synced, keyed (connection key, conversation
+    // id). Conversations are shared between users, so connection filters go by
+    // this rather than the conversation's own connection_id.
+    static CONNECTION_CONVERSATIONS: RefCell<StableBTreeMap<(String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+        )
+    );
+}
+
+// Register a new connection for the owner. Without a label it is named after
+// the platform, numbered when the owner already has one.
+pub fn create_connection(owner: &str, platform: Platform, label: Option<String>) -> Connection {
+    let platform_str = platform_to_string(&platform);
+    let existing = list_connections(owner, Some(&platform)).len();
+    let now = ic_cdk::api::time();
+    
+    // Unique across owners, so conversations can be tagged with the id alone
+    let mut hasher = Sha256::new();
+    hasher.update(owner.as_bytes());
+    hasher.update(platform_str.as_bytes());
+    hasher.update(now.to_be_bytes());
+    hasher.update((existing as u64).to_be_bytes());
+    let hash: String = hasher.finalize()[..6].iter().map(|b| format!("{:02x}", b)).collect();
+    
+    let label = label.unwrap_or_else(|| match existing {
+        0 => default_label(&platform).to_string(),
+        n => format!("{} {}", default_label(&platform), n + 1),
+    });
+    
+    let connection = Connection {
+        id: format!("{}-{}", platform_str, hash),
+        platform,
+        label,
+        created_at: now / 1_000_000,
+        last_synced_at: None,
+    };
+    
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().insert(connection_key(owner, &connection.id), connection.clone());
+    });
+    connection
+}
+
+pub fn get_connection(owner: &str, connection_id: &str) -> Option<Connection> {
+    CONNECTIONS.with(|connections| {
+        connections.borrow().get(&connection_key(owner, connection_id))
+    })
+}
+
+// The owner's connections, oldest first
+pub fn list_connections(owner: &str, platform: Option<&Platform>) -> Vec<Connection> {
+    let prefix = format!("{}:", owner);
+    let platform_str = platform.map(platform_to_string);
+    
+    let mut connections: Vec<Connection> = CONNECTIONS.with(|connections| {
+        connections.borrow().range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, connection)| connection)
+            .filter(|connection| platform_str.as_ref().map_or(true, |p| *p == platform_to_string(&connection.platform)))
+            .collect()
+    });
+    connections.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+    connections
+}
+
+pub fn set_label(owner: &str, connection_id: &str, label: String) -> Option<Connection> {
+    let mut connection = get_connection(owner, connection_id)?;
+    connection.label = label;
+    
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().insert(connection_key(owner, connection_id), connection.clone());
+    });
+    Some(connection)
+}
+
+pub fn record_sync(owner: &str, connection_id: &str) {
+    if let Some(mut connection) = get_connection(owner, connection_id) {
+        connection.last_synced_at = Some(ic_cdk::api::time() / 1_000_000);
+        CONNECTIONS.with(|connections| {
+            connections.borrow_mut().insert(connection_key(owner, connection_id), connection);
+        });
+    }
+}
+
+pub fn remove_connection(owner: &str, connection_id: &str) -> bool {
+    SYNC_RULES.with(|rules| {
+        rules.borrow_mut().remove(&connection_key(owner, connection_id));
+    });
+    CONNECTION_CONVERSATIONS.with(|tags| {
+        let mut tags = tags.borrow_mut();
+        for conversation_id in conversation_ids(owner, connection_id) {
+            tags.remove(&(connection_key(owner, connection_id), conversation_id));
+        }
+    });
+    CONNECTIONS.with(|connections| {
+        connections.borrow_mut().remove(&connection_key(owner, connection_id)).is_some()
+    })
+}
+
+// Record that the owner's connection synced a conversation
+pub fn tag_conversation(owner: &str, connection_id: &str, conversation_id: &str) {
+    CONNECTION_CONVERSATIONS.with(|tags| {
+        tags.borrow_mut().insert((connection_key(owner, connection_id), conversation_id.to_string()), ());
+    });
+}
+
+// Conversations the owner's connection synced
+pub fn conversation_ids(owner: &str, connection_id: &str) -> HashSet<String> {
+    let key = connection_key(owner, connection_id);
+    CONNECTION_CONVERSATIONS.with(|tags| {
+        tags.borrow()
+            .range((key.clone(), String::new())..)
+            .take_while(|((tagged, _), _)| *tagged == key)
+            .map(|((_, conversation_id), _)| conversation_id)
+            .collect()
+    })
+}
+
+// The connection's sync scope; connections without rules sync everything
+pub fn get_rules(owner: &str, connection_id: &str) -> SyncRules {
+    SYNC_RULES.with(|rules| {
+        rules.borrow().get(&connection_key(owner, connection_id)).unwrap_or_default()
+    })
+}
+
+pub fn set_rules(owner: &str, connection_id: &str, sync_rules: SyncRules) {
+    SYNC_RULES.with(|rules| {
+        rules.borrow_mut().insert(connection_key(owner, connection_id), sync_rules);
+    });
+}
+
+// Find the owner's connection by id or by label, where the label's spaces
+// may be written as dashes ("acme-slack" for "Acme Slack")
+pub fn resolve(owner: &str, name: &str) -> Option<Connection> {
+    let name = name.to_lowercase();
+    
+    list_connections(owner, None).into_iter().find(|connection| {
+        connection.id == name || label_slug(&connection.label) == name
+    })
+}
+
+fn label_slug(label: &str) -> String {
+    label.to_lowercase().split_whitespace().collect::<Vec<_>>().join("-")
+}
+
+fn connection_key(owner: &str, connection_id: &str) -> String {
+    format!("{}:{}", owner, connection_id)
+}
+
+fn default_label(platform: &Platform) -> &'static str {
+    match platform {
+        Platform::Telegram => "Telegram",
+        Platform::Slack => "Slack",
+        Platform::Discord => "Discord",
+        Platform::Twitter => "Twitter",
+        Platform::Facebook => "Facebook Messenger",
+        Platform::WhatsApp => "WhatsApp",
+        Platform::Instagram => "Instagram Direct",
+    }
+}
+
+fn platform_to_string(platform: &Platform) -> String {
+    match platform {
+        Platform::Telegram => "telegram".to_string(),
+        Platform::Slack => "slack".to_string(),
+        Platform::Discord => "discord".to_string(),
+        Platform::Twitter => "twitter".to_string(),
+        Platform::Facebook => "facebook".to_string(),
+        Platform::WhatsApp => "whatsapp".to_string(),
+        Platform::Instagram => "instagram".to_string(),
+    }
+}
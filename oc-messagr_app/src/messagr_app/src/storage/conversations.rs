@@ -71,6 +71,23 @@ pub fn get_conversation(conversation_id: &str) -> Option<Conversation> {
     })
 }
 
+// The stored participants of a conversation plus those of `participants` not
+// already among them. A channel synced through several users' connections is
+// stored once, so each sync adds its caller rather than replacing the others.
+pub fn merge_participants(conversation_id: &str, participants: Vec<User>) -> Vec<User> {
+    let mut merged = get_conversation(conversation_id)
+        .map(|c| c.participants)
+        .unwrap_or_default();
+    
+    for participant in participants {
+        if !merged.iter().any(|p| p.id == participant.id) {
+            merged.push(participant);
+        }
+    }
+    
+    merged
+}
+
 pub fn get_user_conversations(user_id: &str, platform: Option<Platform>) -> Vec<Conversation> {
     // Get conversation IDs for the user
     let conversation_ids = USER_CONV_INDEX.with(|index| {
@@ -151,5 +168,6 @@ fn platform_to_string(platform: &Platform) -> String {
         Platform::Twitter => "twitter".to_string(),
         Platform::Facebook => "facebook".to_string(),
         Platform::WhatsApp => "whatsapp".to_string(),
+        Platform::Instagram => "instagram".to_string(),
     }
 }
\ No newline at end of file
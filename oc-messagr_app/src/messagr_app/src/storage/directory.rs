@@ -0,0 +1,31 @@
+use crate::User;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Platform users as connectors resolved them, keyed
+    // "<platform>:<workspace>:<user id>"
+    static DIRECTORY: RefCell<StableBTreeMap<String, User, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+        )
+    );
+}
+
+pub fn get_user(key: &str) -> Option<User> {
+    DIRECTORY.with(|directory| {
+        directory.borrow().get(&key.to_string())
+    })
+}
+
+pub fn store_user(key: &str, user: User) {
+    DIRECTORY.with(|directory| {
+        directory.borrow_mut().insert(key.to_string(), user);
+    });
+}
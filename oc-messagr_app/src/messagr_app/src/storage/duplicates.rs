@@ -0,0 +1,135 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // SimHash fingerprint by message ID
+    static FINGERPRINTS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+    
+    // LSH buckets: (band, band value) -> message IDs
+    static BAND_INDEX: RefCell<StableBTreeMap<(u8, u16), Vec<String>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+    
+    // Cluster ID by message ID (the cluster ID is the first message seen)
+    static MESSAGE_CLUSTERS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+    
+    // Members by cluster ID
+    static CLUSTER_MEMBERS: RefCell<StableBTreeMap<String, Vec<String>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+}
+
+pub fn get_fingerprint(message_id: &str) -> Option<u64> {
+    FINGERPRINTS.with(|store| {
+        store.borrow().get(message_id)
+    })
+}
+
+pub fn store_fingerprint(message_id: &str, fingerprint: u64) {
+    FINGERPRINTS.with(|store| {
+        store.borrow_mut().insert(message_id.to_string(), fingerprint);
+    });
+}
+
+pub fn delete_fingerprint(message_id: &str) -> Option<u64> {
+    FINGERPRINTS.with(|store| {
+        store.borrow_mut().remove(message_id)
+    })
+}
+
+pub fn get_band_members(band: u8, value: u16) -> Vec<String> {
+    BAND_INDEX.with(|index| {
+        index.borrow().get(&(band, value)).unwrap_or_default()
+    })
+}
+
+pub fn add_band_member(band: u8, value: u16, message_id: &str) {
+    BAND_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        let mut members = index.get(&(band, value)).unwrap_or_default();
+        if !members.iter().any(|id| id == message_id) {
+            members.push(message_id.to_string());
+            index.insert((band, value), members);
+        }
+    });
+}
+
+pub fn remove_band_member(band: u8, value: u16, message_id: &str) {
+    BAND_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(mut members) = index.get(&(band, value)) {
+            members.retain(|id| id != message_id);
+            if members.is_empty() {
+                index.remove(&(band, value));
+            } else {
+                index.insert((band, value), members);
+            }
+        }
+    });
+}
+
+pub fn get_cluster_id(message_id: &str) -> Option<String> {
+    MESSAGE_CLUSTERS.with(|clusters| {
+        clusters.borrow().get(message_id)
+    })
+}
+
+pub fn get_cluster_members(cluster_id: &str) -> Vec<String> {
+    CLUSTER_MEMBERS.with(|members| {
+        members.borrow().get(cluster_id).unwrap_or_default()
+    })
+}
+
+pub fn add_to_cluster(cluster_id: &str, message_id: &str) {
+    MESSAGE_CLUSTERS.with(|clusters| {
+        clusters.borrow_mut().insert(message_id.to_string(), cluster_id.to_string());
+    });
+    
+    CLUSTER_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        let mut ids = members.get(cluster_id).unwrap_or_default();
+        if !ids.iter().any(|id| id == message_id) {
+            ids.push(message_id.to_string());
+            members.insert(cluster_id.to_string(), ids);
+        }
+    });
+}
+
+// Remove a message from its cluster. The cluster keeps its ID even if the
+// message it was named after is removed, so other members stay grouped.
+pub fn remove_from_cluster(message_id: &str) {
+    let cluster_id = match MESSAGE_CLUSTERS.with(|clusters| clusters.borrow_mut().remove(message_id)) {
+        Some(cluster_id) => cluster_id,
+        None => return,
+    };
+    
+    CLUSTER_MEMBERS.with(|members| {
+        let mut members = members.borrow_mut();
+        if let Some(mut ids) = members.get(&cluster_id) {
+            ids.retain(|id| id != message_id);
+            if ids.is_empty() {
+                members.remove(&cluster_id);
+            } else {
+                members.insert(cluster_id, ids);
+            }
+        }
+    });
+}
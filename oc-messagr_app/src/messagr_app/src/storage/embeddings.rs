@@ -0,0 +1,232 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use crate::openchat::embeddings::EmbeddingConfig;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// A message embedding together with the model that produced it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StoredEmbedding {
+    pub model: String,
+    pub vector: Vec<f32>,
+}
+
+// A node of the HNSW graph: its top layer and its neighbours on each layer
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct VectorNode {
+    pub level: u8,
+    pub neighbors: Vec<Vec<String>>,
+}
+
+// A corpus-wide job on the vector index, advanced a batch per call
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IndexJob {
+    // Re-insert the stored vectors of this model; None queues every
+    // message for embedding instead (after a provider change)
+    pub rebuild_model: Option<String>,
+    // The old graph is still being removed
+    pub clearing: bool,
+    // Last message ID handled, in ID order
+    pub cursor: Option<String>,
+}
+
+// Key of the active configuration / entry point / job in the single-entry maps
+const ACTIVE_KEY: &str = "active";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Embedding by message ID
+    static EMBEDDING_STORE: RefCell<StableBTreeMap<String, StoredEmbedding, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+    
+    // Messages waiting to be embedded, keyed by (enqueue time, message ID) so
+    // the oldest are processed first
+    static PENDING_EMBEDDINGS: RefCell<StableBTreeMap<(u64, String), String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+    
+    // Active embedding provider configuration
+    static EMBEDDING_CONFIG: RefCell<StableBTreeMap<String, EmbeddingConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+    
+    // Vector index graph, by message ID
+    static VECTOR_NODES: RefCell<StableBTreeMap<String, VectorNode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+    
+    // Vector index entry point (message ID of the top-level node)
+    static VECTOR_ENTRY_POINT: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+    
+    // Running index job, if any
+    static INDEX_JOB: RefCell<StableBTreeMap<String, IndexJob, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+        )
+    );
+}
+
+pub fn get_embedding(message_id: &str) -> Option<StoredEmbedding> {
+    EMBEDDING_STORE.with(|store| {
+        store.borrow().get(message_id)
+    })
+}
+
+pub fn store_embedding(message_id: &str, embedding: StoredEmbedding) {
+    EMBEDDING_STORE.with(|store| {
+        store.borrow_mut().insert(message_id.to_string(), embedding);
+    });
+}
+
+pub fn delete_embedding(message_id: &str) {
+    EMBEDDING_STORE.with(|store| {
+        store.borrow_mut().remove(message_id);
+    });
+}
+
+// Up to `limit` embeddings with message IDs after `cursor` (for rebuilding the vector index)
+pub fn embeddings_after(cursor: Option<&str>, limit: usize) -> Vec<(String, StoredEmbedding)> {
+    EMBEDDING_STORE.with(|store| {
+        let store = store.borrow();
+        match cursor {
+            Some(cursor) => store.range(cursor.to_string()..)
+                .skip_while(|(id, _)| id == cursor)
+                .take(limit)
+                .collect(),
+            None => store.iter().take(limit).collect(),
+        }
+    })
+}
+
+pub fn embedding_count() -> u64 {
+    EMBEDDING_STORE.with(|store| store.borrow().len())
+}
+
+// Queue a message for embedding
+pub fn enqueue(message_id: &str) {
+    let now = ic_cdk::api::time();
+    
+    PENDING_EMBEDDINGS.with(|queue| {
+        queue.borrow_mut().insert((now, message_id.to_string()), message_id.to_string());
+    });
+}
+
+// Take up to `limit` of the oldest queued message IDs off the queue
+pub fn take_pending(limit: usize) -> Vec<String> {
+    PENDING_EMBEDDINGS.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let batch: Vec<((u64, String), String)> = queue.iter().take(limit).collect();
+        
+        for (key, _) in &batch {
+            queue.remove(key);
+        }
+        
+        batch.into_iter().map(|(_, message_id)| message_id).collect()
+    })
+}
+
+pub fn pending_count() -> u64 {
+    PENDING_EMBEDDINGS.with(|queue| queue.borrow().len())
+}
+
+pub fn get_config() -> Option<EmbeddingConfig> {
+    EMBEDDING_CONFIG.with(|store| {
+        store.borrow().get(ACTIVE_KEY)
+    })
+}
+
+pub fn set_config(config: EmbeddingConfig) {
+    EMBEDDING_CONFIG.with(|store| {
+        store.borrow_mut().insert(ACTIVE_KEY.to_string(), config);
+    });
+}
+
+pub fn get_vector_node(message_id: &str) -> Option<VectorNode> {
+    VECTOR_NODES.with(|nodes| {
+        nodes.borrow().get(message_id)
+    })
+}
+
+pub fn store_vector_node(message_id: &str, node: VectorNode) {
+    VECTOR_NODES.with(|nodes| {
+        nodes.borrow_mut().insert(message_id.to_string(), node);
+    });
+}
+
+pub fn delete_vector_node(message_id: &str) -> Option<VectorNode> {
+    VECTOR_NODES.with(|nodes| {
+        nodes.borrow_mut().remove(message_id)
+    })
+}
+
+// Node with the highest level (used to pick a new entry point)
+pub fn highest_vector_node() -> Option<(String, VectorNode)> {
+    VECTOR_NODES.with(|nodes| {
+        nodes.borrow().iter().max_by_key(|(_, node)| node.level)
+    })
+}
+
+// Remove up to `limit` graph nodes. Returns true once the graph is empty.
+pub fn clear_vector_nodes(limit: usize) -> bool {
+    VECTOR_ENTRY_POINT.with(|entry| {
+        entry.borrow_mut().remove(ACTIVE_KEY);
+    });
+    
+    VECTOR_NODES.with(|nodes| {
+        let mut nodes = nodes.borrow_mut();
+        let keys: Vec<String> = nodes.iter().take(limit).map(|(k, _)| k).collect();
+        for key in &keys {
+            nodes.remove(key);
+        }
+        nodes.is_empty()
+    })
+}
+
+pub fn get_entry_point() -> Option<String> {
+    VECTOR_ENTRY_POINT.with(|entry| {
+        entry.borrow().get(ACTIVE_KEY)
+    })
+}
+
+pub fn set_entry_point(message_id: Option<&str>) {
+    VECTOR_ENTRY_POINT.with(|entry| {
+        let mut entry = entry.borrow_mut();
+        match message_id {
+            Some(message_id) => { entry.insert(ACTIVE_KEY.to_string(), message_id.to_string()); },
+            None => { entry.remove(ACTIVE_KEY); },
+        }
+    });
+}
+
+pub fn get_index_job() -> Option<IndexJob> {
+    INDEX_JOB.with(|job| {
+        job.borrow().get(ACTIVE_KEY)
+    })
+}
+
+pub fn set_index_job(index_job: Option<IndexJob>) {
+    INDEX_JOB.with(|job| {
+        let mut job = job.borrow_mut();
+        match index_job {
+            Some(index_job) => { job.insert(ACTIVE_KEY.to_string(), index_job); },
+            None => { job.remove(ACTIVE_KEY); },
+        }
+    });
+}
@@ -0,0 +1,102 @@
+use crate::Message;
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Reply and reaction counts for a message
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MessageEngagement {
+    pub reply_count: u32,
+    pub reaction_count: u32,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Engagement by message ID
+    static ENGAGEMENT_STORE: RefCell<StableBTreeMap<String, MessageEngagement, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+    
+    // Read counts keyed by "user_id:conversation_id"
+    static CONVERSATION_READS: RefCell<StableBTreeMap<String, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+}
+
+pub fn get_engagement(message_id: &str) -> MessageEngagement {
+    ENGAGEMENT_STORE.with(|store| {
+        store.borrow().get(message_id).unwrap_or_default()
+    })
+}
+
+// Count a newly stored message as a reply to its parent (and thread root)
+pub fn record_reply(message: &Message) {
+    let mut parents: Vec<&String> = Vec::new();
+    if let Some(reply_to) = &message.reply_to {
+        parents.push(reply_to);
+    }
+    if let Some(thread_id) = &message.thread_id {
+        // The thread root itself isn't a reply
+        if thread_id != &message.id && !parents.contains(&thread_id) {
+            parents.push(thread_id);
+        }
+    }
+    
+    ENGAGEMENT_STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        for parent_id in parents {
+            let mut engagement = store.get(parent_id).unwrap_or_default();
+            engagement.reply_count += 1;
+            store.insert(parent_id.clone(), engagement);
+        }
+    });
+}
+
+// Record counts reported by the platform; never lowers what we've counted locally
+pub fn record_platform_counts(message_id: &str, reply_count: u32, reaction_count: u32) {
+    ENGAGEMENT_STORE.with(|store| {
+        let mut store = store.borrow_mut();
+        let mut engagement = store.get(message_id).unwrap_or_default();
+        engagement.reply_count = engagement.reply_count.max(reply_count);
+        engagement.reaction_count = reaction_count;
+        store.insert(message_id.to_string(), engagement);
+    });
+}
+
+pub fn delete_engagement(message_id: &str) {
+    ENGAGEMENT_STORE.with(|store| {
+        store.borrow_mut().remove(message_id);
+    });
+}
+
+// Note that a user opened a conversation
+pub fn record_conversation_read(user_id: &str, conversation_id: &str) {
+    let key = format!("{}:{}", user_id, conversation_id);
+    
+    CONVERSATION_READS.with(|reads| {
+        let mut reads = reads.borrow_mut();
+        let count = reads.get(&key).unwrap_or(0);
+        reads.insert(key, count.saturating_add(1));
+    });
+}
+
+// Read counts for every conversation the user has opened
+pub fn get_conversation_reads(user_id: &str) -> HashMap<String, u32> {
+    let prefix = format!("{}:", user_id);
+    
+    CONVERSATION_READS.with(|reads| {
+        reads.borrow().iter()
+            .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|conv_id| (conv_id.to_string(), v)))
+            .collect()
+    })
+}
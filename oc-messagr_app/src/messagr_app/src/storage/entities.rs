@@ -0,0 +1,163 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use crate::indexing::entities::EntityKind;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// An entity seen in at least one message
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EntityRecord {
+    pub key: String,
+    pub name: String,
+    pub kind: EntityKind,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Entity by key ("kind:normalized name")
+    static ENTITIES: RefCell<StableBTreeMap<String, EntityRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+    
+    // One entry per (entity key, message ID), so common entities never
+    // rewrite a growing list
+    static ENTITY_MESSAGES: RefCell<StableBTreeMap<(String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+    
+    // The same entries keyed (entity key, conversation ID, message ID), so
+    // lookups only read mentions in the caller's conversations
+    static CONVERSATION_MENTIONS: RefCell<StableBTreeMap<(String, String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+        )
+    );
+    
+    // Entity keys and mention counts by message ID
+    static MESSAGE_ENTITIES: RefCell<StableBTreeMap<String, Vec<(String, u32)>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+}
+
+pub fn get_entity(key: &str) -> Option<EntityRecord> {
+    ENTITIES.with(|entities| {
+        entities.borrow().get(key)
+    })
+}
+
+// Messages mentioning an entity, among the given conversations
+pub fn get_entity_messages(key: &str, conversation_ids: &HashSet<String>) -> Vec<String> {
+    CONVERSATION_MENTIONS.with(|index| {
+        let index = index.borrow();
+        conversation_ids.iter()
+            .flat_map(|conversation_id| {
+                index.range((key.to_string(), conversation_id.clone(), String::new())..)
+                    .take_while(|((entity_key, conversation, _), _)| entity_key == key && conversation == conversation_id)
+                    .map(|((_, _, message_id), _)| message_id)
+                    .collect::<Vec<String>>()
+            })
+            .collect()
+    })
+}
+
+fn is_mentioned(key: &str) -> bool {
+    ENTITY_MESSAGES.with(|index| {
+        index.borrow()
+            .range((key.to_string(), String::new())..)
+            .next()
+            .map_or(false, |((entity_key, _), _)| entity_key == key)
+    })
+}
+
+pub fn get_message_entities(message_id: &str) -> Vec<(String, u32)> {
+    MESSAGE_ENTITIES.with(|index| {
+        index.borrow().get(message_id).unwrap_or_default()
+    })
+}
+
+// Record the entities found in a message
+pub fn store_message_entities(message_id: &str, conversation_id: &str, entities: Vec<(EntityRecord, u32)>) {
+    let mut keys = Vec::with_capacity(entities.len());
+    
+    for (record, mentions) in entities {
+        let key = record.key.clone();
+        
+        ENTITIES.with(|store| {
+            let mut store = store.borrow_mut();
+            if !store.contains_key(&key) {
+                store.insert(key.clone(), record);
+            }
+        });
+        
+        ENTITY_MESSAGES.with(|index| {
+            index.borrow_mut().insert((key.clone(), message_id.to_string()), ());
+        });
+        
+        CONVERSATION_MENTIONS.with(|index| {
+            index.borrow_mut().insert((key.clone(), conversation_id.to_string(), message_id.to_string()), ());
+        });
+        
+        keys.push((key, mentions));
+    }
+    
+    if !keys.is_empty() {
+        MESSAGE_ENTITIES.with(|index| {
+            index.borrow_mut().insert(message_id.to_string(), keys);
+        });
+    }
+}
+
+// Forget a message's entities; entities no longer mentioned anywhere are dropped
+pub fn remove_message_entities(message_id: &str, conversation_id: &str) {
+    let keys = match MESSAGE_ENTITIES.with(|index| index.borrow_mut().remove(message_id)) {
+        Some(keys) => keys,
+        None => return,
+    };
+    
+    for (key, _) in keys {
+        ENTITY_MESSAGES.with(|index| {
+            index.borrow_mut().remove(&(key.clone(), message_id.to_string()));
+        });
+        
+        CONVERSATION_MENTIONS.with(|index| {
+            index.borrow_mut().remove(&(key.clone(), conversation_id.to_string(), message_id.to_string()));
+        });
+        
+        if !is_mentioned(&key) {
+            ENTITIES.with(|store| {
+                store.borrow_mut().remove(&key);
+            });
+        }
+    }
+}
+
+// Fill the per-conversation mention index from the global one (run after
+// upgrading from a version without it)
+pub fn backfill_conversation_mentions() {
+    let empty = CONVERSATION_MENTIONS.with(|index| index.borrow().is_empty());
+    if !empty {
+        return;
+    }
+    
+    ENTITY_MESSAGES.with(|mentions| {
+        CONVERSATION_MENTIONS.with(|index| {
+            let mut index = index.borrow_mut();
+            for ((key, message_id), _) in mentions.borrow().iter() {
+                if let Some(message) = crate::storage::messages::get_message(&message_id) {
+                    index.insert((key, message.conversation_id, message_id), ());
+                }
+            }
+        });
+    });
+}
@@ -0,0 +1,47 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use crate::openchat::types::QueryInsights;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Insights for a conversation, valid while its latest message and message
+// count are unchanged
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CachedInsights {
+    pub latest_timestamp: u64,
+    pub message_count: u64,
+    pub insights: QueryInsights,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Insights by conversation ID
+    static INSIGHTS_CACHE: RefCell<StableBTreeMap<String, CachedInsights, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+}
+
+// Cached insights, if they were generated from the same messages
+pub fn get_insights(conversation_id: &str, latest_timestamp: u64, message_count: u64) -> Option<QueryInsights> {
+    INSIGHTS_CACHE.with(|cache| {
+        cache.borrow().get(conversation_id)
+            .filter(|cached| cached.latest_timestamp == latest_timestamp && cached.message_count == message_count)
+            .map(|cached| cached.insights)
+    })
+}
+
+pub fn store_insights(conversation_id: &str, latest_timestamp: u64, message_count: u64, insights: QueryInsights) {
+    INSIGHTS_CACHE.with(|cache| {
+        cache.borrow_mut().insert(conversation_id.to_string(), CachedInsights {
+            latest_timestamp,
+            message_count,
+            insights,
+        });
+    });
+}
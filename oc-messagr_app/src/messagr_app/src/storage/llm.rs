@@ -0,0 +1,98 @@
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use crate::openchat::llm::LlmConfig;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Accumulated usage of one model
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LlmUsage {
+    pub calls: u64,
+    pub failed_calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cycles: u128,
+}
+
+// Key of the active configuration
+const ACTIVE_KEY: &str = "active";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Active LLM provider configuration
+    static LLM_CONFIG: RefCell<StableBTreeMap<String, LlmConfig, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+    
+    // Usage by model name
+    static LLM_USAGE: RefCell<StableBTreeMap<String, LlmUsage, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+    
+    // Position in the mock script, kept across upgrades
+    static MOCK_POSITION: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))),
+        )
+    );
+}
+
+pub fn get_config() -> Option<LlmConfig> {
+    LLM_CONFIG.with(|store| {
+        store.borrow().get(ACTIVE_KEY)
+    })
+}
+
+pub fn set_config(config: LlmConfig) {
+    LLM_CONFIG.with(|store| {
+        store.borrow_mut().insert(ACTIVE_KEY.to_string(), config);
+    });
+}
+
+// Index of the next scripted response, advancing the script
+pub fn next_mock_position() -> u64 {
+    MOCK_POSITION.with(|store| {
+        let mut store = store.borrow_mut();
+        let position = store.get(ACTIVE_KEY).unwrap_or(0);
+        store.insert(ACTIVE_KEY.to_string(), position + 1);
+        position
+    })
+}
+
+pub fn reset_mock_position() {
+    MOCK_POSITION.with(|store| {
+        store.borrow_mut().remove(ACTIVE_KEY);
+    });
+}
+
+// Add one call to a model's usage
+pub fn record_usage(model: &str, prompt_tokens: u64, completion_tokens: u64, cycles: u128, failed: bool) {
+    LLM_USAGE.with(|store| {
+        let mut store = store.borrow_mut();
+        let mut usage = store.get(model).unwrap_or_default();
+        
+        usage.calls += 1;
+        if failed {
+            usage.failed_calls += 1;
+        }
+        usage.prompt_tokens += prompt_tokens;
+        usage.completion_tokens += completion_tokens;
+        usage.cycles += cycles;
+        
+        store.insert(model.to_string(), usage);
+    });
+}
+
+pub fn get_usage() -> Vec<(String, LlmUsage)> {
+    LLM_USAGE.with(|store| {
+        store.borrow().iter().collect()
+    })
+}
@@ -0,0 +1,313 @@
+use crate::{Message, Platform};
+use candid::{CandidType, Deserialize};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+// What was learned from an image blob
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ImageInfo {
+    pub hash: String,
+    pub width: u32,
+    pub height: u32,
+    // EXIF capture time, ms since epoch (UTC when the camera gave no offset)
+    pub taken_at: Option<u64>,
+    pub camera: Option<String>,
+    pub thumbnail_hash: Option<String>,
+    // The original carried GPS tags, which are not served
+    pub had_location: bool,
+    pub error: Option<String>,
+    pub processed_at: u64,
+}
+
+// An image attachment, in gallery order
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MediaEntry {
+    pub message_id: String,
+    pub conversation_id: String,
+    pub attachment_index: u32,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct MediaFilters {
+    pub platform: Option<Platform>,
+    pub conversation_id: Option<String>,
+    pub start_time: Option<u64>,
+    pub end_time: Option<u64>,
+    pub limit: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MediaItem {
+    pub message_id: String,
+    pub conversation_id: String,
+    pub platform: Platform,
+    pub timestamp: u64,
+    pub name: Option<String>,
+    pub mime_type: Option<String>,
+    pub url: Option<String>,
+    pub blob_hash: Option<String>,
+    pub thumbnail_url: Option<String>,
+    pub image: Option<ImageInfo>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct MediaPage {
+    pub items: Vec<MediaItem>,
+    // Pass back to continue; None when the gallery is exhausted
+    pub next_cursor: Option<String>,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Image metadata and thumbnail by blob hash
+    static IMAGES: RefCell<StableBTreeMap<String, ImageInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+        )
+    );
+    
+    // Image blobs waiting to be processed, with the user who stored them
+    static PENDING_IMAGES: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+        )
+    );
+    
+    // The same queue by (holder, hash), so anyone holding a queued image
+    // can have it processed, not only the user who stored it
+    static PENDING_BY_HOLDER: RefCell<StableBTreeMap<(String, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))),
+        )
+    );
+    
+    // Each conversation's image attachments newest first, keyed
+    // (conversation_id, u64::MAX - timestamp, "<message id>#<index>")
+    static MEDIA_INDEX: RefCell<StableBTreeMap<(String, u64, String), MediaEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))),
+        )
+    );
+}
+
+pub fn get_image(hash: &str) -> Option<ImageInfo> {
+    IMAGES.with(|images| {
+        images.borrow().get(&hash.to_string())
+    })
+}
+
+pub fn store_image(info: ImageInfo) {
+    IMAGES.with(|images| {
+        images.borrow_mut().insert(info.hash.clone(), info);
+    });
+}
+
+// Forget a deleted blob's metadata and queue entry
+pub fn remove_image(hash: &str) {
+    IMAGES.with(|images| {
+        images.borrow_mut().remove(&hash.to_string());
+    });
+    remove_pending(hash);
+}
+
+// Queue an image blob for processing
+pub fn enqueue(hash: &str, owner: &str) {
+    PENDING_IMAGES.with(|queue| {
+        queue.borrow_mut().insert(hash.to_string(), owner.to_string());
+    });
+    for holder in super::blobs::owners(hash) {
+        add_holder(hash, &holder);
+    }
+}
+
+// Let a new holder of a queued image trigger its processing
+pub fn add_holder(hash: &str, holder: &str) {
+    PENDING_BY_HOLDER.with(|queue| {
+        queue.borrow_mut().insert((holder.to_string(), hash.to_string()), ());
+    });
+}
+
+pub fn remove_holder(hash: &str, holder: &str) {
+    PENDING_BY_HOLDER.with(|queue| {
+        queue.borrow_mut().remove(&(holder.to_string(), hash.to_string()));
+    });
+}
+
+// Up to `limit` queued images a user holds, without removing them
+pub fn pending_for(holder: &str, limit: usize) -> Vec<String> {
+    PENDING_BY_HOLDER.with(|queue| {
+        queue.borrow()
+            .range((holder.to_string(), String::new())..)
+            .take_while(|((h, _), _)| h == holder)
+            .take(limit)
+            .map(|((_, hash), _)| hash)
+            .collect()
+    })
+}
+
+pub fn remove_pending(hash: &str) {
+    for holder in super::blobs::owners(hash) {
+        remove_holder(hash, &holder);
+    }
+    PENDING_IMAGES.with(|queue| {
+        queue.borrow_mut().remove(&hash.to_string());
+    });
+}
+
+// Pending images are not served until their location data has been checked
+pub fn is_pending(hash: &str) -> bool {
+    PENDING_IMAGES.with(|queue| {
+        queue.borrow().contains_key(&hash.to_string())
+    })
+}
+
+// Update a message's gallery entries, replacing those of its previous version
+pub fn index_message(message: &Message, previous: Option<&Message>) {
+    if let Some(previous) = previous {
+        remove_message(previous);
+    }
+    
+    MEDIA_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (i, attachment) in message.content.attachments.iter().enumerate() {
+            if !is_image(&attachment.attachment_type, attachment.mime_type.as_deref()) {
+                continue;
+            }
+            index.insert(
+                media_key(message, i),
+                MediaEntry {
+                    message_id: message.id.clone(),
+                    conversation_id: message.conversation_id.clone(),
+                    attachment_index: i as u32,
+                },
+            );
+        }
+    });
+}
+
+pub fn remove_message(message: &Message) {
+    MEDIA_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for i in 0..message.content.attachments.len() {
+            index.remove(&media_key(message, i));
+        }
+    });
+}
+
+fn media_key(message: &Message, attachment_index: usize) -> (String, u64, String) {
+    (message.conversation_id.clone(), u64::MAX - message.timestamp, format!("{}#{}", message.id, attachment_index))
+}
+
+// A page of image attachments from the given conversations, newest first,
+// merged from a range scan of each. The cursor is the position and entry of
+// the last item on the previous page.
+pub fn list_media(filters: &MediaFilters, cursor: Option<String>, conversation_ids: &[String], owner: &str) -> MediaPage {
+    let limit = filters.limit.map(|l| l as usize).unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let after = cursor.as_deref().and_then(decode_cursor);
+    
+    // Newest first means the end time bounds where each scan starts
+    let (start_position, start_entry) = match (&after, filters.end_time) {
+        (Some(after), _) => after.clone(),
+        (None, Some(end_time)) => (u64::MAX - end_time, String::new()),
+        (None, None) => (0, String::new()),
+    };
+    let last_position = filters.start_time.map(|start| u64::MAX - start).unwrap_or(u64::MAX);
+    let after = after.as_ref();
+    
+    MEDIA_INDEX.with(|index| {
+        let index = index.borrow();
+        
+        let mut scans: Vec<_> = conversation_ids.iter()
+            .map(|conversation_id| {
+                index.range((conversation_id.clone(), start_position, start_entry.clone())..)
+                    .take_while(move |((conversation, position, _), _)| {
+                        conversation == conversation_id && *position <= last_position
+                    })
+                    // The cursor entry itself was on the previous page
+                    .filter(move |((_, position, entry), _)| {
+                        after.map_or(true, |(p, e)| p != position || e != entry)
+                    })
+            })
+            .collect();
+        
+        // Smallest position is the newest entry; each scan's head waits in `current`
+        let mut heads = BinaryHeap::new();
+        let mut current: Vec<Option<MediaEntry>> = Vec::with_capacity(scans.len());
+        for (i, scan) in scans.iter_mut().enumerate() {
+            current.push(scan.next().map(|((_, position, entry), media)| {
+                heads.push(Reverse((position, entry, i)));
+                media
+            }));
+        }
+        
+        let mut items = Vec::new();
+        let mut last_key = None;
+        while let Some(Reverse((position, entry, i))) = heads.pop() {
+            if items.len() >= limit {
+                return MediaPage {
+                    items,
+                    next_cursor: last_key.map(|key| encode_cursor(&key)),
+                };
+            }
+            
+            let media = current[i].take();
+            current[i] = scans[i].next().map(|((_, position, entry), media)| {
+                heads.push(Reverse((position, entry, i)));
+                media
+            });
+            
+            last_key = Some((position, entry));
+            if let Some(item) = media.and_then(|media| media_item(&media, owner)) {
+                items.push(item);
+            }
+        }
+        
+        MediaPage { items, next_cursor: None }
+    })
+}
+
+// Thumbnail URLs are signed for the user listing them
+fn media_item(entry: &MediaEntry, owner: &str) -> Option<MediaItem> {
+    let message = super::messages::get_message(&entry.message_id)?;
+    let attachment = message.content.attachments.get(entry.attachment_index as usize)?;
+    let image = attachment.blob_hash.as_deref().and_then(get_image);
+    let thumbnail_url = image.as_ref()
+        .and_then(|image| image.thumbnail_hash.as_deref())
+        .and_then(|hash| super::blobs::blob_url(hash, owner));
+    
+    Some(MediaItem {
+        message_id: message.id.clone(),
+        conversation_id: message.conversation_id.clone(),
+        platform: message.platform.clone(),
+        timestamp: message.timestamp,
+        name: attachment.name.clone(),
+        mime_type: attachment.mime_type.clone(),
+        url: attachment.url.clone(),
+        blob_hash: attachment.blob_hash.clone(),
+        thumbnail_url,
+        image,
+    })
+}
+
+fn is_image(attachment_type: &str, mime_type: Option<&str>) -> bool {
+    attachment_type == "image" || mime_type.map(|m| m.starts_with("image/")).unwrap_or(false)
+}
+
+fn encode_cursor(key: &(u64, String)) -> String {
+    format!("{}:{}", key.0, key.1)
+}
+
+fn decode_cursor(cursor: &str) -> Option<(u64, String)> {
+    let (position, entry) = cursor.split_once(':')?;
+    Some((position.parse().ok()?, entry.to_string()))
+}
@@ -1,11 +1,19 @@
 use crate::{Message, Error, Result};
 use crate::indexing;
+use super::{embeddings, engagement, media, summaries};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
 use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
+// First look-back when collecting the messages after a search hit; each
+// retry widens it
+const SURROUNDING_WINDOW_MS: u64 = 60 * 60 * 1000;
+const SURROUNDING_WINDOW_GROWTH: u64 = 24;
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -29,6 +37,19 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))),
         )
     );
+    
+    // Each conversation's messages newest first, keyed
+    // (conversation_id, u64::MAX - timestamp, message_id)
+    static CONV_TIME_INDEX: RefCell<StableBTreeMap<(String, u64, String), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))),
+        )
+    );
+}
+
+// Key of a message in CONV_TIME_INDEX
+fn range_key(conversation_id: &str, timestamp: u64, message_id: &str) -> (String, u64, String) {
+    (conversation_id.to_string(), u64::MAX - timestamp, message_id.to_string())
 }
 
 pub fn store_message(message: Message) -> Result<()> {
@@ -37,10 +58,24 @@ pub fn store_message(message: Message) -> Result<()> {
     let timestamp = message.timestamp;
     
     // Store the message
-    MESSAGE_STORE.with(|store| {
-        store.borrow_mut().insert(message_id.clone(), message.clone());
+    let previous = MESSAGE_STORE.with(|store| {
+        store.borrow_mut().insert(message_id.clone(), message.clone())
     });
     
+    // Count replies once, not on every re-sync of the same message
+    if previous.is_none() {
+        engagement::record_reply(&message);
+    }
+    
+    // Queue for embedding and summarising when new or when the text changed
+    if previous.as_ref().map(|p| p.content.text != message.content.text).unwrap_or(true) {
+        embeddings::enqueue(&message_id);
+        summaries::enqueue(&conversation_id, &message_id);
+    }
+    
+    // Image attachments for the media gallery
+    media::index_message(&message, previous.as_ref());
+    
     // Update conversation index
     CONV_MSG_INDEX.with(|index| {
         let mut index = index.borrow_mut();
@@ -56,6 +91,15 @@ pub fn store_message(message: Message) -> Result<()> {
         index.borrow_mut().insert((timestamp, message_id.clone()), message_id.clone());
     });
     
+    // Edits can move a message in time
+    CONV_TIME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(previous) = &previous {
+            index.remove(&range_key(&previous.conversation_id, previous.timestamp, &message_id));
+        }
+        index.insert(range_key(&conversation_id, timestamp, &message_id), ());
+    });
+    
     // Index the message for advanced search
     indexing::index_message(&message)?;
     
@@ -97,6 +141,87 @@ pub fn get_conversation_messages(
     messages
 }
 
+// IDs of a conversation's messages, without loading the messages
+pub fn get_conversation_message_ids(conversation_id: &str) -> Vec<String> {
+    CONV_MSG_INDEX.with(|index| {
+        index.borrow().get(conversation_id).unwrap_or_default()
+    })
+}
+
+// A conversation's messages with timestamps in [start, end], newest first and
+// at most `limit`. The flag is set when more were in range.
+pub fn get_conversation_messages_in_range(
+    conversation_id: &str,
+    start: u64,
+    end: u64,
+    limit: usize
+) -> (Vec<Message>, bool) {
+    get_messages_in_range(&[conversation_id.to_string()], start, end, limit)
+}
+
+// Messages of several conversations with timestamps in [start, end], newest
+// first across all of them and at most `limit`. Only the messages returned
+// are loaded. The flag is set when more were in range.
+pub fn get_messages_in_range(
+    conversation_ids: &[String],
+    start: u64,
+    end: u64,
+    limit: usize
+) -> (Vec<Message>, bool) {
+    let (message_ids, truncated) = message_ids_in_range(conversation_ids, start, end, limit);
+    let messages = message_ids.iter().filter_map(|id| get_message(id)).collect();
+    (messages, truncated)
+}
+
+// Ids behind get_messages_in_range: a k-way merge of each conversation's
+// range scan, stopping after `limit`
+pub fn message_ids_in_range(
+    conversation_ids: &[String],
+    start: u64,
+    end: u64,
+    limit: usize
+) -> (Vec<String>, bool) {
+    if start > end {
+        return (Vec::new(), false);
+    }
+    
+    CONV_TIME_INDEX.with(|index| {
+        let index = index.borrow();
+        
+        // Newest first, so the end time bounds where each scan starts
+        let mut scans: Vec<_> = conversation_ids.iter()
+            .map(|conversation_id| {
+                index.range(range_key(conversation_id, end, "")..)
+                    .take_while(move |((conversation, position, _), _)| {
+                        conversation == conversation_id && *position <= u64::MAX - start
+                    })
+            })
+            .collect();
+        
+        // Smallest position is the newest message
+        let mut heads = BinaryHeap::new();
+        for (i, scan) in scans.iter_mut().enumerate() {
+            if let Some(((_, position, message_id), _)) = scan.next() {
+                heads.push(Reverse((position, message_id, i)));
+            }
+        }
+        
+        let mut message_ids = Vec::new();
+        while let Some(Reverse((_, message_id, i))) = heads.pop() {
+            if message_ids.len() >= limit {
+                return (message_ids, true);
+            }
+            message_ids.push(message_id);
+            
+            if let Some(((_, position, message_id), _)) = scans[i].next() {
+                heads.push(Reverse((position, message_id, i)));
+            }
+        }
+        
+        (message_ids, false)
+    })
+}
+
 pub fn delete_message(message_id: &str) -> Result<()> {
     // Get the message to retrieve its conversation_id and timestamp
     let message = get_message(message_id).ok_or_else(|| {
@@ -124,9 +249,20 @@ pub fn delete_message(message_id: &str) -> Result<()> {
     TIME_MSG_INDEX.with(|index| {
         index.borrow_mut().remove(&(timestamp, message_id.to_string()));
     });
+    CONV_TIME_INDEX.with(|index| {
+        index.borrow_mut().remove(&range_key(&message.conversation_id, timestamp, message_id));
+    });
+    
+    // Remove engagement counters and gallery entries
+    engagement::delete_engagement(message_id);
+    media::remove_message(&message);
+    
+    // Remove the embedding and its vector index node
+    crate::openchat::vector_index::remove(message_id);
+    embeddings::delete_embedding(message_id);
     
     // Remove from search indices
-    indexing::delete_message(message_id)?;
+    indexing::delete_message(&message)?;
     
     Ok(())
 }
@@ -142,23 +278,52 @@ pub fn get_all_messages() -> Result<Vec<Message>> {
     })
 }
 
+// Up to `limit` message IDs after `cursor`, in ID order (for resumable corpus-wide jobs)
+pub fn message_ids_after(cursor: Option<&str>, limit: usize) -> Vec<String> {
+    MESSAGE_STORE.with(|store| {
+        let store = store.borrow();
+        match cursor {
+            Some(cursor) => store.range(cursor.to_string()..)
+                .map(|(id, _)| id)
+                .skip_while(|id| id == cursor)
+                .take(limit)
+                .collect(),
+            None => store.iter().map(|(id, _)| id).take(limit).collect(),
+        }
+    })
+}
+
 // Advanced search using the indexing module
-pub fn search_messages(query: &str, filters: &indexing::search::SearchFilters) -> Result<Vec<Message>> {
+pub fn search_messages(
+    query: &str, 
+    filters: &indexing::search::SearchFilters,
+    ranking: &indexing::ranking::RankingContext
+) -> Result<Vec<Message>> {
+    let hits = search_message_hits(query, filters, ranking)?;
+    Ok(hits.into_iter().map(|(message, _)| message).collect())
+}
+
+// Search returning each message together with its hit (score and highlighted fragment)
+pub fn search_message_hits(
+    query: &str, 
+    filters: &indexing::search::SearchFilters,
+    ranking: &indexing::ranking::RankingContext
+) -> Result<Vec<(Message, indexing::search::SearchHit)>> {
     // Use the indexing module to perform the search
-    let message_ids = indexing::search(query, filters, filters.limit)?;
+    let hits = indexing::search_hits(query, filters, filters.limit, ranking)?;
     
     // Retrieve the actual messages
-    let messages: Vec<Message> = message_ids.iter()
-        .filter_map(|id| get_message(id))
+    let results: Vec<(Message, indexing::search::SearchHit)> = hits.into_iter()
+        .filter_map(|hit| get_message(&hit.message_id).map(|msg| (msg, hit)))
         .collect();
     
     // Apply sorting based on filters
-    let mut sorted_messages = messages;
+    let mut sorted_results = results;
     
     // Sort by specified field
     match filters.sort_by {
         indexing::search::SortField::Timestamp => {
-            sorted_messages.sort_by(|a, b| {
+            sorted_results.sort_by(|(a, _), (b, _)| {
                 match filters.sort_direction {
                     indexing::search::SortDirection::Ascending => a.timestamp.cmp(&b.timestamp),
                     indexing::search::SortDirection::Descending => b.timestamp.cmp(&a.timestamp),
@@ -166,7 +331,7 @@ pub fn search_messages(query: &str, filters: &indexing::search::SearchFilters) -
             });
         },
         indexing::search::SortField::Platform => {
-            sorted_messages.sort_by(|a, b| {
+            sorted_results.sort_by(|(a, _), (b, _)| {
                 let platform_cmp = a.platform.to_string().cmp(&b.platform.to_string());
                 match filters.sort_direction {
                     indexing::search::SortDirection::Ascending => platform_cmp,
@@ -180,7 +345,75 @@ pub fn search_messages(query: &str, filters: &indexing::search::SearchFilters) -
         },
     }
     
-    Ok(sorted_messages)
+    Ok(sorted_results)
+}
+
+// Get up to `count` messages immediately before and after a message in its
+// conversation, each side in chronological order
+pub fn get_surrounding_messages(message: &Message, count: usize) -> (Vec<Message>, Vec<Message>) {
+    if count == 0 {
+        return (Vec::new(), Vec::new());
+    }
+    
+    let key = range_key(&message.conversation_id, message.timestamp, &message.id);
+    let (older, newer) = CONV_TIME_INDEX.with(|index| {
+        let index = index.borrow();
+        let in_conversation = |((conversation, _, _), _): &((String, u64, String), ())| *conversation == message.conversation_id;
+        
+        // Older messages follow the hit in the newest-first index
+        let older: Vec<String> = index.range(key.clone()..)
+            .skip(1)
+            .take_while(in_conversation)
+            .take(count)
+            .map(|((_, _, id), _)| id)
+            .collect();
+        
+        // Newer ones precede it, and the index only scans forward, so look
+        // back over a growing time window until enough are found
+        let mut newer: VecDeque<String> = VecDeque::new();
+        let mut window: u64 = SURROUNDING_WINDOW_MS;
+        loop {
+            let window_start = key.1.saturating_sub(window);
+            newer.clear();
+            for ((_, _, id), _) in index.range((key.0.clone(), window_start, String::new())..key.clone()) {
+                if newer.len() == count {
+                    newer.pop_front();
+                }
+                newer.push_back(id);
+            }
+            if newer.len() == count || window_start == 0 {
+                break;
+            }
+            window = window.saturating_mul(SURROUNDING_WINDOW_GROWTH);
+        }
+        
+        (older, newer)
+    });
+    
+    let mut before: Vec<Message> = older.iter().filter_map(|id| get_message(id)).collect();
+    before.reverse();
+    let mut after: Vec<Message> = newer.iter().filter_map(|id| get_message(id)).collect();
+    after.reverse();
+    
+    (before, after)
+}
+
+// Fill CONV_TIME_INDEX for messages stored before it existed. Runs from
+// post_upgrade, which has the instruction budget for one pass.
+pub fn backfill_range_index() {
+    let empty = CONV_TIME_INDEX.with(|index| index.borrow().is_empty());
+    if !empty {
+        return;
+    }
+    
+    MESSAGE_STORE.with(|store| {
+        CONV_TIME_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for (message_id, message) in store.borrow().iter() {
+                index.insert(range_key(&message.conversation_id, message.timestamp, &message_id), ());
+            }
+        });
+    });
 }
 
 // Rebuild all indices
@@ -1,4 +1,18 @@
 pub mod messages;
 pub mod conversations;
+pub mod engagement;
+pub mod preferences;
+pub mod embeddings;
+pub mod duplicates;
+pub mod llm;
+pub mod insights;
+pub mod entities;
+pub mod topics;
+pub mod summaries;
+pub mod sync_state;
+pub mod blobs;
+pub mod media;
+pub mod directory;
+pub mod connections;
 
 use crate::{Conversation, Message, Error, Result};
\ No newline at end of file
@@ -0,0 +1,43 @@
+use crate::Result;
+use crate::indexing::ranking::RankingWeights;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Search ranking weights by user ID
+    static RANKING_WEIGHTS: RefCell<StableBTreeMap<String, RankingWeights, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+}
+
+// Get a user's ranking weights, falling back to the defaults
+pub fn get_ranking_weights(user_id: &str) -> RankingWeights {
+    RANKING_WEIGHTS.with(|store| {
+        store.borrow().get(user_id).unwrap_or_default()
+    })
+}
+
+pub fn set_ranking_weights(user_id: &str, weights: RankingWeights) -> Result<()> {
+    weights.validate()?;
+    
+    RANKING_WEIGHTS.with(|store| {
+        store.borrow_mut().insert(user_id.to_string(), weights);
+    });
+    
+    Ok(())
+}
+
+// Go back to the default weights
+pub fn reset_ranking_weights(user_id: &str) {
+    RANKING_WEIGHTS.with(|store| {
+        store.borrow_mut().remove(user_id);
+    });
+}
@@ -0,0 +1,92 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use crate::openchat::summaries::ConversationSummary;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Rolling summary by conversation ID
+    static SUMMARIES: RefCell<StableBTreeMap<String, ConversationSummary, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))),
+        )
+    );
+    
+    // Messages not yet in their conversation's summary, keyed by
+    // (conversation ID, message ID) with the time they were queued
+    static PENDING_SUMMARIES: RefCell<StableBTreeMap<(String, String), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+}
+
+pub fn get_summary(conversation_id: &str) -> Option<ConversationSummary> {
+    SUMMARIES.with(|summaries| {
+        summaries.borrow().get(conversation_id)
+    })
+}
+
+pub fn store_summary(summary: ConversationSummary) {
+    SUMMARIES.with(|summaries| {
+        summaries.borrow_mut().insert(summary.conversation_id.clone(), summary);
+    });
+}
+
+// Queue a new or changed message for its conversation's next summary update
+pub fn enqueue(conversation_id: &str, message_id: &str) {
+    let now = ic_cdk::api::time();
+    
+    PENDING_SUMMARIES.with(|queue| {
+        queue.borrow_mut().insert((conversation_id.to_string(), message_id.to_string()), now);
+    });
+}
+
+// Take all queued message IDs for a conversation off the queue
+pub fn take_pending(conversation_id: &str) -> Vec<String> {
+    PENDING_SUMMARIES.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let keys: Vec<(String, String)> = queue
+            .range((conversation_id.to_string(), String::new())..)
+            .take_while(|((conv_id, _), _)| conv_id == conversation_id)
+            .map(|(key, _)| key)
+            .collect();
+        
+        for key in &keys {
+            queue.remove(key);
+        }
+        
+        keys.into_iter().map(|(_, message_id)| message_id).collect()
+    })
+}
+
+// Conversations with queued messages, oldest queued first
+pub fn pending_conversations(limit: usize) -> Vec<String> {
+    PENDING_SUMMARIES.with(|queue| {
+        let mut oldest: Vec<(String, u64)> = Vec::new();
+        for ((conversation_id, _), queued_at) in queue.borrow().iter() {
+            match oldest.last_mut() {
+                Some((last_id, last_queued)) if *last_id == conversation_id => {
+                    *last_queued = (*last_queued).min(queued_at);
+                },
+                _ => oldest.push((conversation_id, queued_at)),
+            }
+        }
+        
+        oldest.sort_by(|(a_id, a), (b_id, b)| a.cmp(b).then_with(|| a_id.cmp(b_id)));
+        oldest.into_iter().take(limit).map(|(conversation_id, _)| conversation_id).collect()
+    })
+}
+
+pub fn pending_count(conversation_id: &str) -> u64 {
+    PENDING_SUMMARIES.with(|queue| {
+        queue.borrow()
+            .range((conversation_id.to_string(), String::new())..)
+            .take_while(|((conv_id, _), _)| conv_id == conversation_id)
+            .count() as u64
+    })
+}
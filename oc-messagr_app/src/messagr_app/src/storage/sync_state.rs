@@ -0,0 +1,111 @@
+use crate::{Message, Result};
+use super::{conversations, messages};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Connector sync cursors (e.g. the newest message fetched from a channel),
+    // keyed by platform-specific names
+    static SYNC_CURSORS: RefCell<StableBTreeMap<String, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+}
+
+pub fn get_cursor(key: &str) -> Option<String> {
+    SYNC_CURSORS.with(|cursors| {
+        cursors.borrow().get(key)
+    })
+}
+
+pub fn set_cursor(key: &str, value: &str) {
+    SYNC_CURSORS.with(|cursors| {
+        cursors.borrow_mut().insert(key.to_string(), value.to_string());
+    });
+}
+
+// Where a connector's sync of a feed stands. Feeds are read newest first,
+// so a pass pages back until it reaches what the last completed pass saw.
+// A pass that runs out of pages resumes from its pagination token on the
+// next sync. Values are stored under "<key>:newest" and so on.
+pub struct FeedCursor {
+    key: String,
+    // Newest item of the last completed pass
+    pub newest: Option<String>,
+    // Token to resume an unfinished pass from, and the newest item it saw
+    pub resume_token: Option<String>,
+    pass_newest: Option<String>,
+}
+
+impl FeedCursor {
+    pub fn load(key: &str) -> Self {
+        let get = |name: &str| {
+            get_cursor(&format!("{}:{}", key, name)).filter(|value| !value.is_empty())
+        };
+        
+        Self {
+            key: key.to_string(),
+            newest: get("newest"),
+            resume_token: get("resume"),
+            pass_newest: get("pass_newest"),
+        }
+    }
+    
+    // Note the first page of a pass, which holds its newest item
+    pub fn start_page(&mut self, newest: Option<&str>) {
+        if self.pass_newest.is_none() {
+            self.pass_newest = newest.map(|id| id.to_string());
+        }
+    }
+    
+    // Record where the pass stopped; no token means it finished
+    pub fn save(&self, next_token: Option<&str>) {
+        let set = |name: &str, value: &str| set_cursor(&format!("{}:{}", self.key, name), value);
+        
+        match next_token {
+            Some(token) => {
+                set("resume", token);
+                set("pass_newest", self.pass_newest.as_deref().unwrap_or(""));
+            },
+            None => {
+                if let Some(newest) = self.pass_newest.as_deref().or(self.newest.as_deref()) {
+                    set("newest", newest);
+                }
+                set("resume", "");
+                set("pass_newest", "");
+            },
+        }
+    }
+    
+    pub fn reset(&self) {
+        for name in ["newest", "resume", "pass_newest"] {
+            set_cursor(&format!("{}:{}", self.key, name), "");
+        }
+    }
+}
+
+// Store a page of messages and move the conversation's last message time forward
+pub fn store_page(page: impl Iterator<Item = Message>, conversation_id: &str) -> Result<u64> {
+    let mut stored = 0;
+    let mut latest = 0;
+    
+    for message in page {
+        latest = latest.max(message.timestamp);
+        messages::store_message(message)?;
+        stored += 1;
+    }
+    
+    let previous = conversations::get_conversation(conversation_id).and_then(|c| c.last_message_at);
+    if stored > 0 && previous.map(|p| latest > p).unwrap_or(true) {
+        conversations::update_conversation_last_message(conversation_id, latest)?;
+    }
+    
+    Ok(stored)
+}
@@ -0,0 +1,60 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap};
+use std::cell::RefCell;
+use crate::openchat::topics::TopicCluster;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = 
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    
+    // Topics keyed "<owner>:<topic id>", so a topic is only ever found
+    // through the principal that discovered it
+    static TOPICS: RefCell<StableBTreeMap<String, TopicCluster, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+}
+
+pub fn get_topic(owner: &str, topic_id: &str) -> Option<TopicCluster> {
+    TOPICS.with(|topics| {
+        topics.borrow().get(&topic_key(owner, topic_id))
+    })
+}
+
+pub fn get_user_topics(owner: &str) -> Vec<TopicCluster> {
+    let prefix = format!("{}:", owner);
+    
+    TOPICS.with(|topics| {
+        topics.borrow().range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(_, topic)| topic)
+            .collect()
+    })
+}
+
+// Replace the owner's topics with the latest run
+pub fn store_user_topics(owner: &str, topics: Vec<TopicCluster>) {
+    let prefix = format!("{}:", owner);
+    
+    TOPICS.with(|store| {
+        let mut store = store.borrow_mut();
+        let previous: Vec<String> = store.range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .map(|(key, _)| key)
+            .collect();
+        
+        for key in previous {
+            store.remove(&key);
+        }
+        for topic in topics {
+            store.insert(topic_key(owner, &topic.id), topic);
+        }
+    });
+}
+
+fn topic_key(owner: &str, topic_id: &str) -> String {
+    format!("{}:{}", owner, topic_id)
+}
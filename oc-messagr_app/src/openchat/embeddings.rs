@@ -1,94 +1,420 @@
 use crate::{Error, Result};
-use std::collections::HashMap;
+use crate::connectors::http;
+use crate::storage::embeddings::{self as embedding_store, StoredEmbedding};
+use super::vector_index;
+use candid::{CandidType, Deserialize};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
 
-// Vector dimensions for simple embeddings
-const EMBEDDING_DIMENSIONS: usize = 128;
+// Model name recorded with vectors produced by the local provider
+pub const LOCAL_MODEL: &str = "local-hashing-v1";
 
-// Thread-local storage for cached embeddings
-thread_local! {
-    static EMBEDDING_CACHE: RefCell<HashMap<String, Vec<f32>>> = RefCell::new(HashMap::new());
+// Vector dimensions of the local provider
+const LOCAL_DIMENSIONS: usize = 256;
+
+// Feature weights for the local provider
+const WORD_WEIGHT: f32 = 1.0;
+const BIGRAM_WEIGHT: f32 = 0.5;
+const TRIGRAM_WEIGHT: f32 = 0.3;
+
+// Number of texts sent to a provider in a single request
+const EMBEDDING_BATCH_SIZE: usize = 32;
+
+// Longest text we send to a provider (roughly 2k tokens)
+const MAX_INPUT_CHARS: usize = 8000;
+
+// Number of query embeddings kept in memory
+const QUERY_CACHE_CAPACITY: usize = 256;
+
+// Canister query that reduces embedding responses to what replicas must agree on
+const EMBEDDING_TRANSFORM_METHOD: &str = "transform_embedding_response";
+
+// Embedding components are rounded to this many decimals in the transform,
+// so float noise between replicas' responses doesn't break consensus
+const EMBEDDING_PRECISION: f32 = 10_000.0;
+
+// Which embedding provider the canister uses
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum EmbeddingConfig {
+    // Deterministic on-canister embeddings (no outcalls, also used in tests)
+    Local,
+    // Any endpoint implementing the OpenAI /v1/embeddings API
+    OpenAiCompatible {
+        endpoint: String,
+        model: String,
+        api_key: String,
+        dimensions: u32,
+    },
 }
 
-// Simple embedding model
-// In a real implementation, this would use a proper embedding model like OpenAI's text-embedding-ada-002
-// For this demo, we use a simple hashing approach
-pub fn embed_text(text: &str) -> Vec<f32> {
-    // Check cache first
-    let cached = EMBEDDING_CACHE.with(|cache| {
-        cache.borrow().get(text).cloned()
+// A source of text embeddings
+#[async_trait::async_trait(?Send)]
+pub trait EmbeddingProvider {
+    // Identifies the vector space; vectors from different models aren't comparable
+    fn model(&self) -> String;
+    fn dimensions(&self) -> usize;
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+// Feature-hashing embeddings over words, word bigrams and character trigrams.
+// Not a learned model, but deterministic, cheap and tolerant of small typos.
+pub struct LocalEmbeddingProvider;
+
+#[async_trait::async_trait(?Send)]
+impl EmbeddingProvider for LocalEmbeddingProvider {
+    fn model(&self) -> String {
+        LOCAL_MODEL.to_string()
+    }
+    
+    fn dimensions(&self) -> usize {
+        LOCAL_DIMENSIONS
+    }
+    
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|text| embed_text(text)).collect())
+    }
+}
+
+// Embeddings from an OpenAI-compatible endpoint via HTTPS outcalls
+pub struct HttpEmbeddingProvider {
+    endpoint: String,
+    model: String,
+    api_key: String,
+    dimensions: usize,
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Serialize, Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[async_trait::async_trait(?Send)]
+impl EmbeddingProvider for HttpEmbeddingProvider {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+    
+    fn dimensions(&self) -> usize {
+        self.dimensions
+    }
+    
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let request = EmbeddingRequest { model: &self.model, input: texts };
+        let body = serde_json::to_vec(&request)
+            .map_err(|e| Error::InternalError(format!("Failed to encode embedding request: {}", e)))?;
+        
+        let outgoing = http::OutgoingRequest::post(&self.endpoint, body)
+            .with_bearer_token(&self.api_key)
+            .with_transform(EMBEDDING_TRANSFORM_METHOD);
+        let response: EmbeddingResponse = http::decode_json(&self.endpoint, http::send(outgoing).await?)?;
+        
+        // The API may return items out of order
+        let mut vectors = vec![Vec::new(); texts.len()];
+        for item in response.data {
+            if item.index < vectors.len() {
+                vectors[item.index] = normalize(item.embedding);
+            }
+        }
+        
+        if vectors.iter().any(|v| v.len() != self.dimensions) {
+            return Err(Error::PlatformError(format!(
+                "Embedding endpoint returned vectors with unexpected dimensions (expected {})", self.dimensions
+            )));
+        }
+        
+        Ok(vectors)
+    }
+}
+
+// Small LRU cache for query embeddings
+struct QueryCache {
+    entries: HashMap<String, Vec<f32>>,
+    order: VecDeque<String>,
+}
+
+impl QueryCache {
+    fn get(&mut self, key: &str) -> Option<Vec<f32>> {
+        let vector = self.entries.get(key).cloned()?;
+        self.order.retain(|k| k != key);
+        self.order.push_back(key.to_string());
+        Some(vector)
+    }
+    
+    fn insert(&mut self, key: String, vector: Vec<f32>) {
+        if self.entries.contains_key(&key) {
+            self.order.retain(|k| k != &key);
+        } else if self.entries.len() >= QUERY_CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        
+        self.order.push_back(key.clone());
+        self.entries.insert(key, vector);
+    }
+}
+
+// Thread-local storage for cached query embeddings
+thread_local! {
+    static QUERY_CACHE: RefCell<QueryCache> = RefCell::new(QueryCache {
+        entries: HashMap::new(),
+        order: VecDeque::new(),
     });
+}
+
+// Provider for the current configuration
+pub fn active_provider() -> Box<dyn EmbeddingProvider> {
+    match embedding_store::get_config().unwrap_or(EmbeddingConfig::Local) {
+        EmbeddingConfig::Local => Box::new(LocalEmbeddingProvider),
+        EmbeddingConfig::OpenAiCompatible { endpoint, model, api_key, dimensions } => {
+            Box::new(HttpEmbeddingProvider {
+                endpoint,
+                model,
+                api_key,
+                dimensions: dimensions as usize,
+            })
+        },
+    }
+}
+
+// Switch provider; existing vectors are from another model, so everything is re-embedded
+pub fn set_config(config: EmbeddingConfig) -> Result<()> {
+    if let EmbeddingConfig::OpenAiCompatible { endpoint, model, dimensions, .. } = &config {
+        if !endpoint.starts_with("https://") {
+            return Err(Error::InvalidParameters("Embedding endpoint must use HTTPS".to_string()));
+        }
+        if model.is_empty() || *dimensions == 0 {
+            return Err(Error::InvalidParameters("Embedding model and dimensions are required".to_string()));
+        }
+    }
+    
+    // Vectors of the old model aren't comparable, so the graph is dropped
+    // and every message re-queued, a batch at a time by process_pending
+    embedding_store::set_config(config);
+    clear_cache();
+    vector_index::start_reembed();
+    
+    Ok(())
+}
+
+// Keep only the rounded vectors, and drop all headers. Error bodies are
+// passed through so failures stay readable.
+pub fn transform_response(args: TransformArgs) -> HttpResponse {
+    let response = args.response;
+    let ok = u16::try_from(response.status.0.clone()).map_or(false, |status| (200..300).contains(&status));
     
-    if let Some(cached_embedding) = cached {
-        return cached_embedding;
+    let body = match serde_json::from_slice::<EmbeddingResponse>(&response.body) {
+        Ok(mut embeddings) if ok => {
+            for item in &mut embeddings.data {
+                for value in &mut item.embedding {
+                    *value = (*value * EMBEDDING_PRECISION).round() / EMBEDDING_PRECISION;
+                }
+            }
+            serde_json::to_vec(&embeddings).unwrap_or_default()
+        },
+        _ => response.body,
+    };
+    
+    HttpResponse {
+        status: response.status,
+        headers: Vec::new(),
+        body,
+    }
+}
+
+// Embed a search query with the active provider (cached)
+pub async fn embed_query(text: &str) -> Result<(String, Vec<f32>)> {
+    let provider = active_provider();
+    let model = provider.model();
+    let key = format!("{}:{}", model, text);
+    
+    if let Some(vector) = QUERY_CACHE.with(|cache| cache.borrow_mut().get(&key)) {
+        return Ok((model, vector));
+    }
+    
+    let vector = provider.embed(&[truncate_input(text)]).await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::InternalError("Embedding provider returned no vector".to_string()))?;
+    
+    QUERY_CACHE.with(|cache| cache.borrow_mut().insert(key, vector.clone()));
+    Ok((model, vector))
+}
+
+// Stored vector for a message in the given model's space, computing it
+// on the fly only for the (cheap) local model
+pub fn message_vector(message_id: &str, text: &str, model: &str) -> Option<Vec<f32>> {
+    match embedding_store::get_embedding(message_id) {
+        Some(stored) if stored.model == model => Some(stored.vector),
+        _ if model == LOCAL_MODEL => Some(embed_text(text)),
+        _ => None,
+    }
+}
+
+// Embed queued messages, storing their vectors and adding them to the vector index.
+// Returns the number of messages embedded.
+pub async fn process_pending(limit: usize) -> Result<u64> {
+    // Nothing is inserted while a rebuild or re-embed is still in progress
+    if !vector_index::advance_job().1 {
+        return Ok(0);
+    }
+    
+    let provider = active_provider();
+    let model = provider.model();
+    let mut processed = 0;
+    
+    while (processed as usize) < limit {
+        let batch_size = EMBEDDING_BATCH_SIZE.min(limit - processed as usize);
+        let message_ids = embedding_store::take_pending(batch_size);
+        if message_ids.is_empty() {
+            break;
+        }
+        
+        // Messages may have been deleted since they were queued
+        let messages: Vec<(String, String)> = message_ids.iter()
+            .filter_map(|id| crate::storage::messages::get_message(id))
+            .filter(|message| !message.content.text.trim().is_empty())
+            .map(|message| (message.id.clone(), truncate_input(&message.content.text)))
+            .collect();
+        
+        if messages.is_empty() {
+            continue;
+        }
+        
+        let texts: Vec<String> = messages.iter().map(|(_, text)| text.clone()).collect();
+        let vectors = match provider.embed(&texts).await {
+            Ok(vectors) => vectors,
+            Err(e) => {
+                // Put the batch back so it's retried on the next run
+                for (message_id, _) in &messages {
+                    embedding_store::enqueue(message_id);
+                }
+                return Err(e);
+            },
+        };
+        
+        for ((message_id, _), vector) in messages.iter().zip(vectors.into_iter()) {
+            embedding_store::store_embedding(message_id, StoredEmbedding {
+                model: model.clone(),
+                vector: vector.clone(),
+            });
+            vector_index::insert(message_id, &vector);
+            processed += 1;
+        }
     }
     
-    // Create a zero vector with the correct dimensions
-    let mut embedding = vec![0.0; EMBEDDING_DIMENSIONS];
+    Ok(processed)
+}
+
+// Local embedding: signed feature hashing, L2-normalized
+pub fn embed_text(text: &str) -> Vec<f32> {
+    let mut embedding = vec![0.0; LOCAL_DIMENSIONS];
     
     // Tokenize the text into words
-    let tokens: Vec<&str> = text
-        .to_lowercase()
+    let lowered = text.to_lowercase();
+    let tokens: Vec<&str> = lowered
         .split(|c: char| !c.is_alphanumeric())
         .filter(|s| !s.is_empty())
         .collect();
     
-    // For each token, update the embedding
-    for token in tokens {
-        let token_hash = simple_hash(token);
-        let position = token_hash % EMBEDDING_DIMENSIONS;
-        embedding[position] += 1.0;
-    }
-    
-    // Normalize the vector
-    let magnitude = (embedding.iter().map(|&x| x * x).sum::<f32>()).sqrt();
-    if magnitude > 0.0 {
-        for value in &mut embedding {
-            *value /= magnitude;
+    for (i, token) in tokens.iter().enumerate() {
+        add_feature(&mut embedding, token, WORD_WEIGHT);
+        
+        // Word bigrams capture some local word order
+        if let Some(next) = tokens.get(i + 1) {
+            add_feature(&mut embedding, &format!("{} {}", token, next), BIGRAM_WEIGHT);
+        }
+        
+        // Character trigrams make near-identical spellings land close together
+        let chars: Vec<char> = format!("<{}>", token).chars().collect();
+        for window in chars.windows(3) {
+            let trigram: String = window.iter().collect();
+            add_feature(&mut embedding, &trigram, TRIGRAM_WEIGHT);
         }
     }
     
-    // Cache the result
-    EMBEDDING_CACHE.with(|cache| {
-        cache.borrow_mut().insert(text.to_string(), embedding.clone());
-    });
-    
-    embedding
+    normalize(embedding)
+}
+
+// Add a hashed feature; the sign bit keeps collisions from always adding up
+fn add_feature(embedding: &mut [f32], feature: &str, weight: f32) {
+    let hash = fnv_hash(feature);
+    let position = (hash % embedding.len() as u64) as usize;
+    let sign = if (hash >> 63) == 0 { 1.0 } else { -1.0 };
+    embedding[position] += sign * weight;
 }
 
 // Calculate cosine similarity between two vectors
 pub fn calculate_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
-    if vec1.len() != vec2.len() {
+    if vec1.len() != vec2.len() || vec1.is_empty() {
         return 0.0;
     }
     
     let dot_product: f32 = vec1.iter().zip(vec2.iter())
         .map(|(&a, &b)| a * b)
         .sum();
+    let magnitude1 = vec1.iter().map(|&x| x * x).sum::<f32>().sqrt();
+    let magnitude2 = vec2.iter().map(|&x| x * x).sum::<f32>().sqrt();
     
-    dot_product
+    if magnitude1 == 0.0 || magnitude2 == 0.0 {
+        return 0.0;
+    }
+    
+    let similarity = dot_product / (magnitude1 * magnitude2);
+    if similarity.is_finite() { similarity } else { 0.0 }
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let magnitude = (vector.iter().map(|&x| x * x).sum::<f32>()).sqrt();
+    if magnitude > 0.0 {
+        for value in &mut vector {
+            *value /= magnitude;
+        }
+    }
+    vector
 }
 
-// Simple string hashing function
-fn simple_hash(s: &str) -> usize {
-    let mut hash: usize = 0;
+fn truncate_input(text: &str) -> String {
+    text.chars().take(MAX_INPUT_CHARS).collect()
+}
+
+// 64-bit FNV-1a hash (stable across builds, unlike DefaultHasher)
+pub fn fnv_hash(s: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
     for byte in s.bytes() {
-        hash = hash.wrapping_mul(31).wrapping_add(byte as usize);
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
     }
     hash
 }
 
-// Clear the embedding cache
+// Clear the query embedding cache
 pub fn clear_cache() {
-    EMBEDDING_CACHE.with(|cache| {
-        cache.borrow_mut().clear();
+    QUERY_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.entries.clear();
+        cache.order.clear();
     });
 }
 
 // Get the current cache size
 pub fn get_cache_size() -> usize {
-    EMBEDDING_CACHE.with(|cache| {
+    QUERY_CACHE.with(|cache| {
         let cache = cache.borrow();
-        cache.len()
+        cache.entries.len()
     })
-}
\ No newline at end of file
+}
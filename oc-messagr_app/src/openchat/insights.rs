@@ -0,0 +1,441 @@
+use crate::{Message, Error, Result};
+use super::llm::{self, ChatMessage, LlmProvider};
+use super::types::*;
+use super::topics;
+use crate::indexing::{entities, sentiment};
+use candid::Deserialize;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+// Token budget for the messages in one map step
+const CHUNK_TOKEN_BUDGET: usize = 6000;
+
+// Token budget for the partial results merged in one reduce step
+const REDUCE_TOKEN_BUDGET: usize = 6000;
+
+// Completion length for analysis calls
+const ANALYSIS_MAX_TOKENS: u32 = 2048;
+
+// Longer messages are cut to this many characters for analysis
+const MAX_MESSAGE_CHARS: usize = 1000;
+
+// Upper bounds on list lengths kept from the model's output
+const MAX_ENTITIES: usize = 20;
+const MAX_TOPICS: usize = 10;
+const MAX_TIMELINE_EVENTS: usize = 20;
+const MAX_POINTS: usize = 10;
+
+const ANALYSIS_PROMPT: &str = "You analyse chat conversations. Each message is given as \
+[message_id] timestamp sender: text. Respond with a JSON object of this shape:
+{\"entities\": [{\"name\": \"...\", \"type\": \"person|organization|location|date|product|other\", \"mentions\": 1, \"sentiment\": 0.0, \"related\": [\"...\"]}],
+ \"topics\": [{\"name\": \"...\", \"relevance\": 0.0, \"message_ids\": [\"...\"], \"summary\": \"...\"}],
+ \"timeline\": [{\"message_id\": \"...\", \"description\": \"...\", \"importance\": 1}],
+ \"sentiment\": {\"overall\": 0.0, \"positive\": 0.0, \"neutral\": 0.0, \"negative\": 0.0, \"positive_points\": [\"...\"], \"negative_points\": [\"...\"]},
+ \"threads\": [{\"topic\": \"...\", \"message_ids\": [\"...\"], \"participants\": [\"...\"], \"resolved\": false}],
+ \"key_decisions\": [\"...\"],
+ \"action_items\": [\"...\"]}
+Sentiment values range from -1 (negative) to 1 (positive); the positive, neutral and \
+negative shares add up to 1. Relevance ranges from 0 to 1 and importance from 1 to 5. \
+Only use message IDs that appear in the input.";
+
+const MERGE_PROMPT: &str = "You are given several partial analyses of consecutive parts \
+of one chat conversation, as JSON objects. Merge them into a single analysis with the \
+same JSON shape: combine entities and topics that refer to the same thing (adding up \
+mentions and message IDs), keep the most important timeline events, weigh sentiment by \
+how much of the conversation each part covers, and merge threads that continue across \
+parts. Only use message IDs that appear in the input.";
+
+// Shape of the model's output. Everything is optional so a partial reply still parses.
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct AnalysisReply {
+    entities: Vec<EntityReply>,
+    topics: Vec<TopicReply>,
+    timeline: Vec<TimelineReply>,
+    sentiment: Option<SentimentReply>,
+    threads: Vec<ThreadReply>,
+    key_decisions: Vec<String>,
+    action_items: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct EntityReply {
+    name: String,
+    #[serde(rename = "type")]
+    entity_type: String,
+    mentions: i32,
+    sentiment: Option<f32>,
+    related: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct TopicReply {
+    name: String,
+    relevance: f32,
+    message_ids: Vec<String>,
+    summary: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct TimelineReply {
+    message_id: String,
+    description: String,
+    importance: i32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct SentimentReply {
+    overall: f32,
+    positive: f32,
+    neutral: f32,
+    negative: f32,
+    positive_points: Vec<String>,
+    negative_points: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct ThreadReply {
+    topic: String,
+    message_ids: Vec<String>,
+    participants: Vec<String>,
+    resolved: bool,
+}
+
+// Insights for a conversation, from the cache when its messages haven't changed
+pub async fn conversation_insights(conversation_id: &str, messages: &[Message]) -> Result<QueryInsights> {
+    let latest_timestamp = messages.iter().map(|m| m.timestamp).max().unwrap_or(0);
+    let message_count = messages.len() as u64;
+    
+    if let Some(insights) = crate::storage::insights::get_insights(conversation_id, latest_timestamp, message_count) {
+        return Ok(insights);
+    }
+    
+    let insights = analyze_messages(messages).await?;
+    crate::storage::insights::store_insights(conversation_id, latest_timestamp, message_count, insights.clone());
+    
+    Ok(insights)
+}
+
+// Analyse messages with the LLM provider. Conversations that don't fit one
+// context window are analysed in chunks (map) whose results are merged (reduce).
+pub async fn analyze_messages(messages: &[Message]) -> Result<QueryInsights> {
+    if messages.is_empty() {
+        return Ok(empty_insights());
+    }
+    
+    let provider = llm::active_provider();
+    
+    let mut sorted: Vec<&Message> = messages.iter().collect();
+    sorted.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    
+    // Map: analyse each chunk of the conversation
+    let mut partials = Vec::new();
+    for chunk in chunk_messages(&sorted, provider.as_ref()) {
+        let prompt = vec![
+            ChatMessage::system(ANALYSIS_PROMPT),
+            ChatMessage::user(&chunk),
+        ];
+        let reply: AnalysisReply = llm::complete_json(&prompt, Some(ANALYSIS_MAX_TOKENS)).await?;
+        partials.push(reply);
+    }
+    
+    // Reduce: merge partial results in groups until one is left
+    while partials.len() > 1 {
+        let mut merged = Vec::new();
+        for mut group in group_partials(partials, provider.as_ref())? {
+            if group.len() == 1 {
+                merged.push(group.remove(0).1);
+                continue;
+            }
+            
+            let parts: Vec<String> = group.into_iter().map(|(json, _)| json).collect();
+            let prompt = vec![
+                ChatMessage::system(MERGE_PROMPT),
+                ChatMessage::user(&parts.join("\n\n")),
+            ];
+            let reply: AnalysisReply = llm::complete_json(&prompt, Some(ANALYSIS_MAX_TOKENS)).await?;
+            merged.push(reply);
+        }
+        partials = merged;
+    }
+    
+    let reply = partials.into_iter().next().unwrap_or_default();
+    Ok(to_insights(reply, &sorted))
+}
+
+// Split messages into prompt-sized chunks of formatted lines
+fn chunk_messages(messages: &[&Message], provider: &dyn LlmProvider) -> Vec<String> {
+    let lines: Vec<String> = messages.iter().map(|message| format_message(message)).collect();
+    
+    llm::chunk_by_tokens(&lines, CHUNK_TOKEN_BUDGET, |line| provider.count_tokens(line))
+        .into_iter()
+        .map(|chunk| chunk.into_iter().map(|line| format!("{}\n", line)).collect())
+        .collect()
+}
+
+// Group partial results (with their JSON) so each group fits one reduce call.
+// Groups always take at least two partials so every round makes progress.
+fn group_partials(
+    partials: Vec<AnalysisReply>,
+    provider: &dyn LlmProvider
+) -> Result<Vec<Vec<(String, AnalysisReply)>>> {
+    let mut groups: Vec<Vec<(String, AnalysisReply)>> = Vec::new();
+    let mut current_tokens = 0;
+    
+    for partial in partials {
+        let json = serde_json::to_string(&partial)
+            .map_err(|e| Error::InternalError(format!("Failed to encode partial analysis: {}", e)))?;
+        let tokens = provider.count_tokens(&json);
+        
+        match groups.last_mut() {
+            Some(group) if group.len() < 2 || current_tokens + tokens <= REDUCE_TOKEN_BUDGET => {
+                group.push((json, partial));
+                current_tokens += tokens;
+            },
+            _ => {
+                groups.push(vec![(json, partial)]);
+                current_tokens = tokens;
+            },
+        }
+    }
+    
+    Ok(groups)
+}
+
+// Turn the model's reply into insights, dropping references to messages that
+// aren't in the conversation
+fn to_insights(reply: AnalysisReply, messages: &[&Message]) -> QueryInsights {
+    let by_id: HashMap<&str, &Message> = messages.iter().map(|m| (m.id.as_str(), *m)).collect();
+    let known_ids = |ids: Vec<String>| -> Vec<String> {
+        let mut seen = HashSet::new();
+        ids.into_iter()
+            .filter(|id| by_id.contains_key(id.as_str()) && seen.insert(id.clone()))
+            .collect()
+    };
+    
+    // Entities from the entity index fill in co-occurrence, and stand in when
+    // the model names none
+    let indexed = entities::aggregate(messages);
+    let entities: Vec<Entity> = if reply.entities.is_empty() {
+        indexed.into_iter()
+            .take(MAX_ENTITIES)
+            .map(|e| Entity {
+                entity_type: indexed_entity_type(e.kind),
+                mentions: e.mentions.min(i32::MAX as u64) as i32,
+                sentiment_score: mention_sentiment(&e.name, messages),
+                related_entities: e.related,
+                name: e.name,
+            })
+            .collect()
+    } else {
+        reply.entities.into_iter()
+            .filter(|e| !e.name.trim().is_empty())
+            .take(MAX_ENTITIES)
+            .map(|e| {
+                let related_entities = if e.related.is_empty() {
+                    let normalized = entities::normalize_name(&e.name);
+                    indexed.iter()
+                        .find(|i| entities::normalize_name(&i.name) == normalized)
+                        .map(|i| i.related.clone())
+                        .unwrap_or_default()
+                } else {
+                    e.related
+                };
+                
+                Entity {
+                    entity_type: entity_type(&e.entity_type),
+                    mentions: e.mentions.max(1),
+                    sentiment_score: e.sentiment
+                        .map(|s| s.clamp(-1.0, 1.0))
+                        .or_else(|| mention_sentiment(&e.name, messages)),
+                    related_entities,
+                    name: e.name,
+                }
+            })
+            .collect()
+    };
+    
+    // Fall back to embedding clusters when the model gives no topics
+    let mut topics: Vec<Topic> = if reply.topics.is_empty() {
+        topics::cluster_messages(messages).into_iter()
+            .map(|cluster| Topic {
+                relevance_score: cluster.message_count as f32 / messages.len().max(1) as f32,
+                message_count: cluster.message_count.min(i32::MAX as u64) as i32,
+                summary: cluster.keyphrases.join(", "),
+                name: cluster.label,
+            })
+            .collect()
+    } else {
+        reply.topics.into_iter()
+            .filter(|t| !t.name.trim().is_empty())
+            .map(|t| {
+                let message_ids = known_ids(t.message_ids);
+                Topic {
+                    name: t.name,
+                    relevance_score: t.relevance.clamp(0.0, 1.0),
+                    message_count: message_ids.len() as i32,
+                    summary: t.summary,
+                }
+            })
+            .collect()
+    };
+    topics.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    topics.truncate(MAX_TOPICS);
+    
+    // Timeline events take their time from the message they refer to
+    let mut timeline: Vec<TimelineEvent> = reply.timeline.into_iter()
+        .filter_map(|event| {
+            let message = by_id.get(event.message_id.as_str())?;
+            Some(TimelineEvent {
+                timestamp: message.timestamp,
+                description: event.description,
+                related_message_ids: vec![event.message_id],
+                importance: event.importance.clamp(1, 5),
+            })
+        })
+        .collect();
+    timeline.sort_by_key(|event| event.timestamp);
+    timeline.truncate(MAX_TIMELINE_EVENTS);
+    
+    // Fall back to the lexicon scorer when the model gives no sentiment
+    let sentiment = reply.sentiment.map(|s| {
+        let mut breakdown = HashMap::new();
+        breakdown.insert("Positive".to_string(), s.positive.clamp(0.0, 1.0));
+        breakdown.insert("Neutral".to_string(), s.neutral.clamp(0.0, 1.0));
+        breakdown.insert("Negative".to_string(), s.negative.clamp(0.0, 1.0));
+        
+        SentimentAnalysis {
+            overall_sentiment: s.overall.clamp(-1.0, 1.0),
+            sentiment_breakdown: breakdown,
+            key_positive_points: s.positive_points.into_iter().take(MAX_POINTS).collect(),
+            key_negative_points: s.negative_points.into_iter().take(MAX_POINTS).collect(),
+        }
+    }).or_else(|| lexicon_sentiment(messages));
+    
+    let main_threads: Vec<ConversationThread> = reply.threads.into_iter()
+        .map(|thread| ConversationThread {
+            topic: thread.topic,
+            message_ids: known_ids(thread.message_ids),
+            participants: thread.participants,
+            resolved: thread.resolved,
+        })
+        .filter(|thread| !thread.message_ids.is_empty())
+        .collect();
+    
+    let conversation_flow = if main_threads.is_empty() && reply.key_decisions.is_empty() && reply.action_items.is_empty() {
+        None
+    } else {
+        Some(ConversationFlow {
+            main_threads,
+            key_decisions: reply.key_decisions.into_iter().take(MAX_POINTS).collect(),
+            action_items: reply.action_items.into_iter().take(MAX_POINTS).collect(),
+        })
+    };
+    
+    QueryInsights {
+        entities,
+        topics,
+        timeline: if timeline.is_empty() { None } else { Some(timeline) },
+        sentiment,
+        conversation_flow,
+    }
+}
+
+// Overall lexicon sentiment with the most positive and negative messages as key points
+fn lexicon_sentiment(messages: &[&Message]) -> Option<SentimentAnalysis> {
+    if messages.is_empty() {
+        return None;
+    }
+    
+    let mut scored: Vec<(&Message, sentiment::SentimentScores)> = messages.iter()
+        .map(|m| (*m, sentiment::analyze_message(m)))
+        .collect();
+    
+    let count = scored.len() as f32;
+    let mut breakdown = HashMap::new();
+    breakdown.insert("Positive".to_string(), scored.iter().map(|(_, s)| s.positive).sum::<f32>() / count);
+    breakdown.insert("Neutral".to_string(), scored.iter().map(|(_, s)| s.neutral).sum::<f32>() / count);
+    breakdown.insert("Negative".to_string(), scored.iter().map(|(_, s)| s.negative).sum::<f32>() / count);
+    let overall = scored.iter().map(|(_, s)| s.compound).sum::<f32>() / count;
+    
+    scored.sort_by(|(a, x), (b, y)| y.compound.total_cmp(&x.compound).then_with(|| a.id.cmp(&b.id)));
+    let key_positive_points = scored.iter()
+        .filter(|(_, s)| s.label() == sentiment::SentimentLabel::Positive)
+        .take(MAX_POINTS)
+        .map(|(m, _)| format_message(m))
+        .collect();
+    let key_negative_points = scored.iter()
+        .rev()
+        .filter(|(_, s)| s.label() == sentiment::SentimentLabel::Negative)
+        .take(MAX_POINTS)
+        .map(|(m, _)| format_message(m))
+        .collect();
+    
+    Some(SentimentAnalysis {
+        overall_sentiment: overall,
+        sentiment_breakdown: breakdown,
+        key_positive_points,
+        key_negative_points,
+    })
+}
+
+// Mean lexicon sentiment of the messages that mention an entity
+fn mention_sentiment(name: &str, messages: &[&Message]) -> Option<f32> {
+    let needle = name.trim().to_lowercase();
+    let scores: Vec<f32> = messages.iter()
+        .filter(|m| m.content.text.to_lowercase().contains(&needle))
+        .map(|m| sentiment::analyze_message(m).compound)
+        .collect();
+    
+    if scores.is_empty() {
+        None
+    } else {
+        Some(scores.iter().sum::<f32>() / scores.len() as f32)
+    }
+}
+
+fn entity_type(name: &str) -> EntityType {
+    match name.trim().to_lowercase().as_str() {
+        "person" => EntityType::Person,
+        "organization" | "organisation" => EntityType::Organization,
+        "location" => EntityType::Location,
+        "date" => EntityType::Date,
+        "product" => EntityType::Product,
+        other => EntityType::Other(other.to_string()),
+    }
+}
+
+fn indexed_entity_type(kind: entities::EntityKind) -> EntityType {
+    match kind {
+        entities::EntityKind::Person => EntityType::Person,
+        entities::EntityKind::Organization => EntityType::Organization,
+        entities::EntityKind::Location => EntityType::Location,
+        entities::EntityKind::Date => EntityType::Date,
+        other => EntityType::Other(other.as_str().to_string()),
+    }
+}
+
+fn empty_insights() -> QueryInsights {
+    QueryInsights {
+        entities: Vec::new(),
+        topics: Vec::new(),
+        timeline: None,
+        sentiment: None,
+        conversation_flow: None,
+    }
+}
+
+// One line per message: [id] time sender: text
+fn format_message(message: &Message) -> String {
+    let text: String = message.content.text.chars().take(MAX_MESSAGE_CHARS).collect();
+    let time = chrono::NaiveDateTime::from_timestamp_millis(message.timestamp as i64)
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| message.timestamp.to_string());
+    
+    format!("[{}] {} {}: {}", message.id, time, message.sender.name, text.replace('\n', " "))
+}
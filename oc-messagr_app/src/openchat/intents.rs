@@ -0,0 +1,329 @@
+use crate::{Message, QueryResult, SemanticSearchFilters, Platform, Result};
+use crate::indexing::{self, search::SearchFilters, ranking::RankingContext};
+use crate::indexing::entities::{self, EntityDetails, EntityKind};
+use crate::indexing::sentiment::{self, SentimentSummary, TimeBucket};
+use super::query::{detect_query_intent, QueryIntent};
+use super::rag::{self, Answer};
+use super::trends::{self, TopicTrend, TrendBucket};
+use candid::{CandidType, Deserialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Messages considered by one analytical handler
+const MAX_ANALYSIS_MESSAGES: usize = 1000;
+
+// Messages counted for time-range activity across all conversations
+const MAX_ACTIVITY_MESSAGES: usize = 5000;
+
+// Full-text matches read for a topic before keeping the newest
+const MAX_TOPIC_CANDIDATES: usize = 5000;
+
+// Rows returned in each breakdown
+const MAX_BREAKDOWN_ROWS: usize = 10;
+
+// Nodes in a relationship graph besides the requested entities
+const MAX_RELATED_NODES: usize = 15;
+
+// Example messages attached to each edge
+const MAX_EDGE_MESSAGES: usize = 5;
+
+// Messages returned with an entity lookup
+const MAX_LOOKUP_MESSAGES: usize = 50;
+
+// Result of a query, by the kind of analysis its intent asked for
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum QueryAnswer {
+    Search(QueryResult),
+    Entities(EntityLookupResult),
+    TimeRange(TimeRangeActivity),
+    Summary(Answer),
+    Trend(TopicTrend),
+    Sentiment(TopicSentiment),
+    Relationships(RelationshipGraph),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct EntityLookupResult {
+    pub entities: Vec<EntityDetails>,
+    pub messages: Vec<Message>,
+}
+
+// A labelled count in a breakdown
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CountEntry {
+    pub id: String,
+    pub label: String,
+    pub count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TimeRangeActivity {
+    pub start: u64,
+    pub end: u64,
+    pub message_count: u64,
+    pub conversations: Vec<CountEntry>,
+    pub senders: Vec<CountEntry>,
+    pub platforms: Vec<CountEntry>,
+    // Set when the range held more messages than were counted; the oldest
+    // were left out
+    pub truncated: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopicSentiment {
+    pub topic: String,
+    pub summary: SentimentSummary,
+    // Set when more messages matched than were scored; the oldest were left out
+    pub truncated: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GraphNode {
+    pub key: String,
+    pub name: String,
+    pub kind: EntityKind,
+    pub mentions: u64,
+    pub requested: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    // Messages mentioning both entities
+    pub weight: u64,
+    pub message_ids: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RelationshipGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+// Detect the query's intent and run the matching handler
+pub async fn dispatch(
+    query_text: &str,
+    platform: Option<Platform>,
+    user_id: &str,
+    limit: usize
+) -> Result<QueryAnswer> {
+    let accessible: HashSet<String> = crate::storage::conversations::get_user_conversations(user_id, platform.clone())
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    match detect_query_intent(query_text) {
+        QueryIntent::EntityLookup(names) => Ok(QueryAnswer::Entities(entity_lookup(&names, &accessible))),
+        QueryIntent::TimeAnalysis(start, end) => Ok(QueryAnswer::TimeRange(time_range_activity(start.max(0) as u64, end.max(0) as u64, &accessible))),
+        QueryIntent::TopicSummary(topic) => {
+            let filters = SemanticSearchFilters {
+                platform,
+                ..Default::default()
+            };
+            let question = format!("Summarize what was discussed about {}", topic);
+            Ok(QueryAnswer::Summary(rag::answer_question(&question, user_id, &filters).await?))
+        },
+        QueryIntent::TrendAnalysis(topic) => {
+            let platforms = platform.map(|p| vec![p]);
+            Ok(QueryAnswer::Trend(trends::topic_trend(&topic, TrendBucket::Day, None, platforms, &accessible)?))
+        },
+        QueryIntent::SentimentAnalysis(topic) => {
+            let (messages, truncated) = topic_messages(&topic, &platform, &accessible, user_id);
+            Ok(QueryAnswer::Sentiment(TopicSentiment {
+                summary: sentiment::summarize(&messages, TimeBucket::Day),
+                topic,
+                truncated,
+            }))
+        },
+        QueryIntent::RelationshipAnalysis(names) => Ok(QueryAnswer::Relationships(relationship_graph(&names, &accessible))),
+        QueryIntent::Search(_) | QueryIntent::Unknown => {
+            super::query_with_openchat(query_text, platform, user_id, limit).await.map(QueryAnswer::Search)
+        },
+    }
+}
+
+// The newest messages matching a topic in the full-text index, limited to
+// accessible conversations. Sentiment is bucketed over time, so the cut is
+// by time rather than relevance. The flag is set when matches were left out.
+fn topic_messages(topic: &str, platform: &Option<Platform>, accessible: &HashSet<String>, user_id: &str) -> (Vec<Message>, bool) {
+    let mut filters = SearchFilters::default();
+    filters.platform = platform.clone();
+    filters.limit = MAX_TOPIC_CANDIDATES;
+    
+    let ranking = RankingContext::for_user(user_id);
+    let hits = match indexing::search_hits(topic, &filters, MAX_TOPIC_CANDIDATES, &ranking) {
+        Ok(hits) => hits,
+        Err(e) => {
+            ic_cdk::println!("Topic search failed: {:?}", e);
+            return (Vec::new(), false);
+        },
+    };
+    
+    // Hitting the candidate cap means lower-ranked matches were never read
+    let mut truncated = hits.len() >= MAX_TOPIC_CANDIDATES;
+    let mut messages: Vec<Message> = hits.into_iter()
+        .filter(|hit| hit.text_match.is_some())
+        .filter_map(|hit| crate::storage::messages::get_message(&hit.message_id))
+        .filter(|message| accessible.contains(&message.conversation_id))
+        .collect();
+    
+    messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then_with(|| a.id.cmp(&b.id)));
+    if messages.len() > MAX_ANALYSIS_MESSAGES {
+        messages.truncate(MAX_ANALYSIS_MESSAGES);
+        truncated = true;
+    }
+    
+    (messages, truncated)
+}
+
+fn entity_lookup(names: &[String], accessible: &HashSet<String>) -> EntityLookupResult {
+    let mut found: Vec<EntityDetails> = Vec::new();
+    for name in names {
+        for entity in entities::lookup(name, accessible) {
+            if !found.iter().any(|e| e.key == entity.key) {
+                found.push(entity);
+            }
+        }
+    }
+    
+    // Most recent mentions of any of the entities
+    let mut message_ids: Vec<&String> = found.iter().flat_map(|e| e.message_ids.iter()).collect();
+    message_ids.sort();
+    message_ids.dedup();
+    
+    let mut messages: Vec<Message> = message_ids.into_iter()
+        .filter_map(|id| crate::storage::messages::get_message(id))
+        .collect();
+    messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    messages.truncate(MAX_LOOKUP_MESSAGES);
+    
+    EntityLookupResult {
+        entities: found,
+        messages,
+    }
+}
+
+// Message counts in a time range by conversation, sender and platform
+fn time_range_activity(start: u64, end: u64, accessible: &HashSet<String>) -> TimeRangeActivity {
+    let mut conversations: HashMap<String, u64> = HashMap::new();
+    let mut senders: HashMap<String, (String, u64)> = HashMap::new();
+    let mut platforms: HashMap<String, u64> = HashMap::new();
+    
+    // Newest first across conversations, so a cut leaves out the oldest
+    let conversation_ids: Vec<String> = accessible.iter().cloned().collect();
+    let (in_range, truncated) = crate::storage::messages::get_messages_in_range(&conversation_ids, start, end, MAX_ACTIVITY_MESSAGES);
+    
+    let message_count = in_range.len() as u64;
+    for message in &in_range {
+        *conversations.entry(message.conversation_id.clone()).or_insert(0) += 1;
+        senders.entry(message.sender.id.clone()).or_insert_with(|| (message.sender.name.clone(), 0)).1 += 1;
+        *platforms.entry(super::format_platform(&message.platform)).or_insert(0) += 1;
+    }
+    
+    let conversations = top_counts(conversations.into_iter()
+        .map(|(id, count)| {
+            let label = crate::storage::conversations::get_conversation(&id)
+                .map(|c| c.name)
+                .unwrap_or_else(|| id.clone());
+            CountEntry { id, label, count }
+        })
+        .collect());
+    let senders = top_counts(senders.into_iter()
+        .map(|(id, (label, count))| CountEntry { id, label, count })
+        .collect());
+    let platforms = top_counts(platforms.into_iter()
+        .map(|(label, count)| CountEntry { id: label.to_lowercase(), label, count })
+        .collect());
+    
+    TimeRangeActivity {
+        start,
+        end,
+        message_count,
+        conversations,
+        senders,
+        platforms,
+        truncated,
+    }
+}
+
+fn top_counts(mut entries: Vec<CountEntry>) -> Vec<CountEntry> {
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.id.cmp(&b.id)));
+    entries.truncate(MAX_BREAKDOWN_ROWS);
+    entries
+}
+
+// Co-occurrence graph around the requested entities
+fn relationship_graph(names: &[String], accessible: &HashSet<String>) -> RelationshipGraph {
+    let requested: Vec<EntityDetails> = names.iter()
+        .flat_map(|name| entities::lookup(name, accessible))
+        .collect();
+    let requested_keys: HashSet<String> = requested.iter().map(|e| e.key.clone()).collect();
+    
+    // Messages mentioning any requested entity
+    let mut message_ids: Vec<String> = requested_keys.iter()
+        .flat_map(|key| crate::storage::entities::get_entity_messages(key, accessible))
+        .collect();
+    message_ids.sort();
+    message_ids.dedup();
+    
+    let mut edges: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+    let mut mentions: HashMap<String, u64> = HashMap::new();
+    
+    for message_id in message_ids {
+        let keys: Vec<String> = crate::storage::entities::get_message_entities(&message_id)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        
+        for (i, a) in keys.iter().enumerate() {
+            *mentions.entry(a.clone()).or_insert(0) += 1;
+            for b in &keys[i + 1..] {
+                // Only edges touching a requested entity
+                if !requested_keys.contains(a) && !requested_keys.contains(b) {
+                    continue;
+                }
+                let pair = if a < b { (a.clone(), b.clone()) } else { (b.clone(), a.clone()) };
+                edges.entry(pair).or_default().push(message_id.clone());
+            }
+        }
+    }
+    
+    let mut edges: Vec<GraphEdge> = edges.into_iter()
+        .map(|((source, target), ids)| GraphEdge {
+            source,
+            target,
+            weight: ids.len() as u64,
+            message_ids: ids.into_iter().take(MAX_EDGE_MESSAGES).collect(),
+        })
+        .collect();
+    edges.sort_by(|a, b| b.weight.cmp(&a.weight).then_with(|| (&a.source, &a.target).cmp(&(&b.source, &b.target))));
+    
+    // Requested entities plus their strongest neighbours
+    let mut node_keys: Vec<String> = requested.iter().map(|e| e.key.clone()).collect();
+    for edge in &edges {
+        if node_keys.len() >= requested_keys.len() + MAX_RELATED_NODES {
+            break;
+        }
+        for key in [&edge.source, &edge.target] {
+            if !node_keys.contains(key) {
+                node_keys.push(key.clone());
+            }
+        }
+    }
+    edges.retain(|edge| node_keys.contains(&edge.source) && node_keys.contains(&edge.target));
+    
+    let nodes = node_keys.into_iter()
+        .filter_map(|key| {
+            let record = crate::storage::entities::get_entity(&key)?;
+            Some(GraphNode {
+                mentions: mentions.get(&key).copied().unwrap_or(0),
+                requested: requested_keys.contains(&key),
+                key,
+                name: record.name,
+                kind: record.kind,
+            })
+        })
+        .collect();
+    
+    RelationshipGraph { nodes, edges }
+}
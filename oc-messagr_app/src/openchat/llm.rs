@@ -0,0 +1,499 @@
+use crate::{Error, Result};
+use crate::connectors::http;
+use crate::storage::llm as llm_store;
+use candid::{CandidType, Deserialize};
+use openchat_sdk::QueryRequest as OCQueryRequest;
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+
+// Response size cap for completion outcalls; the cycle cost scales with it
+const LLM_MAX_RESPONSE_BYTES: u64 = 100_000;
+
+// Largest completion length a configuration may request
+const MAX_COMPLETION_TOKENS: u32 = 16_384;
+
+// Canister query that reduces completion responses to what replicas must agree on
+const LLM_TRANSFORM_METHOD: &str = "transform_llm_response";
+
+// Model APIs that answer every replica's request with a fresh completion.
+// They have to sit behind a caching relay, so configs pointing at them directly
+// are rejected.
+const RAW_MODEL_HOSTS: &[&str] = &["api.openai.com"];
+
+// Appended to the system prompt in JSON mode, for providers without a native JSON mode
+const JSON_INSTRUCTION: &str = "Respond with a single JSON object and nothing else.";
+
+// Which LLM backend the canister uses
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum LlmConfig {
+    // The OpenChat SDK query API
+    OpenChat,
+    // Any endpoint implementing the OpenAI /v1/chat/completions API. Every
+    // replica sends the request, so the endpoint must return the same
+    // completion for the same Idempotency-Key (a caching relay in front of
+    // the model) or the call fails consensus.
+    OpenAiCompatible {
+        endpoint: String,
+        model: String,
+        api_key: String,
+        max_tokens: u32,
+    },
+    // Replies with the scripted responses in order, cycling (for tests)
+    Mock {
+        responses: Vec<String>,
+    },
+}
+
+// Who a chat message is from
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ChatRole {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ChatMessage {
+    pub role: ChatRole,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn system(content: &str) -> Self {
+        Self { role: ChatRole::System, content: content.to_string() }
+    }
+    
+    pub fn user(content: &str) -> Self {
+        Self { role: ChatRole::User, content: content.to_string() }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CompletionOptions {
+    // Ask for a JSON object instead of free text
+    pub json_mode: bool,
+    pub max_tokens: Option<u32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Completion {
+    pub text: String,
+    pub model: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cycles: u128,
+}
+
+// Outcome of one provider call. Cycles are spent whether or not it succeeded.
+pub struct LlmCall {
+    pub result: Result<Completion>,
+    pub cycles: u128,
+}
+
+// A large language model that can continue a chat
+#[async_trait::async_trait(?Send)]
+pub trait LlmProvider {
+    // Identifier of the backing model, recorded alongside generated answers and usage
+    fn name(&self) -> String;
+    
+    // Tokens `text` takes up in the model's context window
+    fn count_tokens(&self, text: &str) -> usize {
+        estimate_tokens(text)
+    }
+    
+    async fn complete(&self, messages: &[ChatMessage], options: &CompletionOptions) -> LlmCall;
+}
+
+// Completions through the OpenChat SDK. The SDK only exposes its query API, so
+// the chat is flattened into the query text and the explanation is the reply.
+pub struct OpenChatLlmProvider;
+
+#[async_trait::async_trait(?Send)]
+impl LlmProvider for OpenChatLlmProvider {
+    fn name(&self) -> String {
+        "openchat".to_string()
+    }
+    
+    async fn complete(&self, messages: &[ChatMessage], options: &CompletionOptions) -> LlmCall {
+        // The SDK doesn't report what it charged; the balance difference is close
+        // enough unless other calls complete in between
+        let balance_before = ic_cdk::api::canister_balance128();
+        let prompt = flatten_chat(&with_json_instruction(messages, options));
+        
+        let result = async {
+            let client = super::client::create_openchat_client().await?;
+            
+            let request = OCQueryRequest {
+                query: prompt.clone(),
+                sources: Vec::new(),
+                max_responses: 1,
+                conversation_id: None,
+            };
+            
+            let response = client.query(request).await?;
+            response.explanation
+                .ok_or_else(|| Error::QueryError("OpenChat returned no completion".to_string()))
+        }.await;
+        
+        let cycles = balance_before.saturating_sub(ic_cdk::api::canister_balance128());
+        
+        LlmCall {
+            result: result.map(|text| Completion {
+                prompt_tokens: self.count_tokens(&prompt) as u64,
+                completion_tokens: self.count_tokens(&text) as u64,
+                text,
+                model: self.name(),
+                cycles,
+            }),
+            cycles,
+        }
+    }
+}
+
+// Completions from an OpenAI-compatible endpoint via HTTPS outcalls
+pub struct HttpLlmProvider {
+    endpoint: String,
+    model: String,
+    api_key: String,
+    max_tokens: u32,
+}
+
+#[derive(Serialize)]
+struct ChatCompletionRequest<'a> {
+    model: &'a str,
+    messages: Vec<ApiMessage<'a>>,
+    max_tokens: u32,
+    // Narrows how far completions vary; it does not make them identical
+    temperature: f32,
+    seed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+}
+
+#[derive(Serialize)]
+struct ApiMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+}
+
+#[derive(Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: &'static str,
+}
+
+// Only the fields the canister reads; the transform drops the rest (ids,
+// creation times, fingerprints) because they differ on every request
+#[derive(Serialize, Deserialize)]
+struct ChatCompletionResponse {
+    choices: Vec<Choice>,
+    usage: Option<Usage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Choice {
+    message: ChoiceMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChoiceMessage {
+    content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmProvider for HttpLlmProvider {
+    fn name(&self) -> String {
+        self.model.clone()
+    }
+    
+    async fn complete(&self, messages: &[ChatMessage], options: &CompletionOptions) -> LlmCall {
+        let messages = with_json_instruction(messages, options);
+        let request = ChatCompletionRequest {
+            model: &self.model,
+            messages: messages.iter()
+                .map(|m| ApiMessage { role: role_name(&m.role), content: &m.content })
+                .collect(),
+            max_tokens: options.max_tokens.unwrap_or(self.max_tokens),
+            temperature: 0.0,
+            seed: 0,
+            response_format: if options.json_mode {
+                Some(ResponseFormat { kind: "json_object" })
+            } else {
+                None
+            },
+        };
+        
+        let body = match serde_json::to_vec(&request) {
+            Ok(body) => body,
+            Err(e) => return LlmCall {
+                result: Err(Error::InternalError(format!("Failed to encode completion request: {}", e))),
+                cycles: 0,
+            },
+        };
+        
+        // Replicas send identical requests at the same consensus time, so
+        // they share the key and a relay can answer all of them with one
+        // completion
+        let mut hasher = Sha256::new();
+        hasher.update(&body);
+        hasher.update(ic_cdk::api::time().to_be_bytes());
+        let idempotency_key: String = hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        
+        let outgoing = http::OutgoingRequest::post(&self.endpoint, body)
+            .with_bearer_token(&self.api_key)
+            .with_header("Idempotency-Key", &idempotency_key)
+            .with_max_response_bytes(LLM_MAX_RESPONSE_BYTES)
+            .with_transform(LLM_TRANSFORM_METHOD);
+        let (response, cycles) = http::send_metered(outgoing).await;
+        
+        let result = response
+            .and_then(|response| http::decode_json::<ChatCompletionResponse>(&self.endpoint, response))
+            .and_then(|response| {
+                let text = response.choices.into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content)
+                    .ok_or_else(|| Error::QueryError("Completion endpoint returned no choices".to_string()))?;
+                
+                // Fall back to our own estimate if the endpoint doesn't report usage
+                let (prompt_tokens, completion_tokens) = match response.usage {
+                    Some(usage) => (usage.prompt_tokens, usage.completion_tokens),
+                    None => (
+                        messages.iter().map(|m| self.count_tokens(&m.content) as u64).sum(),
+                        self.count_tokens(&text) as u64,
+                    ),
+                };
+                
+                Ok(Completion {
+                    text,
+                    model: self.name(),
+                    prompt_tokens,
+                    completion_tokens,
+                    cycles,
+                })
+            });
+        
+        LlmCall { result, cycles }
+    }
+}
+
+// Deterministic provider that replays a script, for tests and local deployments
+pub struct ScriptedLlmProvider {
+    responses: Vec<String>,
+}
+
+#[async_trait::async_trait(?Send)]
+impl LlmProvider for ScriptedLlmProvider {
+    fn name(&self) -> String {
+        "mock".to_string()
+    }
+    
+    async fn complete(&self, messages: &[ChatMessage], _options: &CompletionOptions) -> LlmCall {
+        let call_index = llm_store::next_mock_position() as usize;
+        
+        // With no script, echo the last user message
+        let text = if self.responses.is_empty() {
+            messages.iter()
+                .rev()
+                .find(|m| m.role == ChatRole::User)
+                .map(|m| m.content.clone())
+                .unwrap_or_default()
+        } else {
+            self.responses[call_index % self.responses.len()].clone()
+        };
+        
+        LlmCall {
+            result: Ok(Completion {
+                prompt_tokens: messages.iter().map(|m| self.count_tokens(&m.content) as u64).sum(),
+                completion_tokens: self.count_tokens(&text) as u64,
+                text,
+                model: self.name(),
+                cycles: 0,
+            }),
+            cycles: 0,
+        }
+    }
+}
+
+// Provider for the current configuration
+pub fn active_provider() -> Box<dyn LlmProvider> {
+    match llm_store::get_config().unwrap_or(LlmConfig::OpenChat) {
+        LlmConfig::OpenChat => Box::new(OpenChatLlmProvider),
+        LlmConfig::OpenAiCompatible { endpoint, model, api_key, max_tokens } => {
+            Box::new(HttpLlmProvider {
+                endpoint,
+                model,
+                api_key,
+                max_tokens,
+            })
+        },
+        LlmConfig::Mock { responses } => Box::new(ScriptedLlmProvider { responses }),
+    }
+}
+
+// Switch provider
+pub fn set_config(config: LlmConfig) -> Result<()> {
+    if let LlmConfig::OpenAiCompatible { endpoint, model, max_tokens, .. } = &config {
+        let host = match endpoint.strip_prefix("https://") {
+            Some(rest) => rest.split(['/', ':']).next().unwrap_or_default().to_lowercase(),
+            None => return Err(Error::InvalidParameters("LLM endpoint must use HTTPS".to_string())),
+        };
+        if RAW_MODEL_HOSTS.contains(&host.as_str()) {
+            return Err(Error::InvalidParameters(format!(
+                "{} doesn't deduplicate requests across replicas; point the endpoint at a caching relay", host
+            )));
+        }
+        if model.is_empty() {
+            return Err(Error::InvalidParameters("LLM model is required".to_string()));
+        }
+        if *max_tokens == 0 || *max_tokens > MAX_COMPLETION_TOKENS {
+            return Err(Error::InvalidParameters(format!(
+                "max_tokens must be between 1 and {}", MAX_COMPLETION_TOKENS
+            )));
+        }
+    }
+    
+    llm_store::set_config(config);
+    
+    // A new script starts from its first response
+    llm_store::reset_mock_position();
+    
+    Ok(())
+}
+
+// Keep only the completion text and usage, and drop all headers. Error
+// bodies are passed through so failures stay readable.
+pub fn transform_response(args: TransformArgs) -> HttpResponse {
+    let response = args.response;
+    let ok = u16::try_from(response.status.0.clone()).map_or(false, |status| (200..300).contains(&status));
+    
+    let body = match serde_json::from_slice::<ChatCompletionResponse>(&response.body) {
+        Ok(completion) if ok => serde_json::to_vec(&completion).unwrap_or_default(),
+        _ => response.body,
+    };
+    
+    HttpResponse {
+        status: response.status,
+        headers: Vec::new(),
+        body,
+    }
+}
+
+// Run a chat completion with the active provider, recording its usage and cost
+pub async fn complete(messages: &[ChatMessage], options: &CompletionOptions) -> Result<Completion> {
+    let provider = active_provider();
+    let call = provider.complete(messages, options).await;
+    
+    match &call.result {
+        Ok(completion) => llm_store::record_usage(
+            &provider.name(),
+            completion.prompt_tokens,
+            completion.completion_tokens,
+            call.cycles,
+            false,
+        ),
+        Err(_) => llm_store::record_usage(&provider.name(), 0, 0, call.cycles, true),
+    }
+    
+    call.result
+}
+
+// Run a completion in JSON mode and decode the reply
+pub async fn complete_json<T: DeserializeOwned>(messages: &[ChatMessage], max_tokens: Option<u32>) -> Result<T> {
+    let options = CompletionOptions { json_mode: true, max_tokens };
+    let completion = complete(messages, &options).await?;
+    parse_json(&completion.text)
+}
+
+// Decode a JSON reply, tolerating prose or code fences around the object
+pub fn parse_json<T: DeserializeOwned>(text: &str) -> Result<T> {
+    if let Ok(value) = serde_json::from_str(text.trim()) {
+        return Ok(value);
+    }
+    
+    let object = match (text.find('{'), text.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &text[start..=end],
+        _ => return Err(Error::QueryError("LLM reply did not contain a JSON object".to_string())),
+    };
+    
+    serde_json::from_str(object)
+        .map_err(|e| Error::QueryError(format!("LLM reply was not valid JSON: {}", e)))
+}
+
+// Rough token count: about four characters per token, and at least one per word
+pub fn estimate_tokens(text: &str) -> usize {
+    let chars = text.chars().count();
+    let words = text.split_whitespace().count();
+    ((chars + 3) / 4).max(words)
+}
+
+// Split items, in order, into chunks of at most `budget` tokens. An item over
+// the budget gets a chunk of its own.
+pub fn chunk_by_tokens<'a, T>(items: &'a [T], budget: usize, count_tokens: impl Fn(&T) -> usize) -> Vec<Vec<&'a T>> {
+    let mut chunks = Vec::new();
+    let mut current: Vec<&T> = Vec::new();
+    let mut current_tokens = 0;
+    
+    for item in items {
+        let tokens = count_tokens(item);
+        
+        if current_tokens + tokens > budget && !current.is_empty() {
+            chunks.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+        
+        current.push(item);
+        current_tokens += tokens;
+    }
+    
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    
+    chunks
+}
+
+// Add the JSON instruction to the system prompt when in JSON mode
+fn with_json_instruction(messages: &[ChatMessage], options: &CompletionOptions) -> Vec<ChatMessage> {
+    let mut messages = messages.to_vec();
+    if !options.json_mode {
+        return messages;
+    }
+    
+    match messages.iter_mut().find(|m| m.role == ChatRole::System) {
+        Some(system) => system.content = format!("{}\n\n{}", system.content, JSON_INSTRUCTION),
+        None => messages.insert(0, ChatMessage::system(JSON_INSTRUCTION)),
+    }
+    
+    messages
+}
+
+fn role_name(role: &ChatRole) -> &'static str {
+    match role {
+        ChatRole::System => "system",
+        ChatRole::User => "user",
+        ChatRole::Assistant => "assistant",
+    }
+}
+
+// Render a chat as a single prompt for backends without a chat format
+fn flatten_chat(messages: &[ChatMessage]) -> String {
+    messages.iter()
+        .map(|message| {
+            let role = match message.role {
+                ChatRole::System => "System",
+                ChatRole::User => "User",
+                ChatRole::Assistant => "Assistant",
+            };
+            format!("{}: {}", role, message.content)
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n")
+}
@@ -1,19 +1,31 @@
 mod client;
-mod types;
+#[path = "type.rs"]
+pub mod types;
 mod query;
-mod embeddings;
+pub mod embeddings;
+pub mod vector_index;
+pub mod llm;
+pub mod rag;
+pub mod insights;
+pub mod intents;
+pub mod trends;
+pub mod topics;
+pub mod summaries;
 
-use crate::{Message, Conversation, QueryResult, Error, Result, Platform};
+use crate::{Message, Conversation, QueryResult, SemanticSearchFilters, Error, Result, Platform};
 use openchat_sdk::{OpenChatClient, QueryResponse, MessageContent, QueryRequest as OCQueryRequest};
 use ic_cdk::api::{call, management_canister::main::{CanisterIdRecord, CanisterStatusResponse, canister_status}};
 use ic_cdk::api::time;
 use ic_cdk::trap;
 use candid::Principal;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 // OpenChat Community canister ID on mainnet
 const OPENCHAT_COMMUNITY_CANISTER_ID: &str = "xomae-vyaaa-aaaaq-aabhq-cai";
 
+// Upper bound on vector index candidates examined for one semantic search
+const MAX_SEMANTIC_CANDIDATES: usize = 2000;
+
 // AI-assisted query interface with OpenChat SDK
 pub async fn query_with_openchat(
     query_text: &str,
@@ -45,7 +57,8 @@ pub async fn query_with_openchat(
     let formatted_messages = format_messages_for_openchat(&accessible_messages);
     
     // Prepare query with context window optimization
-    let optimized_messages = optimize_context_window(formatted_messages, query_text);
+    let (model, query_vector) = embeddings::embed_query(query_text).await?;
+    let optimized_messages = optimize_context_window(formatted_messages, &model, &query_vector);
     
     // Build the query request
     let request = OCQueryRequest {
@@ -83,15 +96,14 @@ fn format_messages_for_openchat(messages: &[Message]) -> Vec<MessageContent> {
 }
 
 // Optimize context window by selecting most relevant messages
-fn optimize_context_window(messages: Vec<MessageContent>, query: &str) -> Vec<MessageContent> {
-    // Use vector embeddings to find semantic similarity with query
-    let embedded_query = embeddings::embed_text(query);
-    
-    // Score and rank messages by relevance to query
+fn optimize_context_window(messages: Vec<MessageContent>, model: &str, query_vector: &[f32]) -> Vec<MessageContent> {
+    // Score and rank messages by similarity of their stored embeddings to the query;
+    // messages not embedded yet rank last
     let mut scored_messages: Vec<(MessageContent, f32)> = messages.into_iter()
         .map(|msg| {
-            let embedded_msg = embeddings::embed_text(&msg.text);
-            let score = embeddings::calculate_similarity(&embedded_query, &embedded_msg);
+            let score = embeddings::message_vector(&msg.id, &msg.text, model)
+                .map(|vector| embeddings::calculate_similarity(query_vector, &vector))
+                .unwrap_or(0.0);
             (msg, score)
         })
         .collect();
@@ -119,6 +131,73 @@ fn optimize_context_window(messages: Vec<MessageContent>, query: &str) -> Vec<Me
     selected_messages
 }
 
+// Semantic search over the caller's messages using the vector index.
+// Returns messages with their cosine similarity to the query, best first.
+pub async fn semantic_search(
+    query_text: &str,
+    k: usize,
+    user_id: &str,
+    filters: &SemanticSearchFilters
+) -> Result<Vec<(Message, f32)>> {
+    let (model, query_vector) = embeddings::embed_query(query_text).await?;
+    
+    let matches = filters.matcher(user_id);
+    Ok(nearest_messages(&query_vector, &model, k, user_id, |message| matches(message)))
+}
+
+// Messages most similar to the given one, excluding the message itself
+pub fn find_similar(message: &Message, k: usize, user_id: &str) -> Result<Vec<(Message, f32)>> {
+    let model = embeddings::active_provider().model();
+    let vector = embeddings::message_vector(&message.id, &message.content.text, &model)
+        .ok_or_else(|| Error::QueryError(format!("Message {} has not been embedded yet", message.id)))?;
+    
+    Ok(nearest_messages(&vector, &model, k, user_id, |other| other.id != message.id))
+}
+
+// Nearest neighbours of a vector among messages the user can access that pass `keep`
+fn nearest_messages<F>(
+    vector: &[f32],
+    model: &str,
+    k: usize,
+    user_id: &str,
+    keep: F
+) -> Vec<(Message, f32)>
+where
+    F: Fn(&Message) -> bool,
+{
+    // Only conversations the caller takes part in
+    let accessible: HashSet<String> = crate::storage::conversations::get_user_conversations(user_id, None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    // Filters are applied after the ANN search, so widen it until enough hits survive
+    let mut candidates_wanted = k * 4;
+    loop {
+        let candidates = vector_index::search(vector, candidates_wanted, candidates_wanted);
+        let exhausted = candidates.len() < candidates_wanted || candidates_wanted >= MAX_SEMANTIC_CANDIDATES;
+        
+        let results: Vec<(Message, f32)> = candidates.into_iter()
+            .filter(|(message_id, _)| {
+                crate::storage::embeddings::get_embedding(message_id)
+                    .map(|e| e.model == model)
+                    .unwrap_or(false)
+            })
+            .filter_map(|(message_id, similarity)| {
+                crate::storage::messages::get_message(&message_id).map(|m| (m, similarity))
+            })
+            .filter(|(message, _)| accessible.contains(&message.conversation_id) && keep(message))
+            .take(k)
+            .collect();
+        
+        if results.len() >= k || exhausted {
+            return results;
+        }
+        
+        candidates_wanted = (candidates_wanted * 2).min(MAX_SEMANTIC_CANDIDATES);
+    }
+}
+
 // Process query response from OpenChat
 fn process_query_response(
     response: QueryResponse,
@@ -161,5 +240,6 @@ fn format_platform(platform: &Platform) -> String {
         Platform::Twitter => "Twitter".to_string(),
         Platform::Facebook => "Facebook".to_string(),
         Platform::WhatsApp => "WhatsApp".to_string(),
+        Platform::Instagram => "Instagram".to_string(),
     }
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 use crate::{Message, Error, Result, QueryResult};
 use crate::storage::messages;
+use crate::indexing;
 use super::embeddings;
 use openchat_sdk::{MessageContent, EntityExtraction, TopicAnalysis};
 use std::collections::{HashMap, HashSet};
@@ -36,14 +37,32 @@ pub fn detect_query_intent(query: &str) -> QueryIntent {
     }
     
     // Check for relationship analysis
+    let entities = extract_entities(query);
     if query_lower.contains("relationship") || query_lower.contains("between") || 
        query_lower.contains("connection") {
-        let entities = extract_entities(&query_lower);
         if entities.len() >= 2 {
             return QueryIntent::RelationshipAnalysis(entities);
         }
     }
     
+    // Check for entity lookup: only questions about the entity itself, so
+    // "notes about the Monday release" stays a search
+    let asked: Vec<String> = entities.into_iter()
+        .filter(|entity| asks_about_entity(&query_lower, &entity.to_lowercase()))
+        .collect();
+    if !asked.is_empty() {
+        return QueryIntent::EntityLookup(asked);
+    }
+    
+    // Check for activity in a time range ("what happened last week")
+    if query_lower.contains("what happened") || query_lower.contains("activity") || 
+       query_lower.contains("busiest") {
+        let (_, filters) = indexing::search::SearchFilters::from_natural_language(query);
+        if let (Some(start), Some(end)) = (filters.start_time, filters.end_time) {
+            return QueryIntent::TimeAnalysis(start as i64, end as i64);
+        }
+    }
+    
     // Check for topic summary
     if query_lower.contains("summarize") || query_lower.contains("summary") || 
        query_lower.starts_with("what") {
@@ -56,20 +75,28 @@ pub fn detect_query_intent(query: &str) -> QueryIntent {
     QueryIntent::Search(query.to_string())
 }
 
-// Extract entities from a query
+// Whether the query is a question about the entity, e.g. "who is Alice",
+// "mentions of Acme" or "what did Bob say"
+fn asks_about_entity(query: &str, entity: &str) -> bool {
+    const BEFORE: [&str; 9] = [
+        "who is ", "who's ", "what is ", "what's ", "tell me about ",
+        "mentions of ", "messages mentioning ", "what did ", "what has ",
+    ];
+    const AFTER: [&str; 3] = [" mentioned", " was mentioned", " said"];
+    
+    let query = query.trim_end_matches(|c: char| c == '?' || c == '.' || c.is_whitespace());
+    
+    BEFORE.iter().any(|cue| {
+        query.starts_with(&format!("{}{}", cue, entity)) || query.contains(&format!(" {}{}", cue, entity))
+    }) || AFTER.iter().any(|cue| query.ends_with(&format!("{}{}", entity, cue)))
+}
+
+// Extract entities from a query: recognised patterns plus names in the entity index
 fn extract_entities(query: &str) -> Vec<String> {
-    // This is a placeholder - a real implementation would use NLP
-    // For now, we'll do a very simple extraction of capitalized words
-    let words = query.split_whitespace();
-    let mut entities = Vec::new();
-    
-    for word in words {
-        if !word.is_empty() && word.chars().next().unwrap().is_uppercase() {
-            entities.push(word.to_string());
-        }
-    }
-    
-    entities
+    indexing::entities::query_entities(query)
+        .into_iter()
+        .map(|entity| entity.name)
+        .collect()
 }
 
 // Extract the main topic from a query
@@ -134,10 +161,12 @@ pub fn generate_topic_summary(messages: &[Message], topic: &str) -> String {
 fn filter_messages_by_topic(messages: &[Message], topic: &str) -> Vec<Message> {
     let topic_embedding = embeddings::embed_text(topic);
     
+    // Reuse stored local embeddings where we have them
     let mut scored_messages: Vec<(Message, f32)> = messages.iter()
         .map(|msg| {
-            let msg_embedding = embeddings::embed_text(&msg.content.text);
-            let score = embeddings::calculate_similarity(&topic_embedding, &msg_embedding);
+            let score = embeddings::message_vector(&msg.id, &msg.content.text, embeddings::LOCAL_MODEL)
+                .map(|msg_embedding| embeddings::calculate_similarity(&topic_embedding, &msg_embedding))
+                .unwrap_or(0.0);
             (msg.clone(), score)
         })
         .filter(|(_, score)| *score > 0.1) // Threshold for relevance
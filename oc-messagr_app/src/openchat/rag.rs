@@ -0,0 +1,309 @@
+use crate::{Message, SemanticSearchFilters, Result};
+use crate::indexing::{self, search::SearchFilters, ranking::RankingContext};
+use super::embeddings;
+use super::llm::{self, ChatMessage, CompletionOptions, LlmProvider};
+use candid::{CandidType, Deserialize};
+use std::collections::{HashMap, HashSet};
+
+// Candidates taken from each index before fusion
+const TEXT_CANDIDATES: usize = 50;
+const VECTOR_CANDIDATES: usize = 50;
+
+// Reciprocal rank fusion constant; larger values flatten the advantage of top ranks
+const RRF_K: f32 = 60.0;
+
+// Maximum messages packed into the prompt
+const MAX_SOURCES: usize = 40;
+
+// Token budget for the packed messages
+const CONTEXT_TOKEN_BUDGET: usize = 6000;
+
+// Tokens taken by the marker, timestamp and sender on each packed line
+const LINE_OVERHEAD_TOKENS: usize = 16;
+
+// Longer messages are cut to this many characters in the prompt
+const MAX_SOURCE_CHARS: usize = 1000;
+
+// Length of the excerpt returned with each citation
+const EXCERPT_CHARS: usize = 200;
+
+const SYSTEM_PROMPT: &str = "You answer questions about the user's chat history. \
+Use only the numbered messages provided. After each claim, cite the messages that \
+support it with their numbers in square brackets, e.g. [3] or [2, 5]. If the messages \
+do not contain the answer, say so plainly and do not guess.";
+
+// A message cited by an answer
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Citation {
+    pub marker: u32,
+    pub message_id: String,
+    pub conversation_id: String,
+    pub sender: String,
+    pub timestamp: u64,
+    pub excerpt: String,
+}
+
+// A generated answer with the messages it cites
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Answer {
+    pub question: String,
+    pub text: String,
+    pub citations: Vec<Citation>,
+    pub model: String,
+    pub sources_considered: u64,
+    // Milliseconds, like message timestamps
+    pub generated_at: u64,
+}
+
+// Answer a question from the user's messages, citing the messages used
+pub async fn answer_question(
+    question: &str,
+    user_id: &str,
+    filters: &SemanticSearchFilters
+) -> Result<Answer> {
+    let provider = llm::active_provider();
+    
+    // Only conversations the caller takes part in
+    let accessible: HashSet<String> = crate::storage::conversations::get_user_conversations(user_id, None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    let candidates = retrieve(question, user_id, filters, &accessible).await;
+    if candidates.is_empty() {
+        return Ok(Answer {
+            question: question.to_string(),
+            text: "I couldn't find any messages related to this question.".to_string(),
+            citations: Vec::new(),
+            model: provider.name(),
+            sources_considered: 0,
+            generated_at: ic_cdk::api::time() / 1_000_000,
+        });
+    }
+    
+    let (context, sources) = pack_context(candidates, provider.as_ref());
+    
+    let prompt = vec![
+        ChatMessage::system(SYSTEM_PROMPT),
+        ChatMessage::user(&format!("Messages:\n\n{}\nQuestion: {}", context, question)),
+    ];
+    let completion = llm::complete(&prompt, &CompletionOptions::default()).await?;
+    
+    let (text, citations) = resolve_citations(&completion.text, &sources, &accessible);
+    
+    Ok(Answer {
+        question: question.to_string(),
+        text,
+        citations,
+        model: completion.model,
+        sources_considered: sources.len() as u64,
+        generated_at: ic_cdk::api::time() / 1_000_000,
+    })
+}
+
+// Hybrid retrieval: full-text and vector candidates merged by reciprocal rank fusion
+async fn retrieve(
+    question: &str,
+    user_id: &str,
+    filters: &SemanticSearchFilters,
+    accessible: &HashSet<String>
+) -> Vec<Message> {
+    let mut fused: HashMap<String, f32> = HashMap::new();
+    let matches = filters.matcher(user_id);
+    
+    for (rank, message_id) in text_candidates(question, user_id, filters).into_iter().enumerate() {
+        *fused.entry(message_id).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+    }
+    
+    // Without an embedding we still have the text results
+    match embeddings::embed_query(question).await {
+        Ok((model, vector)) => {
+            let nearest = super::nearest_messages(&vector, &model, VECTOR_CANDIDATES, user_id, |m| matches(m));
+            for (rank, (message, _)) in nearest.into_iter().enumerate() {
+                *fused.entry(message.id).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+            }
+        },
+        Err(e) => ic_cdk::println!("Vector retrieval skipped: {:?}", e),
+    }
+    
+    let mut ranked: Vec<(String, f32)> = fused.into_iter().collect();
+    ranked.sort_by(|(id_a, a), (id_b, b)| b.total_cmp(a).then_with(|| id_a.cmp(id_b)));
+    
+    ranked.into_iter()
+        .filter_map(|(message_id, _)| crate::storage::messages::get_message(&message_id))
+        .filter(|message| accessible.contains(&message.conversation_id) && matches(message))
+        .take(MAX_SOURCES)
+        .collect()
+}
+
+// Message IDs matching the question in the full-text index, best first
+fn text_candidates(question: &str, user_id: &str, filters: &SemanticSearchFilters) -> Vec<String> {
+    let (clean_query, mut text_filters) = SearchFilters::from_natural_language(question);
+    if clean_query.trim().is_empty() {
+        return Vec::new();
+    }
+    
+    // Explicit filters take precedence over ones parsed from the question
+    if filters.platform.is_some() {
+        text_filters.platform = filters.platform.clone();
+    }
+    if filters.conversation_id.is_some() {
+        text_filters.conversation_id = filters.conversation_id.clone();
+    }
+    text_filters.start_time = filters.start_time.or(text_filters.start_time);
+    text_filters.end_time = filters.end_time.or(text_filters.end_time);
+    text_filters.limit = TEXT_CANDIDATES;
+    
+    let ranking = RankingContext::for_user(user_id);
+    match indexing::search_hits(&clean_query, &text_filters, TEXT_CANDIDATES, &ranking) {
+        Ok(hits) => hits.into_iter()
+            .filter(|hit| hit.text_match.is_some())
+            .map(|hit| hit.message_id)
+            .collect(),
+        Err(e) => {
+            ic_cdk::println!("Text retrieval failed: {:?}", e);
+            Vec::new()
+        },
+    }
+}
+
+// Pack the most relevant messages that fit the budget, grouped by conversation
+// and in chronological order within each. Returns the prompt text and the
+// sources in marker order (marker n is sources[n - 1]).
+fn pack_context(candidates: Vec<Message>, provider: &dyn LlmProvider) -> (String, Vec<Message>) {
+    let mut budget = CONTEXT_TOKEN_BUDGET;
+    let mut groups: Vec<(String, Vec<Message>)> = Vec::new();
+    
+    for message in candidates {
+        let tokens = provider.count_tokens(&truncate_chars(&message.content.text, MAX_SOURCE_CHARS)) + LINE_OVERHEAD_TOKENS;
+        if tokens > budget {
+            continue;
+        }
+        budget -= tokens;
+        
+        // Conversations appear in order of their most relevant message
+        match groups.iter_mut().find(|(conversation_id, _)| *conversation_id == message.conversation_id) {
+            Some((_, messages)) => messages.push(message),
+            None => groups.push((message.conversation_id.clone(), vec![message])),
+        }
+    }
+    
+    let mut context = String::new();
+    let mut sources = Vec::new();
+    
+    for (conversation_id, mut messages) in groups {
+        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+        
+        let conversation = crate::storage::conversations::get_conversation(&conversation_id);
+        let name = conversation.as_ref().map(|c| c.name.clone()).unwrap_or_else(|| conversation_id.clone());
+        let platform = super::format_platform(&messages[0].platform);
+        context.push_str(&format!("### {} ({})\n", name, platform));
+        
+        for message in messages {
+            sources.push(message);
+            let message = &sources[sources.len() - 1];
+            context.push_str(&format!(
+                "[{}] {} {}: {}\n",
+                sources.len(),
+                format_time(message.timestamp),
+                message.sender.name,
+                truncate_chars(&message.content.text, MAX_SOURCE_CHARS).replace('\n', " ")
+            ));
+        }
+        
+        context.push('\n');
+    }
+    
+    (context, sources)
+}
+
+// Map [n] markers in the completion to messages. Markers that don't refer to a
+// packed message the caller can access are removed from the text.
+fn resolve_citations(
+    completion: &str,
+    sources: &[Message],
+    accessible: &HashSet<String>
+) -> (String, Vec<Citation>) {
+    let mut text = String::with_capacity(completion.len());
+    let mut citations: Vec<Citation> = Vec::new();
+    let mut rest = completion;
+    
+    while let Some(open) = rest.find('[') {
+        text.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        
+        let close = match after.find(']') {
+            Some(close) => close,
+            None => {
+                text.push_str(&rest[open..]);
+                rest = "";
+                break;
+            },
+        };
+        
+        match parse_markers(&after[..close]) {
+            Some(markers) => {
+                let valid: Vec<u32> = markers.into_iter()
+                    .filter(|&marker| {
+                        marker >= 1 && sources.get(marker as usize - 1)
+                            .map(|source| accessible.contains(&source.conversation_id))
+                            .unwrap_or(false)
+                    })
+                    .collect();
+                
+                for &marker in &valid {
+                    if !citations.iter().any(|c| c.marker == marker) {
+                        let source = &sources[marker as usize - 1];
+                        citations.push(Citation {
+                            marker,
+                            message_id: source.id.clone(),
+                            conversation_id: source.conversation_id.clone(),
+                            sender: source.sender.name.clone(),
+                            timestamp: source.timestamp,
+                            excerpt: truncate_chars(&source.content.text, EXCERPT_CHARS),
+                        });
+                    }
+                }
+                
+                if !valid.is_empty() {
+                    let markers: Vec<String> = valid.iter().map(|m| m.to_string()).collect();
+                    text.push_str(&format!("[{}]", markers.join(", ")));
+                }
+            },
+            // Not a citation, keep the brackets as written
+            None => text.push_str(&rest[open..open + close + 2]),
+        }
+        
+        rest = &after[close + 1..];
+    }
+    
+    text.push_str(rest);
+    citations.sort_by_key(|c| c.marker);
+    
+    (text.trim().to_string(), citations)
+}
+
+// "3" or "2, 5" -> marker numbers; anything else isn't a citation
+fn parse_markers(inner: &str) -> Option<Vec<u32>> {
+    if inner.trim().is_empty() {
+        return None;
+    }
+    
+    inner.split(',')
+        .map(|part| part.trim().parse::<u32>().ok())
+        .collect()
+}
+
+fn format_time(timestamp_ms: u64) -> String {
+    chrono::NaiveDateTime::from_timestamp_millis(timestamp_ms as i64)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_else(|| timestamp_ms.to_string())
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        format!("{}...", text.chars().take(max_chars).collect::<String>())
+    }
+}
@@ -0,0 +1,394 @@
+use crate::{Message, Error, Result};
+use super::llm::{self, ChatMessage};
+use crate::storage::summaries as summary_store;
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+// Rolling conversation summaries. New messages are queued as they are stored
+// and only that delta is summarised and folded into the stored summary.
+
+// Token budget for the messages in one update
+const CHUNK_TOKEN_BUDGET: usize = 6000;
+
+// Completion length for summary calls
+const SUMMARY_MAX_TOKENS: u32 = 1536;
+
+// Longer messages are cut to this many characters
+const MAX_MESSAGE_CHARS: usize = 1000;
+
+// Recent history summarised when a conversation has no summary yet
+const MAX_INITIAL_MESSAGES: usize = 1000;
+
+// Updates kept for "since" digests; older ones are dropped
+const MAX_SEGMENTS: usize = 100;
+
+const MAX_THREADS: usize = 50;
+const MAX_POINTS: usize = 10;
+
+const SUMMARY_PROMPT: &str = "You maintain a running summary of a chat conversation. You \
+are given the current summary as a JSON object (empty for a new conversation), followed by \
+new messages, each given as [message_id] (thread thread_id) timestamp sender: text. Respond \
+with a JSON object of this shape:
+{\"summary\": \"...\", \"key_points\": [\"...\"], \"action_items\": [\"...\"],
+ \"update\": {\"summary\": \"...\", \"key_points\": [\"...\"]},
+ \"threads\": [{\"thread_id\": \"...\", \"summary\": \"...\"}]}
+summary, key_points and action_items describe the whole conversation including the new \
+messages; drop action items the new messages resolve. update describes only what the new \
+messages add. threads has an updated summary for each thread in the new messages.";
+
+// One update to a conversation summary, covering the messages it added
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SummarySegment {
+    pub start: u64,
+    pub end: u64,
+    pub message_count: u64,
+    pub summary: String,
+    pub key_points: Vec<String>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ThreadSummary {
+    pub thread_id: String,
+    pub summary: String,
+    pub message_count: u64,
+    pub last_message_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConversationSummary {
+    pub conversation_id: String,
+    pub summary: String,
+    pub key_points: Vec<String>,
+    pub action_items: Vec<String>,
+    pub threads: Vec<ThreadSummary>,
+    // Oldest first
+    pub segments: Vec<SummarySegment>,
+    pub message_count: u64,
+    pub last_message_at: u64,
+    pub updated_at: u64,
+}
+
+// "What happened since I last looked"
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SummaryDigest {
+    pub conversation_id: String,
+    pub since: Option<u64>,
+    pub summary: String,
+    pub key_points: Vec<String>,
+    pub action_items: Vec<String>,
+    // Updates with messages after `since`
+    pub changes: Vec<SummarySegment>,
+    pub threads: Vec<ThreadSummary>,
+    pub new_message_count: u64,
+    // `since` predates the oldest kept update, so `changes` may be incomplete
+    pub partial: bool,
+    // Messages still waiting to be summarised
+    pub pending_messages: u64,
+    pub updated_at: u64,
+}
+
+// Current summary sent with each update
+#[derive(Serialize)]
+struct SummaryState<'a> {
+    summary: &'a str,
+    key_points: &'a [String],
+    action_items: &'a [String],
+    threads: Vec<ThreadState<'a>>,
+}
+
+#[derive(Serialize)]
+struct ThreadState<'a> {
+    thread_id: &'a str,
+    summary: &'a str,
+}
+
+// Shape of the model's output. Everything is optional so a partial reply still parses.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SummaryReply {
+    summary: String,
+    key_points: Vec<String>,
+    action_items: Vec<String>,
+    update: UpdateReply,
+    threads: Vec<ThreadReply>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct UpdateReply {
+    summary: String,
+    key_points: Vec<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ThreadReply {
+    thread_id: String,
+    summary: String,
+}
+
+thread_local! {
+    // Conversations being updated, so concurrent calls don't overwrite each other
+    static REFRESHING: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+// Summary of a conversation, brought up to date first, with the updates after `since`
+pub async fn conversation_digest(conversation_id: &str, since: Option<u64>) -> Result<SummaryDigest> {
+    let summary = match refresh(conversation_id).await {
+        Ok(summary) => summary,
+        // Serve the stored summary if the update failed; its messages stay queued
+        Err(e) => match summary_store::get_summary(conversation_id) {
+            Some(summary) => {
+                ic_cdk::println!("Failed to update summary of {}: {:?}", conversation_id, e);
+                Some(summary)
+            },
+            None => return Err(e),
+        },
+    };
+    let summary = summary.unwrap_or_else(|| empty_summary(conversation_id));
+    
+    let (changes, threads, partial) = match since {
+        Some(since) => {
+            let changes: Vec<SummarySegment> = summary.segments.iter()
+                .filter(|segment| segment.end > since)
+                .cloned()
+                .collect();
+            let threads = summary.threads.iter()
+                .filter(|thread| thread.last_message_at > since)
+                .cloned()
+                .collect();
+            let partial = summary.segments.len() >= MAX_SEGMENTS
+                && summary.segments.first().map(|s| s.start > since).unwrap_or(false);
+            (changes, threads, partial)
+        },
+        None => (Vec::new(), summary.threads.clone(), false),
+    };
+    
+    Ok(SummaryDigest {
+        conversation_id: conversation_id.to_string(),
+        since,
+        new_message_count: changes.iter().map(|c| c.message_count).sum(),
+        pending_messages: summary_store::pending_count(conversation_id),
+        summary: summary.summary,
+        key_points: summary.key_points,
+        action_items: summary.action_items,
+        changes,
+        threads,
+        partial,
+        updated_at: summary.updated_at,
+    })
+}
+
+// Bring the summaries of up to `limit` conversations with queued messages up
+// to date. Returns the number of conversations updated.
+pub async fn process_pending(limit: usize) -> Result<u64> {
+    let mut updated = 0;
+    
+    // One failing conversation doesn't hold up the rest; its messages stay queued
+    for conversation_id in summary_store::pending_conversations(limit) {
+        match refresh(&conversation_id).await {
+            Ok(Some(_)) => updated += 1,
+            Ok(None) => {},
+            Err(e) => ic_cdk::println!("Failed to update summary of {}: {:?}", conversation_id, e),
+        }
+    }
+    
+    Ok(updated)
+}
+
+// Fold a conversation's queued messages into its summary. A conversation
+// without a summary starts from its recent history.
+pub async fn refresh(conversation_id: &str) -> Result<Option<ConversationSummary>> {
+    let _guard = match RefreshGuard::acquire(conversation_id) {
+        Some(guard) => guard,
+        None => return Ok(summary_store::get_summary(conversation_id)),
+    };
+    
+    refresh_summary(conversation_id).await
+}
+
+// Marks a conversation as being refreshed until dropped. The mark is also
+// cleared when a callback traps mid-refresh, since the CDK drops the
+// pending future during cleanup.
+struct RefreshGuard(String);
+
+impl RefreshGuard {
+    fn acquire(conversation_id: &str) -> Option<Self> {
+        let started = REFRESHING.with(|refreshing| refreshing.borrow_mut().insert(conversation_id.to_string()));
+        started.then(|| RefreshGuard(conversation_id.to_string()))
+    }
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        REFRESHING.with(|refreshing| refreshing.borrow_mut().remove(&self.0));
+    }
+}
+
+async fn refresh_summary(conversation_id: &str) -> Result<Option<ConversationSummary>> {
+    let pending = summary_store::take_pending(conversation_id);
+    let stored = summary_store::get_summary(conversation_id);
+    
+    let mut messages: Vec<Message> = match &stored {
+        // Messages may have been deleted since they were queued
+        Some(_) => pending.iter()
+            .filter_map(|id| crate::storage::messages::get_message(id))
+            .filter(|message| message.conversation_id == conversation_id)
+            .collect(),
+        None => crate::storage::messages::get_conversation_messages(conversation_id, MAX_INITIAL_MESSAGES, None),
+    };
+    messages.retain(|message| !message.content.text.trim().is_empty());
+    
+    if messages.is_empty() {
+        return Ok(stored);
+    }
+    messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    
+    let provider = llm::active_provider();
+    let chunks = llm::chunk_by_tokens(&messages, CHUNK_TOKEN_BUDGET, |message| provider.count_tokens(&format_message(message)));
+    let mut summary = stored.unwrap_or_else(|| empty_summary(conversation_id));
+    let mut failure = None;
+    
+    for (i, chunk) in chunks.iter().enumerate() {
+        match summarize_chunk(&summary, chunk).await {
+            Ok(reply) => apply_reply(&mut summary, reply, chunk),
+            Err(e) => {
+                // Requeue what wasn't summarised so the next update retries it
+                for message in chunks[i..].iter().flatten() {
+                    summary_store::enqueue(conversation_id, &message.id);
+                }
+                failure = Some(e);
+                break;
+            },
+        }
+    }
+    
+    // Keep the chunks that did go through
+    if !summary.segments.is_empty() {
+        summary_store::store_summary(summary.clone());
+    }
+    
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(Some(summary)),
+    }
+}
+
+async fn summarize_chunk(summary: &ConversationSummary, chunk: &[&Message]) -> Result<SummaryReply> {
+    // Only the threads this chunk continues
+    let chunk_threads: HashSet<&str> = chunk.iter().filter_map(|m| m.thread_id.as_deref()).collect();
+    let state = SummaryState {
+        summary: &summary.summary,
+        key_points: &summary.key_points,
+        action_items: &summary.action_items,
+        threads: summary.threads.iter()
+            .filter(|thread| chunk_threads.contains(thread.thread_id.as_str()))
+            .map(|thread| ThreadState {
+                thread_id: &thread.thread_id,
+                summary: &thread.summary,
+            })
+            .collect(),
+    };
+    let state = serde_json::to_string(&state)
+        .map_err(|e| Error::InternalError(format!("Failed to encode summary: {}", e)))?;
+    
+    let lines: Vec<String> = chunk.iter().map(|m| format_message(m)).collect();
+    let prompt = vec![
+        ChatMessage::system(SUMMARY_PROMPT),
+        ChatMessage::user(&format!("{}\n\n{}", state, lines.join("\n"))),
+    ];
+    
+    llm::complete_json(&prompt, Some(SUMMARY_MAX_TOKENS)).await
+}
+
+fn apply_reply(summary: &mut ConversationSummary, reply: SummaryReply, chunk: &[&Message]) {
+    let now = ic_cdk::api::time() / 1_000_000;
+    let start = chunk.iter().map(|m| m.timestamp).min().unwrap_or(0);
+    let end = chunk.iter().map(|m| m.timestamp).max().unwrap_or(0);
+    
+    if !reply.summary.trim().is_empty() {
+        summary.summary = reply.summary;
+        summary.key_points = reply.key_points.into_iter().take(MAX_POINTS).collect();
+        summary.action_items = reply.action_items.into_iter().take(MAX_POINTS).collect();
+    }
+    
+    // Thread counts come from the messages, summaries from the model
+    let mut chunk_threads: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for message in chunk {
+        if let Some(thread_id) = &message.thread_id {
+            let entry = chunk_threads.entry(thread_id.as_str()).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 = entry.1.max(message.timestamp);
+        }
+    }
+    for (thread_id, (count, last_message_at)) in chunk_threads {
+        let text = reply.threads.iter()
+            .find(|t| t.thread_id == thread_id && !t.summary.trim().is_empty())
+            .map(|t| t.summary.clone());
+        
+        match summary.threads.iter_mut().find(|t| t.thread_id == thread_id) {
+            Some(thread) => {
+                thread.message_count += count;
+                thread.last_message_at = thread.last_message_at.max(last_message_at);
+                if let Some(text) = text {
+                    thread.summary = text;
+                }
+            },
+            None => summary.threads.push(ThreadSummary {
+                thread_id: thread_id.to_string(),
+                summary: text.unwrap_or_default(),
+                message_count: count,
+                last_message_at,
+            }),
+        }
+    }
+    summary.threads.sort_by(|a, b| b.last_message_at.cmp(&a.last_message_at).then_with(|| a.thread_id.cmp(&b.thread_id)));
+    summary.threads.truncate(MAX_THREADS);
+    
+    summary.segments.push(SummarySegment {
+        start,
+        end,
+        message_count: chunk.len() as u64,
+        summary: reply.update.summary,
+        key_points: reply.update.key_points.into_iter().take(MAX_POINTS).collect(),
+        created_at: now,
+    });
+    if summary.segments.len() > MAX_SEGMENTS {
+        let excess = summary.segments.len() - MAX_SEGMENTS;
+        summary.segments.drain(..excess);
+    }
+    
+    summary.message_count += chunk.len() as u64;
+    summary.last_message_at = summary.last_message_at.max(end);
+    summary.updated_at = now;
+}
+
+fn empty_summary(conversation_id: &str) -> ConversationSummary {
+    ConversationSummary {
+        conversation_id: conversation_id.to_string(),
+        summary: String::new(),
+        key_points: Vec::new(),
+        action_items: Vec::new(),
+        threads: Vec::new(),
+        segments: Vec::new(),
+        message_count: 0,
+        last_message_at: 0,
+        updated_at: 0,
+    }
+}
+
+// One line per message: [id] (thread id) time sender: text
+fn format_message(message: &Message) -> String {
+    let text: String = message.content.text.chars().take(MAX_MESSAGE_CHARS).collect();
+    let time = chrono::NaiveDateTime::from_timestamp_millis(message.timestamp as i64)
+        .map(|time| time.format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|| message.timestamp.to_string());
+    let thread = message.thread_id.as_ref()
+        .map(|thread_id| format!(" (thread {})", thread_id))
+        .unwrap_or_default();
+    
+    format!("[{}]{} {} {}: {}", message.id, thread, time, message.sender.name, text.replace('\n', " "))
+}
@@ -0,0 +1,408 @@
+use crate::{Message, Result};
+use super::embeddings;
+use super::trends::TimeRange;
+use candid::{CandidType, Deserialize};
+use std::collections::HashMap;
+
+// Unsupervised topic discovery: k-means over message embeddings, with each
+// cluster labelled by its most distinctive phrases (class-based TF-IDF).
+
+// Most recent messages clustered in one run
+const MAX_TOPIC_MESSAGES: usize = 2000;
+
+// Default range when none is given
+const DEFAULT_RANGE_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+// Clusters smaller than this are treated as noise
+const MIN_TOPIC_SIZE: usize = 3;
+
+const MAX_TOPICS: usize = 12;
+const MAX_ITERATIONS: usize = 25;
+
+// Messages with fewer content words carry no topic ("ok", "thanks!")
+const MIN_CONTENT_WORDS: usize = 3;
+
+const MAX_KEYPHRASES: usize = 5;
+
+// Bigrams must occur this often in a cluster to be a keyphrase
+const MIN_BIGRAM_COUNT: usize = 2;
+
+const STOP_WORDS: &[&str] = &[
+    "the", "and", "for", "are", "but", "not", "you", "your", "yours", "all", "any", "can",
+    "had", "has", "have", "her", "his", "him", "its", "our", "out", "she", "they", "them",
+    "their", "there", "then", "than", "this", "that", "these", "those", "was", "were",
+    "what", "when", "where", "which", "who", "whom", "why", "how", "with", "will", "would",
+    "should", "could", "from", "into", "about", "just", "also", "been", "being", "did",
+    "does", "doing", "done", "get", "got", "let", "lets", "like", "more", "most", "much",
+    "now", "off", "one", "only", "over", "some", "such", "too", "very", "yes", "yeah",
+    "okay", "thanks", "thank", "please", "here", "going", "think", "know", "want", "need",
+    "really", "still", "well", "back", "after", "before", "because", "again", "ill", "im",
+    "ive", "dont", "didnt", "doesnt", "cant", "wont", "isnt", "thats", "its", "were",
+];
+
+// A discovered topic and the messages in it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopicCluster {
+    pub id: String,
+    pub label: String,
+    pub keyphrases: Vec<String>,
+    pub message_ids: Vec<String>,
+    pub message_count: u64,
+    // Mean similarity of the members to the cluster centre
+    pub cohesion: f32,
+    pub first_message_at: u64,
+    pub last_message_at: u64,
+    pub created_at: u64,
+}
+
+// Discover topics in the caller's messages and store them for `topic:<id>` search
+pub fn discover_topics(user_id: &str, range: Option<TimeRange>) -> Result<Vec<TopicCluster>> {
+    let now = ic_cdk::api::time() / 1_000_000;
+    let (start, end) = match range {
+        Some(range) => (range.start, range.end.max(range.start)),
+        None => (now.saturating_sub(DEFAULT_RANGE_MS), now),
+    };
+    
+    let accessible: Vec<String> = crate::storage::conversations::get_user_conversations(user_id, None)
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+    
+    // The caller's most recent messages in the range, merged across
+    // conversations so only those are loaded
+    let (messages, _) = crate::storage::messages::get_messages_in_range(&accessible, start, end, MAX_TOPIC_MESSAGES);
+    
+    let message_refs: Vec<&Message> = messages.iter().collect();
+    let mut topics = cluster_messages(&message_refs);
+    for topic in &mut topics {
+        topic.id = topic_id(user_id, &topic.message_ids);
+    }
+    
+    crate::storage::topics::store_user_topics(user_id, topics.clone());
+    Ok(topics)
+}
+
+// Cluster messages by their embeddings in the active model's space. Topics
+// come back largest first, without IDs.
+pub fn cluster_messages(messages: &[&Message]) -> Vec<TopicCluster> {
+    let model = embeddings::active_provider().model();
+    
+    let mut points: Vec<(&Message, Vec<f32>)> = messages.iter()
+        .filter(|m| content_words(&m.content.text).len() >= MIN_CONTENT_WORDS)
+        .filter_map(|m| embeddings::message_vector(&m.id, &m.content.text, &model).map(|v| (*m, normalize(v))))
+        .collect();
+    points.sort_by(|(a, _), (b, _)| a.timestamp.cmp(&b.timestamp).then_with(|| a.id.cmp(&b.id)));
+    
+    if points.len() < MIN_TOPIC_SIZE * 2 {
+        return Vec::new();
+    }
+    
+    // Roughly sqrt(n / 2) clusters, each able to reach the minimum size
+    let k = ((points.len() as f32 / 2.0).sqrt().round() as usize)
+        .clamp(2, MAX_TOPICS)
+        .min(points.len() / MIN_TOPIC_SIZE);
+    
+    let vectors: Vec<&[f32]> = points.iter().map(|(_, v)| v.as_slice()).collect();
+    let (assignments, centroids) = kmeans(&vectors, k);
+    
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); k];
+    for (i, &cluster) in assignments.iter().enumerate() {
+        members[cluster].push(i);
+    }
+    
+    let kept: Vec<(usize, Vec<usize>)> = members.into_iter()
+        .enumerate()
+        .filter(|(_, m)| m.len() >= MIN_TOPIC_SIZE)
+        .collect();
+    
+    // Keyphrases compare each cluster's terms against the other clusters
+    let documents: Vec<Vec<String>> = kept.iter()
+        .map(|(_, m)| m.iter().flat_map(|&i| terms(&points[i].0.content.text)).collect())
+        .collect();
+    let keyphrases = class_tfidf(&documents);
+    
+    let now = ic_cdk::api::time();
+    let mut topics: Vec<TopicCluster> = kept.into_iter()
+        .zip(keyphrases)
+        .map(|((cluster, member_indices), phrases)| {
+            let cohesion = member_indices.iter()
+                .map(|&i| dot(&points[i].1, &centroids[cluster]))
+                .sum::<f32>() / member_indices.len() as f32;
+            let timestamps = member_indices.iter().map(|&i| points[i].0.timestamp);
+            
+            TopicCluster {
+                id: String::new(),
+                label: phrases.iter().take(2).cloned().collect::<Vec<String>>().join(", "),
+                message_ids: member_indices.iter().map(|&i| points[i].0.id.clone()).collect(),
+                message_count: member_indices.len() as u64,
+                cohesion,
+                first_message_at: timestamps.clone().min().unwrap_or(0),
+                last_message_at: timestamps.max().unwrap_or(0),
+                created_at: now,
+                keyphrases: phrases,
+            }
+        })
+        .collect();
+    
+    topics.sort_by(|a, b| b.message_count.cmp(&a.message_count).then_with(|| a.label.cmp(&b.label)));
+    topics
+}
+
+// Spherical k-means (cosine) with deterministic k-means++ seeding. Returns the
+// cluster of each point and the cluster centres.
+fn kmeans(vectors: &[&[f32]], k: usize) -> (Vec<usize>, Vec<Vec<f32>>) {
+    let mut rng = SplitMix64(vectors.len() as u64);
+    
+    // k-means++: each next centre is picked with probability proportional to
+    // its squared distance from the nearest existing centre
+    let mut centroids: Vec<Vec<f32>> = vec![vectors[0].to_vec()];
+    while centroids.len() < k {
+        let distances: Vec<f32> = vectors.iter()
+            .map(|v| {
+                centroids.iter()
+                    .map(|c| (1.0 - dot(v, c)).max(0.0))
+                    .fold(f32::MAX, f32::min)
+                    .powi(2)
+            })
+            .collect();
+        let total: f32 = distances.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        
+        let mut target = rng.next_f32() * total;
+        let mut chosen = distances.len() - 1;
+        for (i, distance) in distances.iter().enumerate() {
+            if target <= *distance {
+                chosen = i;
+                break;
+            }
+            target -= distance;
+        }
+        centroids.push(vectors[chosen].to_vec());
+    }
+    
+    let mut assignments = vec![usize::MAX; vectors.len()];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (i, v) in vectors.iter().enumerate() {
+            let nearest = nearest_centroid(v, &centroids);
+            if assignments[i] != nearest {
+                assignments[i] = nearest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        
+        // Centres move to the normalized mean of their members
+        let dimensions = centroids[0].len();
+        let mut sums = vec![vec![0.0f32; dimensions]; centroids.len()];
+        for (i, v) in vectors.iter().enumerate() {
+            for (sum, value) in sums[assignments[i]].iter_mut().zip(v.iter()) {
+                *sum += value;
+            }
+        }
+        for (centroid, sum) in centroids.iter_mut().zip(sums) {
+            // An empty cluster keeps its previous centre
+            if sum.iter().any(|x| *x != 0.0) {
+                *centroid = normalize(sum);
+            }
+        }
+    }
+    
+    (assignments, centroids)
+}
+
+fn nearest_centroid(vector: &[f32], centroids: &[Vec<f32>]) -> usize {
+    centroids.iter()
+        .enumerate()
+        .map(|(i, c)| (i, dot(vector, c)))
+        .fold((0, f32::MIN), |best, (i, similarity)| if similarity > best.1 { (i, similarity) } else { best })
+        .0
+}
+
+// Top phrases of each document by class-based TF-IDF: term frequency in the
+// cluster, weighted by how rare the term is across clusters
+fn class_tfidf(documents: &[Vec<String>]) -> Vec<Vec<String>> {
+    let counts: Vec<HashMap<&str, usize>> = documents.iter()
+        .map(|terms| {
+            let mut counts = HashMap::new();
+            for term in terms {
+                *counts.entry(term.as_str()).or_insert(0) += 1;
+            }
+            counts
+        })
+        .collect();
+    
+    let mut corpus_counts: HashMap<&str, usize> = HashMap::new();
+    for document in &counts {
+        for (term, count) in document {
+            *corpus_counts.entry(*term).or_insert(0) += *count;
+        }
+    }
+    let average_length = documents.iter().map(|d| d.len()).sum::<usize>() as f32 / documents.len().max(1) as f32;
+    
+    counts.iter()
+        .zip(documents)
+        .map(|(document, terms)| {
+            let length = terms.len().max(1) as f32;
+            let mut scored: Vec<(&str, f32)> = document.iter()
+                .filter(|(term, count)| !term.contains(' ') || **count >= MIN_BIGRAM_COUNT)
+                .map(|(term, count)| {
+                    let tf = *count as f32 / length;
+                    let idf = (1.0 + average_length / corpus_counts[term] as f32).ln();
+                    (*term, tf * idf)
+                })
+                .collect();
+            scored.sort_by(|(a, x), (b, y)| y.total_cmp(x).then_with(|| a.cmp(b)));
+            
+            // Skip words already covered by a chosen phrase
+            let mut phrases: Vec<String> = Vec::new();
+            for (term, _) in scored {
+                if phrases.len() >= MAX_KEYPHRASES {
+                    break;
+                }
+                let overlaps = phrases.iter().any(|p| {
+                    p.split(' ').any(|w| term.split(' ').any(|t| t == w))
+                });
+                if !overlaps {
+                    phrases.push(term.to_string());
+                }
+            }
+            phrases
+        })
+        .collect()
+}
+
+// Content words and the bigrams between adjacent content words
+fn terms(text: &str) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut previous: Option<String> = None;
+    
+    for word in text.split(|c: char| !c.is_alphanumeric() && c != '\'') {
+        let word = word.replace('\'', "").to_lowercase();
+        if is_content_word(&word) {
+            if let Some(previous) = &previous {
+                terms.push(format!("{} {}", previous, word));
+            }
+            terms.push(word.clone());
+            previous = Some(word);
+        } else {
+            previous = None;
+        }
+    }
+    
+    terms
+}
+
+fn content_words(text: &str) -> Vec<String> {
+    terms(text).into_iter().filter(|t| !t.contains(' ')).collect()
+}
+
+fn is_content_word(word: &str) -> bool {
+    word.chars().count() >= 3
+        && !word.chars().all(|c| c.is_ascii_digit())
+        && !STOP_WORDS.contains(&word)
+}
+
+// Stable ID from the owner and members, still short enough to type as
+// topic:<id>. Topics are stored per owner, so it only has to be unique
+// among the owner's topics.
+fn topic_id(user_id: &str, message_ids: &[String]) -> String {
+    let mut sorted = message_ids.to_vec();
+    sorted.sort();
+    format!("{:016x}", embeddings::fnv_hash(&format!("{}|{}", user_id, sorted.join(","))))
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
+}
+
+fn normalize(mut vector: Vec<f32>) -> Vec<f32> {
+    let magnitude = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if magnitude > 0.0 {
+        for value in &mut vector {
+            *value /= magnitude;
+        }
+    }
+    vector
+}
+
+// Small deterministic PRNG for seeding, so every replica picks the same centres
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_f32(&mut self) -> f32 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^= z >> 31;
+        (z >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn kmeans_separates_distinct_groups() {
+        let points: Vec<Vec<f32>> = vec![
+            normalize(vec![1.0, 0.0, 0.0]),
+            normalize(vec![0.99, 0.1, 0.0]),
+            normalize(vec![0.99, 0.0, 0.1]),
+            normalize(vec![0.0, 1.0, 0.0]),
+            normalize(vec![0.1, 0.99, 0.0]),
+            normalize(vec![0.0, 0.99, 0.1]),
+        ];
+        let vectors: Vec<&[f32]> = points.iter().map(|v| v.as_slice()).collect();
+        
+        let (assignments, centroids) = kmeans(&vectors, 2);
+        assert_eq!(centroids.len(), 2);
+        assert!(assignments[..3].iter().all(|&c| c == assignments[0]));
+        assert!(assignments[3..].iter().all(|&c| c == assignments[3]));
+        assert_ne!(assignments[0], assignments[3]);
+    }
+    
+    #[test]
+    fn kmeans_is_deterministic() {
+        let points: Vec<Vec<f32>> = (0..20)
+            .map(|i| normalize(vec![(i % 3) as f32 + 0.1, (i % 5) as f32 + 0.1, (i % 7) as f32 + 0.1]))
+            .collect();
+        let vectors: Vec<&[f32]> = points.iter().map(|v| v.as_slice()).collect();
+        
+        assert_eq!(kmeans(&vectors, 3).0, kmeans(&vectors, 3).0);
+    }
+    
+    #[test]
+    fn terms_skip_stop_words_and_break_bigrams_at_punctuation() {
+        assert_eq!(terms("The deploy failed, rollback now"), vec![
+            "deploy".to_string(),
+            "deploy failed".to_string(),
+            "failed".to_string(),
+            "rollback".to_string(),
+        ]);
+    }
+    
+    #[test]
+    fn class_tfidf_prefers_distinctive_terms() {
+        let documents = vec![
+            vec!["deploy".to_string(), "deploy".to_string(), "server".to_string()],
+            vec!["lunch".to_string(), "lunch".to_string(), "server".to_string()],
+        ];
+        let phrases = class_tfidf(&documents);
+        assert_eq!(phrases[0][0], "deploy");
+        assert_eq!(phrases[1][0], "lunch");
+    }
+    
+    #[test]
+    fn topic_ids_ignore_member_order() {
+        let a = topic_id("owner", &["m1".to_string(), "m2".to_string()]);
+        let b = topic_id("owner", &["m2".to_string(), "m1".to_string()]);
+        assert_eq!(a, b);
+        assert_ne!(a, topic_id("other", &["m1".to_string(), "m2".to_string()]));
+    }
+}
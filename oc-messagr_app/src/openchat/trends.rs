@@ -0,0 +1,297 @@
+use crate::{Message, Platform, Error, Result};
+use crate::indexing::schema::platform_to_string;
+use super::intents::CountEntry;
+use candid::{CandidType, Deserialize};
+use chrono::Datelike;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// Upper bound on messages scanned for one trend
+const MAX_SCANNED_MESSAGES: usize = 50_000;
+
+// Most buckets one trend may span (over five years of days)
+const MAX_TREND_BUCKETS: usize = 2000;
+
+// Default range when none is given
+const DEFAULT_RANGE_MS: u64 = 90 * DAY_MS;
+
+// Buckets in the rolling baseline a bucket is compared against
+const BASELINE_BUCKETS: usize = 7;
+
+// A spike needs at least this many earlier buckets to compare with
+const MIN_BASELINE_BUCKETS: usize = 3;
+
+// Standard deviations above the baseline mean for a spike
+const SPIKE_Z_SCORE: f32 = 3.0;
+
+// Buckets with fewer matches are never spikes
+const MIN_SPIKE_COUNT: u64 = 3;
+
+// Conversations listed per spike
+const MAX_SPIKE_CONVERSATIONS: usize = 3;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+pub enum TrendBucket {
+    Day,
+    Week,
+    Month,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TimeRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrendPoint {
+    pub start: u64,
+    pub count: u64,
+    // Mean of the preceding buckets
+    pub baseline: f32,
+    pub is_spike: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TrendSpike {
+    pub start: u64,
+    pub count: u64,
+    pub baseline: f32,
+    pub z_score: f32,
+    pub top_conversations: Vec<CountEntry>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TopicTrend {
+    pub query: String,
+    pub bucket: TrendBucket,
+    pub start: u64,
+    pub end: u64,
+    pub total: u64,
+    pub points: Vec<TrendPoint>,
+    pub spikes: Vec<TrendSpike>,
+    // More than MAX_SCANNED_MESSAGES of the caller's messages were in the
+    // range; the oldest were left out
+    pub truncated: bool,
+}
+
+// Messages matching the query per bucket, with spikes against a rolling baseline.
+// Only the caller's conversations are scanned, newest messages first.
+pub fn topic_trend(
+    query: &str,
+    bucket: TrendBucket,
+    range: Option<TimeRange>,
+    platforms: Option<Vec<Platform>>,
+    accessible: &HashSet<String>
+) -> Result<TopicTrend> {
+    let now = ic_cdk::api::time() / 1_000_000;
+    let (start, end) = match range {
+        Some(range) => (range.start, range.end.max(range.start)),
+        None => (now.saturating_sub(DEFAULT_RANGE_MS), now),
+    };
+    
+    // Every bucket in the range, including quiet ones
+    let mut bucket_starts = Vec::new();
+    let mut current = Some(bucket_start(start, bucket));
+    while let Some(bucket_time) = current.filter(|time| *time <= end) {
+        if bucket_starts.len() >= MAX_TREND_BUCKETS {
+            return Err(Error::InvalidParameters(format!(
+                "Range spans more than {} buckets; narrow it or use a larger bucket", MAX_TREND_BUCKETS
+            )));
+        }
+        bucket_starts.push(bucket_time);
+        current = next_bucket(bucket_time, bucket);
+    }
+    
+    let terms = query_terms(query);
+    let platforms: Option<HashSet<String>> = platforms.map(|p| p.iter().map(platform_to_string).collect());
+    
+    // Platforms are a property of the conversation, so filter those first
+    let conversation_ids: Vec<String> = accessible.iter()
+        .filter(|conversation_id| match &platforms {
+            Some(platforms) => crate::storage::conversations::get_conversation(conversation_id)
+                .map_or(false, |c| platforms.contains(&platform_to_string(&c.platform))),
+            None => true,
+        })
+        .cloned()
+        .collect();
+    
+    // The newest messages in range across those conversations, matched or
+    // not, so the cap applies to what was scanned
+    let (scanned, truncated) = crate::storage::messages::get_messages_in_range(&conversation_ids, start, end, MAX_SCANNED_MESSAGES);
+    
+    let mut counts: BTreeMap<u64, u64> = BTreeMap::new();
+    let mut conversations: HashMap<u64, HashMap<String, u64>> = HashMap::new();
+    let mut total = 0;
+    
+    for message in scanned.into_iter().filter(|message| matches_terms(message, &terms)) {
+        let key = bucket_start(message.timestamp, bucket);
+        *counts.entry(key).or_insert(0) += 1;
+        *conversations.entry(key).or_default().entry(message.conversation_id).or_insert(0) += 1;
+        total += 1;
+    }
+    
+    let mut points: Vec<TrendPoint> = bucket_starts.into_iter()
+        .map(|bucket_time| TrendPoint {
+            start: bucket_time,
+            count: counts.get(&bucket_time).copied().unwrap_or(0),
+            baseline: 0.0,
+            is_spike: false,
+        })
+        .collect();
+    
+    let mut spikes = Vec::new();
+    for i in 0..points.len() {
+        let window = &points[i.saturating_sub(BASELINE_BUCKETS)..i];
+        if window.is_empty() {
+            continue;
+        }
+        
+        let mean = window.iter().map(|p| p.count as f32).sum::<f32>() / window.len() as f32;
+        let variance = window.iter().map(|p| (p.count as f32 - mean).powi(2)).sum::<f32>() / window.len() as f32;
+        // A flat baseline still needs a clear jump, not one extra message
+        let z_score = (points[i].count as f32 - mean) / variance.sqrt().max(1.0);
+        points[i].baseline = mean;
+        
+        if window.len() >= MIN_BASELINE_BUCKETS && points[i].count >= MIN_SPIKE_COUNT && z_score >= SPIKE_Z_SCORE {
+            points[i].is_spike = true;
+            spikes.push(TrendSpike {
+                start: points[i].start,
+                count: points[i].count,
+                baseline: mean,
+                z_score,
+                top_conversations: top_conversations(conversations.remove(&points[i].start).unwrap_or_default()),
+            });
+        }
+    }
+    
+    Ok(TopicTrend {
+        query: query.to_string(),
+        bucket,
+        start,
+        end,
+        total,
+        points,
+        spikes,
+        truncated,
+    })
+}
+
+// Lowercased query words; a trailing * matches any word with that prefix
+fn query_terms(query: &str) -> Vec<String> {
+    query.split_whitespace()
+        .map(|word| {
+            word.trim_matches(|c: char| !c.is_alphanumeric() && c != '*')
+                .to_lowercase()
+        })
+        .filter(|word| !word.is_empty() && word != "*")
+        .collect()
+}
+
+// Every query term appears as a word in the message
+fn matches_terms(message: &Message, terms: &[String]) -> bool {
+    if terms.is_empty() {
+        return true;
+    }
+    
+    let words: HashSet<String> = message.content.text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect();
+    
+    terms.iter().all(|term| match term.strip_suffix('*') {
+        Some(prefix) => words.iter().any(|word| word.starts_with(prefix)),
+        None => words.contains(term),
+    })
+}
+
+// Start of the bucket containing a timestamp: midnight UTC, Monday, or the 1st
+fn bucket_start(timestamp: u64, bucket: TrendBucket) -> u64 {
+    let day = timestamp / DAY_MS;
+    match bucket {
+        TrendBucket::Day => day * DAY_MS,
+        // 1970-01-01 was a Thursday
+        TrendBucket::Week => day.saturating_sub((day + 3) % 7) * DAY_MS,
+        TrendBucket::Month => {
+            chrono::NaiveDateTime::from_timestamp_millis(timestamp as i64)
+                .and_then(|time| time.date().with_day(1))
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+                .map(|time| time.timestamp_millis() as u64)
+                .unwrap_or(day * DAY_MS)
+        },
+    }
+}
+
+// None once the next bucket would be past the end of time
+fn next_bucket(start: u64, bucket: TrendBucket) -> Option<u64> {
+    match bucket {
+        TrendBucket::Day => start.checked_add(DAY_MS),
+        TrendBucket::Week => start.checked_add(7 * DAY_MS),
+        // 32 days from the 1st always lands in the next month
+        TrendBucket::Month => start.checked_add(32 * DAY_MS).map(|time| bucket_start(time, TrendBucket::Month)),
+    }
+}
+
+fn top_conversations(counts: HashMap<String, u64>) -> Vec<CountEntry> {
+    let mut entries: Vec<CountEntry> = counts.into_iter()
+        .map(|(id, count)| {
+            let label = crate::storage::conversations::get_conversation(&id)
+                .map(|c| c.name)
+                .unwrap_or_else(|| id.clone());
+            CountEntry { id, label, count }
+        })
+        .collect();
+    
+    entries.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.id.cmp(&b.id)));
+    entries.truncate(MAX_SPIKE_CONVERSATIONS);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn millis(year: i32, month: u32, day: u32, hour: u32) -> u64 {
+        chrono::NaiveDate::from_ymd_opt(year, month, day)
+            .and_then(|date| date.and_hms_opt(hour, 0, 0))
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+    
+    #[test]
+    fn day_buckets_start_at_midnight() {
+        assert_eq!(bucket_start(millis(2024, 3, 13, 15), TrendBucket::Day), millis(2024, 3, 13, 0));
+        assert_eq!(next_bucket(millis(2024, 3, 13, 0), TrendBucket::Day), Some(millis(2024, 3, 14, 0)));
+    }
+    
+    #[test]
+    fn week_buckets_start_on_monday() {
+        // Wednesday, Monday and Sunday of the same week
+        assert_eq!(bucket_start(millis(2024, 3, 13, 15), TrendBucket::Week), millis(2024, 3, 11, 0));
+        assert_eq!(bucket_start(millis(2024, 3, 11, 9), TrendBucket::Week), millis(2024, 3, 11, 0));
+        assert_eq!(bucket_start(millis(2024, 3, 17, 23), TrendBucket::Week), millis(2024, 3, 11, 0));
+        assert_eq!(next_bucket(millis(2024, 3, 11, 0), TrendBucket::Week), Some(millis(2024, 3, 18, 0)));
+    }
+    
+    #[test]
+    fn month_buckets_follow_calendar_months() {
+        assert_eq!(bucket_start(millis(2024, 3, 13, 15), TrendBucket::Month), millis(2024, 3, 1, 0));
+        assert_eq!(next_bucket(millis(2024, 1, 1, 0), TrendBucket::Month), Some(millis(2024, 2, 1, 0)));
+        // Leap February and the year boundary
+        assert_eq!(next_bucket(millis(2024, 2, 1, 0), TrendBucket::Month), Some(millis(2024, 3, 1, 0)));
+        assert_eq!(next_bucket(millis(2024, 12, 1, 0), TrendBucket::Month), Some(millis(2025, 1, 1, 0)));
+    }
+    
+    #[test]
+    fn next_bucket_stops_at_the_end_of_time() {
+        assert_eq!(next_bucket(u64::MAX - DAY_MS / 2, TrendBucket::Day), None);
+        assert_eq!(next_bucket(u64::MAX - DAY_MS, TrendBucket::Week), None);
+    }
+    
+    #[test]
+    fn query_terms_keep_prefix_wildcards() {
+        assert_eq!(query_terms("Deploy* \"rollback\" *"), vec!["deploy*".to_string(), "rollback".to_string()]);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{Message, Error, Result};
+use candid::CandidType;
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 
@@ -19,7 +20,7 @@ pub struct EnhancedQueryResult {
 }
 
 // AI-generated insights about the query results
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct QueryInsights {
     // Key entities mentioned in the results
     pub entities: Vec<Entity>,
@@ -38,7 +39,7 @@ pub struct QueryInsights {
 }
 
 // Entity representation (person, place, organization, etc.)
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct Entity {
     pub name: String,
     pub entity_type: EntityType,
@@ -48,7 +49,7 @@ pub struct Entity {
 }
 
 // Types of entities
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub enum EntityType {
     Person,
     Organization,
@@ -59,7 +60,7 @@ pub enum EntityType {
 }
 
 // Topic identified in messages
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct Topic {
     pub name: String,
     pub relevance_score: f32,
@@ -68,7 +69,7 @@ pub struct Topic {
 }
 
 // Event on the timeline
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct TimelineEvent {
     pub timestamp: u64,
     pub description: String,
@@ -77,7 +78,7 @@ pub struct TimelineEvent {
 }
 
 // Sentiment analysis
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct SentimentAnalysis {
     pub overall_sentiment: f32,  // -1.0 to 1.0
     pub sentiment_breakdown: HashMap<String, f32>,
@@ -86,7 +87,7 @@ pub struct SentimentAnalysis {
 }
 
 // Conversation flow analysis
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct ConversationFlow {
     pub main_threads: Vec<ConversationThread>,
     pub key_decisions: Vec<String>,
@@ -94,7 +95,7 @@ pub struct ConversationFlow {
 }
 
 // Thread in a conversation
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(CandidType, Debug, Clone, Serialize, Deserialize)]
 pub struct ConversationThread {
     pub topic: String,
     pub message_ids: Vec<String>,
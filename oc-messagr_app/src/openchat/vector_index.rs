@@ -0,0 +1,362 @@
+use crate::storage::embeddings::{self as embedding_store, IndexJob, VectorNode};
+use super::embeddings::{calculate_similarity, fnv_hash};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+// Hierarchical Navigable Small World graph over message embeddings.
+// The graph lives in stable memory next to the vectors, so it survives upgrades.
+
+// Maximum neighbours per node on upper layers, and on the base layer
+const MAX_CONNECTIONS: usize = 16;
+const MAX_CONNECTIONS_BASE: usize = 32;
+
+// Candidate list size while inserting
+const EF_CONSTRUCTION: usize = 100;
+
+// Minimum candidate list size while searching
+const MIN_EF_SEARCH: usize = 50;
+
+// Hard cap on the number of layers
+const MAX_LEVEL: u8 = 16;
+
+// Entries read per step of an index job, and the instructions a call may
+// spend on the job before it stops and leaves the rest to the next call
+const JOB_BATCH_SIZE: usize = 500;
+const JOB_INSTRUCTION_BUDGET: u64 = 15_000_000_000;
+
+// A node and its distance to the current query
+#[derive(Clone, Debug)]
+struct Candidate {
+    distance: f32,
+    id: String,
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance.total_cmp(&other.distance).then_with(|| self.id.cmp(&other.id))
+    }
+}
+
+// Vectors read during one operation, so each is loaded from stable memory once
+struct VectorCache {
+    vectors: HashMap<String, Option<Vec<f32>>>,
+}
+
+impl VectorCache {
+    fn new() -> Self {
+        Self { vectors: HashMap::new() }
+    }
+    
+    fn distance(&mut self, query: &[f32], id: &str) -> f32 {
+        let vector = self.vectors.entry(id.to_string())
+            .or_insert_with(|| embedding_store::get_embedding(id).map(|e| e.vector));
+        
+        match vector {
+            Some(vector) => 1.0 - calculate_similarity(query, vector),
+            // Missing vectors are pushed to the back
+            None => f32::MAX,
+        }
+    }
+}
+
+// Add (or replace) a vector in the index
+pub fn insert(id: &str, vector: &[f32]) {
+    if embedding_store::get_vector_node(id).is_some() {
+        remove(id);
+    }
+    
+    let level = random_level(id);
+    let mut node = VectorNode {
+        level,
+        neighbors: vec![Vec::new(); level as usize + 1],
+    };
+    
+    let entry_id = match embedding_store::get_entry_point() {
+        Some(entry_id) => entry_id,
+        None => {
+            // First node becomes the entry point
+            embedding_store::store_vector_node(id, node);
+            embedding_store::set_entry_point(Some(id));
+            return;
+        },
+    };
+    
+    let entry_level = embedding_store::get_vector_node(&entry_id).map(|n| n.level).unwrap_or(0);
+    let mut cache = VectorCache::new();
+    let mut current = vec![Candidate { distance: cache.distance(vector, &entry_id), id: entry_id }];
+    
+    // Greedy descent through the layers above the new node
+    for layer in (level as usize + 1..=entry_level as usize).rev() {
+        current = search_layer(vector, current, 1, layer, &mut cache);
+    }
+    
+    // Connect the node on each of its layers
+    for layer in (0..=level.min(entry_level) as usize).rev() {
+        let candidates = search_layer(vector, current.clone(), EF_CONSTRUCTION, layer, &mut cache);
+        let selected: Vec<String> = candidates.iter()
+            .filter(|c| c.id != id)
+            .take(max_connections(layer))
+            .map(|c| c.id.clone())
+            .collect();
+        
+        for neighbor_id in &selected {
+            connect(neighbor_id, id, layer, &mut cache);
+        }
+        
+        node.neighbors[layer] = selected;
+        current = candidates;
+    }
+    
+    embedding_store::store_vector_node(id, node);
+    
+    if level > entry_level {
+        embedding_store::set_entry_point(Some(id));
+    }
+}
+
+// Add an edge from `from` to `to`, pruning `from` back to its closest neighbours
+fn connect(from: &str, to: &str, layer: usize, cache: &mut VectorCache) {
+    let mut node = match embedding_store::get_vector_node(from) {
+        Some(node) if node.neighbors.len() > layer => node,
+        _ => return,
+    };
+    
+    if !node.neighbors[layer].iter().any(|n| n == to) {
+        node.neighbors[layer].push(to.to_string());
+    }
+    
+    if node.neighbors[layer].len() > max_connections(layer) {
+        node.neighbors[layer] = closest(from, &node.neighbors[layer], max_connections(layer), cache);
+    }
+    
+    embedding_store::store_vector_node(from, node);
+}
+
+// The `limit` ids closest to a node
+fn closest(id: &str, ids: &[String], limit: usize, cache: &mut VectorCache) -> Vec<String> {
+    let vector = match embedding_store::get_embedding(id) {
+        Some(embedding) => embedding.vector,
+        None => return ids.iter().take(limit).cloned().collect(),
+    };
+    
+    let mut candidates: Vec<Candidate> = ids.iter()
+        .map(|other| Candidate { distance: cache.distance(&vector, other), id: other.clone() })
+        .collect();
+    candidates.sort();
+    candidates.into_iter().take(limit).map(|c| c.id).collect()
+}
+
+// Remove a vector from the index, reconnecting its neighbours to each other
+pub fn remove(id: &str) {
+    let removed = match embedding_store::delete_vector_node(id) {
+        Some(node) => node,
+        None => return,
+    };
+    
+    let mut cache = VectorCache::new();
+    for (layer, neighbors) in removed.neighbors.iter().enumerate() {
+        for neighbor_id in neighbors {
+            let mut node = match embedding_store::get_vector_node(neighbor_id) {
+                Some(node) if node.neighbors.len() > layer => node,
+                _ => continue,
+            };
+            
+            let mut candidates: Vec<String> = node.neighbors[layer].iter()
+                .filter(|n| n.as_str() != id)
+                .cloned()
+                .collect();
+            for other in neighbors {
+                if other != neighbor_id && !candidates.contains(other) {
+                    candidates.push(other.clone());
+                }
+            }
+            
+            node.neighbors[layer] = closest(neighbor_id, &candidates, max_connections(layer), &mut cache);
+            embedding_store::store_vector_node(neighbor_id, node);
+        }
+    }
+    
+    if embedding_store::get_entry_point().as_deref() == Some(id) {
+        let next = embedding_store::highest_vector_node().map(|(next_id, _)| next_id);
+        embedding_store::set_entry_point(next.as_deref());
+    }
+}
+
+// Approximate k nearest neighbours, as (message ID, cosine similarity), best first
+pub fn search(query: &[f32], k: usize, ef: usize) -> Vec<(String, f32)> {
+    let entry_id = match embedding_store::get_entry_point() {
+        Some(entry_id) => entry_id,
+        None => return Vec::new(),
+    };
+    
+    let entry_level = embedding_store::get_vector_node(&entry_id).map(|n| n.level).unwrap_or(0);
+    let mut cache = VectorCache::new();
+    let mut current = vec![Candidate { distance: cache.distance(query, &entry_id), id: entry_id }];
+    
+    for layer in (1..=entry_level as usize).rev() {
+        current = search_layer(query, current, 1, layer, &mut cache);
+    }
+    
+    search_layer(query, current, ef.max(k).max(MIN_EF_SEARCH), 0, &mut cache)
+        .into_iter()
+        .filter(|c| c.distance < f32::MAX)
+        .take(k)
+        .map(|c| (c.id, 1.0 - c.distance))
+        .collect()
+}
+
+// Best-first search on one layer, returning up to `ef` candidates sorted by distance
+fn search_layer(
+    query: &[f32],
+    entry_points: Vec<Candidate>,
+    ef: usize,
+    layer: usize,
+    cache: &mut VectorCache,
+) -> Vec<Candidate> {
+    let mut visited: HashSet<String> = entry_points.iter().map(|c| c.id.clone()).collect();
+    
+    // Min-heap of nodes to expand, max-heap of the best results so far
+    let mut to_visit: BinaryHeap<std::cmp::Reverse<Candidate>> = entry_points.iter()
+        .cloned()
+        .map(std::cmp::Reverse)
+        .collect();
+    let mut results: BinaryHeap<Candidate> = entry_points.into_iter().collect();
+    
+    while let Some(std::cmp::Reverse(candidate)) = to_visit.pop() {
+        let worst = results.peek().map(|c| c.distance).unwrap_or(f32::MAX);
+        if candidate.distance > worst && results.len() >= ef {
+            break;
+        }
+        
+        let neighbors = match embedding_store::get_vector_node(&candidate.id) {
+            Some(node) if node.neighbors.len() > layer => node.neighbors[layer].clone(),
+            _ => continue,
+        };
+        
+        for neighbor_id in neighbors {
+            if !visited.insert(neighbor_id.clone()) {
+                continue;
+            }
+            
+            let distance = cache.distance(query, &neighbor_id);
+            let worst = results.peek().map(|c| c.distance).unwrap_or(f32::MAX);
+            
+            if results.len() < ef || distance < worst {
+                let neighbor = Candidate { distance, id: neighbor_id };
+                to_visit.push(std::cmp::Reverse(neighbor.clone()));
+                results.push(neighbor);
+                
+                if results.len() > ef {
+                    results.pop();
+                }
+            }
+        }
+    }
+    
+    results.into_sorted_vec()
+}
+
+// Drop the whole graph (e.g. when switching embedding model)
+// Start rebuilding the graph from the stored vectors of the given model,
+// unless a job is already running
+pub fn start_rebuild(model: &str) {
+    if embedding_store::get_index_job().is_none() {
+        embedding_store::set_index_job(Some(IndexJob {
+            rebuild_model: Some(model.to_string()),
+            clearing: true,
+            cursor: None,
+        }));
+    }
+}
+
+// Drop the graph and queue every message for embedding, replacing any running job
+pub fn start_reembed() {
+    embedding_store::set_index_job(Some(IndexJob {
+        rebuild_model: None,
+        clearing: true,
+        cursor: None,
+    }));
+}
+
+// Advance the running job within this call's instruction budget. Returns the
+// number of messages inserted or queued, and whether no job is left.
+pub fn advance_job() -> (u64, bool) {
+    let mut job = match embedding_store::get_index_job() {
+        Some(job) => job,
+        None => return (0, true),
+    };
+    let mut handled = 0;
+    
+    while ic_cdk::api::instruction_counter() < JOB_INSTRUCTION_BUDGET {
+        if job.clearing {
+            job.clearing = !embedding_store::clear_vector_nodes(JOB_BATCH_SIZE);
+            continue;
+        }
+        
+        let done = match &job.rebuild_model {
+            Some(model) => {
+                let batch = embedding_store::embeddings_after(job.cursor.as_deref(), JOB_BATCH_SIZE);
+                let mut complete = true;
+                for (message_id, embedding) in &batch {
+                    if ic_cdk::api::instruction_counter() >= JOB_INSTRUCTION_BUDGET {
+                        complete = false;
+                        break;
+                    }
+                    if embedding.model == *model {
+                        insert(message_id, &embedding.vector);
+                        handled += 1;
+                    }
+                    job.cursor = Some(message_id.clone());
+                }
+                complete && batch.len() < JOB_BATCH_SIZE
+            },
+            None => {
+                let batch = crate::storage::messages::message_ids_after(job.cursor.as_deref(), JOB_BATCH_SIZE);
+                for message_id in &batch {
+                    embedding_store::enqueue(message_id);
+                    handled += 1;
+                }
+                if let Some(last) = batch.last() {
+                    job.cursor = Some(last.clone());
+                }
+                batch.len() < JOB_BATCH_SIZE
+            },
+        };
+        
+        if done {
+            embedding_store::set_index_job(None);
+            return (handled, true);
+        }
+    }
+    
+    embedding_store::set_index_job(Some(job));
+    (handled, false)
+}
+
+fn max_connections(layer: usize) -> usize {
+    if layer == 0 { MAX_CONNECTIONS_BASE } else { MAX_CONNECTIONS }
+}
+
+// Layer for a new node, drawn from an exponential distribution. Derived from
+// the message ID so it's deterministic across replicas.
+fn random_level(id: &str) -> u8 {
+    let uniform = ((fnv_hash(id) >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    let level_multiplier = 1.0 / (MAX_CONNECTIONS as f64).ln();
+    let level = (-uniform.ln() * level_multiplier).floor();
+    
+    (level as u8).min(MAX_LEVEL)
+}
@@ -2,6 +2,7 @@ import React, { useState } from "react";
 import { useNavigate } from "react-router-dom";
 import { FiSearch, FiLoader, FiZap, FiX, FiCommand } from "react-icons/fi";
 import { usePlatforms } from "../hooks/usePlatforms";
+import { useQuery } from "../hooks/useQuery";
 
 const AIEnhancedQueryInput = ({ useAIQuery }) => {
     const [query, setQuery] = useState("");
@@ -10,6 +11,7 @@ const AIEnhancedQueryInput = ({ useAIQuery }) => {
     const [selectedPlatform, setSelectedPlatform] = useState("");
     const navigate = useNavigate();
     const { connectedPlatforms } = usePlatforms();
+    const { queryMessages, aiEnhancedQuery, aiQueryPlatform } = useQuery();
 
     const handleSubmit = async (e) => {
         e.preventDefault();
@@ -25,14 +27,14 @@ const AIEnhancedQueryInput = ({ useAIQuery }) => {
                 // Use AI-enhanced query
                 if (selectedPlatform) {
                     // Platform-specific AI query
-                    results = await window.aiQueryPlatform(query, selectedPlatform);
+                    results = await aiQueryPlatform(query, selectedPlatform);
                 } else {
                     // General AI query
-                    results = await window.aiEnhancedQuery(query);
+                    results = await aiEnhancedQuery(query);
                 }
             } else {
                 // Standard query
-                results = await window.queryMessages(query);
+                results = await queryMessages(query);
             }
 
             // Navigate to results page
@@ -0,0 +1,158 @@
+import React from "react";
+import { Link } from "react-router-dom";
+import { format } from "date-fns";
+import { FiUsers, FiCalendar, FiFileText, FiTrendingUp, FiSmile, FiShare2 } from "react-icons/fi";
+
+// Renders the parts of an AI query answer that aren't a message list
+const QueryAnswerDetails = ({ result }) => {
+    if (!result?.kind || result.kind === "Search") return null;
+
+    const { kind, answer } = result;
+
+    const formatDate = (timestamp) => format(new Date(Number(timestamp)), "MMM d, yyyy");
+
+    const variantName = (variant) => Object.keys(variant)[0];
+
+    const countList = (title, entries) => (
+        <div>
+            <h4 className="text-sm font-medium text-gray-700 dark:text-gray-300 mb-1">{title}</h4>
+            {entries.length > 0 ? (
+                <ul className="text-sm text-gray-600 dark:text-gray-400 space-y-1">
+                    {entries.map((entry) => (
+                        <li key={entry.id} className="flex justify-between">
+                            <span>{entry.label}</span>
+                            <span>{Number(entry.count)}</span>
+                        </li>
+                    ))}
+                </ul>
+            ) : (
+                <p className="text-sm text-gray-500 dark:text-gray-400">None</p>
+            )}
+        </div>
+    );
+
+    const sections = {
+        Entities: {
+            icon: <FiUsers />,
+            title: "Entities",
+            body: (
+                <div className="grid grid-cols-1 md:grid-cols-2 gap-4">
+                    {answer.entities?.map((entity) => (
+                        <div key={entity.key} className="bg-gray-50 dark:bg-gray-800 p-3 rounded-md">
+                            <div className="font-medium">{entity.name}</div>
+                            <div className="text-sm text-gray-500 dark:text-gray-400">
+                                <span className="mr-3">Type: {variantName(entity.kind)}</span>
+                                <span className="mr-3">Mentions: {Number(entity.mentions)}</span>
+                                <span>Last seen: {formatDate(entity.last_seen)}</span>
+                            </div>
+                            {entity.related.length > 0 && (
+                                <div className="text-sm mt-1">Often with: {entity.related.join(", ")}</div>
+                            )}
+                        </div>
+                    ))}
+                </div>
+            )
+        },
+        TimeRange: {
+            icon: <FiCalendar />,
+            title: `Activity from ${formatDate(answer.start ?? 0)} to ${formatDate(answer.end ?? 0)}`,
+            body: (
+                <div className="grid grid-cols-1 md:grid-cols-3 gap-4">
+                    {countList("Conversations", answer.conversations ?? [])}
+                    {countList("Senders", answer.senders ?? [])}
+                    {countList("Platforms", answer.platforms ?? [])}
+                </div>
+            )
+        },
+        Summary: {
+            icon: <FiFileText />,
+            title: "Sources",
+            body: (
+                <ol className="space-y-2">
+                    {answer.citations?.map((citation) => (
+                        <li key={citation.marker} className="text-sm">
+                            <Link
+                                to={`/conversations/${citation.conversation_id}`}
+                                className="text-indigo-600 dark:text-indigo-400 hover:underline"
+                            >
+                                [{citation.marker}] {citation.sender}, {formatDate(citation.timestamp)}
+                            </Link>
+                            <p className="text-gray-600 dark:text-gray-400">{citation.excerpt}</p>
+                        </li>
+                    ))}
+                </ol>
+            )
+        },
+        Trend: {
+            icon: <FiTrendingUp />,
+            title: `Trend (${variantName(answer.bucket ?? { Day: null })})${answer.truncated ? ", partial" : ""}`,
+            body: (
+                <ul className="text-sm space-y-1">
+                    {answer.points?.map((point) => (
+                        <li
+                            key={String(point.start)}
+                            className={`flex justify-between ${point.is_spike ? "font-semibold text-indigo-700 dark:text-indigo-300" : "text-gray-600 dark:text-gray-400"}`}
+                        >
+                            <span>{formatDate(point.start)}</span>
+                            <span>{Number(point.count)}</span>
+                        </li>
+                    ))}
+                </ul>
+            )
+        },
+        Sentiment: {
+            icon: <FiSmile />,
+            title: `Sentiment${answer.truncated ? " (partial)" : ""}`,
+            body: answer.summary && (
+                <div className="text-sm text-gray-600 dark:text-gray-400 space-y-2">
+                    <div>
+                        Positive {Number(answer.summary.overall.positive_count)}, neutral{" "}
+                        {Number(answer.summary.overall.neutral_count)}, negative{" "}
+                        {Number(answer.summary.overall.negative_count)} (mean{" "}
+                        {answer.summary.overall.mean_compound.toFixed(2)})
+                    </div>
+                    <ul className="space-y-1">
+                        {answer.summary.by_sender.map((sender) => (
+                            <li key={sender.sender_id} className="flex justify-between">
+                                <span>{sender.sender_name}</span>
+                                <span>{sender.stats.mean_compound.toFixed(2)}</span>
+                            </li>
+                        ))}
+                    </ul>
+                </div>
+            )
+        },
+        Relationships: {
+            icon: <FiShare2 />,
+            title: "Connections",
+            body: (
+                <ul className="text-sm text-gray-600 dark:text-gray-400 space-y-1">
+                    {answer.edges?.map((edge) => {
+                        const name = (key) => answer.nodes.find((node) => node.key === key)?.name ?? key;
+                        return (
+                            <li key={`${edge.source}-${edge.target}`} className="flex justify-between">
+                                <span>{name(edge.source)} and {name(edge.target)}</span>
+                                <span>{Number(edge.weight)}</span>
+                            </li>
+                        );
+                    })}
+                </ul>
+            )
+        }
+    };
+
+    const section = sections[kind];
+    if (!section) return null;
+
+    return (
+        <div className="card mb-6">
+            <div className="card-header flex items-center">
+                <span className="mr-2 text-indigo-500">{section.icon}</span>
+                <h3 className="text-md font-medium">{section.title}</h3>
+            </div>
+            <div className="card-body">{section.body}</div>
+        </div>
+    );
+};
+
+export default QueryAnswerDetails;
@@ -43,7 +43,7 @@ export const PlatformProvider = ({ children }) => {
                 setIsConnectingPlatform(true);
                 setError(null);
 
-                const result = await actor.connect_platform(authConfig);
+                const result = await actor.connect_platform(authConfig, [], []);
 
                 if ("Ok" in result) {
                     // Refresh the list of connected platforms
@@ -99,7 +99,7 @@ export const PlatformProvider = ({ children }) => {
             try {
                 setError(null);
                 const platformEnum = { [platform]: null };
-                const result = await actor.sync_messages(platformEnum);
+                const result = await actor.sync_messages(platformEnum, []);
 
                 if ("Ok" in result) {
                     return result.Ok;
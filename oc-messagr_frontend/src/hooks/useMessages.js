@@ -46,7 +46,7 @@ export const useMessages = () => {
 
                 // In a real implementation, this would be a specific API call
                 // Here we're using the general sync method
-                const result = await actor.sync_messages(platformEnum);
+                const result = await actor.sync_messages(platformEnum, []);
 
                 if ("Ok" in result) {
                     return result.Ok;
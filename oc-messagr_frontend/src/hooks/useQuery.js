@@ -1,6 +1,52 @@
 import { useState, useCallback } from "react";
 import { useApi } from "./useApi";
 
+// AI queries answer with one variant per detected intent. Each is flattened to
+// { kind, messages, context, answer } so result pages can list any messages
+// and render the rest of the answer by kind.
+const unwrapQueryAnswer = (queryAnswer) => {
+    const [kind, answer] = Object.entries(queryAnswer)[0];
+
+    switch (kind) {
+        case "Search":
+            return { kind, messages: answer.messages, context: answer.context, answer };
+        case "Entities":
+            return { kind, messages: answer.messages, context: `${answer.entities.length} matching entities`, answer };
+        case "TimeRange":
+            return {
+                kind,
+                messages: [],
+                context: `${answer.message_count} messages in this period${answer.truncated ? " (partial count)" : ""}`,
+                answer
+            };
+        case "Summary":
+            return { kind, messages: [], context: answer.text, answer };
+        case "Trend":
+            return {
+                kind,
+                messages: [],
+                context: `${answer.total} messages about "${answer.query}", ${answer.spikes.length} spikes`,
+                answer
+            };
+        case "Sentiment":
+            return {
+                kind,
+                messages: [],
+                context: `Sentiment about "${answer.topic}" across ${answer.summary.overall.message_count} messages`,
+                answer
+            };
+        case "Relationships":
+            return {
+                kind,
+                messages: [],
+                context: `${answer.nodes.length} entities, ${answer.edges.length} connections`,
+                answer
+            };
+        default:
+            return { kind, messages: [], context: "Unsupported answer", answer };
+    }
+};
+
 export const useQuery = () => {
     const { actor } = useApi();
     const [isQuerying, setIsQuerying] = useState(false);
@@ -201,12 +247,13 @@ export const useQuery = () => {
                 const result = await actor.ai_enhanced_query(queryText);
 
                 if ("Ok" in result) {
-                    return result.Ok;
+                    return unwrapQueryAnswer(result.Ok);
                 } else if ("Err" in result) {
                     throw new Error(formatError(result.Err));
                 }
 
                 return {
+                    kind: "Search",
                     messages: [],
                     context: "No results found"
                 };
@@ -236,12 +283,13 @@ export const useQuery = () => {
                 const result = await actor.ai_query_platform(queryText, platformEnum);
 
                 if ("Ok" in result) {
-                    return result.Ok;
+                    return unwrapQueryAnswer(result.Ok);
                 } else if ("Err" in result) {
                     throw new Error(formatError(result.Err));
                 }
 
                 return {
+                    kind: "Search",
                     messages: [],
                     context: `No results found for ${platform}`
                 };
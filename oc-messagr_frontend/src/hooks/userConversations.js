@@ -26,7 +26,7 @@ export const useConversations = () => {
                 for (const platform of platforms) {
                     try {
                         const platformEnum = { [platform]: null };
-                        const result = await actor.get_conversations(platformEnum);
+                        const result = await actor.get_conversations(platformEnum, []);
 
                         if ("Ok" in result) {
                             allConversations.push(...result.Ok);
@@ -22,6 +22,7 @@ import {
     FaWhatsapp,
 } from "react-icons/fa";
 import AIEnhancedQueryInput from "../components/AIEnhancedQueryInput";
+import QueryAnswerDetails from "../components/QueryAnswerDetails";
 
 const Conversations = () => {
     const navigate = useNavigate();
@@ -165,6 +166,9 @@ const Conversations = () => {
                 </div>
             )}
 
+            {/* Answer details for AI queries that aren't a plain search */}
+            <QueryAnswerDetails result={queryResults} />
+
             {/* Filter and Sort Controls */}
             <div className="mb-6 flex flex-col md:flex-row md:items-center md:justify-between gap-4">
                 <div className="flex items-center space-x-2">
@@ -344,7 +348,7 @@ const Conversations = () => {
                                 </div>
                             </Link>
                         ))
-                    ) : (
+                    ) : queryResults.kind && queryResults.kind !== "Search" ? null : (
                         <div className="text-center py-12">
                             <FiSearch className="mx-auto h-12 w-12 text-gray-400" />
                             <h3 className="mt-2 text-lg font-medium text-gray-900 dark:text-white">